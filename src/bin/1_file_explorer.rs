@@ -1,28 +1,562 @@
 // src/bin/1_file_explorer.rs
 use anyhow::{Context, Result};
-use futures_util::StreamExt;
+use futures_util::{stream, StreamExt};
 use multi_agent_file_processor::{
-    connect_to_nats, setup_tracing, AgentResponse, FileDiscovered, FileListRequest,
-    FileListResponse, ProcessFileRequest,
+    allowed_roots_from_env,
+    compression::{maybe_gunzip, transparent_gzip_from_env, Codec, FileContent},
+    connect_to_nats, encode_response, path_rejection, publish_chunked, queue_group_from_env,
+    resubscribe, run_until_shutdown, setup_tracing, spawn_discovery, subscribe_in_group,
+    validate_path_within_roots, AgentError, AgentResponse, DirSummary, DirSummaryRequest,
+    ErrorCode, ExplorerMetrics, FileDiscovered, FileListRequest, FileListResponse, FileMatches,
+    FileRangeRequest, FileRangeResponse, FileSearchRequest, FileSearchResponse, InFlight,
+    LineMatch, ProcessFileRequest, ScanError, SubjectMetrics,
 };
+use regex::{Regex, RegexBuilder};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::env;
 use std::fs;
-use std::path::Path;
-use tracing::{error, info, instrument};
-
-#[instrument(skip(dir_path))]
-fn scan_directory(dir_path: &str) -> Result<Vec<FileDiscovered>> {
-    info!("[Explorer] Escaneando directorio '{}'...", dir_path);
-    let discovered_files = fs::read_dir(dir_path)?
-        .filter_map(Result::ok)
-        .filter(|e| e.path().is_file())
-        .map(|entry| FileDiscovered {
-            name: entry.file_name().to_string_lossy().to_string(),
-            path: entry.path().to_string_lossy().to_string(),
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Semaphore;
+use tracing::{error, info, instrument, warn};
+
+/// Límite por defecto para un escaneo de directorio (configurable con `SCAN_TIMEOUT_SECS`).
+const DEFAULT_SCAN_TIMEOUT_SECS: u64 = 10;
+
+/// Peticiones atendidas a la vez por defecto (configurable con `EXPLORER_MAX_CONCURRENCY`).
+const DEFAULT_MAX_CONCURRENCY: usize = 8;
+
+/// Líneas coincidentes que devuelve una búsqueda si la petición no fija `max_results`.
+const DEFAULT_SEARCH_MAX_RESULTS: usize = 200;
+
+/// Archivos más grandes no se examinan al buscar (configurable con `SEARCH_MAX_FILE_BYTES`).
+const DEFAULT_SEARCH_MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Archivos examinados a la vez por una búsqueda.
+const SEARCH_CONCURRENCY: usize = 8;
+
+/// Caracteres de cada línea coincidente que se devuelven.
+const SNIPPET_CHARS: usize = 200;
+
+/// Extensiones que el explorador expone. Con `EXPLORER_ALLOWED_EXTENSIONS` solo se
+/// listan y sirven esas (los archivos sin extensión quedan fuera); con
+/// `EXPLORER_DENIED_EXTENSIONS` se ocultan esas. Sin ninguna de las dos, todo vale.
+#[derive(Debug, Default)]
+struct ExtensionFilter {
+    allowed: Option<HashSet<String>>,
+    denied: HashSet<String>,
+}
+
+impl ExtensionFilter {
+    fn from_env() -> Self {
+        let parse = |key: &str| -> Option<HashSet<String>> {
+            let raw = env::var(key).ok()?;
+            let set: HashSet<String> = raw
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect();
+            (!set.is_empty()).then_some(set)
+        };
+        Self {
+            allowed: parse("EXPLORER_ALLOWED_EXTENSIONS"),
+            denied: parse("EXPLORER_DENIED_EXTENSIONS").unwrap_or_default(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.allowed.is_some() || !self.denied.is_empty()
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if let Some(allowed) = &self.allowed {
+            if !ext.as_ref().is_some_and(|e| allowed.contains(e)) {
+                return false;
+            }
+        }
+        !ext.is_some_and(|e| self.denied.contains(&e))
+    }
+
+    /// Mensaje de rechazo para una ruta con extensión no permitida; no revela la lista.
+    fn denial(path: &Path) -> String {
+        let ext = path
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy()))
+            .unwrap_or_else(|| "(sin extensión)".into());
+        format!("Tipo de archivo no permitido: {}", ext)
+    }
+}
+
+/// Ventana deslizante de las métricas de tráfico (configurable con `EXPLORER_METRICS_WINDOW_SECS`).
+const DEFAULT_METRICS_WINDOW_SECS: u64 = 300;
+
+/// Intervalo del resumen de tráfico en el log (`EXPLORER_METRICS_LOG_SECS`; 0 lo desactiva).
+const DEFAULT_METRICS_LOG_SECS: u64 = 60;
+
+/// Peticiones servidas y bytes enviados por subject, con una ventana deslizante para
+/// medias y tasas recientes. Sirve para dimensionar los límites de NATS (`max_payload`).
+struct TrafficMetrics {
+    started: Instant,
+    window: Duration,
+    totals: BTreeMap<&'static str, (u64, u64)>,
+    recent: VecDeque<(Instant, &'static str, u64)>,
+}
+
+type SharedMetrics = Arc<Mutex<TrafficMetrics>>;
+
+impl TrafficMetrics {
+    fn new(window: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            window,
+            totals: BTreeMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Anota una respuesta de `bytes` enviada en `subject`.
+    fn record(&mut self, subject: &'static str, bytes: usize) {
+        let now = Instant::now();
+        let total = self.totals.entry(subject).or_default();
+        total.0 += 1;
+        total.1 += bytes as u64;
+        self.recent.push_back((now, subject, bytes as u64));
+        self.prune(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(t, _, _)| now.duration_since(*t) > self.window)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    fn snapshot(&mut self) -> ExplorerMetrics {
+        let now = Instant::now();
+        self.prune(now);
+        // Hasta completar la primera ventana la tasa se calcula sobre el tiempo transcurrido.
+        let span = now
+            .duration_since(self.started)
+            .min(self.window)
+            .as_secs_f64()
+            .max(1.0);
+        let mut subjects = BTreeMap::new();
+        for (subject, (requests, bytes)) in &self.totals {
+            let (window_requests, window_bytes) = self
+                .recent
+                .iter()
+                .filter(|(_, s, _)| s == subject)
+                .fold((0u64, 0u64), |(n, b), (_, _, bytes)| (n + 1, b + bytes));
+            subjects.insert(
+                subject.to_string(),
+                SubjectMetrics {
+                    requests: *requests,
+                    bytes: *bytes,
+                    window_requests,
+                    window_bytes,
+                    window_avg_bytes: window_bytes.checked_div(window_requests).unwrap_or(0),
+                    window_rate: window_requests as f64 / span,
+                },
+            );
+        }
+        ExplorerMetrics {
+            window_secs: self.window.as_secs(),
+            uptime_secs: now.duration_since(self.started).as_secs(),
+            subjects,
+        }
+    }
+}
+
+/// Escribe en el log, cada `every`, el tráfico de la ventana de cada subject con actividad.
+fn spawn_metrics_log(metrics: SharedMetrics, every: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+        loop {
+            ticker.tick().await;
+            let snapshot = metrics.lock().unwrap_or_else(|e| e.into_inner()).snapshot();
+            for (subject, m) in snapshot
+                .subjects
+                .iter()
+                .filter(|(_, m)| m.window_requests > 0)
+            {
+                info!(
+                    "[Explorer] Tráfico '{}' (últimos {}s): {} peticiones ({:.2}/s), {} bytes, media {} bytes — total {} peticiones, {} bytes",
+                    subject, snapshot.window_secs, m.window_requests, m.window_rate, m.window_bytes, m.window_avg_bytes, m.requests, m.bytes
+                );
+            }
+        }
+    });
+}
+
+/// Responde en `subject` con las métricas actuales (solo si se configuró `EXPLORER_METRICS_SUBJECT`).
+fn spawn_metrics_endpoint(client: async_nats::Client, subject: String, metrics: SharedMetrics) {
+    tokio::spawn(async move {
+        let mut sub = match client.subscribe(subject.clone()).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(
+                    "[Explorer] No se pudo exponer las métricas en '{}': {}",
+                    subject, e
+                );
+                return;
+            }
+        };
+        info!("[Explorer] Métricas disponibles en '{}'.", subject);
+        loop {
+            let Some(msg) = sub.next().await else {
+                match resubscribe(&client, "Explorer", &subject, None).await {
+                    Ok(s) => sub = s,
+                    Err(e) => {
+                        warn!("[Explorer] {:#}", e);
+                        return;
+                    }
+                }
+                continue;
+            };
+            let snapshot = metrics.lock().unwrap_or_else(|e| e.into_inner()).snapshot();
+            if let (Some(reply), Ok(payload)) = (
+                msg.reply,
+                serde_json::to_vec(&AgentResponse::Success(snapshot)),
+            ) {
+                client.publish(reply, payload.into()).await.ok();
+            }
+        }
+    });
+}
+
+#[instrument(skip(dir_path, filter))]
+fn scan_directory(
+    dir_path: &str,
+    recursive: bool,
+    filter: &ExtensionFilter,
+) -> Result<FileListResponse> {
+    info!(
+        "[Explorer] Escaneando directorio '{}'{}...",
+        dir_path,
+        if recursive { " (recursivo)" } else { "" }
+    );
+    // El directorio raíz debe poder leerse; los fallos por debajo solo se anotan.
+    let mut pending = vec![(PathBuf::from(dir_path), fs::read_dir(dir_path)?)];
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    while let Some((dir, entries)) = pending.pop() {
+        for entry in entries {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    errors.push(ScanError {
+                        path: dir.to_string_lossy().to_string(),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if recursive && entry.file_type().is_ok_and(|t| t.is_dir()) {
+                match fs::read_dir(&path) {
+                    Ok(sub) => pending.push((path, sub)),
+                    Err(e) => errors.push(ScanError {
+                        path: path.to_string_lossy().to_string(),
+                        reason: e.to_string(),
+                    }),
+                }
+            } else if path.is_file() && filter.allows(&path) {
+                files.push(FileDiscovered {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                    path: path.to_string_lossy().to_string(),
+                });
+            }
+        }
+    }
+    if errors.is_empty() {
+        info!("[Explorer] Se encontraron {} archivos.", files.len());
+    } else {
+        warn!(
+            "[Explorer] Se encontraron {} archivos; {} rutas omitidas.",
+            files.len(),
+            errors.len()
+        );
+    }
+    Ok(FileListResponse { files, errors })
+}
+
+/// Ejecuta `scan_directory` en un hilo bloqueante y aborta la espera si supera `timeout`.
+/// El hilo puede seguir vivo (no se puede cancelar una llamada al SO), pero el agente responde.
+async fn scan_directory_with_timeout(
+    dir_path: String,
+    recursive: bool,
+    filter: Arc<ExtensionFilter>,
+    timeout: Duration,
+) -> Result<FileListResponse> {
+    let task = tokio::task::spawn_blocking(move || scan_directory(&dir_path, recursive, &filter));
+    match tokio::time::timeout(timeout, task).await {
+        Ok(joined) => joined.context("La tarea de escaneo terminó de forma inesperada")?,
+        Err(_) => Err(AgentError::new(
+            ErrorCode::Timeout,
+            format!("El escaneo superó {} segundos", timeout.as_secs()),
+        )
+        .into()),
+    }
+}
+
+/// Agrupa por extensión los archivos de un listado, con su número y tamaño total.
+/// Los archivos cuyo tamaño no se puede leer pasan a `errors`.
+fn summarize_listing(listing: FileListResponse) -> DirSummary {
+    let mut summary = DirSummary {
+        errors: listing.errors,
+        ..Default::default()
+    };
+    for file in listing.files {
+        let len = match fs::metadata(&file.path) {
+            Ok(meta) => meta.len(),
+            Err(e) => {
+                summary.errors.push(ScanError {
+                    path: file.path,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let ext = Path::new(&file.name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let stats = summary.extensions.entry(ext).or_default();
+        stats.count += 1;
+        stats.bytes += len;
+        summary.total_files += 1;
+        summary.total_bytes += len;
+    }
+    summary
+}
+
+/// Compila la consulta: literal (escapada) o expresión regular, según la petición.
+fn search_pattern(request: &FileSearchRequest) -> Result<Regex> {
+    if request.query.is_empty() {
+        return Err(AgentError::new(ErrorCode::InvalidRequest, "La búsqueda está vacía").into());
+    }
+    let pattern = if request.regex {
+        request.query.clone()
+    } else {
+        regex::escape(&request.query)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!request.case_sensitive)
+        .build()
+        .map_err(|e| {
+            AgentError::new(
+                ErrorCode::InvalidRequest,
+                format!("Expresión regular inválida: {}", e),
+            )
+            .into()
+        })
+}
+
+/// Busca `pattern` línea a línea, hasta `limit` coincidencias. Devuelve `None` si el
+/// archivo se omite por superar `max_bytes` o por parecer binario.
+fn search_file(
+    path: &Path,
+    pattern: &Regex,
+    max_bytes: u64,
+    limit: usize,
+) -> std::io::Result<Option<Vec<LineMatch>>> {
+    if fs::metadata(path)?.len() > max_bytes {
+        return Ok(None);
+    }
+    let bytes = fs::read(path)?;
+    // Un byte nulo en el primer bloque delata un archivo binario.
+    if bytes.iter().take(8192).any(|b| *b == 0) {
+        return Ok(None);
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| pattern.is_match(line))
+        .take(limit)
+        .map(|(i, line)| LineMatch {
+            line: i as u64 + 1,
+            snippet: snippet(line),
         })
-        .collect::<Vec<_>>();
-    info!("[Explorer] Se encontraron {} archivos.", discovered_files.len());
-    Ok(discovered_files)
+        .collect();
+    Ok(Some(lines))
+}
+
+fn snippet(line: &str) -> String {
+    let line = line.trim();
+    if line.chars().count() <= SNIPPET_CHARS {
+        line.to_string()
+    } else {
+        format!("{}…", line.chars().take(SNIPPET_CHARS).collect::<String>())
+    }
+}
+
+/// Lista `dir` y busca en sus archivos con concurrencia acotada, hasta reunir
+/// `max_results` líneas. Los archivos ilegibles se anotan en `errors`.
+async fn search_directory(
+    dir: String,
+    request: FileSearchRequest,
+    filter: Arc<ExtensionFilter>,
+    scan_timeout: Duration,
+    max_file_bytes: u64,
+) -> Result<FileSearchResponse> {
+    let pattern = Arc::new(search_pattern(&request)?);
+    let limit = request
+        .max_results
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_SEARCH_MAX_RESULTS);
+    let listing = scan_directory_with_timeout(dir, request.recursive, filter, scan_timeout).await?;
+    let mut response = FileSearchResponse {
+        errors: listing.errors,
+        ..Default::default()
+    };
+
+    let mut searches = stream::iter(listing.files)
+        .map(|file| {
+            let pattern = Arc::clone(&pattern);
+            async move {
+                let path = PathBuf::from(&file.path);
+                let found = tokio::task::spawn_blocking(move || {
+                    search_file(&path, &pattern, max_file_bytes, limit)
+                })
+                .await;
+                (file, found)
+            }
+        })
+        .buffer_unordered(SEARCH_CONCURRENCY);
+    let mut total = 0;
+    while let Some((file, found)) = searches.next().await {
+        let mut lines = match found {
+            Ok(Ok(Some(lines))) => {
+                response.files_scanned += 1;
+                lines
+            }
+            Ok(Ok(None)) => continue,
+            Ok(Err(e)) => {
+                response.errors.push(ScanError {
+                    path: file.path,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+            Err(e) => {
+                response.errors.push(ScanError {
+                    path: file.path,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        if lines.is_empty() {
+            continue;
+        }
+        lines.truncate(limit - total);
+        total += lines.len();
+        response.files.push(FileMatches {
+            name: file.name,
+            path: file.path,
+            lines,
+        });
+        if total >= limit {
+            response.truncated = true;
+            break;
+        }
+    }
+    response.files.sort_by(|a, b| a.path.cmp(&b.path));
+    info!(
+        "[Explorer] Búsqueda: {} líneas en {} archivos ({} examinados).",
+        total,
+        response.files.len(),
+        response.files_scanned
+    );
+    Ok(response)
+}
+
+/// Lee solo el rango pedido del archivo, sin cargarlo entero en memoria.
+async fn read_range(path: &Path, request: &FileRangeRequest) -> Result<FileRangeResponse> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .context(format!("No se pudo abrir '{}'", request.path))?;
+    let total_len = file.metadata().await?.len();
+
+    let (offset, length) = match request.tail_bytes {
+        Some(tail) => (total_len.saturating_sub(tail), tail.min(total_len)),
+        None => {
+            let offset = request.offset.unwrap_or(0);
+            if offset > total_len {
+                anyhow::bail!(
+                    "Rango fuera de límites: offset {} > tamaño {}",
+                    offset,
+                    total_len
+                );
+            }
+            let length = request.length.unwrap_or(total_len - offset);
+            match offset.checked_add(length) {
+                Some(end) if end <= total_len => (offset, length),
+                _ => anyhow::bail!(
+                    "Rango fuera de límites: offset {} + {} bytes excede el tamaño {}",
+                    offset,
+                    length,
+                    total_len
+                ),
+            }
+        }
+    };
+
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = Vec::with_capacity(length as usize);
+    file.take(length).read_to_end(&mut buf).await?;
+    Ok(FileRangeResponse {
+        offset,
+        total_len,
+        content: String::from_utf8_lossy(&buf).into_owned(),
+    })
+}
+
+/// Lee el archivo completo tras validar que está dentro de las raíces permitidas.
+/// Los `.gz` se descomprimen salvo que la petición o `TRANSPARENT_GZIP` lo desactiven.
+async fn read_content(
+    request: &ProcessFileRequest,
+    allowed_roots: &[PathBuf],
+    filter: &ExtensionFilter,
+) -> AgentResponse<String> {
+    match validate_path_within_roots(&request.path, allowed_roots) {
+        Err(e) => {
+            warn!("[Explorer] Ruta rechazada '{}': {}", &request.path, e);
+            AgentResponse::Error(path_rejection(&request.path, allowed_roots, &e))
+        }
+        Ok(path) if !filter.allows(&path) => {
+            warn!("[Explorer] Extensión no permitida: '{}'", &request.path);
+            AgentResponse::error(ErrorCode::AccessDenied, ExtensionFilter::denial(&path))
+        }
+        Ok(path) => match read_text(
+            &path,
+            request.decompress.unwrap_or_else(transparent_gzip_from_env),
+        )
+        .await
+        {
+            Ok(content) => AgentResponse::Success(content),
+            Err(e) => {
+                error!(
+                    "[Explorer] Error al leer archivo '{}': {}",
+                    &request.path, e
+                );
+                AgentResponse::failed(&e.context(format!("No se pudo leer '{}'", &request.path)))
+            }
+        },
+    }
+}
+
+async fn read_text(path: &Path, decompress: bool) -> Result<String> {
+    let bytes = tokio::fs::read(path).await?;
+    let bytes = maybe_gunzip(path, bytes, decompress)?;
+    String::from_utf8(bytes).context("El contenido no es UTF-8 válido")
 }
 
 #[tokio::main]
@@ -32,37 +566,350 @@ async fn main() -> Result<()> {
 
     let client = connect_to_nats().await?;
     info!("[Explorer] Agente conectado a NATS.");
-    let dir_to_scan = env::var("DIRECTORY_TO_SCAN").context("DIRECTORY_TO_SCAN no está definida")?;
+    let dir_to_scan =
+        env::var("DIRECTORY_TO_SCAN").context("DIRECTORY_TO_SCAN no está definida")?;
+    let allowed_roots = Arc::new(allowed_roots_from_env());
+    let codec = Codec::from_env();
+    info!("[Explorer] Raíces permitidas: {:?}", allowed_roots);
+    let filter = Arc::new(ExtensionFilter::from_env());
+    if filter.is_active() {
+        info!("[Explorer] Filtro de extensiones: {:?}", filter);
+    }
+    let scan_timeout = Duration::from_secs(
+        env::var("SCAN_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_SCAN_TIMEOUT_SECS),
+    );
+    let max_concurrency = env::var("EXPLORER_MAX_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n: &usize| *n > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENCY);
+    let search_max_file_bytes = env::var("SEARCH_MAX_FILE_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SEARCH_MAX_FILE_BYTES);
+    // Limita las peticiones en curso: con el cupo lleno el bucle espera en vez de lanzar más tareas.
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+    info!("[Explorer] Concurrencia máxima: {}", max_concurrency);
+    let metrics_secs = |key: &str, default: u64| {
+        env::var(key)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let metrics: SharedMetrics = Arc::new(Mutex::new(TrafficMetrics::new(Duration::from_secs(
+        metrics_secs("EXPLORER_METRICS_WINDOW_SECS", DEFAULT_METRICS_WINDOW_SECS).max(1),
+    ))));
+    let log_every = metrics_secs("EXPLORER_METRICS_LOG_SECS", DEFAULT_METRICS_LOG_SECS);
+    if log_every > 0 {
+        spawn_metrics_log(Arc::clone(&metrics), Duration::from_secs(log_every));
+    }
+    if let Some(subject) = env::var("EXPLORER_METRICS_SUBJECT")
+        .ok()
+        .filter(|s| !s.trim().is_empty())
+    {
+        spawn_metrics_endpoint(client.clone(), subject, Arc::clone(&metrics));
+    }
 
-    let mut list_sub = client.subscribe("files.list.request").await?;
-    let mut content_sub = client.subscribe("file.request.content").await?;
+    let group = queue_group_from_env();
+    if let Some(g) = &group {
+        info!(
+            "[Explorer] Grupo de cola '{}': las peticiones se reparten entre instancias.",
+            g
+        );
+    }
+    let mut list_sub = subscribe_in_group(&client, "files.list.request", group.as_deref()).await?;
+    let mut content_sub =
+        subscribe_in_group(&client, "file.request.content", group.as_deref()).await?;
+    let mut range_sub = subscribe_in_group(&client, "file.request.range", group.as_deref()).await?;
+    let mut summary_sub =
+        subscribe_in_group(&client, "dir.summary.request", group.as_deref()).await?;
+    let mut search_sub =
+        subscribe_in_group(&client, "files.search.request", group.as_deref()).await?;
+    spawn_discovery(
+        &client,
+        "File Explorer",
+        &[
+            "files.list.request",
+            "file.request.content",
+            "file.request.range",
+            "dir.summary.request",
+            "files.search.request",
+        ],
+    );
 
-    info!("[Explorer] Escuchando en 'files.list.request' y 'file.request.content'");
+    info!(
+        "[Explorer] Escuchando en 'files.list.request', 'file.request.content', 'file.request.range', 'dir.summary.request' y 'files.search.request'"
+    );
 
-    loop {
-        tokio::select! {
-            Some(msg) = list_sub.next() => {
-                let _req: FileListRequest = serde_json::from_slice(&msg.payload)?;
-                let response = match scan_directory(&dir_to_scan) {
-                    Ok(files) => AgentResponse::Success(FileListResponse { files }),
-                    Err(e) => {
-                        error!("[Explorer] Error al escanear directorio: {}", e);
-                        AgentResponse::Error(format!("Error del explorador al escanear: {}", e))
-                    }
-                };
-                if let Some(reply) = msg.reply { client.publish(reply, serde_json::to_vec(&response)?.into()).await?; }
+    let tasks = InFlight::default();
+    let in_flight = tasks.clone();
+    let control = client.clone();
+    // El bucle es dueño de las suscripciones: al soltarlo en el apagado dejan de entrar peticiones.
+    let service = async move {
+        // Solo sale del bucle si no se puede recuperar una suscripción perdida.
+        let outcome: Result<()> = loop {
+            tokio::select! {
+                msg = list_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Explorer", "files.list.request", group.as_deref()).await {
+                            Ok(s) => list_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    // Se acepta payload vacío o `null` por compatibilidad con clientes antiguos.
+                    let req: FileListRequest = if msg.payload.is_empty() || msg.payload.as_ref() == b"null" {
+                        FileListRequest::default()
+                    } else {
+                        match serde_json::from_slice(&msg.payload) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                error!("[Explorer] Solicitud de listado malformada: {}", e);
+                                continue;
+                            }
+                        }
+                    };
+                    let client = client.clone();
+                    let recursive = req.recursive;
+                    let dir = match req.directory {
+                        Some(d) => validate_path_within_roots(&d, &allowed_roots)
+                            .map(|p| p.to_string_lossy().to_string())
+                            .map_err(|e| {
+                                warn!("[Explorer] Ruta rechazada '{}': {}", d, e);
+                                anyhow::Error::from(path_rejection(&d, &allowed_roots, &e))
+                            }),
+                        None => Ok(dir_to_scan.clone()),
+                    };
+                    let metrics = Arc::clone(&metrics);
+                    let filter = Arc::clone(&filter);
+                    let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                    // Se atiende en su propia tarea para no bloquear el resto de subjects.
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        let scanned = match dir {
+                            Ok(dir) => scan_directory_with_timeout(dir, recursive, filter, scan_timeout).await,
+                            Err(e) => Err(e),
+                        };
+                        let response = match scanned {
+                            Ok(listing) => AgentResponse::Success(listing),
+                            Err(e) => {
+                                error!("[Explorer] Error al escanear directorio: {}", e);
+                                AgentResponse::failed(&e.context("Error del explorador al escanear"))
+                            }
+                        };
+                        if let (Some(reply), Ok(payload)) = (msg.reply, serde_json::to_vec(&response)) {
+                            metrics.lock().unwrap_or_else(|e| e.into_inner()).record("files.list.request", payload.len());
+                            client.publish(reply, payload.into()).await.ok();
+                        }
+                    });
+                }
+                msg = content_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Explorer", "file.request.content", group.as_deref()).await {
+                            Ok(s) => content_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: ProcessFileRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Explorer] Solicitud de contenido malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    let client = client.clone();
+                    let roots = Arc::clone(&allowed_roots);
+                    let metrics = Arc::clone(&metrics);
+                    let filter = Arc::clone(&filter);
+                    let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        let started = Instant::now();
+                        let response = read_content(&request, &roots, &filter).await;
+                        let payload = if request.compress {
+                            let response = match response {
+                                AgentResponse::Success(text) => match FileContent::encode(text, codec) {
+                                    Ok(content) => AgentResponse::Success(content),
+                                    Err(e) => AgentResponse::failed(&e.context(format!("No se pudo comprimir '{}'", &request.path))),
+                                },
+                                AgentResponse::Error(e) => AgentResponse::Error(e),
+                            };
+                            encode_response("file_explorer", started, request.envelope, response)
+                        } else {
+                            encode_response("file_explorer", started, request.envelope, response)
+                        };
+                        // Los contenidos grandes pueden superar el max_payload de NATS: se trocean.
+                        if let (Some(reply), Ok(payload)) = (msg.reply, payload) {
+                            metrics.lock().unwrap_or_else(|e| e.into_inner()).record("file.request.content", payload.len());
+                            if let Err(e) = publish_chunked(&client, reply, payload).await {
+                                error!("[Explorer] No se pudo enviar el contenido de '{}': {}", &request.path, e);
+                            }
+                        }
+                    });
+                }
+                msg = range_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Explorer", "file.request.range", group.as_deref()).await {
+                            Ok(s) => range_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: FileRangeRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Explorer] Solicitud de rango malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    let client = client.clone();
+                    let roots = Arc::clone(&allowed_roots);
+                    let metrics = Arc::clone(&metrics);
+                    let filter = Arc::clone(&filter);
+                    let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        let response = match validate_path_within_roots(&request.path, &roots) {
+                            Err(e) => {
+                                warn!("[Explorer] Ruta rechazada '{}': {}", &request.path, e);
+                                AgentResponse::Error(path_rejection(&request.path, &roots, &e))
+                            }
+                            Ok(path) if !filter.allows(&path) => {
+                                warn!("[Explorer] Extensión no permitida: '{}'", &request.path);
+                                AgentResponse::error(ErrorCode::AccessDenied, ExtensionFilter::denial(&path))
+                            }
+                            Ok(path) => match read_range(&path, &request).await {
+                                Ok(range) => AgentResponse::Success(range),
+                                Err(e) => {
+                                    error!("[Explorer] Error al leer rango de '{}': {}", &request.path, e);
+                                    AgentResponse::failed(&e.context(format!("No se pudo leer el rango de '{}'", &request.path)))
+                                }
+                            },
+                        };
+                        if let (Some(reply), Ok(payload)) = (msg.reply, serde_json::to_vec(&response)) {
+                            metrics.lock().unwrap_or_else(|e| e.into_inner()).record("file.request.range", payload.len());
+                            client.publish(reply, payload.into()).await.ok();
+                        }
+                    });
+                }
+                msg = summary_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Explorer", "dir.summary.request", group.as_deref()).await {
+                            Ok(s) => summary_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let req: DirSummaryRequest = if msg.payload.is_empty() {
+                        DirSummaryRequest::default()
+                    } else {
+                        match serde_json::from_slice(&msg.payload) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                error!("[Explorer] Solicitud de composición malformada: {}", e);
+                                continue;
+                            }
+                        }
+                    };
+                    let client = client.clone();
+                    let dir = match req.directory {
+                        Some(d) => validate_path_within_roots(&d, &allowed_roots)
+                            .map(|p| p.to_string_lossy().to_string())
+                            .map_err(|e| {
+                                warn!("[Explorer] Ruta rechazada '{}': {}", d, e);
+                                anyhow::Error::from(path_rejection(&d, &allowed_roots, &e))
+                            }),
+                        None => Ok(dir_to_scan.clone()),
+                    };
+                    let metrics = Arc::clone(&metrics);
+                    let filter = Arc::clone(&filter);
+                    let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        let summarized = match dir {
+                            Ok(dir) => match scan_directory_with_timeout(dir, req.recursive, filter, scan_timeout).await {
+                                // Un `stat` por archivo: se hace fuera de los hilos del runtime.
+                                Ok(listing) => tokio::task::spawn_blocking(move || summarize_listing(listing))
+                                    .await
+                                    .context("La tarea de composición terminó de forma inesperada"),
+                                Err(e) => Err(e),
+                            },
+                            Err(e) => Err(e),
+                        };
+                        let response = match summarized {
+                            Ok(summary) => AgentResponse::Success(summary),
+                            Err(e) => {
+                                error!("[Explorer] Error al analizar el directorio: {}", e);
+                                AgentResponse::failed(&e.context("Error del explorador al analizar"))
+                            }
+                        };
+                        if let (Some(reply), Ok(payload)) = (msg.reply, serde_json::to_vec(&response)) {
+                            metrics.lock().unwrap_or_else(|e| e.into_inner()).record("dir.summary.request", payload.len());
+                            client.publish(reply, payload.into()).await.ok();
+                        }
+                    });
+                }
+                msg = search_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Explorer", "files.search.request", group.as_deref()).await {
+                            Ok(s) => search_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let req: FileSearchRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Explorer] Solicitud de búsqueda malformada: {}", e);
+                            if let Some(reply) = msg.reply {
+                                let response: AgentResponse<FileSearchResponse> =
+                                    AgentResponse::error(ErrorCode::InvalidRequest, format!("Solicitud de búsqueda malformada: {}", e));
+                                if let Ok(payload) = serde_json::to_vec(&response) {
+                                    client.publish(reply, payload.into()).await.ok();
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    let client = client.clone();
+                    let dir = match req.directory.as_deref() {
+                        Some(d) => validate_path_within_roots(d, &allowed_roots)
+                            .map(|p| p.to_string_lossy().to_string())
+                            .map_err(|e| {
+                                warn!("[Explorer] Ruta rechazada '{}': {}", d, e);
+                                anyhow::Error::from(path_rejection(d, &allowed_roots, &e))
+                            }),
+                        None => Ok(dir_to_scan.clone()),
+                    };
+                    let metrics = Arc::clone(&metrics);
+                    let filter = Arc::clone(&filter);
+                    let permit = Arc::clone(&semaphore).acquire_owned().await?;
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        let searched = match dir {
+                            Ok(dir) => search_directory(dir, req, filter, scan_timeout, search_max_file_bytes).await,
+                            Err(e) => Err(e),
+                        };
+                        let response = match searched {
+                            Ok(found) => AgentResponse::Success(found),
+                            Err(e) => {
+                                error!("[Explorer] Error en la búsqueda: {}", e);
+                                AgentResponse::failed(&e.context("Error del explorador al buscar"))
+                            }
+                        };
+                        if let (Some(reply), Ok(payload)) = (msg.reply, serde_json::to_vec(&response)) {
+                            metrics.lock().unwrap_or_else(|e| e.into_inner()).record("files.search.request", payload.len());
+                            client.publish(reply, payload.into()).await.ok();
+                        }
+                    });
+                }
             }
-            Some(msg) = content_sub.next() => {
-                let request: ProcessFileRequest = serde_json::from_slice(&msg.payload)?;
-                let response = match fs::read_to_string(Path::new(&request.path)) {
-                    Ok(content) => AgentResponse::Success(content),
-                    Err(e) => {
-                        error!("[Explorer] Error al leer archivo '{}': {}", &request.path, e);
-                        AgentResponse::Error(format!("No se pudo leer '{}': {}", &request.path, e))
-                    }
-                };
-                if let Some(reply) = msg.reply { client.publish(reply, serde_json::to_vec(&response)?.into()).await?; }
-            }
-        }
-    }
-}
\ No newline at end of file
+        };
+        outcome
+    };
+    run_until_shutdown("Explorer", &control, &tasks, service).await
+}
@@ -1,11 +1,207 @@
 // src/bin/2_metadata_extractor.rs
 use anyhow::Result;
-use futures_util::StreamExt;
+use futures_util::{stream, StreamExt};
 use multi_agent_file_processor::{
-    connect_to_nats, setup_tracing, AgentResponse, FileMetadata, FileType, ProcessFileRequest,
+    allowed_roots_from_env, connect_to_nats, encode_response, path_rejection, queue_group_from_env,
+    request_json, resubscribe, run_until_shutdown, setup_tracing, spawn_discovery,
+    subscribe_in_group, validate_path_within_roots, AgentResponse, DirMetadataEntry,
+    DirMetadataRequest, FileListRequest, FileListResponse, FileMetadata, FileType, InFlight,
+    ProcessFileRequest,
 };
+use sha2::{Digest, Sha256};
 use std::fs;
-use tracing::{error, info};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Cuenta (líneas, palabras, caracteres) leyendo el archivo por bloques.
+/// Devuelve `None` si el contenido no es UTF-8 válido (archivo binario).
+fn text_stats(path: &Path) -> Option<(u64, u64, u64)> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut chunk = vec![0u8; 64 * 1024];
+    // Bytes de un carácter multibyte partido entre dos bloques.
+    let mut carry: Vec<u8> = Vec::new();
+    let (mut lines, mut words, mut chars) = (0u64, 0u64, 0u64);
+    let mut in_word = false;
+    let mut last_char = None;
+
+    loop {
+        let n = file.read(&mut chunk).ok()?;
+        if n == 0 {
+            break;
+        }
+        carry.extend_from_slice(&chunk[..n]);
+        let valid = match std::str::from_utf8(&carry) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return None,
+        };
+        let text = std::str::from_utf8(&carry[..valid]).ok()?;
+        for c in text.chars() {
+            chars += 1;
+            if c == '\n' {
+                lines += 1;
+            }
+            if c.is_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+            last_char = Some(c);
+        }
+        carry.drain(..valid);
+    }
+
+    if !carry.is_empty() {
+        return None;
+    }
+    // Última línea sin salto final.
+    if matches!(last_char, Some(c) if c != '\n') {
+        lines += 1;
+    }
+    Some((lines, words, chars))
+}
+
+/// SHA-256 del archivo en hexadecimal, leyendo por bloques.
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&chunk[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Metadatos de `path` (ya validada y canónica). `requested` es la ruta tal como llegó:
+/// `symlink_metadata` sobre ella detecta el enlace que `canonicalize` ya ha resuelto.
+async fn extract_metadata(
+    requested: &Path,
+    path: PathBuf,
+    with_stats: bool,
+    with_hash: bool,
+) -> std::io::Result<FileMetadata> {
+    let is_symlink = fs::symlink_metadata(requested)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false);
+    let symlink_target = if is_symlink {
+        fs::read_link(requested)
+            .ok()
+            .map(|t| t.to_string_lossy().to_string())
+    } else {
+        None
+    };
+    let meta = fs::metadata(&path)?;
+    let stats = if with_stats && meta.is_file() {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || text_stats(&path))
+            .await
+            .unwrap_or(None)
+    } else {
+        None
+    };
+    let sha256 = if with_hash && meta.is_file() {
+        match tokio::task::spawn_blocking(move || sha256_file(&path)).await {
+            Ok(result) => Some(result?),
+            Err(_) => None,
+        }
+    } else {
+        None
+    };
+    Ok(FileMetadata {
+        file_type: if is_symlink {
+            FileType::Symlink
+        } else if meta.is_file() {
+            FileType::File
+        } else {
+            FileType::Directory
+        },
+        len_bytes: meta.len(),
+        created: meta.created().ok(),
+        modified: meta.modified().ok(),
+        line_count: stats.map(|s| s.0),
+        word_count: stats.map(|s| s.1),
+        char_count: stats.map(|s| s.2),
+        sha256,
+        is_symlink,
+        symlink_target,
+    })
+}
+
+/// Archivos procesados a la vez en `metadata.dir.request`.
+const DIR_CONCURRENCY: usize = 8;
+
+/// Espera máxima por el listado del explorador.
+const LIST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pide al explorador el listado del directorio y extrae los metadatos de cada archivo
+/// con concurrencia acotada. Los fallos por archivo se anotan en su fila sin abortar el resto.
+async fn extract_dir_metadata(
+    client: &async_nats::Client,
+    request: DirMetadataRequest,
+    allowed_roots: &[PathBuf],
+) -> Result<Vec<DirMetadataEntry>> {
+    let list_request = FileListRequest {
+        directory: Some(request.directory.clone()),
+        recursive: false,
+    };
+    let listing: FileListResponse =
+        request_json(client, "files.list.request", &list_request, LIST_TIMEOUT).await?;
+    info!(
+        "[Metadata] Extrayendo metadatos de {} archivos en '{}'",
+        listing.files.len(),
+        request.directory
+    );
+
+    let with_hash = request.hash;
+    let mut entries: Vec<DirMetadataEntry> = stream::iter(listing.files)
+        .map(|file| async move {
+            let result = match validate_path_within_roots(&file.path, allowed_roots) {
+                Err(e) => Err(path_rejection(&file.path, allowed_roots, &e).to_string()),
+                Ok(path) => extract_metadata(Path::new(&file.path), path, false, with_hash)
+                    .await
+                    .map_err(|e| e.to_string()),
+            };
+            let (metadata, error) = match result {
+                Ok(meta) => (Some(meta), None),
+                Err(e) => (None, Some(e)),
+            };
+            DirMetadataEntry {
+                name: file.name,
+                path: file.path,
+                metadata,
+                error,
+            }
+        })
+        .buffer_unordered(DIR_CONCURRENCY)
+        .collect()
+        .await;
+    // Las entradas que el explorador no pudo leer también aparecen, con su motivo.
+    entries.extend(listing.errors.into_iter().map(|skipped| {
+        DirMetadataEntry {
+            name: Path::new(&skipped.path)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| skipped.path.clone()),
+            path: skipped.path,
+            metadata: None,
+            error: Some(skipped.reason),
+        }
+    }));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -14,26 +210,115 @@ async fn main() -> Result<()> {
 
     let client = connect_to_nats().await?;
     info!("[Metadata] Agente conectado a NATS.");
-    let mut sub = client.subscribe("metadata.request").await?;
-    info!("[Metadata] Escuchando en 'metadata.request'.");
-
-    while let Some(msg) = sub.next().await {
-        let request: ProcessFileRequest = serde_json::from_slice(&msg.payload)?;
-        if let Some(reply) = msg.reply {
-            let response = match fs::metadata(&request.path) {
-                Ok(meta) => AgentResponse::Success(FileMetadata {
-                    file_type: if meta.is_file() { FileType::File } else { FileType::Directory },
-                    len_bytes: meta.len(),
-                    created: meta.created().ok(),
-                    modified: meta.modified().ok(),
-                }),
-                Err(e) => {
-                    error!("[Metadata] Fallo al obtener metadatos para '{}': {}", request.path, e);
-                    AgentResponse::Error(format!("Error al obtener metadatos: {}", e))
-                }
-            };
-            client.publish(reply, serde_json::to_vec(&response)?.into()).await?;
-        }
+    let group = queue_group_from_env();
+    if let Some(g) = &group {
+        info!(
+            "[Metadata] Grupo de cola '{}': las peticiones se reparten entre instancias.",
+            g
+        );
+    }
+    let mut sub = subscribe_in_group(&client, "metadata.request", group.as_deref()).await?;
+    let mut dir_sub = subscribe_in_group(&client, "metadata.dir.request", group.as_deref()).await?;
+    spawn_discovery(
+        &client,
+        "Metadata Extractor",
+        &["metadata.request", "metadata.dir.request"],
+    );
+    info!("[Metadata] Escuchando en 'metadata.request' y 'metadata.dir.request'.");
+    let allowed_roots = Arc::new(allowed_roots_from_env());
+    if allowed_roots.is_empty() {
+        warn!("[Metadata] Sin raíces permitidas (ALLOWED_ROOTS / DIRECTORY_TO_SCAN): se denegará todo acceso.");
     }
-    Ok(())
-}
\ No newline at end of file
+
+    let tasks = InFlight::default();
+    let in_flight = tasks.clone();
+    let control = client.clone();
+    // El bucle es dueño de las suscripciones: al soltarlo en el apagado dejan de entrar peticiones.
+    let service = async move {
+        // Solo sale del bucle si no se puede recuperar una suscripción perdida.
+        let outcome: Result<()> = loop {
+            tokio::select! {
+                msg = sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Metadata", "metadata.request", group.as_deref()).await {
+                            Ok(s) => sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: ProcessFileRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Metadata] Solicitud malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    let Some(reply) = msg.reply else { continue };
+                    let client = client.clone();
+                    let roots = Arc::clone(&allowed_roots);
+                    // El hash de un archivo grande puede tardar: se atiende en su propia tarea.
+                    in_flight.spawn(async move {
+                        let started = Instant::now();
+                        let response = match validate_path_within_roots(&request.path, &roots) {
+                            Err(e) => {
+                                warn!("[Metadata] Ruta rechazada '{}': {}", request.path, e);
+                                AgentResponse::Error(path_rejection(&request.path, &roots, &e))
+                            }
+                            Ok(path) => match extract_metadata(Path::new(&request.path), path, request.text_stats, request.hash).await {
+                                Ok(meta) => AgentResponse::Success(meta),
+                                Err(e) => {
+                                    error!("[Metadata] Fallo al obtener metadatos para '{}': {}", request.path, e);
+                                    AgentResponse::failed(&anyhow::Error::from(e).context("Error al obtener metadatos"))
+                                }
+                            },
+                        };
+                        let payload = match encode_response("metadata_extractor", started, request.envelope, response) {
+                            Ok(p) => p,
+                            Err(e) => {
+                                error!("[Metadata] No se pudo serializar la respuesta para '{}': {}", request.path, e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = client.publish(reply, payload.into()).await {
+                            error!("[Metadata] No se pudo enviar la respuesta para '{}': {}", request.path, e);
+                        }
+                    });
+                }
+                msg = dir_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Metadata", "metadata.dir.request", group.as_deref()).await {
+                            Ok(s) => dir_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: DirMetadataRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Metadata] Solicitud de directorio malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    let Some(reply) = msg.reply else { continue };
+                    let client = client.clone();
+                    let roots = Arc::clone(&allowed_roots);
+                    // Puede tardar (muchos archivos, hashes): se atiende en su propia tarea.
+                    in_flight.spawn(async move {
+                        let response = match extract_dir_metadata(&client, request, &roots).await {
+                            Ok(entries) => AgentResponse::Success(entries),
+                            Err(e) => {
+                                error!("[Metadata] Fallo en metadatos de directorio: {}", e);
+                                AgentResponse::failed(&e)
+                            }
+                        };
+                        if let Ok(payload) = serde_json::to_vec(&response) {
+                            client.publish(reply, payload.into()).await.ok();
+                        }
+                    });
+                }
+            }
+        };
+        outcome
+    };
+    run_until_shutdown("Metadata", &control, &tasks, service).await
+}
@@ -1,54 +1,624 @@
 // src/bin/3_summarizer.rs
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures_util::StreamExt;
 use multi_agent_file_processor::{
-    connect_to_nats,
+    allowed_roots_from_env,
+    compression::{maybe_gunzip, transparent_gzip_from_env},
+    connect_to_nats, encode_response,
     mcp_protocol::{McpMessageTurn, McpRequest, McpResponse},
-    setup_tracing, AgentResponse, ProcessFileRequest,
+    path_rejection, progress_subject, queue_group_from_env, request_json, resubscribe,
+    run_until_shutdown, setup_tracing, spawn_discovery, style_instruction, subscribe_in_group,
+    validate_path_within_roots, AgentError, AgentResponse, BatchSummaryItem, BatchSummaryReport,
+    BatchSummaryRequest, ContentKind, ErrorCode, FollowupRequest, InFlight, MultiSummaryRequest,
+    ProcessFileRequest, ProgressUpdate, SummaryLength,
 };
-use std::time::Duration;
-use tracing::{error, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info, warn};
+
+/// Tiempo máximo de espera por una respuesta del LLM Gateway.
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Prioridad en el Gateway de los resúmenes conjuntos, que ceden el paso a los interactivos.
+const BULK_PRIORITY: u8 = 64;
+
+/// Rutas que admite un resumen conjunto (`summary.multi.request`).
+const MAX_MULTI_PATHS: usize = 50;
+
+/// Tamaño máximo por defecto de un archivo a resumir (configurable con `SUMMARIZER_MAX_BYTES`).
+const DEFAULT_MAX_INPUT_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Resúmenes simultáneos por defecto (configurable con `SUMMARIZER_MAX_CONCURRENCY`).
+const DEFAULT_MAX_CONCURRENCY: usize = 4;
+
+/// Espera máxima por un hueco libre antes de responder "ocupado" (`SUMMARIZER_QUEUE_WAIT_SECS`).
+const DEFAULT_QUEUE_WAIT_SECS: u64 = 5;
+
+/// Temperatura de los resúmenes si la petición no la fija (configurable con `SUMMARIZER_TEMPERATURE`).
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// Archivos de un lote resumidos a la vez si la petición no fija `max_concurrency`.
+const DEFAULT_BATCH_CONCURRENCY: usize = 2;
+
+/// Preguntas (con su respuesta) que conserva una sesión (`SUMMARIZER_SESSION_MAX_TURNS`).
+const DEFAULT_SESSION_MAX_TURNS: usize = 10;
+
+/// Sesiones abiertas a la vez (`SUMMARIZER_MAX_SESSIONS`); cada una guarda el archivo entero,
+/// así que al llegar al límite se descarta la usada hace más tiempo.
+const DEFAULT_MAX_SESSIONS: usize = 32;
+
+/// Inactividad tras la que se olvida una sesión (`SUMMARIZER_SESSION_TTL_SECS`).
+const DEFAULT_SESSION_TTL_SECS: u64 = 1800;
+
+/// Conversación sobre un archivo: prompt de sistema y contenido, el resumen y las
+/// preguntas posteriores, con el modelo que generó el resumen.
+struct Session {
+    turns: Vec<McpMessageTurn>,
+    model: String,
+    provider: Option<String>,
+    last_used: Instant,
+}
+
+/// Turnos fijos de una sesión (sistema, archivo y resumen) que nunca se recortan.
+const SESSION_FIXED_TURNS: usize = 3;
+
+/// Sesiones de `summary.followup.request`, en memoria de esta instancia. Las inactivas
+/// más de `ttl` se descartan al acceder; hay como mucho `max_sessions`, y de cada una se
+/// guardan las últimas `max_turns` preguntas con su respuesta además del archivo y el resumen.
+#[derive(Clone)]
+struct Sessions {
+    inner: Arc<Mutex<HashMap<String, Session>>>,
+    ttl: Duration,
+    max_turns: usize,
+    max_sessions: usize,
+}
+
+impl Sessions {
+    fn from_env() -> Self {
+        let env_num = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|n| *n > 0)
+        };
+        Self {
+            inner: Arc::default(),
+            ttl: Duration::from_secs(
+                env_num("SUMMARIZER_SESSION_TTL_SECS").unwrap_or(DEFAULT_SESSION_TTL_SECS),
+            ),
+            max_turns: env_num("SUMMARIZER_SESSION_MAX_TURNS")
+                .map(|n| n as usize)
+                .unwrap_or(DEFAULT_SESSION_MAX_TURNS),
+            max_sessions: env_num("SUMMARIZER_MAX_SESSIONS")
+                .map(|n| n as usize)
+                .unwrap_or(DEFAULT_MAX_SESSIONS),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        let mut sessions = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let ttl = self.ttl;
+        sessions.retain(|_, s| s.last_used.elapsed() < ttl);
+        sessions
+    }
+
+    /// Abre (o reemplaza) la sesión `id` tras un resumen.
+    fn start(
+        &self,
+        id: String,
+        system_prompt: String,
+        content: String,
+        summary: String,
+        model: String,
+        provider: Option<String>,
+    ) {
+        let turns = vec![
+            McpMessageTurn {
+                role: "system".to_string(),
+                content: system_prompt,
+            },
+            McpMessageTurn {
+                role: "user".to_string(),
+                content,
+            },
+            McpMessageTurn {
+                role: "assistant".to_string(),
+                content: summary,
+            },
+        ];
+        let mut sessions = self.lock();
+        if !sessions.contains_key(&id) && sessions.len() >= self.max_sessions {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                info!(
+                    "[Summarizer] Límite de {} sesiones: se descarta '{}'",
+                    self.max_sessions, oldest
+                );
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(
+            id,
+            Session {
+                turns,
+                model,
+                provider,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    /// Historial de la sesión con `question` añadida, y el modelo con el que continuarla.
+    fn prompt(
+        &self,
+        id: &str,
+        question: &str,
+    ) -> Result<(Vec<McpMessageTurn>, String, Option<String>)> {
+        let mut sessions = self.lock();
+        let session = sessions.get_mut(id).ok_or_else(|| {
+            AgentError::new(
+                ErrorCode::NotFound,
+                format!(
+                    "La sesión '{}' no existe o ha caducado; vuelva a resumir el archivo",
+                    id
+                ),
+            )
+        })?;
+        session.last_used = Instant::now();
+        let mut turns = session.turns.clone();
+        turns.push(McpMessageTurn {
+            role: "user".to_string(),
+            content: question.to_string(),
+        });
+        Ok((turns, session.model.clone(), session.provider.clone()))
+    }
+
+    /// Guarda una pregunta respondida, descartando las más antiguas si se supera `max_turns`.
+    fn record(&self, id: &str, question: String, answer: String) {
+        let mut sessions = self.lock();
+        let Some(session) = sessions.get_mut(id) else {
+            return;
+        };
+        session.turns.push(McpMessageTurn {
+            role: "user".to_string(),
+            content: question,
+        });
+        session.turns.push(McpMessageTurn {
+            role: "assistant".to_string(),
+            content: answer,
+        });
+        // Tras los turnos fijos solo hay pares pregunta/respuesta: el exceso es siempre par.
+        let excess = session
+            .turns
+            .len()
+            .saturating_sub(SESSION_FIXED_TURNS + 2 * self.max_turns);
+        session
+            .turns
+            .drain(SESSION_FIXED_TURNS..SESSION_FIXED_TURNS + excess);
+        session.last_used = Instant::now();
+    }
+}
+
+/// Reserva un hueco de procesamiento o devuelve el error "ocupado" si no llega a tiempo.
+async fn acquire_slot(semaphore: Arc<Semaphore>, wait: Duration) -> Result<OwnedSemaphorePermit> {
+    if semaphore.available_permits() == 0 {
+        warn!(
+            "[Summarizer] Límite de resúmenes simultáneos alcanzado; la petición queda en espera."
+        );
+    }
+    match tokio::time::timeout(wait, semaphore.acquire_owned()).await {
+        Ok(permit) => Ok(permit?),
+        Err(_) => {
+            warn!(
+                "[Summarizer] Petición rechazada: sin hueco libre tras {}s.",
+                wait.as_secs()
+            );
+            Err(AgentError::new(
+                ErrorCode::Busy,
+                "Summarizer ocupado: demasiados resúmenes en curso, inténtelo más tarde",
+            )
+            .into())
+        }
+    }
+}
+
+/// Devuelve la ruta pasada con `--file <ruta>` o `--file=<ruta>`, si la hay; un `--file`
+/// sin ruta es un error de uso en lugar de arrancar el servicio.
+fn file_arg(args: impl IntoIterator<Item = String>) -> Result<Option<String>> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let path = if arg == "--file" {
+            args.next()
+        } else if let Some(path) = arg.strip_prefix("--file=") {
+            Some(path.to_string())
+        } else {
+            continue;
+        };
+        return match path.filter(|p| !p.is_empty()) {
+            Some(path) => Ok(Some(path)),
+            None => bail!("Uso: summarizer [--file <ruta>]: falta la ruta tras --file"),
+        };
+    }
+    Ok(None)
+}
+
+/// Modo de una sola ejecución: resume `path`, imprime el resultado en stdout y termina.
+/// El directorio del propio archivo se permite siempre, además de las raíces configuradas:
+/// quien lo pide por la línea de comandos ya tiene acceso a él.
+async fn run_once(
+    path: String,
+    model: String,
+    provider: Option<String>,
+    mut roots: Vec<PathBuf>,
+) -> Result<()> {
+    let parent = std::fs::canonicalize(&path)
+        .context(format!("No se pudo resolver la ruta: {}", path))?
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| anyhow!("Ruta sin directorio padre: {}", path))?;
+    roots.push(parent);
+    let client = connect_to_nats().await?;
+    info!("[Summarizer] Modo CLI: resumiendo '{}'", path);
+    let request = ProcessFileRequest {
+        path,
+        ..Default::default()
+    };
+    let summary = process_file(&client, request, model, provider, &roots, None, None).await?;
+    println!("{}", summary);
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
-    setup_tracing();
-
-    let client = connect_to_nats().await?;
-    info!("[Summarizer] Agente conectado a NATS.");
-    let mut sub = client.subscribe("summary.request").await?;
-    info!("[Summarizer] Escuchando en 'summary.request'.");
+    let one_shot = file_arg(std::env::args().skip(1))?;
+    if one_shot.is_some() {
+        // En modo CLI los logs van a stderr para no mezclarse con el resumen.
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .init();
+    } else {
+        setup_tracing();
+    }
 
     // Prefijo del modelo permite forzar proveedor desde aquí:
     // openai:gpt-4o-mini | ollama:llama3.1:8b | groq:llama-3.1-70b-versatile
     let summarizer_model =
         std::env::var("SUMMARIZER_MODEL").unwrap_or_else(|_| "openai:gpt-4o-mini".to_string());
     let default_provider = std::env::var("LLM_PROVIDER").ok(); // "openai" | "ollama" | "groq" | "auto"
+    if let Some(path) = one_shot {
+        return run_once(
+            path,
+            summarizer_model,
+            default_provider,
+            allowed_roots_from_env(),
+        )
+        .await;
+    }
+    let allowed_roots = Arc::new(allowed_roots_from_env());
+    if allowed_roots.is_empty() {
+        warn!("[Summarizer] Sin raíces permitidas (ALLOWED_ROOTS / DIRECTORY_TO_SCAN): se denegará todo acceso.");
+    }
+    let env_num = |key: &str| std::env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+    let max_concurrency = env_num("SUMMARIZER_MAX_CONCURRENCY")
+        .filter(|n| *n > 0)
+        .map(|n| n as usize)
+        .unwrap_or(DEFAULT_MAX_CONCURRENCY);
+    let queue_wait = Duration::from_secs(
+        env_num("SUMMARIZER_QUEUE_WAIT_SECS").unwrap_or(DEFAULT_QUEUE_WAIT_SECS),
+    );
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+    info!(
+        "[Summarizer] Máximo de {} resúmenes simultáneos; archivos de hasta {} bytes.",
+        max_concurrency,
+        max_input_bytes()
+    );
+
+    let client = connect_to_nats().await?;
+    info!("[Summarizer] Agente conectado a NATS.");
+    let group = queue_group_from_env();
+    if let Some(g) = &group {
+        info!(
+            "[Summarizer] Grupo de cola '{}': las peticiones se reparten entre instancias.",
+            g
+        );
+    }
+    let mut sub = subscribe_in_group(&client, "summary.request", group.as_deref()).await?;
+    let mut multi_sub =
+        subscribe_in_group(&client, "summary.multi.request", group.as_deref()).await?;
+    let mut batch_sub =
+        subscribe_in_group(&client, "summary.batch.request", group.as_deref()).await?;
+    // Las sesiones viven en cada instancia: con grupo de cola, una pregunta puede llegar a
+    // otra instancia que no la conoce y recibirá "not_found".
+    let mut followup_sub =
+        subscribe_in_group(&client, "summary.followup.request", group.as_deref()).await?;
+    spawn_discovery(
+        &client,
+        "Summarizer",
+        &[
+            "summary.request",
+            "summary.multi.request",
+            "summary.batch.request",
+            "summary.followup.request",
+        ],
+    );
+    info!("[Summarizer] Escuchando en 'summary.request', 'summary.multi.request', 'summary.batch.request' y 'summary.followup.request'.");
+    let sessions = Sessions::from_env();
+
+    let tasks = InFlight::default();
+    let in_flight = tasks.clone();
+    let control = client.clone();
+    // El bucle es dueño de las suscripciones: al soltarlo en el apagado dejan de entrar peticiones.
+    let service = async move {
+        // Solo sale del bucle si no se puede recuperar una suscripción perdida.
+        let outcome: Result<()> = loop {
+            tokio::select! {
+                msg = sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Summarizer", "summary.request", group.as_deref()).await {
+                            Ok(s) => sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: ProcessFileRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Summarizer] Solicitud malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(reply_to) = msg.reply {
+                        let client = client.clone();
+                        let model = summarizer_model.clone();
+                        let provider = default_provider.clone();
+                        let roots = Arc::clone(&allowed_roots);
+                        let semaphore = Arc::clone(&semaphore);
+                        let sessions = sessions.clone();
+
+                        in_flight.spawn(async move {
+                            let started = Instant::now();
+                            let envelope = request.envelope;
+                            let result = match acquire_slot(semaphore, queue_wait).await {
+                                Ok(_permit) => {
+                                    info!("[Summarizer] Procesando solicitud para '{}'", request.path);
+                                    process_file(&client, request, model, provider, &roots, None, Some(&sessions)).await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            let response = match result {
+                                Ok(summary) => AgentResponse::Success(summary),
+                                Err(e) => {
+                                    error!("[Summarizer] Fallo en el procesamiento: {:?}", e);
+                                    AgentResponse::failed(&e)
+                                }
+                            };
 
-    while let Some(msg) = sub.next().await {
-        let request: ProcessFileRequest = serde_json::from_slice(&msg.payload)?;
-        if let Some(reply_to) = msg.reply {
-            let client = client.clone();
-            let model = summarizer_model.clone();
-            let provider = default_provider.clone();
-
-            tokio::spawn(async move {
-                info!("[Summarizer] Procesando solicitud para '{}'", request.path);
-                let response = match process_file(&client, request, model, provider).await {
-                    Ok(summary) => AgentResponse::Success(summary),
-                    Err(e) => {
-                        error!("[Summarizer] Fallo en el procesamiento: {:?}", e);
-                        AgentResponse::Error(e.to_string())
+                            if let Ok(payload) = encode_response("summarizer", started, envelope, response) {
+                                client.publish(reply_to, payload.into()).await.ok();
+                            }
+                        });
                     }
-                };
+                }
+                msg = multi_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Summarizer", "summary.multi.request", group.as_deref()).await {
+                            Ok(s) => multi_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: MultiSummaryRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Summarizer] Solicitud múltiple malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(reply_to) = msg.reply {
+                        let client = client.clone();
+                        let model = summarizer_model.clone();
+                        let provider = default_provider.clone();
+                        let roots = Arc::clone(&allowed_roots);
+                        let semaphore = Arc::clone(&semaphore);
+
+                        in_flight.spawn(async move {
+                            let started = Instant::now();
+                            let envelope = request.envelope;
+                            let result = match acquire_slot(semaphore, queue_wait).await {
+                                Ok(_permit) => {
+                                    info!("[Summarizer] Procesando resumen conjunto de {} archivos", request.paths.len());
+                                    process_files(&client, request, model, provider, &roots).await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            let response = match result {
+                                Ok(summary) => AgentResponse::Success(summary),
+                                Err(e) => {
+                                    error!("[Summarizer] Fallo en el resumen conjunto: {:?}", e);
+                                    AgentResponse::failed(&e)
+                                }
+                            };
 
-                if let Ok(payload) = serde_json::to_vec(&response) {
-                    client.publish(reply_to, payload.into()).await.ok();
+                            if let Ok(payload) = encode_response("summarizer", started, envelope, response) {
+                                client.publish(reply_to, payload.into()).await.ok();
+                            }
+                        });
+                    }
                 }
-            });
-        }
+                msg = batch_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Summarizer", "summary.batch.request", group.as_deref()).await {
+                            Ok(s) => batch_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: BatchSummaryRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Summarizer] Solicitud de lote malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(reply_to) = msg.reply {
+                        let client = client.clone();
+                        let model = summarizer_model.clone();
+                        let provider = default_provider.clone();
+                        let roots = Arc::clone(&allowed_roots);
+                        let semaphore = Arc::clone(&semaphore);
+
+                        in_flight.spawn(async move {
+                            let started = Instant::now();
+                            let envelope = request.envelope;
+                            let concurrency = request
+                                .max_concurrency
+                                .unwrap_or(DEFAULT_BATCH_CONCURRENCY)
+                                .clamp(1, max_concurrency);
+                            info!("[Summarizer] Lote de {} archivos ({} a la vez)", request.paths.len(), concurrency);
+                            let report = process_batch(&client, request, model, provider, roots, semaphore, concurrency).await;
+                            info!("[Summarizer] Lote terminado: {} resumidos, {} con error", report.succeeded, report.failed);
+                            let response = AgentResponse::Success(report);
+                            if let Ok(payload) = encode_response("summarizer", started, envelope, response) {
+                                client.publish(reply_to, payload.into()).await.ok();
+                            }
+                        });
+                    }
+                }
+                msg = followup_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "Summarizer", "summary.followup.request", group.as_deref()).await {
+                            Ok(s) => followup_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let request: FollowupRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            error!("[Summarizer] Pregunta de seguimiento malformada: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(reply_to) = msg.reply {
+                        let client = client.clone();
+                        let semaphore = Arc::clone(&semaphore);
+                        let sessions = sessions.clone();
+
+                        in_flight.spawn(async move {
+                            let started = Instant::now();
+                            let envelope = request.envelope;
+                            let result = match acquire_slot(semaphore, queue_wait).await {
+                                Ok(_permit) => answer_followup(&client, request, &sessions).await,
+                                Err(e) => Err(e),
+                            };
+                            let response = match result {
+                                Ok(answer) => AgentResponse::Success(answer),
+                                Err(e) => {
+                                    error!("[Summarizer] Fallo en la pregunta de seguimiento: {:?}", e);
+                                    AgentResponse::failed(&e)
+                                }
+                            };
+                            if let Ok(payload) = encode_response("summarizer", started, envelope, response) {
+                                client.publish(reply_to, payload.into()).await.ok();
+                            }
+                        });
+                    }
+                }
+            }
+        };
+        outcome
+    };
+    run_until_shutdown("Summarizer", &control, &tasks, service).await
+}
+
+/// Publica un aviso de progreso si la petición trae `request_id`.
+/// Es best-effort: los fallos se ignoran y nunca retrasan la respuesta principal.
+async fn report_progress(
+    client: &async_nats::Client,
+    request_id: Option<&str>,
+    message: &str,
+    step: Option<(u32, u32)>,
+) {
+    let Some(id) = request_id else { return };
+    let update = ProgressUpdate {
+        message: message.to_string(),
+        step: step.map(|s| s.0),
+        total: step.map(|s| s.1),
+    };
+    if let Ok(payload) = serde_json::to_vec(&update) {
+        client
+            .publish(progress_subject(id), payload.into())
+            .await
+            .ok();
     }
-    Ok(())
+}
+
+/// Límite de `SUMMARIZER_MAX_BYTES`, o `DEFAULT_MAX_INPUT_BYTES` si no está o no es válido.
+fn max_input_bytes() -> u64 {
+    std::env::var("SUMMARIZER_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_INPUT_BYTES)
+}
+
+/// Temperatura de `SUMMARIZER_TEMPERATURE`, o `DEFAULT_TEMPERATURE` si no está o no es válida.
+fn default_temperature() -> f32 {
+    std::env::var("SUMMARIZER_TEMPERATURE")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|t| t.is_finite() && *t >= 0.0)
+        .unwrap_or(DEFAULT_TEMPERATURE)
+}
+
+fn too_large(path: &str, size: u64, limit: u64) -> anyhow::Error {
+    AgentError::new(
+        ErrorCode::TooLarge,
+        format!(
+            "Archivo demasiado grande para resumir: {} ({} bytes, máximo {})",
+            path, size, limit
+        ),
+    )
+    .into()
+}
+
+/// Lee un archivo tras comprobar que está dentro de las raíces permitidas;
+/// con `decompress`, los `.gz` se resumen descomprimidos. El tamaño se comprueba antes
+/// de leer (y de nuevo tras descomprimir) contra `max_input_bytes()`.
+async fn read_allowed(path: &str, allowed_roots: &[PathBuf], decompress: bool) -> Result<String> {
+    let resolved = validate_path_within_roots(path, allowed_roots).map_err(|e| {
+        warn!("[Summarizer] Ruta rechazada '{}': {}", path, e);
+        anyhow::Error::from(path_rejection(path, allowed_roots, &e))
+    })?;
+    let limit = max_input_bytes();
+    let size = tokio::fs::metadata(&resolved)
+        .await
+        .context(format!("No se pudo leer el archivo: {}", path))?
+        .len();
+    if size > limit {
+        warn!(
+            "[Summarizer] '{}' rechazado: {} bytes superan el máximo de {}",
+            path, size, limit
+        );
+        return Err(too_large(path, size, limit));
+    }
+    let bytes = tokio::fs::read(&resolved)
+        .await
+        .context(format!("No se pudo leer el archivo: {}", path))?;
+    let bytes = maybe_gunzip(&resolved, bytes, decompress)
+        .context(format!("No se pudo descomprimir: {}", path))?;
+    if bytes.len() as u64 > limit {
+        return Err(too_large(path, bytes.len() as u64, limit));
+    }
+    String::from_utf8(bytes).context(format!("El archivo no es texto UTF-8: {}", path))
 }
 
 async fn process_file(
@@ -56,48 +626,460 @@ async fn process_file(
     request: ProcessFileRequest,
     model: String,
     provider_env: Option<String>,
+    allowed_roots: &[PathBuf],
+    priority: Option<u8>,
+    sessions: Option<&Sessions>,
 ) -> Result<String> {
-    let content = std::fs::read_to_string(&request.path)
-        .context(format!("No se pudo leer el archivo: {}", request.path))?;
+    let target = request
+        .target_length
+        .map(SummaryLength::validate)
+        .transpose()?;
+    let mut system_prompt = request
+        .system_prompt
+        .clone()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or_else(|| {
+            ContentKind::from_path(&request.path)
+                .system_prompt()
+                .to_string()
+        });
+    if let Some(style) = request.style.as_deref().filter(|s| !s.trim().is_empty()) {
+        system_prompt.push(' ');
+        system_prompt.push_str(style_instruction(style)?);
+    }
+    let id = request.request_id.as_deref();
+    report_progress(client, id, "Leyendo archivo", None).await;
+    let decompress = request.decompress.unwrap_or_else(transparent_gzip_from_env);
+    let content = read_allowed(&request.path, allowed_roots, decompress).await?;
+    report_progress(client, id, "Resumiendo con el LLM", None).await;
+    // Un modelo pedido explícitamente decide también el proveedor (por su prefijo o la config del Gateway).
+    let (model, provider_env) = match request.model.filter(|m| !m.trim().is_empty()) {
+        Some(m) => (m, None),
+        None => (model, provider_env),
+    };
+    let options = SummaryOptions {
+        target,
+        temperature: request.temperature,
+        priority,
+    };
+    let Some((sessions, session_id)) = sessions.zip(request.session_id.filter(|id| !id.is_empty()))
+    else {
+        return summarize_text(client, content, model, provider_env, system_prompt, options).await;
+    };
+    let summary = summarize_text(
+        client,
+        content.clone(),
+        model.clone(),
+        provider_env.clone(),
+        system_prompt.clone(),
+        options,
+    )
+    .await?;
+    sessions.start(
+        session_id,
+        system_prompt,
+        content,
+        summary.clone(),
+        model,
+        provider_env,
+    );
+    Ok(summary)
+}
 
-    let mcp_request = McpRequest {
-        model,                    // puede llevar prefijo: openai:/ollama:/groq:
-        provider: provider_env,   // None => decide Gateway
-        messages: vec![
-            McpMessageTurn {
-                role: "system".to_string(),
-                content: "Eres un experto en resumir textos de forma concisa.".to_string(),
-            },
-            McpMessageTurn { role: "user".to_string(), content },
-        ],
-        temperature: Some(0.7),
+/// Responde una pregunta sobre un archivo ya resumido con todo el historial de la sesión.
+async fn answer_followup(
+    client: &async_nats::Client,
+    request: FollowupRequest,
+    sessions: &Sessions,
+) -> Result<String> {
+    let question = request.question.trim().to_string();
+    if question.is_empty() {
+        return Err(AgentError::new(ErrorCode::InvalidRequest, "La pregunta está vacía").into());
+    }
+    let (messages, model, provider_env) = sessions.prompt(&request.session_id, &question)?;
+    info!(
+        "[Summarizer] Pregunta de seguimiento en la sesión '{}' ({} turnos)",
+        request.session_id,
+        messages.len()
+    );
+    let options = SummaryOptions {
+        temperature: request.temperature,
+        ..Default::default()
     };
+    let answer = complete(client, messages, model, provider_env, options).await?;
+    sessions.record(&request.session_id, question, answer.clone());
+    Ok(answer)
+}
 
-    // Request/Reply manual con inbox propio + timeout largo (120 s)
-    let inbox = client.new_inbox();
-    let mut replies = client.subscribe(inbox.clone()).await?;
-    client
-        .publish_with_reply(
-            "mcp.request.completion",
-            inbox,
-            serde_json::to_vec(&mcp_request)?.into(),
+/// Resume cada archivo del lote por separado, hasta `concurrency` a la vez. Cada archivo
+/// espera su hueco en `semaphore` (sin el límite de `SUMMARIZER_QUEUE_WAIT_SECS`: el lote
+/// ya es una operación larga) y va al Gateway con prioridad baja. Un fallo no detiene el
+/// resto; queda en su elemento del informe.
+async fn process_batch(
+    client: &async_nats::Client,
+    request: BatchSummaryRequest,
+    model: String,
+    provider_env: Option<String>,
+    allowed_roots: Arc<Vec<PathBuf>>,
+    semaphore: Arc<Semaphore>,
+    concurrency: usize,
+) -> BatchSummaryReport {
+    let started = Instant::now();
+    let id = request.request_id.as_deref();
+    let total = request.paths.len() as u32;
+    let mut results = futures_util::stream::iter(request.paths.iter().cloned().enumerate())
+        .map(|(index, path)| {
+            let file_request = ProcessFileRequest {
+                path: path.clone(),
+                style: request.style.clone(),
+                model: request.model.clone(),
+                temperature: request.temperature,
+                ..Default::default()
+            };
+            let (model, provider_env) = (model.clone(), provider_env.clone());
+            let (roots, semaphore) = (Arc::clone(&allowed_roots), Arc::clone(&semaphore));
+            async move {
+                let result = match semaphore.acquire_owned().await {
+                    Ok(_permit) => {
+                        process_file(
+                            client,
+                            file_request,
+                            model,
+                            provider_env,
+                            &roots,
+                            Some(BULK_PRIORITY),
+                            None,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                (index, path, result)
+            }
+        })
+        .buffer_unordered(concurrency);
+
+    let mut items: Vec<Option<BatchSummaryItem>> = vec![None; request.paths.len()];
+    let mut report = BatchSummaryReport::default();
+    let mut done = 0;
+    while let Some((index, path, result)) = results.next().await {
+        done += 1;
+        let name = Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.clone());
+        let item = match result {
+            Ok(summary) => {
+                report.succeeded += 1;
+                report_progress(
+                    client,
+                    id,
+                    &format!("Resumido {}", name),
+                    Some((done, total)),
+                )
+                .await;
+                BatchSummaryItem {
+                    path,
+                    summary: Some(summary),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                report.failed += 1;
+                warn!("[Summarizer] Lote: '{}' falló: {:#}", path, e);
+                report_progress(
+                    client,
+                    id,
+                    &format!("Error en {}", name),
+                    Some((done, total)),
+                )
+                .await;
+                BatchSummaryItem {
+                    path,
+                    summary: None,
+                    error: Some(AgentError::from_anyhow(&e)),
+                }
+            }
+        };
+        items[index] = Some(item);
+    }
+    report.items = items.into_iter().flatten().collect();
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    report
+}
+
+/// Rechaza un resumen conjunto sin rutas o con más de `MAX_MULTI_PATHS`.
+fn check_multi_paths(paths: &[String]) -> Result<()> {
+    if paths.is_empty() || paths.len() > MAX_MULTI_PATHS {
+        return Err(AgentError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Se esperan entre 1 y {} rutas; llegaron {}",
+                MAX_MULTI_PATHS,
+                paths.len()
+            ),
         )
-        .await?;
+        .into());
+    }
+    Ok(())
+}
 
-    // timeout :: Result<Option<Message>, Elapsed>
-    let maybe_msg = tokio::time::timeout(Duration::from_secs(120), replies.next())
-        .await
-        .map_err(|_| anyhow::anyhow!("Timeout esperando respuesta del LLM Gateway (120s)."))?;
-    let msg = maybe_msg
-        .ok_or_else(|| anyhow::anyhow!("El LLM Gateway cerró la respuesta sin emitir mensaje"))?;
+/// Concatena los archivos legibles con una cabecera por archivo y produce un único resumen.
+/// Los archivos que no se pueden leer, y los que ya no caben en `max_input_bytes()` en
+/// total, se omiten y se listan como advertencia al final.
+async fn process_files(
+    client: &async_nats::Client,
+    request: MultiSummaryRequest,
+    model: String,
+    provider_env: Option<String>,
+    allowed_roots: &[PathBuf],
+) -> Result<String> {
+    check_multi_paths(&request.paths)?;
+    let limit = max_input_bytes();
+    let mut combined = String::new();
+    let mut warnings = Vec::new();
+    let mut full = false;
+    let id = request.request_id.as_deref();
+    let total = request.paths.len() as u32;
+    for (i, path) in request.paths.iter().enumerate() {
+        if full {
+            warnings.push(format!(
+                "{}: no cabe en el máximo de {} bytes del resumen conjunto",
+                path, limit
+            ));
+            continue;
+        }
+        report_progress(client, id, "Leyendo archivo", Some((i as u32 + 1, total))).await;
+        match read_allowed(path, allowed_roots, transparent_gzip_from_env()).await {
+            Ok(content) => {
+                let name = Path::new(path)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.clone());
+                let section = format!("### {}\n\n{}\n\n", name, content);
+                // A partir del primero que no cabe, el resto ni se lee.
+                if (combined.len() + section.len()) as u64 > limit {
+                    warn!("[Summarizer] Resumen conjunto: '{}' y siguientes superan {} bytes en total", path, limit);
+                    warnings.push(format!(
+                        "{}: no cabe en el máximo de {} bytes del resumen conjunto",
+                        path, limit
+                    ));
+                    full = true;
+                    continue;
+                }
+                combined.push_str(&section);
+            }
+            Err(e) => warnings.push(format!("{}: {}", path, e)),
+        }
+    }
+    if combined.is_empty() {
+        bail!(
+            "Ninguno de los {} archivos se pudo leer",
+            request.paths.len()
+        );
+    }
 
-    let mcp_response: AgentResponse<McpResponse> =
-        serde_json::from_slice(&msg.payload).context("Respuesta del Gateway malformada")?;
+    report_progress(client, id, "Combinando y resumiendo", None).await;
+    let system_prompt = ContentKind::Text.system_prompt().to_string();
+    let options = SummaryOptions {
+        priority: Some(BULK_PRIORITY),
+        ..Default::default()
+    };
+    let mut summary = summarize_text(
+        client,
+        combined,
+        model,
+        provider_env,
+        system_prompt,
+        options,
+    )
+    .await?;
+    if !warnings.is_empty() {
+        summary.push_str("\n\n---\n⚠️ Archivos omitidos:\n");
+        for w in warnings {
+            summary.push_str(&format!("- {}\n", w));
+        }
+    }
+    Ok(summary)
+}
 
-    match mcp_response {
-        AgentResponse::Success(resp) => Ok(resp.content),
-        AgentResponse::Error(e) => bail!("El LLM Gateway devolvió un error: {}", e),
+/// Ajustes de una petición de resumen al Gateway; `None` deja el valor por defecto.
+#[derive(Default)]
+struct SummaryOptions {
+    /// Longitud deseada: se añade al prompt y acota `max_tokens`.
+    target: Option<SummaryLength>,
+    /// `None` usa `SUMMARIZER_TEMPERATURE`; el Gateway la ajusta al rango del proveedor.
+    temperature: Option<f32>,
+    /// Orden en la cola del Gateway.
+    priority: Option<u8>,
+}
+
+/// Envía `content` al LLM Gateway y devuelve el resumen generado.
+async fn summarize_text(
+    client: &async_nats::Client,
+    content: String,
+    model: String,
+    provider_env: Option<String>,
+    mut system_prompt: String,
+    options: SummaryOptions,
+) -> Result<String> {
+    if let Some(t) = options.target {
+        system_prompt.push(' ');
+        system_prompt.push_str(&t.instruction());
     }
+    let messages = vec![
+        McpMessageTurn {
+            role: "system".to_string(),
+            content: system_prompt,
+        },
+        McpMessageTurn {
+            role: "user".to_string(),
+            content,
+        },
+    ];
+    complete(client, messages, model, provider_env, options).await
 }
 
+/// Envía la conversación `messages` al LLM Gateway y devuelve la respuesta.
+async fn complete(
+    client: &async_nats::Client,
+    messages: Vec<McpMessageTurn>,
+    model: String,
+    provider_env: Option<String>,
+    options: SummaryOptions,
+) -> Result<String> {
+    let SummaryOptions {
+        target,
+        temperature,
+        priority,
+    } = options;
+    let mcp_request = McpRequest {
+        model,                  // puede llevar prefijo: openai:/ollama:/groq:
+        provider: provider_env, // None => decide Gateway
+        messages,
+        temperature: Some(temperature.unwrap_or_else(default_temperature)),
+        top_p: None,
+        frequency_penalty: None,
+        presence_penalty: None,
+        stop: None,
+        // El Gateway debe rendirse antes de que expire nuestra propia espera.
+        deadline_secs: Some(GATEWAY_TIMEOUT.as_secs().saturating_sub(5)),
+        json_mode: None,
+        seed: None,
+        max_tokens: target.map(SummaryLength::max_tokens),
+        priority,
+        trim_history: None,
+    };
+
+    // Request/Reply nativo con un timeout largo propio (120 s).
+    let resp: McpResponse = request_json(
+        client,
+        "mcp.request.completion",
+        &mcp_request,
+        GATEWAY_TIMEOUT,
+    )
+    .await
+    .context("LLM Gateway")?;
+    Ok(resp.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(max_turns: usize, max_sessions: usize) -> Sessions {
+        Sessions {
+            inner: Arc::default(),
+            ttl: Duration::from_secs(60),
+            max_turns,
+            max_sessions,
+        }
+    }
+
+    fn open(sessions: &Sessions, id: &str) {
+        let text = |s: &str| s.to_string();
+        sessions.start(
+            text(id),
+            text("sistema"),
+            text("archivo"),
+            text("resumen"),
+            text("modelo"),
+            None,
+        );
+    }
 
+    #[test]
+    fn record_keeps_the_latest_questions() {
+        let sessions = sessions(1, 4);
+        open(&sessions, "a");
+        sessions.record("a", "p1".into(), "r1".into());
+        sessions.record("a", "p2".into(), "r2".into());
+        let (turns, _, _) = sessions.prompt("a", "p3").unwrap();
+        let contents: Vec<&str> = turns.iter().map(|t| t.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["sistema", "archivo", "resumen", "p2", "r2", "p3"]
+        );
+    }
+
+    #[test]
+    fn least_recently_used_session_is_evicted() {
+        let sessions = sessions(4, 2);
+        open(&sessions, "a");
+        std::thread::sleep(Duration::from_millis(5));
+        open(&sessions, "b");
+        std::thread::sleep(Duration::from_millis(5));
+        // Usar "a" la hace más reciente que "b".
+        sessions.prompt("a", "¿?").unwrap();
+        open(&sessions, "c");
+        assert!(sessions.prompt("a", "¿?").is_ok());
+        assert!(sessions.prompt("b", "¿?").is_err());
+        assert!(sessions.prompt("c", "¿?").is_ok());
+    }
+
+    #[test]
+    fn file_arg_requires_a_path() {
+        let args = |a: &[&str]| file_arg(a.iter().map(|s| s.to_string()));
+        assert_eq!(args(&[]).unwrap(), None);
+        assert_eq!(
+            args(&["--file", "a.txt"]).unwrap().as_deref(),
+            Some("a.txt")
+        );
+        assert_eq!(args(&["--file=b.txt"]).unwrap().as_deref(), Some("b.txt"));
+        assert!(args(&["--file"]).is_err());
+        assert!(args(&["--file="]).is_err());
+    }
+
+    #[test]
+    fn multi_paths_must_be_between_one_and_the_maximum() {
+        let paths = |n: usize| (0..n).map(|i| format!("/docs/{i}.txt")).collect::<Vec<_>>();
+        let code = |n: usize| {
+            check_multi_paths(&paths(n))
+                .err()
+                .and_then(|e| e.downcast_ref::<AgentError>().map(|e| e.code))
+        };
+        assert_eq!(code(0), Some(ErrorCode::InvalidRequest));
+        assert_eq!(code(1), None);
+        assert_eq!(code(MAX_MULTI_PATHS), None);
+        assert_eq!(code(MAX_MULTI_PATHS + 1), Some(ErrorCode::InvalidRequest));
+    }
+
+    #[tokio::test]
+    async fn rejected_paths_keep_their_error_code() {
+        let root = std::env::temp_dir().join(format!("mafp-summarizer-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let roots = vec![root.canonicalize().unwrap()];
+        let code = |err: anyhow::Error| err.downcast_ref::<AgentError>().map(|e| e.code);
+        let missing = root.join("no-existe.txt");
+        let err = read_allowed(&missing.to_string_lossy(), &roots, false)
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), Some(ErrorCode::NotFound));
+        let outside = std::env::current_exe().unwrap();
+        let err = read_allowed(&outside.to_string_lossy(), &roots, false)
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), Some(ErrorCode::AccessDenied));
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
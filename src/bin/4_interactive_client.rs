@@ -4,9 +4,17 @@ use anyhow::{Context as AnyhowContext, Result};
 use async_nats::Client as NatsClient;
 use eframe::{egui, egui::Context as EguiContext};
 use egui::{Color32, RichText, TextStyle, Ui};
+use egui_extras::{Column, TableBuilder};
+use futures_util::{stream, StreamExt};
+use multi_agent_file_processor::{
+    compression::{gunzip_limited, is_gzip, transparent_gzip_from_env},
+    connect_with_state, discover_services, BatchSummaryReport, BatchSummaryRequest, mcp_protocol::UsageReport, ServiceInfo, normalize_base_url, not_pulled_model, progress_subject, request_json, request_with_timeout, AgentEnvelope, AgentResponse, ErrorCode,
+    ConnectionState, DirMetadataEntry, DirSummary, DirSummaryRequest, FileMetadata, FileSearchRequest, FileSearchResponse, OllamaPullRequest, ProgressUpdate, SUMMARY_STYLES,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
+    collections::HashMap,
     env, fs,
     io::Read,
     path::{Path, PathBuf},
@@ -15,16 +23,206 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 
+/// Número máximo de archivos recientes que se recuerdan.
+const MAX_RECENT_FILES: usize = 12;
+
+/// Color de acento inicial del tema.
+const DEFAULT_ACCENT: Color32 = Color32::from_rgb(52, 120, 246);
+
+/// Vigencia de la lista de modelos cacheada por proveedor + base URL.
+const MODELS_CACHE_TTL: Duration = Duration::from_secs(120);
+
+/// Espera antes del único reintento al listar modelos.
+const MODELS_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Hilos del runtime de tokio de la GUI (configurable con `GUI_WORKER_THREADS`). La GUI
+/// hace pocas peticiones a la vez: no compensa un hilo por núcleo.
+const DEFAULT_WORKER_THREADS: usize = 2;
+
+/// Entradas del listado que se muestran como máximo (configurable con `GUI_MAX_DIR_ENTRIES`);
+/// con carpetas de cientos de miles de archivos ordenar y pintar todo bloquea la GUI.
+const DEFAULT_MAX_DIR_ENTRIES: usize = 10_000;
+
+/// Peticiones simultáneas de las acciones por lotes (multiselección).
+const BATCH_CONCURRENCY: usize = 4;
+
+/// Timeout por defecto de las consultas cortas: ping, modelos, config, depuración (`GUI_QUICK_TIMEOUT_SECS`).
+const DEFAULT_QUICK_TIMEOUT_SECS: u64 = 10;
+
+/// Timeout por defecto de las peticiones de metadatos, que pueden leer el archivo entero (`GUI_METADATA_TIMEOUT_SECS`).
+const DEFAULT_METADATA_TIMEOUT_SECS: u64 = 60;
+
+/// Timeout por defecto de un resumen; cubre la espera del summarizer al gateway (`GUI_SUMMARY_TIMEOUT_SECS`).
+const DEFAULT_SUMMARY_TIMEOUT_SECS: u64 = 150;
+
+/// Timeouts de las peticiones NATS de la GUI: un agente caído produce un error, no una acción colgada.
+#[derive(Clone, Copy, Debug)]
+struct RequestTimeouts {
+    quick: Duration,
+    metadata: Duration,
+    summary: Duration,
+}
+
+/// Timeouts leídos del entorno la primera vez que se piden.
+fn timeouts() -> RequestTimeouts {
+    static TIMEOUTS: std::sync::OnceLock<RequestTimeouts> = std::sync::OnceLock::new();
+    *TIMEOUTS.get_or_init(|| {
+        let secs = |key: &str, default: u64| {
+            Duration::from_secs(
+                env::var(key).ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(default),
+            )
+        };
+        RequestTimeouts {
+            quick: secs("GUI_QUICK_TIMEOUT_SECS", DEFAULT_QUICK_TIMEOUT_SECS),
+            metadata: secs("GUI_METADATA_TIMEOUT_SECS", DEFAULT_METADATA_TIMEOUT_SECS),
+            summary: secs("GUI_SUMMARY_TIMEOUT_SECS", DEFAULT_SUMMARY_TIMEOUT_SECS),
+        }
+    })
+}
+
+/// Timeout de la exportación de metadatos de una carpeta completa.
+const DIR_METADATA_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Tiempo que se esperan respuestas a `discovery.request` antes de pintar el mapa de servicios.
+const DISCOVERY_WAIT: Duration = Duration::from_millis(1500);
+
+/// Lado máximo (en píxeles) de las miniaturas de imagen de la vista previa.
+const THUMBNAIL_MAX_DIM: u32 = 512;
+
+/// Miniaturas que se mantienen en caché antes de vaciarla.
+const THUMBNAIL_CACHE_LIMIT: usize = 64;
+
+/// Extensiones que la vista previa muestra como imagen.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
+/// Timeout de la descarga de un modelo de Ollama (algo mayor que el del gateway).
+const OLLAMA_PULL_TIMEOUT: Duration = Duration::from_secs(3660);
+
+/// Acción que puede repetirse tras un error transitorio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RetryAction {
+    Metadata,
+    Summary,
+}
+
+impl RetryAction {
+    fn label(self) -> &'static str {
+        match self {
+            RetryAction::Metadata => "metadatos",
+            RetryAction::Summary => "resumen",
+        }
+    }
+}
+
 /// Eventos que envían las tareas async hacia la GUI.
 #[derive(Debug)]
 enum GuiEvent {
+    /// Cambio de estado de la conexión NATS del cliente.
+    Connection(ConnectionState),
     Status(String),
     Error(String),
+    /// Error transitorio (timeout, agente caído, cola llena): la GUI ofrece reintentar.
+    Retryable(String, RetryAction),
     PingMs(u128),
-    Models(Vec<String>),
+    StressPing(PingStats),
+    /// Lista de modelos de un proveedor; `key` es (proveedor, base URL) para la caché.
+    Models { key: (String, String), list: Vec<String> },
     ProviderReport(Value),
+    /// Config efectiva del gateway; `apply` indica si debe copiarse sobre los ajustes locales.
+    GatewayConfig { cfg: Value, apply: bool },
     Metadata(String),
     Summary(String),
+    /// Respuesta del summarizer a una pregunta de seguimiento (`summary.followup.request`).
+    FollowupAnswer(String),
+    /// Línea de estado publicada por el summarizer mientras trabaja.
+    SummaryProgress(String),
+    /// Avance de `summary.batch.request` (archivos terminados / total).
+    BatchProgress(ProgressUpdate),
+    /// Informe final de un resumen por lotes.
+    BatchSummary(BatchSummaryReport),
+    /// cURL equivalente devuelto por `llm.debug.curl`.
+    Curl(String),
+    /// Manifiesto de metadatos de una carpeta, listo para guardarse.
+    DirMetadata(Vec<DirMetadataEntry>),
+    /// Metadatos de los dos archivos comparados (A = fijado, B = seleccionado).
+    Comparison(Box<MetadataComparison>),
+    /// Composición por extensión de una carpeta.
+    DirSummary(PathBuf, DirSummary),
+    SearchResults(FileSearchResponse),
+    /// Avance de la descarga de un modelo de Ollama.
+    PullProgress(String),
+    /// Descarga de modelo terminada (mensaje del gateway).
+    Pulled(String),
+    /// Consumo de una completion publicado por el gateway en `llm.usage`.
+    Usage(UsageReport),
+    /// Agentes que respondieron a `discovery.request`.
+    Services(Vec<ServiceInfo>),
+}
+
+/// Metadatos de dos archivos para la vista de comparación.
+#[derive(Debug)]
+struct MetadataComparison {
+    a: (PathBuf, FileMetadata),
+    b: (PathBuf, FileMetadata),
+}
+
+/// Nivel de una línea del log de eventos.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Deduce el nivel a partir del prefijo del mensaje ("❌" error, "⚠️" aviso).
+    fn infer(line: &str) -> Self {
+        if line.starts_with('❌') {
+            LogLevel::Error
+        } else if line.starts_with('⚠') {
+            LogLevel::Warn
+        } else {
+            LogLevel::Info
+        }
+    }
+
+    fn color(self) -> Option<Color32> {
+        match self {
+            LogLevel::Info => None,
+            LogLevel::Warn => Some(Color32::from_rgb(220, 170, 60)),
+            LogLevel::Error => Some(Color32::from_rgb(220, 80, 80)),
+        }
+    }
+}
+
+/// Resultado de una ráfaga de pings concurrentes.
+#[derive(Clone, Debug)]
+struct PingStats {
+    total: usize,
+    errors: usize,
+    min_ms: f64,
+    avg_ms: f64,
+    p95_ms: f64,
+    max_ms: f64,
+}
+
+impl PingStats {
+    fn from_samples(mut samples: Vec<f64>, total: usize) -> Self {
+        samples.sort_by(|a, b| a.total_cmp(b));
+        let errors = total - samples.len();
+        if samples.is_empty() {
+            return Self { total, errors, min_ms: 0.0, avg_ms: 0.0, p95_ms: 0.0, max_ms: 0.0 };
+        }
+        let p95_idx = ((samples.len() as f64 * 0.95).ceil() as usize).saturating_sub(1);
+        Self {
+            total,
+            errors,
+            min_ms: samples[0],
+            avg_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+            p95_ms: samples[p95_idx.min(samples.len() - 1)],
+            max_ms: samples[samples.len() - 1],
+        }
+    }
 }
 
 /// Nodo del explorador de archivos (para el árbol opcional).
@@ -34,6 +232,8 @@ struct DirNode {
     path: PathBuf,
     is_dir: bool,
     children: Option<Vec<DirNode>>,
+    /// Recuento cacheado (carpetas, archivos); `Some(None)` si no se pudo leer el directorio.
+    counts: Option<Option<(usize, usize)>>,
 }
 
 impl DirNode {
@@ -49,7 +249,29 @@ impl DirNode {
             path,
             is_dir,
             children: None,
+            counts: None,
+        }
+    }
+
+    /// Cuenta los hijos directos una sola vez, usando el tipo de la entrada (sin `stat` por archivo).
+    fn ensure_counts_loaded(&mut self) -> Option<(usize, usize)> {
+        if !self.is_dir {
+            return None;
+        }
+        if let Some(counts) = self.counts {
+            return counts;
         }
+        let counts = fs::read_dir(&self.path).ok().map(|read_dir| {
+            read_dir.flatten().fold((0, 0), |(dirs, files), entry| {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    (dirs + 1, files)
+                } else {
+                    (dirs, files + 1)
+                }
+            })
+        });
+        self.counts = Some(counts);
+        counts
     }
 
     fn ensure_children_loaded(&mut self) {
@@ -79,8 +301,10 @@ struct EntryView {
     name: String,
     path: PathBuf,
     is_dir: bool,
+    is_symlink: bool,
     size: Option<u64>,
     kind: String, // "Carpeta" o extensión
+    modified: Option<SystemTime>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -88,6 +312,18 @@ enum SortBy {
     Name,
     Kind,
     Size,
+    Modified,
+}
+
+impl SortBy {
+    fn label(self) -> &'static str {
+        match self {
+            SortBy::Name => "Nombre",
+            SortBy::Kind => "Tipo",
+            SortBy::Size => "Tamaño",
+            SortBy::Modified => "Modificado",
+        }
+    }
 }
 
 /// Configuración del LLM.
@@ -98,6 +334,9 @@ struct LlmConfig {
     api_key: String,    // para openai/groq
     model: String,      // nombre del modelo
     temperature: f32,   // 0.0..=1.5
+    top_p: f32,         // 0.0..=1.0
+    frequency_penalty: f32, // -2.0..=2.0
+    presence_penalty: f32,  // -2.0..=2.0
     max_tokens: u32,    // límite
 }
 
@@ -109,17 +348,62 @@ impl Default for LlmConfig {
             api_key: String::new(),
             model: "llama3".to_string(),
             temperature: 0.2,
+            top_p: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
             max_tokens: 2048,
         }
     }
 }
 
+/// Preferencias del cliente que se conservan entre sesiones.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ClientSettings {
+    #[serde(default)]
+    favorites: Vec<PathBuf>,
+    /// Últimos archivos seleccionados, el más reciente primero.
+    #[serde(default)]
+    recent_files: Vec<PathBuf>,
+    /// Tema oscuro (`true`) o claro; `None` deja el de egui por defecto.
+    #[serde(default)]
+    dark_mode: Option<bool>,
+    /// Color de acento en RGB.
+    #[serde(default)]
+    accent: Option<[u8; 3]>,
+    /// Editor para abrir archivos en una línea (p. ej. "code", "subl", "idea");
+    /// `None` usa `$VISUAL` o `$EDITOR`.
+    #[serde(default)]
+    editor: Option<String>,
+}
+
+impl ClientSettings {
+    /// `<config_dir>/multi_agent_client/settings.json`
+    fn file_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("multi_agent_client").join("settings.json"))
+    }
+
+    fn load() -> Option<Self> {
+        let data = fs::read_to_string(Self::file_path()?).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::file_path().context("No se encontró el directorio de configuración")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
 /// App principal
 struct ClientApp {
     // Infraestructura
     rt: tokio::runtime::Runtime,
     nats_url: String,
     nats: Option<NatsClient>,
+    nats_state: ConnectionState,
     tx: Sender<GuiEvent>,
     events_rx: Option<Receiver<GuiEvent>>,
 
@@ -132,14 +416,91 @@ struct ClientApp {
     show_settings_window: bool,
 
     // Estado UI y datos
-    logs: Vec<String>,
+    logs: Vec<(LogLevel, String)>,
+    log_show_info: bool,
+    log_show_warn: bool,
+    log_show_error: bool,
+    log_search: String,
+    /// Color de acento: títulos, selección y enlaces.
     accent: Color32,
+    dark_mode: bool,
+    /// Comando del editor (ver `open_in_editor`); vacío = abrir con la aplicación del sistema.
+    editor: String,
     selected_path: Option<PathBuf>,
+    /// Multiselección del listado (Ctrl/Shift+clic); con un solo elemento equivale a `selected_path`.
+    selected_paths: Vec<PathBuf>,
+    /// Índice en `dir_items` desde el que se extiende la selección con Shift.
+    selection_anchor: Option<usize>,
     metadata_text: String,
     summary_text: String,
+    metadata_pending: bool,
+    summary_pending: bool,
+    /// Formato del resumen (`SUMMARY_STYLES`); vacío = prosa libre.
+    summary_style: String,
+    summary_progress: Option<String>,
+    /// Archivos terminados / total del resumen por lotes en curso.
+    batch_progress: Option<(u32, u32)>,
+    /// Resultado por archivo del último resumen por lotes.
+    batch_report: Option<BatchSummaryReport>,
+    /// Modelo que generó el resumen mostrado; `None` = el `SUMMARIZER_MODEL` del agente.
+    summary_model: Option<String>,
+    /// Modelo de la petición de resumen en curso.
+    summary_pending_model: Option<String>,
+    /// Resumen sustituido por el último "Regenerar con…" (modelo, texto), para compararlos.
+    previous_summary: Option<(String, String)>,
+    /// Sesión del summarizer abierta con el resumen mostrado, para hacerle preguntas.
+    summary_session: Option<String>,
+    /// Sesión de la petición de resumen en curso.
+    summary_pending_session: Option<String>,
+    followup_question: String,
+    followup_pending: bool,
+    /// Preguntas y respuestas sobre el resumen mostrado.
+    conversation: Vec<(String, String)>,
+    /// Modelo de Ollama que falta descargar, según el último error del gateway.
+    pull_offer: Option<String>,
+    pull_pending: bool,
+    /// Acción que falló por un error transitorio y se puede reintentar.
+    retry: Option<RetryAction>,
+    pull_progress: Option<String>,
+    last_curl: Option<String>,
+    base_url_error: Option<String>,
+    compare_path: Option<PathBuf>,
+    dir_export_hash: bool,
+    dir_summary: Option<(PathBuf, DirSummary)>,
+    dir_summary_pending: bool,
+    /// Búsqueda en el contenido de la carpeta actual (`files.search.request`).
+    search_query: String,
+    search_regex: bool,
+    search_recursive: bool,
+    search_pending: bool,
+    search_results: Option<FileSearchResponse>,
+    dir_summary_recursive: bool,
+    show_dir_summary: bool,
+    dir_export_pending: bool,
+    comparison: Option<Box<MetadataComparison>>,
+    compare_pending: bool,
+    show_compare_window: bool,
     last_ping_ms: Option<u128>,
+    /// Tokens (entrada, salida) y coste estimado acumulados en la sesión desde `llm.usage`.
+    session_tokens: (u64, u64),
+    session_cost_usd: f64,
+    /// Completions sin precio conocido: el coste acumulado es una cota inferior.
+    session_unpriced: usize,
+    /// Mapa de servicios del último descubrimiento (`None` si no se ha consultado).
+    services: Option<Vec<ServiceInfo>>,
+    services_pending: bool,
+    stress_ping_count: usize,
+    stress_ping_running: bool,
+    stress_ping_stats: Option<PingStats>,
     models: Vec<String>,
+    models_filter: String,
+    /// Listas de modelos por (proveedor, base URL) con el momento en que se obtuvieron.
+    models_cache: HashMap<(String, String), (Vec<String>, SystemTime)>,
+    /// Momento de la lista mostrada en `models` (si viene de la caché, su hora original).
+    models_updated: Option<SystemTime>,
     provider_report: Option<Value>,
+    inspect_only_active: bool,
+    gateway_config: Option<Value>,
 
     // Explorador
     current_dir: PathBuf,
@@ -147,9 +508,22 @@ struct ClientApp {
     needs_refresh: bool,
     show_hidden: bool,
     filter_text: String,
+    filter_has_focus: bool,
+    /// Entradas del directorio actual que no se pudieron leer, con el motivo.
+    dir_skipped: Vec<String>,
+    max_dir_entries: usize,
+    /// Entradas que pasan el filtro, incluidas las que no caben en `max_dir_entries`.
+    dir_total: usize,
+    /// Intervalo de auto-refresco del listado en segundos (0 = desactivado).
+    auto_refresh_secs: u32,
+    last_refresh: Instant,
     sort_by: SortBy,
     sort_asc: bool,
     favorites: Vec<PathBuf>,
+    recent_files: Vec<PathBuf>,
+    path_edit_mode: bool,
+    path_edit_text: String,
+    path_edit_error: Option<String>,
 
     // Árbol opcional
     root: DirNode,
@@ -160,24 +534,54 @@ struct ClientApp {
     // Vista previa
     preview_text: String,
     preview_error: Option<String>,
+    preview_encoding: Option<&'static str>,
+    /// El archivo cabe entero en la vista previa y es UTF-8 sin comprimir: se puede editar.
+    preview_editable: bool,
+    /// Texto en edición; `Some` mientras el modo edición está activo.
+    preview_edit: Option<String>,
+    /// Tamaño y fecha de modificación del archivo al cargar la vista previa: Guardar se
+    /// niega si han cambiado, para no pisar cambios hechos fuera de la GUI.
+    preview_loaded: Option<(u64, Option<SystemTime>)>,
+    /// Miniatura del archivo seleccionado cuando es una imagen.
+    preview_image: Option<egui::TextureHandle>,
+    /// Miniaturas ya decodificadas, por ruta.
+    thumbnails: HashMap<PathBuf, egui::TextureHandle>,
     preview_max_bytes: usize,
     preview_dirty: bool,
 }
 
 impl ClientApp {
-    fn new(_cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(cc: &eframe::CreationContext<'_>) -> Self {
         let (tx, rx) = mpsc::channel::<GuiEvent>();
-        let rt = tokio::runtime::Runtime::new().expect("Tokio runtime");
+        let workers = env::var("GUI_WORKER_THREADS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n: &usize| *n > 0)
+            .unwrap_or(DEFAULT_WORKER_THREADS);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(workers)
+            .thread_name("gui-tokio")
+            .enable_all()
+            .build()
+            .expect("Tokio runtime");
 
         let nats_url = env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string());
 
         let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-        let mut favorites = Vec::new();
-        favorites.push(home.clone());
-        for name in ["Downloads", "Descargas", "Documents", "Documentos", "Desktop", "Escritorio"] {
-            let cand = home.join(name);
-            if cand.exists() && cand.is_dir() {
-                favorites.push(cand);
+        let saved = ClientSettings::load();
+        let first_run = saved.is_none();
+        let settings = saved.unwrap_or_default();
+        let mut favorites = settings.favorites;
+        let mut recent_files = settings.recent_files;
+        recent_files.retain(|p| p.is_file());
+        // Primera ejecución (sin ajustes guardados): carpetas habituales del usuario.
+        if first_run {
+            favorites.push(home.clone());
+            for name in ["Downloads", "Descargas", "Documents", "Documentos", "Desktop", "Escritorio"] {
+                let cand = home.join(name);
+                if cand.exists() && cand.is_dir() {
+                    favorites.push(cand);
+                }
             }
         }
 
@@ -187,6 +591,7 @@ impl ClientApp {
             rt,
             nats_url,
             nats: None,
+            nats_state: ConnectionState::Disconnected,
             tx,
             events_rx: Some(rx),
 
@@ -198,32 +603,114 @@ impl ClientApp {
             show_settings_window: true,
 
             logs: Vec::new(),
-            accent: Color32::from_rgb(52, 120, 246),
+            log_show_info: true,
+            log_show_warn: true,
+            log_show_error: true,
+            log_search: String::new(),
+            accent: settings.accent.map(|[r, g, b]| Color32::from_rgb(r, g, b)).unwrap_or(DEFAULT_ACCENT),
+            dark_mode: settings.dark_mode.unwrap_or(true),
+            editor: settings
+                .editor
+                .clone()
+                .or_else(|| env::var("VISUAL").ok())
+                .or_else(|| env::var("EDITOR").ok())
+                .unwrap_or_default(),
             selected_path: None,
+            selected_paths: Vec::new(),
+            selection_anchor: None,
             metadata_text: String::new(),
             summary_text: String::new(),
+            metadata_pending: false,
+            summary_pending: false,
+            summary_style: String::new(),
+            summary_progress: None,
+            batch_progress: None,
+            batch_report: None,
+            summary_model: None,
+            summary_pending_model: None,
+            previous_summary: None,
+            summary_session: None,
+            summary_pending_session: None,
+            followup_question: String::new(),
+            followup_pending: false,
+            conversation: Vec::new(),
+            pull_offer: None,
+            retry: None,
+            pull_pending: false,
+            pull_progress: None,
+            last_curl: None,
+            base_url_error: None,
+            compare_path: None,
+            dir_export_hash: false,
+            dir_summary: None,
+            dir_summary_pending: false,
+            search_query: String::new(),
+            search_regex: false,
+            search_recursive: true,
+            search_pending: false,
+            search_results: None,
+            dir_summary_recursive: false,
+            show_dir_summary: false,
+            dir_export_pending: false,
+            comparison: None,
+            compare_pending: false,
+            show_compare_window: false,
             last_ping_ms: None,
+            session_tokens: (0, 0),
+            session_cost_usd: 0.0,
+            session_unpriced: 0,
+            services: None,
+            services_pending: false,
+            stress_ping_count: 50,
+            stress_ping_running: false,
+            stress_ping_stats: None,
             models: Vec::new(),
+            models_filter: String::new(),
+            models_cache: HashMap::new(),
+            models_updated: None,
             provider_report: None,
+            inspect_only_active: true,
+            gateway_config: None,
 
             current_dir: home.clone(),
             dir_items: Vec::new(),
             needs_refresh: true,
             show_hidden: false,
             filter_text: String::new(),
+            filter_has_focus: false,
+            dir_skipped: Vec::new(),
+            max_dir_entries: env::var("GUI_MAX_DIR_ENTRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n: &usize| *n > 0)
+                .unwrap_or(DEFAULT_MAX_DIR_ENTRIES),
+            dir_total: 0,
+            auto_refresh_secs: 0,
+            last_refresh: Instant::now(),
             sort_by: SortBy::Name,
             sort_asc: true,
             favorites,
+            recent_files,
+            path_edit_mode: false,
+            path_edit_text: String::new(),
+            path_edit_error: None,
 
             root,
             llm: LlmConfig::default(),
 
             preview_text: String::new(),
             preview_error: None,
+            preview_encoding: None,
+            preview_editable: false,
+            preview_edit: None,
+            preview_loaded: None,
+            preview_image: None,
+            thumbnails: HashMap::new(),
             preview_max_bytes: 64 * 1024, // 64KB
             preview_dirty: false,
         };
 
+        app.apply_theme(&cc.egui_ctx);
         app.spawn_connect_and_ping();
         app
     }
@@ -240,7 +727,7 @@ impl ClientApp {
                     let _ = tx.send(GuiEvent::Status("✅ Conectado a NATS".to_string()));
 
                     let start = Instant::now();
-                    match client.request("mcp.ping", Vec::<u8>::new().into()).await {
+                    match request_with_timeout(&client, "mcp.ping", Vec::new(), timeouts().quick).await {
                         Ok(_msg) => {
                             let _ = tx.send(GuiEvent::PingMs(start.elapsed().as_millis()));
                         }
@@ -248,6 +735,7 @@ impl ClientApp {
                             let _ = tx.send(GuiEvent::Error(format!("Ping LLM Gateway falló: {e}")));
                         }
                     }
+                    fetch_gateway_config(&client, &tx, true).await;
                 }
                 Err(e) => {
                     let _ = tx.send(GuiEvent::Error(format!("❌ Error conectando a NATS ({url}): {e}")));
@@ -261,21 +749,75 @@ impl ClientApp {
             return Ok(());
         }
         let url = self.nats_url.clone();
-        let client = self
-            .rt
-            .block_on(async_nats::connect(&url))
-            .with_context(|| format!("No se pudo conectar a NATS en {url}"))?;
+        let tx = self.tx.clone();
+        let connected = self.rt.block_on(connect_with_state(&url, move |state| {
+            let _ = tx.send(GuiEvent::Connection(state));
+        }));
+        let client = match connected {
+            Ok(client) => client,
+            Err(e) => {
+                self.nats_state = ConnectionState::Disconnected;
+                return Err(e);
+            }
+        };
+        self.watch_usage(client.clone());
         self.nats = Some(client);
+        self.nats_state = ConnectionState::Connected;
         self.push_log("✅ Conectado a NATS");
         Ok(())
     }
 
+    /// Escucha `llm.usage` para acumular tokens y coste de la sesión. La tarea termina
+    /// sola cuando el cliente se descarta (p. ej. al reconectar).
+    fn watch_usage(&self, client: NatsClient) {
+        let tx = self.tx.clone();
+        self.rt.spawn(async move {
+            let mut sub = match client.subscribe("llm.usage").await {
+                Ok(s) => s,
+                Err(e) => {
+                    let _ = tx.send(GuiEvent::Status(format!("⚠️ Sin seguimiento de coste (llm.usage): {e}")));
+                    return;
+                }
+            };
+            while let Some(msg) = sub.next().await {
+                if let Ok(report) = serde_json::from_slice::<UsageReport>(&msg.payload) {
+                    if tx.send(GuiEvent::Usage(report)).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Descarta el cliente actual y abre una conexión nueva.
+    fn reconnect_nats(&mut self) {
+        self.nats = None;
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ Reconexión NATS falló: {e}"));
+        } else {
+            self.push_log("✅ Reconectado a NATS");
+        }
+    }
+
     fn client_clone(&self) -> Option<NatsClient> {
         self.nats.as_ref().cloned()
     }
 
     fn push_log(&mut self, s: &str) {
-        self.logs.push(s.to_string());
+        self.logs.push((LogLevel::infer(s), s.to_string()));
+    }
+
+    fn save_settings(&mut self) {
+        let settings = ClientSettings {
+            favorites: self.favorites.clone(),
+            recent_files: self.recent_files.clone(),
+            dark_mode: Some(self.dark_mode),
+            accent: Some([self.accent.r(), self.accent.g(), self.accent.b()]),
+            editor: Some(self.editor.clone()).filter(|e| !e.trim().is_empty()),
+        };
+        if let Err(e) = settings.save() {
+            self.push_log(&format!("❌ No se pudieron guardar los ajustes: {e}"));
+        }
     }
 
     // ===== Acciones LLM/NATS =====
@@ -289,7 +831,7 @@ impl ClientApp {
         if let Some(c) = self.client_clone() {
             self.rt.spawn(async move {
                 let start = Instant::now();
-                match c.request("mcp.ping", Vec::<u8>::new().into()).await {
+                match request_with_timeout(&c, "mcp.ping", Vec::new(), timeouts().quick).await {
                     Ok(_m) => {
                         let _ = tx.send(GuiEvent::PingMs(start.elapsed().as_millis()));
                         let _ = tx.send(GuiEvent::Status("📡 Ping OK".to_string()));
@@ -302,8 +844,83 @@ impl ClientApp {
         }
     }
 
-    /// Obtiene la lista de modelos para el proveedor actual.
-    fn list_models(&mut self) {
+    /// Pregunta en `discovery.request` qué agentes están en marcha y qué subjects atienden.
+    fn discover_services(&mut self) {
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        if let Some(c) = self.client_clone() {
+            self.services_pending = true;
+            self.rt.spawn(async move {
+                match discover_services(&c, DISCOVERY_WAIT).await {
+                    Ok(list) => {
+                        let _ = tx.send(GuiEvent::Services(list));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Services(Vec::new()));
+                        let _ = tx.send(GuiEvent::Error(format!("Descubrimiento falló: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Lanza `stress_ping_count` pings concurrentes y resume sus latencias.
+    fn stress_ping(&mut self) {
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let n = self.stress_ping_count.max(1);
+        if let Some(c) = self.client_clone() {
+            self.stress_ping_running = true;
+            self.rt.spawn(async move {
+                let pings = (0..n).map(|_| {
+                    let c = c.clone();
+                    async move {
+                        let start = Instant::now();
+                        request_with_timeout(&c, "mcp.ping", Vec::new(), timeouts().quick)
+                            .await
+                            .ok()
+                            .map(|_| start.elapsed().as_secs_f64() * 1000.0)
+                    }
+                });
+                let samples: Vec<f64> = futures_util::future::join_all(pings)
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                let _ = tx.send(GuiEvent::StressPing(PingStats::from_samples(samples, n)));
+            });
+        }
+    }
+
+    /// Obtiene la lista de modelos para el proveedor actual. Reutiliza la caché si tiene
+    /// menos de `MODELS_CACHE_TTL`, salvo con `force`.
+    fn list_models(&mut self, force: bool) {
+        if self.llm.provider != "mock" {
+            match normalize_base_url(&self.llm.base_url) {
+                Ok(url) => self.llm.base_url = url,
+                Err(e) => {
+                    self.push_log(&format!("❌ {e}"));
+                    self.base_url_error = Some(e.to_string());
+                    return;
+                }
+            }
+        }
+        let key = (self.llm.provider.clone(), self.llm.base_url.clone());
+        if !force {
+            if let Some((list, fetched)) = self.models_cache.get(&key).cloned() {
+                if fetched.elapsed().map(|age| age < MODELS_CACHE_TTL).unwrap_or(false) {
+                    self.push_log(&format!("📚 Modelos desde caché (hace {})", Self::age_since(fetched)));
+                    self.apply_models(list, fetched);
+                    return;
+                }
+            }
+        }
         if let Err(e) = self.ensure_nats() {
             self.push_log(&format!("❌ NATS no disponible: {e}"));
             return;
@@ -317,46 +934,117 @@ impl ClientApp {
                     "base_url": cfg.base_url,
                     "api_key": cfg.api_key,
                 });
-                let data = serde_json::to_vec(&payload).unwrap_or_default();
-                match c.request("mcp.provider.list", data.into()).await {
-                    Ok(msg) => {
-                        let Ok(body) = String::from_utf8(msg.payload.to_vec()) else {
-                            let _ = tx.send(GuiEvent::Error("Respuesta binaria inválida al listar modelos".into()));
-                            return;
-                        };
-                        match serde_json::from_str::<Value>(&body) {
-                            Ok(v) => {
-                                let models = if let Some(arr) = v.get("models").and_then(|m| m.as_array()) {
-                                    arr.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect::<Vec<_>>()
-                                } else if let Some(arr) = v.as_array() {
-                                    arr.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect::<Vec<_>>()
-                                } else {
-                                    Vec::new()
-                                };
-                                let _ = tx.send(GuiEvent::Models(models));
-                            }
-                            Err(_) => {
-                                let _ = tx.send(GuiEvent::Error(format!("No se pudo parsear modelos: {body}")));
-                            }
-                        }
+                // Un reintento cubre cortes puntuales (gateway reiniciándose, timeout aislado).
+                let result = match fetch_models(&c, &payload).await {
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Status(format!("⚠️ Listar modelos falló ({e}); reintentando…")));
+                        tokio::time::sleep(MODELS_RETRY_DELAY).await;
+                        fetch_models(&c, &payload).await
+                    }
+                    ok => ok,
+                };
+                match result {
+                    Ok(list) => {
+                        let _ = tx.send(GuiEvent::Models { key, list });
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(e));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Muestra una lista de modelos y ajusta el modelo activo si ya no figura en ella.
+    fn apply_models(&mut self, list: Vec<String>, fetched: SystemTime) {
+        self.models = list;
+        self.models_updated = Some(fetched);
+        if !self.models.is_empty() && !self.models.contains(&self.llm.model) {
+            self.llm.model = self.models[0].clone();
+            self.push_log(&format!("ℹ️ Modelo ajustado a '{}'", self.llm.model));
+        }
+    }
+
+    /// Trae la configuración efectiva del gateway. Con `apply` sincroniza además los ajustes locales.
+    fn sync_gateway_config(&mut self, apply: bool) {
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        if let Some(c) = self.client_clone() {
+            self.rt.spawn(async move {
+                fetch_gateway_config(&c, &tx, apply).await;
+            });
+        }
+    }
+
+    /// Pide al gateway el cURL equivalente a una petición de prueba con la config actual.
+    fn request_curl(&mut self) {
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let cfg = self.llm.clone();
+        if let Some(c) = self.client_clone() {
+            self.rt.spawn(async move {
+                let payload = serde_json::json!({
+                    "provider": cfg.provider,
+                    "model": cfg.model,
+                    "temperature": cfg.temperature,
+                    "top_p": cfg.top_p,
+                    "frequency_penalty": cfg.frequency_penalty,
+                    "presence_penalty": cfg.presence_penalty,
+                    "messages": [{ "role": "user", "content": "Hola" }],
+                });
+                match request_json::<_, String>(&c, "llm.debug.curl", &payload, timeouts().quick).await {
+                    Ok(cmd) => {
+                        let _ = tx.send(GuiEvent::Curl(cmd));
                     }
                     Err(e) => {
-                        let _ = tx.send(GuiEvent::Error(format!("Solicitud de modelos falló: {e}")));
+                        let _ = tx.send(GuiEvent::Error(format!("llm.debug.curl: {e}")));
                     }
                 }
             });
         }
     }
 
+    /// Campos en los que la config local difiere de la del gateway (provider/model/base_url).
+    fn gateway_config_mismatches(&self) -> Vec<String> {
+        let Some(cfg) = &self.gateway_config else {
+            return Vec::new();
+        };
+        let mut diffs = Vec::new();
+        for (key, local) in [
+            ("provider", &self.llm.provider),
+            ("model", &self.llm.model),
+            ("base_url", &self.llm.base_url),
+        ] {
+            if let Some(remote) = cfg.get(key).and_then(|v| v.as_str()) {
+                if remote != local {
+                    diffs.push(format!("{key}: local '{local}' ≠ gateway '{remote}'"));
+                }
+            }
+        }
+        diffs
+    }
+
     fn inspect_providers(&mut self) {
         if let Err(e) = self.ensure_nats() {
             self.push_log(&format!("❌ NATS no disponible: {e}"));
             return;
         }
         let tx = self.tx.clone();
+        // Sin filtro el gateway inspecciona todos los proveedores.
+        let payload = if self.inspect_only_active {
+            serde_json::to_vec(&serde_json::json!({ "provider": self.llm.provider })).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
         if let Some(c) = self.client_clone() {
             self.rt.spawn(async move {
-                match c.request("mcp.provider.inspect", Vec::<u8>::new().into()).await {
+                match request_with_timeout(&c, "mcp.provider.inspect", payload, timeouts().quick).await {
                     Ok(msg) => {
                         let Ok(body) = String::from_utf8(msg.payload.to_vec()) else {
                             let _ = tx.send(GuiEvent::Error("Respuesta binaria inválida al inspeccionar proveedores".into()));
@@ -386,134 +1074,778 @@ impl ClientApp {
         }
         let tx = self.tx.clone();
         if let Some(c) = self.client_clone() {
+            self.metadata_pending = true;
+            self.retry = None;
             self.rt.spawn(async move {
-                let payload = serde_json::json!({ "path": path });
+                let payload = serde_json::json!({ "path": path, "text_stats": true, "envelope": true });
                 let data = serde_json::to_vec(&payload).unwrap_or_default();
-                match c.request("metadata.request", data.into()).await {
-                    Ok(msg) => {
-                        let body = String::from_utf8_lossy(&msg.payload).to_string();
-                        let _ = tx.send(GuiEvent::Metadata(body));
-                    }
+                match request_with_timeout(&c, "metadata.request", data, timeouts().metadata).await {
+                    Ok(msg) => match serde_json::from_slice::<AgentEnvelope<FileMetadata>>(&msg.payload)
+                        .map(|env| report_timing(&tx, env))
+                    {
+                        Ok(AgentResponse::Success(meta)) => {
+                            let body = serde_json::to_string_pretty(&meta).unwrap_or_default();
+                            let _ = tx.send(GuiEvent::Metadata(body));
+                        }
+                        Ok(AgentResponse::Error(e)) => {
+                            let _ = tx.send(failure(format!("Extractor de metadatos: {e}"), e.code, RetryAction::Metadata));
+                        }
+                        Err(e) => {
+                            let body = String::from_utf8_lossy(&msg.payload);
+                            let _ = tx.send(GuiEvent::Error(format!("Respuesta de metadatos inválida: {e} / {body}")));
+                        }
+                    },
                     Err(e) => {
-                        let _ = tx.send(GuiEvent::Error(format!("metadata.request falló: {e}")));
+                        let msg = format!("metadata.request falló: {e}");
+                        let _ = tx.send(failure(msg, ErrorCode::classify(&e), RetryAction::Metadata));
                     }
                 }
             });
         }
     }
 
-    fn request_summary(&mut self) {
-        let Some(path) = self.selected_path.clone() else {
-            self.push_log("Seleccione un archivo para resumir");
-            return;
-        };
+    /// Pide al extractor el manifiesto de metadatos de la carpeta actual.
+    fn request_dir_metadata(&mut self) {
         if let Err(e) = self.ensure_nats() {
             self.push_log(&format!("❌ NATS no disponible: {e}"));
             return;
         }
         let tx = self.tx.clone();
+        let directory = self.current_dir.to_string_lossy().to_string();
+        let hash = self.dir_export_hash;
         if let Some(c) = self.client_clone() {
+            self.dir_export_pending = true;
+            self.push_log(&format!("📦 Extrayendo metadatos de {directory}…"));
             self.rt.spawn(async move {
-                let payload = serde_json::json!({ "path": path });
-                let data = serde_json::to_vec(&payload).unwrap_or_default();
-                match c.request("summary.request", data.into()).await {
-                    Ok(msg) => {
-                        let body = String::from_utf8_lossy(&msg.payload).to_string();
-                        let _ = tx.send(GuiEvent::Summary(body));
+                let payload = serde_json::json!({ "directory": directory, "hash": hash });
+                match request_json(&c, "metadata.dir.request", &payload, DIR_METADATA_TIMEOUT).await {
+                    Ok(entries) => {
+                        let _ = tx.send(GuiEvent::DirMetadata(entries));
                     }
                     Err(e) => {
-                        let _ = tx.send(GuiEvent::Error(format!("summary.request falló: {e}")));
+                        let _ = tx.send(GuiEvent::Error(format!("metadata.dir.request: {e}")));
                     }
                 }
             });
         }
     }
 
-    // ===== Vista previa =====
-
-    fn load_preview_now(&mut self) {
-        self.preview_error = None;
-        self.preview_text.clear();
-        let Some(path) = self.selected_path.clone() else {
+    fn request_dir_summary(&mut self) {
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
             return;
+        }
+        let tx = self.tx.clone();
+        let dir = self.current_dir.clone();
+        let req = DirSummaryRequest {
+            directory: Some(dir.to_string_lossy().to_string()),
+            recursive: self.dir_summary_recursive,
         };
-        if path.is_dir() {
-            self.preview_text = "(La vista previa solo está disponible para archivos)".to_string();
+        if let Some(c) = self.client_clone() {
+            self.dir_summary_pending = true;
+            self.show_dir_summary = true;
+            self.rt.spawn(async move {
+                match request_json(&c, "dir.summary.request", &req, timeouts().metadata).await {
+                    Ok(summary) => {
+                        let _ = tx.send(GuiEvent::DirSummary(dir, summary));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(format!("dir.summary.request: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Busca `search_query` en el contenido de los archivos de la carpeta actual.
+    fn request_search(&mut self) {
+        if self.search_pending || self.search_query.is_empty() {
             return;
         }
-        let mut file = match fs::File::open(&path) {
-            Ok(f) => f,
-            Err(e) => {
-                self.preview_error = Some(format!("No se pudo abrir el archivo: {e}"));
-                return;
-            }
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let req = FileSearchRequest {
+            directory: Some(self.current_dir.to_string_lossy().to_string()),
+            query: self.search_query.clone(),
+            regex: self.search_regex,
+            recursive: self.search_recursive,
+            ..Default::default()
         };
-        let mut buf = vec![0u8; self.preview_max_bytes];
-        let mut read_total = 0usize;
-        match file.read(&mut buf) {
-            Ok(n) => read_total = n,
-            Err(e) => {
-                self.preview_error = Some(format!("Error leyendo: {e}"));
-                return;
-            }
+        if let Some(c) = self.client_clone() {
+            self.search_pending = true;
+            self.rt.spawn(async move {
+                match request_json(&c, "files.search.request", &req, timeouts().metadata).await {
+                    Ok(found) => {
+                        let _ = tx.send(GuiEvent::SearchResults(found));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(format!("files.search.request: {e}")));
+                    }
+                }
+            });
         }
-        buf.truncate(read_total);
-        let mut text = String::from_utf8_lossy(&buf).to_string();
+    }
 
-        // Si no termina en \n y hay más datos, indica truncado:
-        if read_total == self.preview_max_bytes {
-            text.push_str("\n… (vista previa truncada)");
+    /// Selecciona `path` y navega a su carpeta.
+    fn open_path(&mut self, path: PathBuf) {
+        if let Some(parent) = path.parent() {
+            self.current_dir = parent.to_path_buf();
+            self.needs_refresh = true;
         }
-        self.preview_text = text;
+        self.selected_path = Some(path);
+        self.preview_dirty = true;
     }
 
-    // ===== Explorador =====
-
-    fn refresh_dir(&mut self) {
-        self.dir_items.clear();
-        let dir = self.current_dir.clone();
-        let show_hidden = self.show_hidden;
-        let filter = self.filter_text.to_lowercase();
+    /// Guarda el manifiesto como CSV o JSON según la extensión elegida.
+    fn save_dir_metadata_dialog(&mut self, entries: &[DirMetadataEntry]) {
+        let stem = self
+            .current_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "carpeta".to_string());
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Guardar manifiesto de metadatos…")
+            .set_file_name(format!("{stem}_manifiesto.csv"))
+            .add_filter("CSV", &["csv"])
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return;
+        };
+        let is_json = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        let content = if is_json {
+            serde_json::to_string_pretty(entries).unwrap_or_default()
+        } else {
+            dir_metadata_csv(entries)
+        };
+        match fs::write(&path, content) {
+            Ok(()) => self.push_log(&format!("💾 Manifiesto de {} archivos guardado en {}", entries.len(), path.display())),
+            Err(e) => self.push_log(&format!("❌ No se pudo guardar el manifiesto: {e}")),
+        }
+    }
 
-        let mut entries: Vec<EntryView> = Vec::new();
-        if let Ok(read) = fs::read_dir(&dir) {
-            for ent in read.flatten() {
-                let p = ent.path();
-                let file_name = ent.file_name().to_string_lossy().to_string();
+    /// Listado del directorio como tabla de columnas redimensionables. Pulsar una
+    /// cabecera ordena por esa columna (otra vez, invierte el sentido).
+    fn ui_dir_table(&mut self, ui: &mut Ui) {
+        let mut clicked: Option<(usize, egui::Modifiers)> = None;
+        let mut open_dir: Option<PathBuf> = None;
+        let mut sort_click: Option<SortBy> = None;
+        let row_height = ui.text_style_height(&TextStyle::Body) + 4.0;
 
-                // ocultos
-                if !show_hidden && file_name.starts_with('.') {
-                    continue;
+        TableBuilder::new(ui)
+            .striped(true)
+            .resizable(true)
+            .auto_shrink([false; 2])
+            .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+            .column(Column::initial(220.0).at_least(80.0).clip(true))
+            .column(Column::initial(70.0).at_least(40.0).clip(true))
+            .column(Column::initial(80.0).at_least(50.0))
+            .column(Column::remainder().at_least(70.0))
+            .header(row_height + 2.0, |mut header| {
+                for sort in [SortBy::Name, SortBy::Kind, SortBy::Size, SortBy::Modified] {
+                    header.col(|ui| {
+                        let arrow = match (self.sort_by == sort, self.sort_asc) {
+                            (false, _) => "",
+                            (true, true) => " ⬆",
+                            (true, false) => " ⬇",
+                        };
+                        let text = RichText::new(format!("{}{arrow}", sort.label())).strong();
+                        if ui.add(egui::Button::new(text).frame(false)).clicked() {
+                            sort_click = Some(sort);
+                        }
+                    });
                 }
+            })
+            .body(|body| {
+                body.rows(row_height, self.dir_items.len(), |mut row| {
+                    let index = row.index();
+                    let item = &self.dir_items[index];
+                    let selected = self.selected_path.as_ref() == Some(&item.path)
+                        || (self.selected_paths.len() > 1 && self.selected_paths.contains(&item.path));
+                    row.col(|ui| {
+                        let icon = match (item.is_symlink, item.is_dir) {
+                            (true, _) => "🔗",
+                            (false, true) => "📁",
+                            (false, false) => "📄",
+                        };
+                        let resp = ui.selectable_label(selected, format!("{icon} {}", item.name));
+                        if resp.clicked() {
+                            clicked = Some((index, ui.input(|i| i.modifiers)));
+                        }
+                        if resp.double_clicked() && item.is_dir {
+                            open_dir = Some(item.path.clone());
+                        }
+                    });
+                    row.col(|ui| {
+                        ui.label(&item.kind);
+                    });
+                    row.col(|ui| {
+                        ui.label(item.size.map(Self::human_size).unwrap_or_else(|| "—".into()));
+                    });
+                    row.col(|ui| {
+                        ui.label(item.modified.map(|t| format!("hace {}", Self::age_since(t))).unwrap_or_else(|| "—".into()));
+                    });
+                });
+            });
 
-                // filtro
-                if !filter.is_empty() && !file_name.to_lowercase().contains(&filter) {
-                    continue;
-                }
+        if let Some(sort) = sort_click {
+            if self.sort_by == sort {
+                self.sort_asc = !self.sort_asc;
+            } else {
+                self.sort_by = sort;
+                self.sort_asc = true;
+            }
+            self.needs_refresh = true;
+        }
+        if let Some((index, modifiers)) = clicked {
+            self.click_dir_item(index, modifiers);
+        }
+        if let Some(dir) = open_dir {
+            self.current_dir = dir;
+            self.needs_refresh = true;
+        }
+    }
+
+    /// Archivos sobre los que actúan las acciones por lotes. Solo hay lote si la
+    /// multiselección tiene varios elementos e incluye la selección actual; si no,
+    /// se actúa sobre `selected_path` como siempre.
+    fn batch_paths(&self) -> Vec<PathBuf> {
+        let multi = self.selected_paths.len() > 1
+            && self.selected_path.as_ref().is_some_and(|p| self.selected_paths.contains(p));
+        if multi {
+            self.selected_paths.iter().filter(|p| p.is_file()).cloned().collect()
+        } else {
+            self.selected_path.iter().cloned().collect()
+        }
+    }
 
-                let is_dir = p.is_dir();
-                let (size, kind) = if is_dir {
-                    (None, "Carpeta".to_string())
+    /// Clic en la fila `index` del listado: Ctrl alterna, Shift extiende desde el ancla.
+    fn click_dir_item(&mut self, index: usize, modifiers: egui::Modifiers) {
+        let Some(path) = self.dir_items.get(index).map(|i| i.path.clone()) else {
+            return;
+        };
+        match self.selection_anchor.filter(|_| modifiers.shift) {
+            Some(anchor) => {
+                let (from, to) = if anchor <= index { (anchor, index) } else { (index, anchor) };
+                self.selected_paths = self.dir_items[from..=to.min(self.dir_items.len() - 1)]
+                    .iter()
+                    .map(|i| i.path.clone())
+                    .collect();
+            }
+            None if modifiers.command => {
+                // Al empezar una multiselección se conserva la selección simple previa.
+                if self.selected_paths.is_empty() {
+                    self.selected_paths.extend(self.selected_path.clone());
+                }
+                if let Some(pos) = self.selected_paths.iter().position(|p| p == &path) {
+                    self.selected_paths.remove(pos);
                 } else {
-                    let meta = fs::metadata(&p).ok();
-                    let sz = meta.as_ref().map(|m| m.len());
-                    let kind = p
-                        .extension()
-                        .and_then(|e| e.to_str())
-                        .unwrap_or("archivo")
-                        .to_string();
-                    (sz, kind)
+                    self.selected_paths.push(path.clone());
+                }
+                self.selection_anchor = Some(index);
+            }
+            None => {
+                self.selected_paths = vec![path.clone()];
+                self.selection_anchor = Some(index);
+            }
+        }
+        self.selected_path = Some(path);
+        self.preview_dirty = true; // cargar vista previa
+    }
+
+    /// Metadatos de todos los archivos seleccionados, unidos en el panel de metadatos.
+    fn request_batch_metadata(&mut self) {
+        let paths = self.batch_paths();
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        if let Some(c) = self.client_clone() {
+            self.metadata_pending = true;
+            self.push_log(&format!("📊 Metadatos de {} archivos…", paths.len()));
+            self.rt.spawn(async move {
+                let job = |path: PathBuf| {
+                    let c = c.clone();
+                    async move {
+                        let meta = fetch_metadata(&c, &path).await?;
+                        Ok(serde_json::to_string_pretty(&meta).unwrap_or_default())
+                    }
                 };
+                let (text, failed) = run_batch(paths, job).await;
+                if failed > 0 {
+                    let _ = tx.send(GuiEvent::Status(format!("⚠️ {failed} archivos sin metadatos")));
+                }
+                let _ = tx.send(GuiEvent::Metadata(text));
+            });
+        }
+    }
+
+    /// Resume cada archivo seleccionado por separado y une los resúmenes.
+    fn request_batch_summary(&mut self) {
+        if self.summary_pending {
+            return;
+        }
+        let paths = self.batch_paths();
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let style = Some(self.summary_style.clone()).filter(|s| !s.is_empty());
+        let model = self.summary_llm_model();
+        let temperature = Some(self.llm.temperature);
+        if let Some(c) = self.client_clone() {
+            self.summary_pending = true;
+            self.summary_progress = None;
+            self.summary_pending_model = model.clone();
+            self.previous_summary = None;
+            self.batch_progress = Some((0, paths.len() as u32));
+            self.batch_report = None;
+            self.push_log(&format!("📝 Resumiendo {} archivos…", paths.len()));
+            // El summarizer responde al terminar el lote: se espera un resumen por archivo.
+            let timeout = timeouts().summary * paths.len().max(1) as u32;
+            self.rt.spawn(async move {
+                let request_id = new_request_id();
+                let progress = forward_progress(&c, &request_id, tx.clone(), GuiEvent::BatchProgress).await;
+                let req = BatchSummaryRequest {
+                    paths: paths.iter().map(|p| p.to_string_lossy().to_string()).collect(),
+                    style,
+                    model,
+                    temperature,
+                    request_id: Some(request_id),
+                    ..Default::default()
+                };
+                let result = request_json::<_, BatchSummaryReport>(&c, "summary.batch.request", &req, timeout).await;
+                if let Some(task) = progress {
+                    task.abort();
+                }
+                match result {
+                    Ok(report) => {
+                        if report.failed > 0 {
+                            let _ = tx.send(GuiEvent::Status(format!("⚠️ {} archivos sin resumen", report.failed)));
+                        }
+                        let _ = tx.send(GuiEvent::BatchSummary(report));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(format!("Resumen por lotes: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Pide metadatos (con hash) del archivo fijado y del seleccionado para compararlos.
+    fn request_comparison(&mut self) {
+        let (Some(a), Some(b)) = (self.compare_path.clone(), self.selected_path.clone()) else {
+            self.push_log("Fije un archivo para comparar y seleccione otro");
+            return;
+        };
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        if let Some(c) = self.client_clone() {
+            self.compare_pending = true;
+            self.show_compare_window = true;
+            self.rt.spawn(async move {
+                let (meta_a, meta_b) = tokio::join!(fetch_metadata(&c, &a), fetch_metadata(&c, &b));
+                match (meta_a, meta_b) {
+                    (Ok(ma), Ok(mb)) => {
+                        let cmp = MetadataComparison { a: (a, ma), b: (b, mb) };
+                        let _ = tx.send(GuiEvent::Comparison(Box::new(cmp)));
+                    }
+                    (Err(e), _) | (_, Err(e)) => {
+                        let _ = tx.send(GuiEvent::Error(format!("Comparación: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    fn request_summary(&mut self) {
+        self.request_summary_with(None);
+    }
+
+    /// Modelo de los resúmenes según la configuración LLM ("proveedor:modelo");
+    /// `None` si no hay modelo y decide el `SUMMARIZER_MODEL` del agente.
+    fn summary_llm_model(&self) -> Option<String> {
+        let model = self.llm.model.trim();
+        (!model.is_empty()).then(|| format!("{}:{}", self.llm.provider, model))
+    }
+
+    /// Repite el resumen del archivo seleccionado con `model` ("proveedor:modelo"),
+    /// conservando el actual para compararlos.
+    fn regenerate_summary(&mut self, model: String) {
+        if self.summary_pending || self.summary_text.is_empty() {
+            return;
+        }
+        let label = self.summary_model.clone().unwrap_or_else(|| "modelo por defecto".to_string());
+        self.previous_summary = Some((label, self.summary_text.clone()));
+        self.request_summary_with(Some(model));
+    }
+
+    fn request_summary_with(&mut self, model: Option<String>) {
+        if self.summary_pending {
+            return;
+        }
+        let Some(path) = self.selected_path.clone() else {
+            self.push_log("Seleccione un archivo para resumir");
+            return;
+        };
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let style = Some(self.summary_style.clone()).filter(|s| !s.is_empty());
+        // Sin modelo explícito (regeneración) se resume con el LLM configurado en la GUI.
+        let regenerating = model.is_some();
+        let model = model.or_else(|| self.summary_llm_model());
+        let temperature = self.llm.temperature;
+        if let Some(c) = self.client_clone() {
+            self.summary_pending = true;
+            self.summary_progress = None;
+            self.summary_pending_model = model.clone();
+            if !regenerating {
+                self.previous_summary = None;
+            }
+            // El summarizer guarda el archivo y el resumen bajo este id para las preguntas posteriores.
+            let session_id = new_request_id();
+            self.summary_pending_session = Some(session_id.clone());
+            self.retry = None;
+            self.rt.spawn(async move {
+                // Id único por petición: el summarizer publica su progreso en un subject derivado.
+                let request_id = new_request_id();
+                // La suscripción se abre antes de enviar la petición para no perder avisos.
+                let progress = forward_progress(&c, &request_id, tx.clone(), |u| GuiEvent::SummaryProgress(progress_line(u))).await;
+                let payload = serde_json::json!({
+                    "path": path,
+                    "envelope": true,
+                    "request_id": request_id,
+                    "style": style,
+                    "model": model,
+                    "temperature": temperature,
+                    "session_id": session_id,
+                });
+                let data = serde_json::to_vec(&payload).unwrap_or_default();
+                let result = request_with_timeout(&c, "summary.request", data, timeouts().summary).await;
+                if let Some(task) = progress {
+                    task.abort();
+                }
+                match result {
+                    Ok(msg) => match serde_json::from_slice::<AgentEnvelope<String>>(&msg.payload)
+                        .map(|env| report_timing(&tx, env))
+                    {
+                        Ok(AgentResponse::Success(summary)) => {
+                            let _ = tx.send(GuiEvent::Summary(summary));
+                        }
+                        Ok(AgentResponse::Error(e)) => {
+                            let _ = tx.send(failure(format!("Summarizer: {e}"), e.code, RetryAction::Summary));
+                        }
+                        Err(e) => {
+                            let body = String::from_utf8_lossy(&msg.payload);
+                            let _ = tx.send(GuiEvent::Error(format!("Respuesta de resumen inválida: {e} / {body}")));
+                        }
+                    },
+                    Err(e) => {
+                        let msg = format!("summary.request falló: {e}");
+                        let _ = tx.send(failure(msg, ErrorCode::classify(&e), RetryAction::Summary));
+                    }
+                }
+            });
+        }
+    }
 
-                entries.push(EntryView {
-                    name: file_name,
-                    path: p,
-                    is_dir,
-                    size,
-                    kind,
+    /// Envía `followup_question` a la sesión del resumen mostrado.
+    fn ask_followup(&mut self) {
+        let question = self.followup_question.trim().to_string();
+        let Some(session_id) = self.summary_session.clone() else {
+            return;
+        };
+        if self.followup_pending || question.is_empty() {
+            return;
+        }
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        let temperature = self.llm.temperature;
+        if let Some(c) = self.client_clone() {
+            self.followup_pending = true;
+            self.conversation.push((question.clone(), String::new()));
+            self.followup_question.clear();
+            self.rt.spawn(async move {
+                let payload = serde_json::json!({
+                    "session_id": session_id,
+                    "question": question,
+                    "temperature": temperature,
+                    "envelope": true,
                 });
+                let data = serde_json::to_vec(&payload).unwrap_or_default();
+                match request_with_timeout(&c, "summary.followup.request", data, timeouts().summary).await {
+                    Ok(msg) => match serde_json::from_slice::<AgentEnvelope<String>>(&msg.payload)
+                        .map(|env| report_timing(&tx, env))
+                    {
+                        Ok(AgentResponse::Success(answer)) => {
+                            let _ = tx.send(GuiEvent::FollowupAnswer(answer));
+                        }
+                        Ok(AgentResponse::Error(e)) => {
+                            let _ = tx.send(GuiEvent::Error(format!("Summarizer: {e}")));
+                        }
+                        Err(e) => {
+                            let body = String::from_utf8_lossy(&msg.payload);
+                            let _ = tx.send(GuiEvent::Error(format!("Respuesta de seguimiento inválida: {e} / {body}")));
+                        }
+                    },
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(format!("summary.followup.request falló: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Pide al gateway que descargue en Ollama el modelo que faltaba.
+    fn request_pull(&mut self, model: String) {
+        if self.pull_pending {
+            return;
+        }
+        if let Err(e) = self.ensure_nats() {
+            self.push_log(&format!("❌ NATS no disponible: {e}"));
+            return;
+        }
+        let tx = self.tx.clone();
+        if let Some(c) = self.client_clone() {
+            self.pull_pending = true;
+            self.pull_progress = None;
+            self.push_log(&format!("⬇ Descargando modelo '{model}' en Ollama…"));
+            self.rt.spawn(async move {
+                let request_id = new_request_id();
+                let progress = forward_progress(&c, &request_id, tx.clone(), |u| GuiEvent::PullProgress(progress_line(u))).await;
+                let req = OllamaPullRequest { model, request_id: Some(request_id) };
+                let result = request_json::<_, String>(&c, "llm.ollama.pull", &req, OLLAMA_PULL_TIMEOUT).await;
+                if let Some(task) = progress {
+                    task.abort();
+                }
+                match result {
+                    Ok(done) => {
+                        let _ = tx.send(GuiEvent::Pulled(done));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(GuiEvent::Error(format!("llm.ollama.pull: {e}")));
+                    }
+                }
+            });
+        }
+    }
+
+    // ===== Vista previa =====
+
+    fn load_preview_now(&mut self, ctx: &EguiContext) {
+        self.preview_error = None;
+        self.preview_encoding = None;
+        self.preview_editable = false;
+        self.preview_edit = None;
+        self.preview_loaded = None;
+        self.preview_image = None;
+        self.preview_text.clear();
+        let Some(path) = self.selected_path.clone() else {
+            return;
+        };
+        if path.is_dir() {
+            self.preview_text = "(La vista previa solo está disponible para archivos)".to_string();
+            return;
+        }
+        let is_image = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+        if is_image {
+            match self.thumbnail(ctx, &path) {
+                Ok(texture) => {
+                    self.preview_image = Some(texture);
+                    return;
+                }
+                // Imagen ilegible: se cae a la vista previa de texto/binario.
+                Err(e) => self.preview_error = Some(format!("No se pudo decodificar la imagen: {e}")),
+            }
+        }
+        let file = match fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                self.preview_error = Some(format!("No se pudo abrir el archivo: {e}"));
+                return;
+            }
+        };
+        let loaded = file.metadata().map(|m| (m.len(), m.modified().ok())).ok();
+        // Un byte de más distingue el archivo que llena justo el límite del que lo supera.
+        let mut buf = Vec::with_capacity(self.preview_max_bytes.min(1 << 20));
+        if let Err(e) = file.take(self.preview_max_bytes as u64 + 1).read_to_end(&mut buf) {
+            self.preview_error = Some(format!("Error leyendo: {e}"));
+            return;
+        }
+        let mut truncated = buf.len() > self.preview_max_bytes;
+        buf.truncate(self.preview_max_bytes);
+        let complete = loaded.is_some_and(|(len, _)| len == buf.len() as u64);
+        let gzip = transparent_gzip_from_env() && is_gzip(&path, &buf);
+        // Los .gz se muestran descomprimidos (solo los primeros `preview_max_bytes`).
+        if gzip {
+            match fs::File::open(&path).map_err(anyhow::Error::from).and_then(|f| gunzip_limited(f, self.preview_max_bytes as u64)) {
+                Ok((raw, cut)) => {
+                    buf = raw;
+                    truncated = cut;
+                }
+                Err(e) => self.preview_error = Some(format!("No se pudo descomprimir: {e}")),
             }
         }
+        let (mut text, encoding) = decode_preview(&buf);
+        self.preview_encoding = Some(encoding);
+        // Guardar reescribe el archivo entero: solo si la vista previa lo contiene completo y tal cual.
+        self.preview_editable = complete && !truncated && !gzip && encoding == "UTF-8" && self.preview_error.is_none();
+        self.preview_loaded = loaded;
+
+        // Si no termina en \n y hay más datos, indica truncado:
+        if truncated {
+            text.push_str("\n… (vista previa truncada)");
+        }
+        self.preview_text = text;
+    }
+
+    /// Escribe el texto editado sobre el archivo seleccionado, tras confirmarlo.
+    /// Se niega si el archivo ha cambiado en disco desde que se cargó la vista previa.
+    fn save_preview_edit(&mut self) {
+        let (Some(path), Some(text), Some(loaded)) =
+            (self.selected_path.clone(), self.preview_edit.clone(), self.preview_loaded)
+        else {
+            return;
+        };
+        match fs::metadata(&path) {
+            Ok(meta) if (meta.len(), meta.modified().ok()) != loaded => {
+                self.push_log(&format!(
+                    "❌ {} ha cambiado en disco desde que se abrió; recárguelo antes de guardar",
+                    path.display()
+                ));
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                self.push_log(&format!("❌ No se pudo comprobar {}: {e}", path.display()));
+                return;
+            }
+        }
+        let confirmed = rfd::MessageDialog::new()
+            .set_level(rfd::MessageLevel::Warning)
+            .set_title("Guardar cambios")
+            .set_description(format!("¿Sobrescribir {}?", path.display()))
+            .set_buttons(rfd::MessageButtons::YesNo)
+            .show()
+            == rfd::MessageDialogResult::Yes;
+        if !confirmed {
+            return;
+        }
+        match fs::write(&path, text.as_bytes()) {
+            Ok(()) => {
+                self.push_log(&format!("💾 Guardado: {} ({} bytes)", path.display(), text.len()));
+                self.preview_dirty = true;
+            }
+            Err(e) => self.push_log(&format!("❌ No se pudo guardar {}: {e}", path.display())),
+        }
+    }
+
+    /// Miniatura de `path` (lado máximo `THUMBNAIL_MAX_DIM`), decodificada una sola vez.
+    fn thumbnail(&mut self, ctx: &EguiContext, path: &Path) -> Result<egui::TextureHandle> {
+        if let Some(texture) = self.thumbnails.get(path) {
+            return Ok(texture.clone());
+        }
+        let img = image::open(path)?
+            .thumbnail(THUMBNAIL_MAX_DIM, THUMBNAIL_MAX_DIM)
+            .to_rgba8();
+        let size = [img.width() as usize, img.height() as usize];
+        let color = egui::ColorImage::from_rgba_unmultiplied(size, img.as_raw());
+        let texture = ctx.load_texture(path.to_string_lossy(), color, egui::TextureOptions::LINEAR);
+        if self.thumbnails.len() >= THUMBNAIL_CACHE_LIMIT {
+            self.thumbnails.clear();
+        }
+        self.thumbnails.insert(path.to_path_buf(), texture.clone());
+        Ok(texture)
+    }
+
+    // ===== Explorador =====
+
+    fn refresh_dir(&mut self) {
+        self.dir_items.clear();
+        let dir = self.current_dir.clone();
+        let show_hidden = self.show_hidden;
+        let filter = self.filter_text.to_lowercase();
+
+        let mut entries: Vec<EntryView> = Vec::new();
+        let mut skipped = Vec::new();
+        match fs::read_dir(&dir) {
+            Err(e) => skipped.push(format!("{}: {e}", dir.display())),
+            Ok(read) => {
+                for ent in read {
+                    let ent = match ent {
+                        Ok(ent) => ent,
+                        Err(e) => {
+                            skipped.push(e.to_string());
+                            continue;
+                        }
+                    };
+                    let p = ent.path();
+                    let file_name = ent.file_name().to_string_lossy().to_string();
+
+                    // ocultos
+                    if !show_hidden && file_name.starts_with('.') {
+                        continue;
+                    }
+
+                    // filtro
+                    if !filter.is_empty() && !file_name.to_lowercase().contains(&filter) {
+                        continue;
+                    }
+
+                    let is_dir = p.is_dir();
+                    let is_symlink = ent.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+                    let (size, kind, modified) = if is_dir {
+                        let modified = fs::metadata(&p).ok().and_then(|m| m.modified().ok());
+                        (None, "Carpeta".to_string(), modified)
+                    } else {
+                        let meta = match fs::metadata(&p) {
+                            Ok(m) => Some(m),
+                            Err(e) => {
+                                skipped.push(format!("{file_name}: {e}"));
+                                None
+                            }
+                        };
+                        let sz = meta.as_ref().map(|m| m.len());
+                        let modified = meta.as_ref().and_then(|m| m.modified().ok());
+                        let kind = p
+                            .extension()
+                            .and_then(|e| e.to_str())
+                            .unwrap_or("archivo")
+                            .to_string();
+                        (sz, kind, modified)
+                    };
+
+                    entries.push(EntryView {
+                        name: file_name,
+                        path: p,
+                        is_dir,
+                        is_symlink,
+                        size,
+                        kind,
+                        modified,
+                    });
+                }
+            }
+        }
+        self.dir_skipped = skipped;
 
         // ordenar
         entries.sort_by(|a, b| {
@@ -531,6 +1863,7 @@ impl ClientApp {
                 SortBy::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
                 SortBy::Kind => a.kind.to_lowercase().cmp(&b.kind.to_lowercase()),
                 SortBy::Size => a.size.unwrap_or(0).cmp(&b.size.unwrap_or(0)),
+                SortBy::Modified => a.modified.cmp(&b.modified),
             };
             if self.sort_asc {
                 ord
@@ -539,8 +1872,17 @@ impl ClientApp {
             }
         });
 
+        self.dir_total = entries.len();
+        entries.truncate(self.max_dir_entries);
+
+        // La multiselección sobrevive al refresco solo para lo que sigue en el listado.
+        self.selected_paths.retain(|p| entries.iter().any(|e| &e.path == p));
+        if self.selected_paths.len() < 2 {
+            self.selection_anchor = None;
+        }
         self.dir_items = entries;
         self.needs_refresh = false;
+        self.last_refresh = Instant::now();
     }
 
     fn human_size(size: u64) -> String {
@@ -563,11 +1905,10 @@ impl ClientApp {
         }
     }
 
-    fn age_str(path: &PathBuf) -> Option<String> {
-        let meta = fs::metadata(path).ok()?;
-        let modified = meta.modified().ok()?;
+    /// Tiempo transcurrido desde `time` en formato corto ("5 min", "2 d").
+    fn age_since(time: SystemTime) -> String {
         let now = SystemTime::now();
-        let dur = now.duration_since(modified).ok().unwrap_or(Duration::ZERO);
+        let dur = now.duration_since(time).ok().unwrap_or(Duration::ZERO);
 
         let secs = dur.as_secs();
         let (val, unit) = if secs < 60 {
@@ -579,7 +1920,7 @@ impl ClientApp {
         } else {
             (secs / 86400, "d")
         };
-        Some(format!("{val} {unit}"))
+        format!("{val} {unit}")
     }
 
     fn go_up(&mut self) {
@@ -589,6 +1930,41 @@ impl ClientApp {
         }
     }
 
+    /// Selecciona el primer archivo soltado sobre la ventana y navega a su carpeta.
+    fn handle_dropped_files(&mut self, ctx: &EguiContext) {
+        let dropped: Vec<PathBuf> = ctx.input(|i| {
+            i.raw.dropped_files.iter().filter_map(|f| f.path.clone()).collect()
+        });
+        let Some((first, rest)) = dropped.split_first() else {
+            return;
+        };
+        if let Some(parent) = first.parent() {
+            self.current_dir = parent.to_path_buf();
+            self.needs_refresh = true;
+        }
+        self.selected_path = Some(first.clone());
+        self.preview_dirty = true;
+        self.push_log(&format!("📥 Archivo soltado: {}", first.display()));
+        for extra in rest {
+            self.push_log(&format!("ℹ️ Ignorado (solo se selecciona el primero): {}", extra.display()));
+        }
+    }
+
+    /// Pone el archivo seleccionado al principio de los recientes (sin duplicados) y lo persiste.
+    fn track_recent_selection(&mut self) {
+        let Some(sel) = &self.selected_path else {
+            return;
+        };
+        if self.recent_files.first() == Some(sel) || !sel.is_file() {
+            return;
+        }
+        let sel = sel.clone();
+        self.recent_files.retain(|p| p != &sel && p.exists());
+        self.recent_files.insert(0, sel);
+        self.recent_files.truncate(MAX_RECENT_FILES);
+        self.save_settings();
+    }
+
     // ===== Acciones rápidas (OS) =====
 
     fn open_in_os(path: &Path) -> Result<()> {
@@ -607,6 +1983,123 @@ impl ClientApp {
         Ok(())
     }
 
+    /// Abre `path` en la línea `line` con `editor`, que puede llevar argumentos propios
+    /// ("code --new-window"). Cada familia de editores recibe la línea a su manera; los
+    /// desconocidos solo reciben la ruta. Sin editor se usa la aplicación del sistema.
+    fn open_in_editor(editor: &str, path: &Path, line: u64) -> Result<()> {
+        let mut parts = editor.split_whitespace();
+        let Some(program) = parts.next() else {
+            return Self::open_in_os(path);
+        };
+        let name = Path::new(program)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let at_line = format!("{}:{}", path.display(), line);
+        let mut cmd = Command::new(program);
+        cmd.args(parts);
+        match name.as_str() {
+            "code" | "code-insiders" | "codium" | "cursor" => cmd.arg("-g").arg(at_line),
+            "subl" | "sublime_text" | "zed" | "hx" | "helix" => cmd.arg(at_line),
+            "idea" | "idea64" | "clion" | "rustrover" | "pycharm" | "webstorm" | "goland" => {
+                cmd.arg("--line").arg(line.to_string()).arg(path)
+            }
+            "vim" | "nvim" | "vi" | "gvim" | "nano" | "emacs" | "emacsclient" | "kak" | "micro" => {
+                cmd.arg(format!("+{line}")).arg(path)
+            }
+            _ => cmd.arg(path),
+        };
+        cmd.spawn().with_context(|| format!("No se pudo lanzar el editor '{program}'"))?;
+        Ok(())
+    }
+
+    // ===== Exportar resultados =====
+
+    /// Nombre base sugerido para los archivos exportados (según el archivo seleccionado).
+    fn export_stem(&self) -> String {
+        self.selected_path
+            .as_ref()
+            .and_then(|p| p.file_stem())
+            .and_then(|s| s.to_str())
+            .unwrap_or("resultado")
+            .to_string()
+    }
+
+    fn save_summary_dialog(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Guardar resumen como…")
+            .set_file_name(format!("{}_resumen.md", self.export_stem()))
+            .add_filter("Markdown", &["md"])
+            .add_filter("Texto", &["txt"])
+            .save_file()
+        else {
+            return;
+        };
+        match fs::write(&path, &self.summary_text) {
+            Ok(()) => self.push_log(&format!("💾 Resumen guardado en {}", path.display())),
+            Err(e) => self.push_log(&format!("❌ No se pudo guardar el resumen: {e}")),
+        }
+    }
+
+    fn save_metadata_dialog(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Guardar metadatos como…")
+            .set_file_name(format!("{}_metadatos.json", self.export_stem()))
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return;
+        };
+        // Si el texto es JSON válido se guarda formateado; si no, tal cual.
+        let content = serde_json::from_str::<Value>(&self.metadata_text)
+            .ok()
+            .and_then(|v| serde_json::to_string_pretty(&v).ok())
+            .unwrap_or_else(|| self.metadata_text.clone());
+        match fs::write(&path, content) {
+            Ok(()) => self.push_log(&format!("💾 Metadatos guardados en {}", path.display())),
+            Err(e) => self.push_log(&format!("❌ No se pudieron guardar los metadatos: {e}")),
+        }
+    }
+
+    /// Exporta el informe de inspección junto con la URL de NATS y la config actual
+    /// (API key enmascarada) para adjuntarlo a reportes de errores.
+    fn export_provider_report_dialog(&mut self) {
+        let Some(report) = self.provider_report.clone() else {
+            self.push_log("ℹ️ No hay informe de proveedores que exportar");
+            return;
+        };
+        let ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let Some(path) = rfd::FileDialog::new()
+            .set_title("Exportar informe de proveedores…")
+            .set_file_name(format!("informe_proveedores_{ts}.json"))
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return;
+        };
+        let mut llm = self.llm.clone();
+        if !llm.api_key.is_empty() {
+            llm.api_key = "****".to_string();
+        }
+        let bundle = serde_json::json!({
+            "exported_at": ts,
+            "nats_url": self.nats_url,
+            "llm_config": llm,
+            "gateway_config": self.gateway_config,
+            "report": report,
+        });
+        let result = serde_json::to_string_pretty(&bundle)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| fs::write(&path, json).map_err(anyhow::Error::from));
+        match result {
+            Ok(()) => self.push_log(&format!("💾 Informe exportado a {}", path.display())),
+            Err(e) => self.push_log(&format!("❌ No se pudo exportar el informe: {e}")),
+        }
+    }
+
     // ===== UI helpers =====
 
     fn poll_events(&mut self) {
@@ -615,48 +2108,243 @@ impl ClientApp {
             while let Ok(evt) = rx.try_recv() {
                 match evt {
                     GuiEvent::Status(s) => self.push_log(&s),
-                    GuiEvent::Error(e) => self.push_log(&format!("❌ {e}")),
+                    GuiEvent::Connection(state) => {
+                        if state != self.nats_state {
+                            match state {
+                                ConnectionState::Reconnecting => self.push_log("⚠️ Conexión NATS perdida; reintentando…"),
+                                ConnectionState::Connected => self.push_log("✅ Conexión NATS restablecida"),
+                                ConnectionState::Disconnected => {}
+                            }
+                            self.nats_state = state;
+                        }
+                    }
+                    GuiEvent::Error(e) => self.fail(&e),
+                    GuiEvent::Retryable(e, action) => {
+                        self.fail(&e);
+                        self.retry = Some(action);
+                    }
+                    GuiEvent::StressPing(stats) => {
+                        self.stress_ping_running = false;
+                        self.push_log(&format!(
+                            "📡 Ráfaga de {} pings: avg {:.1} ms, p95 {:.1} ms, errores {}",
+                            stats.total, stats.avg_ms, stats.p95_ms, stats.errors
+                        ));
+                        self.stress_ping_stats = Some(stats);
+                    }
+                    GuiEvent::Services(list) => {
+                        self.services_pending = false;
+                        self.push_log(&format!("🧭 {} servicios descubiertos", list.len()));
+                        self.services = Some(list);
+                    }
+                    GuiEvent::Usage(report) => {
+                        self.session_tokens.0 += report.prompt_tokens as u64;
+                        self.session_tokens.1 += report.completion_tokens as u64;
+                        match report.estimated_cost_usd {
+                            Some(cost) => self.session_cost_usd += cost,
+                            None => self.session_unpriced += 1,
+                        }
+                    }
                     GuiEvent::PingMs(ms) => {
                         self.last_ping_ms = Some(ms);
                         self.push_log(&format!("📡 Ping Gateway: {ms} ms"));
                     }
-                    GuiEvent::Models(list) => {
-                        self.models = list;
-                        if !self.models.is_empty() && !self.models.contains(&self.llm.model) {
-                            self.llm.model = self.models[0].clone();
-                            self.push_log(&format!("ℹ️ Modelo ajustado a '{}'", self.llm.model));
+                    GuiEvent::Models { key, list } => {
+                        let now = SystemTime::now();
+                        self.push_log(&format!("📚 Modelos disponibles: {}", list.len()));
+                        self.models_cache.insert(key.clone(), (list.clone(), now));
+                        // Si el proveedor cambió mientras tanto, solo se guarda en caché.
+                        if key == (self.llm.provider.clone(), self.llm.base_url.clone()) {
+                            self.apply_models(list, now);
                         }
-                        self.push_log(&format!("📚 Modelos disponibles: {}", self.models.len()));
                     }
                     GuiEvent::ProviderReport(rep) => {
                         self.provider_report = Some(rep);
                         self.push_log("🔍 Inspección de proveedores actualizada");
                     }
+                    GuiEvent::GatewayConfig { cfg, apply } => {
+                        self.gateway_config = Some(cfg.clone());
+                        if !apply {
+                            self.push_log("🛰️ Config del gateway actualizada");
+                            continue;
+                        }
+                        // La API key llega enmascarada: nunca se copia sobre la local.
+                        let get = |k: &str| cfg.get(k).and_then(|v| v.as_str()).map(|s| s.to_string());
+                        if let Some(p) = get("provider") {
+                            self.llm.provider = p;
+                        }
+                        if let Some(m) = get("model") {
+                            self.llm.model = m;
+                        }
+                        if let Some(u) = get("base_url") {
+                            self.llm.base_url = u;
+                        }
+                        if let Some(t) = cfg.get("temperature").and_then(|v| v.as_f64()) {
+                            self.llm.temperature = t as f32;
+                        }
+                        if let Some(p) = cfg.get("top_p").and_then(|v| v.as_f64()) {
+                            self.llm.top_p = p as f32;
+                        }
+                        if let Some(p) = cfg.get("frequency_penalty").and_then(|v| v.as_f64()) {
+                            self.llm.frequency_penalty = p as f32;
+                        }
+                        if let Some(p) = cfg.get("presence_penalty").and_then(|v| v.as_f64()) {
+                            self.llm.presence_penalty = p as f32;
+                        }
+                        self.push_log("🔄 Ajustes sincronizados con el gateway");
+                    }
                     GuiEvent::Metadata(m) => {
+                        self.metadata_pending = false;
                         self.metadata_text = m;
                         self.push_log("📊 Metadatos recibidos");
                     }
+                    GuiEvent::DirMetadata(entries) => {
+                        self.dir_export_pending = false;
+                        let failed = entries.iter().filter(|e| e.error.is_some()).count();
+                        self.push_log(&format!("📦 Metadatos de {} archivos ({} con error)", entries.len(), failed));
+                        self.save_dir_metadata_dialog(&entries);
+                    }
+                    GuiEvent::Comparison(cmp) => {
+                        self.compare_pending = false;
+                        self.comparison = Some(cmp);
+                        self.push_log("⚖ Comparación de metadatos lista");
+                    }
+                    GuiEvent::Curl(cmd) => {
+                        self.last_curl = Some(cmd);
+                        self.push_log("🐚 cURL del gateway recibido");
+                    }
+                    GuiEvent::SummaryProgress(line) => {
+                        if self.summary_pending {
+                            self.summary_progress = Some(line);
+                        }
+                    }
+                    GuiEvent::BatchProgress(update) => {
+                        if self.summary_pending {
+                            if let (Some(done), Some(total)) = (update.step, update.total) {
+                                self.batch_progress = Some((done, total));
+                            }
+                            self.summary_progress = Some(progress_line(update));
+                        }
+                    }
+                    GuiEvent::BatchSummary(report) => {
+                        self.summary_pending = false;
+                        self.batch_progress = None;
+                        self.summary_model = self.summary_pending_model.take();
+                        let mut text = String::new();
+                        for item in &report.items {
+                            let name = Path::new(&item.path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                            text.push_str(&format!("### {name}\n\n"));
+                            match (&item.summary, &item.error) {
+                                (Some(summary), _) => text.push_str(summary),
+                                (None, Some(e)) => text.push_str(&format!("❌ {e}")),
+                                (None, None) => {}
+                            }
+                            text.push_str("\n\n");
+                        }
+                        self.summary_text = text;
+                        self.summary_session = None;
+                        self.conversation.clear();
+                        self.push_log(&format!(
+                            "📝 Lote resumido: {} correctos, {} con error ({:.1} s)",
+                            report.succeeded,
+                            report.failed,
+                            report.elapsed_ms as f64 / 1000.0
+                        ));
+                        self.batch_report = Some(report);
+                    }
+                    GuiEvent::SearchResults(found) => {
+                        self.search_pending = false;
+                        let lines: usize = found.files.iter().map(|f| f.lines.len()).sum();
+                        self.push_log(&format!(
+                            "🔎 {} coincidencias en {} archivos{}",
+                            lines,
+                            found.files.len(),
+                            if found.truncated { " (resultados truncados)" } else { "" }
+                        ));
+                        self.search_results = Some(found);
+                    }
+                    GuiEvent::DirSummary(dir, summary) => {
+                        self.dir_summary_pending = false;
+                        self.push_log(&format!(
+                            "📊 Composición de {}: {} archivos en {} extensiones",
+                            dir.display(),
+                            summary.total_files,
+                            summary.extensions.len()
+                        ));
+                        self.dir_summary = Some((dir, summary));
+                    }
+                    GuiEvent::PullProgress(line) => {
+                        if self.pull_pending {
+                            self.pull_progress = Some(line);
+                        }
+                    }
+                    GuiEvent::Pulled(done) => {
+                        self.pull_pending = false;
+                        self.pull_offer = None;
+                        self.push_log(&format!("✅ {done}"));
+                    }
                     GuiEvent::Summary(s) => {
+                        self.summary_pending = false;
                         self.summary_text = s;
+                        self.summary_model = self.summary_pending_model.take();
+                        self.summary_session = self.summary_pending_session.take();
+                        self.conversation.clear();
+                        self.batch_report = None;
                         self.push_log("📝 Resumen recibido");
                     }
+                    GuiEvent::FollowupAnswer(answer) => {
+                        self.followup_pending = false;
+                        if let Some((_, slot)) = self.conversation.last_mut() {
+                            *slot = answer;
+                        }
+                        self.push_log("💬 Respuesta recibida");
+                    }
                 }
             }
         }
         self.events_rx = rx_opt;
     }
 
-    fn apply_theme(&mut self, ctx: &EguiContext, dark: bool) {
-        if dark {
-            ctx.set_visuals(egui::Visuals::dark());
-        } else {
-            ctx.set_visuals(egui::Visuals::light());
+    /// Aplica el tema (`dark_mode`) teñido con el color de acento.
+    /// Un error cierra cualquier acción pendiente; el log indica cuál falló.
+    fn fail(&mut self, e: &str) {
+        self.metadata_pending = false;
+        if self.summary_pending && self.summary_pending_model.take().is_some() {
+            // La regeneración falló: el resumen mostrado sigue siendo el anterior.
+            self.previous_summary = None;
+        }
+        self.summary_pending = false;
+        self.summary_pending_session = None;
+        if self.followup_pending {
+            // La pregunta sin respuesta se devuelve al campo para poder repetirla.
+            self.followup_pending = false;
+            if let Some((question, _)) = self.conversation.pop() {
+                self.followup_question = question;
+            }
         }
+        self.batch_progress = None;
+        self.compare_pending = false;
+        self.dir_export_pending = false;
+        self.dir_summary_pending = false;
+        self.search_pending = false;
+        self.pull_pending = false;
+        if let Some(model) = not_pulled_model(e) {
+            self.pull_offer = Some(model.to_string());
+        }
+        self.push_log(&format!("❌ {e}"));
+    }
+
+    fn apply_theme(&self, ctx: &EguiContext) {
+        let mut visuals = if self.dark_mode { egui::Visuals::dark() } else { egui::Visuals::light() };
+        visuals.selection.bg_fill = self.accent;
+        visuals.hyperlink_color = self.accent;
+        visuals.widgets.hovered.bg_stroke.color = self.accent;
+        visuals.widgets.active.bg_fill = self.accent;
+        ctx.set_visuals(visuals);
     }
 
     fn ui_menubar(&mut self, ctx: &EguiContext, ui: &mut Ui) {
         egui::menu::bar(ui, |ui| {
-            ui.heading(RichText::new("🧩 Multi-Agent Client").strong());
+            ui.heading(RichText::new("🧩 Multi-Agent Client").strong().color(self.accent));
             ui.separator();
 
             // Menú de paneles/ventanas
@@ -672,14 +2360,31 @@ impl ClientApp {
 
             ui.separator();
 
-            ui.menu_button("🎨 Tema", |ui| {
-                if ui.button("Oscuro").clicked() {
-                    self.apply_theme(ctx, true);
-                    ui.close_menu();
+            ui.menu_button("✏ Editor", |ui| {
+                ui.label("Comando para abrir en una línea:");
+                let edit = ui.add(egui::TextEdit::singleline(&mut self.editor).hint_text("code, subl, idea, vim…"));
+                if edit.lost_focus() {
+                    self.save_settings();
                 }
-                if ui.button("Claro").clicked() {
-                    self.apply_theme(ctx, false);
-                    ui.close_menu();
+                ui.weak("Vacío: se abre con la aplicación del sistema.");
+            });
+
+            ui.menu_button("🎨 Tema", |ui| {
+                let mut changed = false;
+                changed |= ui.radio_value(&mut self.dark_mode, true, "Oscuro").changed();
+                changed |= ui.radio_value(&mut self.dark_mode, false, "Claro").changed();
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Acento:");
+                    changed |= ui.color_edit_button_srgba(&mut self.accent).changed();
+                    if ui.small_button("↺").on_hover_text("Color por defecto").clicked() {
+                        self.accent = DEFAULT_ACCENT;
+                        changed = true;
+                    }
+                });
+                if changed {
+                    self.apply_theme(ctx);
+                    self.save_settings();
                 }
             });
 
@@ -694,6 +2399,35 @@ impl ClientApp {
             };
             ui.label(format!("Ping: {ping_text}"));
 
+            let (color, state) = match self.nats_state {
+                ConnectionState::Connected => (Color32::from_rgb(60, 180, 75), "conectado"),
+                ConnectionState::Reconnecting => (Color32::from_rgb(230, 180, 40), "reconectando…"),
+                ConnectionState::Disconnected => (Color32::from_rgb(210, 60, 60), "desconectado"),
+            };
+            let dot = ui
+                .add(egui::Label::new(RichText::new("●").color(color)).sense(egui::Sense::click()))
+                .on_hover_text(format!("NATS {state} ({}). Clic para reconectar.", self.nats_url));
+            if dot.clicked() {
+                self.reconnect_nats();
+            }
+
+            let mut cost_hover = format!(
+                "Sesión: {} tokens de entrada, {} de salida.",
+                self.session_tokens.0, self.session_tokens.1
+            );
+            if self.session_unpriced > 0 {
+                cost_hover.push_str(&format!(" {} respuestas sin precio conocido.", self.session_unpriced));
+            }
+            cost_hover.push_str(" Clic para reiniciar.");
+            let cost = ui
+                .add(egui::Label::new(format!("💲 {:.4}", self.session_cost_usd)).sense(egui::Sense::click()))
+                .on_hover_text(cost_hover);
+            if cost.clicked() {
+                self.session_tokens = (0, 0);
+                self.session_cost_usd = 0.0;
+                self.session_unpriced = 0;
+            }
+
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                 ui.label(RichText::new("Angel A. Urbina — Copyright 2025").italics());
             });
@@ -705,7 +2439,7 @@ impl ClientApp {
             self.refresh_dir();
         }
 
-        ui.heading("📁 Explorador de archivos");
+        ui.heading(RichText::new("📁 Explorador de archivos").color(self.accent));
         ui.add_space(6.0);
 
         // Barra de acciones de navegación
@@ -716,47 +2450,76 @@ impl ClientApp {
             if ui.button("⟳ Recargar").clicked() {
                 self.needs_refresh = true;
             }
-            if ui.button("⭐ Favorito").clicked() {
-                if !self.favorites.contains(&self.current_dir) {
-                    self.favorites.push(self.current_dir.clone());
-                }
+            if ui.button("⭐ Favorito").clicked() && !self.favorites.contains(&self.current_dir) {
+                self.favorites.push(self.current_dir.clone());
+                self.save_settings();
             }
         });
 
         // Breadcrumbs seguros (snapshot para evitar préstamos activos)
         ui.add_space(4.0);
-        ui.horizontal_wrapped(|ui| {
-            let path_snapshot = self.current_dir.clone();
-            let mut acc = PathBuf::new();
-            let mut first = true;
-
-            #[cfg(target_family = "unix")]
-            {
-                acc.push("/");
-                if ui.button("/").clicked() {
-                    self.current_dir = PathBuf::from("/");
-                    self.needs_refresh = true;
-                }
-                ui.label(" / ");
+        ui.horizontal(|ui| {
+            let label = if self.path_edit_mode { "🧭" } else { "✏" };
+            let hover = if self.path_edit_mode { "Volver a las migas de pan" } else { "Escribir una ruta" };
+            if ui.button(label).on_hover_text(hover).clicked() {
+                self.path_edit_mode = !self.path_edit_mode;
+                self.path_edit_text = self.current_dir.to_string_lossy().to_string();
+                self.path_edit_error = None;
             }
-
-            for comp in path_snapshot.components() {
-                let c = comp.as_os_str().to_string_lossy().to_string();
-                if c == "/" {
-                    continue;
+            if self.path_edit_mode {
+                let resp = ui.add(
+                    egui::TextEdit::singleline(&mut self.path_edit_text).desired_width(f32::INFINITY),
+                );
+                if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    let target = PathBuf::from(self.path_edit_text.trim());
+                    if target.is_dir() {
+                        self.current_dir = target;
+                        self.needs_refresh = true;
+                        self.path_edit_mode = false;
+                        self.path_edit_error = None;
+                    } else {
+                        self.path_edit_error = Some(format!("No es un directorio: {}", target.display()));
+                    }
                 }
-                if !first {
+            }
+        });
+        if let Some(err) = &self.path_edit_error {
+            ui.colored_label(Color32::from_rgb(200, 80, 80), err);
+        }
+        if !self.path_edit_mode {
+            ui.horizontal_wrapped(|ui| {
+                let path_snapshot = self.current_dir.clone();
+                let mut acc = PathBuf::new();
+                let mut first = true;
+
+                #[cfg(target_family = "unix")]
+                {
+                    acc.push("/");
+                    if ui.button("/").clicked() {
+                        self.current_dir = PathBuf::from("/");
+                        self.needs_refresh = true;
+                    }
                     ui.label(" / ");
                 }
-                first = false;
-                acc = acc.join(&c);
-                let acc_clone = acc.clone();
-                if ui.button(&c).clicked() {
-                    self.current_dir = acc_clone;
-                    self.needs_refresh = true;
+
+                for comp in path_snapshot.components() {
+                    let c = comp.as_os_str().to_string_lossy().to_string();
+                    if c == "/" {
+                        continue;
+                    }
+                    if !first {
+                        ui.label(" / ");
+                    }
+                    first = false;
+                    acc = acc.join(&c);
+                    let acc_clone = acc.clone();
+                    if ui.button(&c).clicked() {
+                        self.current_dir = acc_clone;
+                        self.needs_refresh = true;
+                    }
                 }
-            }
-        });
+            });
+        }
 
         ui.add_space(6.0);
 
@@ -764,7 +2527,7 @@ impl ClientApp {
         ui.group(|ui| {
             ui.horizontal(|ui| {
                 ui.label("Filtro:");
-                ui.text_edit_singleline(&mut self.filter_text);
+                self.filter_has_focus = ui.text_edit_singleline(&mut self.filter_text).has_focus();
                 if ui.button("Limpiar").clicked() {
                     self.filter_text.clear();
                     self.needs_refresh = true;
@@ -772,14 +2535,26 @@ impl ClientApp {
                 ui.checkbox(&mut self.show_hidden, "Ocultos");
             });
 
+            ui.horizontal(|ui| {
+                ui.label("Auto-refresco:");
+                ui.add(
+                    egui::DragValue::new(&mut self.auto_refresh_secs)
+                        .clamp_range(0..=3600)
+                        .suffix(" s"),
+                )
+                .on_hover_text("0 = desactivado");
+            });
+
             ui.horizontal(|ui| {
                 ui.label("Ordenar por:");
                 egui::ComboBox::from_id_source("sort_by")
-                    .selected_text(match self.sort_by { SortBy::Name => "Nombre", SortBy::Kind => "Tipo", SortBy::Size => "Tamaño" })
+                    .selected_text(self.sort_by.label())
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.sort_by, SortBy::Name, "Nombre");
-                        ui.selectable_value(&mut self.sort_by, SortBy::Kind, "Tipo");
-                        ui.selectable_value(&mut self.sort_by, SortBy::Size, "Tamaño");
+                        for sort in [SortBy::Name, SortBy::Kind, SortBy::Size, SortBy::Modified] {
+                            if ui.selectable_value(&mut self.sort_by, sort, sort.label()).changed() {
+                                self.needs_refresh = true;
+                            }
+                        }
                     });
                 if ui.button(if self.sort_asc { "⬆︎ Asc" } else { "⬇︎ Desc" }).clicked() {
                     self.sort_asc = !self.sort_asc;
@@ -794,14 +2569,72 @@ impl ClientApp {
         egui::CollapsingHeader::new("⭐ Favoritos")
             .default_open(true)
             .show(ui, |ui| {
-                for fav in self.favorites.clone() {
-                    ui.horizontal(|ui| {
-                        if ui.button("➡").clicked() {
+                let mut remove: Option<usize> = None;
+                let mut reorder: Option<(usize, usize)> = None;
+                for (idx, fav) in self.favorites.clone().into_iter().enumerate() {
+                    let exists = fav.is_dir();
+                    let row = ui.horizontal(|ui| {
+                        ui.dnd_drag_source(egui::Id::new(("fav_drag", idx)), idx, |ui| {
+                            ui.label("☰").on_hover_text("Arrastrar para reordenar");
+                        });
+                        if ui.add_enabled(exists, egui::Button::new("➡")).clicked() {
                             self.current_dir = fav.clone();
                             self.needs_refresh = true;
                         }
-                        ui.label(fav.to_string_lossy());
+                        if exists {
+                            ui.label(fav.to_string_lossy());
+                        } else {
+                            ui.weak(fav.to_string_lossy()).on_hover_text("La carpeta ya no existe");
+                        }
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            if ui.small_button("✖").on_hover_text("Quitar de favoritos").clicked() {
+                                remove = Some(idx);
+                            }
+                        });
                     });
+                    if let Some(from) = row.response.dnd_release_payload::<usize>() {
+                        reorder = Some((*from, idx));
+                    }
+                }
+
+                if let Some(idx) = remove {
+                    self.favorites.remove(idx);
+                    self.save_settings();
+                } else if let Some((from, to)) = reorder {
+                    if from != to && from < self.favorites.len() {
+                        let item = self.favorites.remove(from);
+                        self.favorites.insert(to.min(self.favorites.len()), item);
+                        self.save_settings();
+                    }
+                }
+            });
+
+        egui::CollapsingHeader::new("🕘 Recientes")
+            .default_open(false)
+            .show(ui, |ui| {
+                if self.recent_files.is_empty() {
+                    ui.weak("— Sin archivos recientes.");
+                }
+                let mut pick: Option<PathBuf> = None;
+                for path in &self.recent_files {
+                    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                    let selected = self.selected_path.as_ref() == Some(path);
+                    if ui
+                        .selectable_label(selected, format!("📄 {name}"))
+                        .on_hover_text(path.to_string_lossy())
+                        .clicked()
+                    {
+                        pick = Some(path.clone());
+                    }
+                }
+                if let Some(path) = pick {
+                    if path.is_file() {
+                        self.open_path(path);
+                    } else {
+                        self.recent_files.retain(|p| p != &path);
+                        self.save_settings();
+                        self.push_log(&format!("ℹ️ El archivo ya no existe: {}", path.display()));
+                    }
                 }
             });
 
@@ -820,57 +2653,23 @@ impl ClientApp {
         ui.separator();
 
         // Contenidos del directorio actual (lista con SCROLL)
-        ui.heading("📂 Contenido");
+        ui.horizontal(|ui| {
+            ui.heading(RichText::new("📂 Contenido").color(self.accent));
+            if !self.dir_skipped.is_empty() {
+                ui.colored_label(Color32::YELLOW, format!("⚠ {} omitidos", self.dir_skipped.len()))
+                    .on_hover_text(self.dir_skipped.join("\n"));
+            }
+            if self.dir_total > self.dir_items.len() {
+                ui.colored_label(
+                    Color32::YELLOW,
+                    format!("⚠ Mostrando los primeros {} de {}", self.dir_items.len(), self.dir_total),
+                )
+                .on_hover_text("Use el filtro para acotar el listado o suba GUI_MAX_DIR_ENTRIES");
+            }
+        });
         ui.add_space(4.0);
 
-        egui::ScrollArea::vertical()
-            .id_source("dir_list")
-            .auto_shrink([false; 2])
-            .show(ui, |ui| {
-                // Cabecera
-                ui.horizontal(|ui| {
-                    ui.label(RichText::new("Nombre").strong());
-                    ui.add_space(12.0);
-                    ui.label(RichText::new("Tipo").strong());
-                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                        ui.label(RichText::new("Tamaño").strong());
-                    });
-                });
-                ui.separator();
-
-                for item in self.dir_items.clone() {
-                    let row = ui.horizontal(|ui| {
-                        let icon = if item.is_dir { "📁" } else { "📄" };
-                        let label = format!("{icon} {}", item.name);
-                        let resp = ui.selectable_label(
-                            self.selected_path.as_ref().map(|p| p == &item.path).unwrap_or(false),
-                            label,
-                        );
-                        if resp.clicked() {
-                            self.selected_path = Some(item.path.clone());
-                            self.preview_dirty = true; // cargar vista previa
-                        }
-                        if resp.double_clicked() && item.is_dir {
-                            self.current_dir = item.path.clone();
-                            self.needs_refresh = true;
-                        }
-
-                        ui.add_space(12.0);
-                        ui.label(&item.kind);
-
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            let size_str = item.size.map(Self::human_size).unwrap_or_else(|| "—".into());
-                            ui.label(size_str);
-                        });
-                    });
-
-                    if row.response.hovered() {
-                        if let Some(age) = Self::age_str(&item.path) {
-                            row.response.on_hover_text(format!("Modificado hace {age}"));
-                        }
-                    }
-                }
-            });
+        self.ui_dir_table(ui);
 
         ui.add_space(6.0);
         // Acciones sobre el archivo seleccionado:
@@ -880,18 +2679,98 @@ impl ClientApp {
                 if ui.button("📊 Metadatos").clicked() {
                     self.request_metadata();
                 }
-                if ui.button("📝 Resumen").clicked() {
+                let summary_btn = ui.add_enabled(!self.summary_pending, egui::Button::new("📝 Resumen"));
+                if summary_btn.clicked() {
                     self.request_summary();
                 }
+                let style_label = if self.summary_style.is_empty() { "libre" } else { self.summary_style.as_str() };
+                egui::ComboBox::from_id_source("summary_style")
+                    .selected_text(style_label.to_string())
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.summary_style, String::new(), "libre");
+                        for (name, _) in SUMMARY_STYLES {
+                            ui.selectable_value(&mut self.summary_style, name.to_string(), *name);
+                        }
+                    })
+                    .response
+                    .on_hover_text("Formato del resumen");
+                if ui.button("📌 Fijar").on_hover_text("Fijar como archivo A para comparar").clicked() {
+                    self.compare_path = self.selected_path.clone();
+                }
+                let can_compare = self.compare_path.is_some()
+                    && self.compare_path != self.selected_path
+                    && !self.compare_pending;
+                if ui.add_enabled(can_compare, egui::Button::new("⚖ Comparar")).clicked() {
+                    self.request_comparison();
+                }
             });
             if let Some(sel) = &self.selected_path {
                 ui.label(format!("Seleccionado: {}", sel.file_name().and_then(|s| s.to_str()).unwrap_or("")));
             }
         });
+        if let Some(action) = self.retry {
+            ui.horizontal(|ui| {
+                ui.colored_label(Color32::YELLOW, format!("⚠ Falló la petición de {} por un error transitorio", action.label()));
+                if ui.button("🔁 Reintentar").clicked() {
+                    match action {
+                        RetryAction::Metadata => self.request_metadata(),
+                        RetryAction::Summary => self.request_summary(),
+                    }
+                }
+                if ui.small_button("✖").clicked() {
+                    self.retry = None;
+                }
+            });
+        }
+        let batch = self.batch_paths();
+        if batch.len() > 1 {
+            ui.horizontal(|ui| {
+                ui.label(format!("🗂 {} archivos seleccionados:", batch.len()));
+                if ui.add_enabled(!self.metadata_pending, egui::Button::new("📊 Metadatos")).clicked() {
+                    self.request_batch_metadata();
+                }
+                if ui.add_enabled(!self.summary_pending, egui::Button::new("📝 Resúmenes")).clicked() {
+                    self.request_batch_summary();
+                }
+                if ui.button("🔗 Copiar rutas").clicked() {
+                    let text = batch.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join("\n");
+                    ui.output_mut(|o| o.copied_text = text);
+                    self.push_log(&format!("📋 Copiadas {} rutas", batch.len()));
+                }
+                if ui.small_button("✖").on_hover_text("Quitar multiselección").clicked() {
+                    self.selected_paths.clear();
+                    self.selection_anchor = None;
+                }
+            });
+        }
+        ui.horizontal(|ui| {
+            let export = ui
+                .add_enabled(!self.dir_export_pending, egui::Button::new("📦 Exportar metadatos de la carpeta"))
+                .on_hover_text("Manifiesto CSV/JSON con tamaño, tipo y fechas de cada archivo");
+            if export.clicked() {
+                self.request_dir_metadata();
+            }
+            ui.checkbox(&mut self.dir_export_hash, "Incluir SHA-256");
+            if self.dir_export_pending {
+                ui.spinner();
+            }
+        });
+        ui.horizontal(|ui| {
+            let summary = ui
+                .add_enabled(!self.dir_summary_pending, egui::Button::new("📊 Composición de la carpeta"))
+                .on_hover_text("Archivos y bytes por extensión");
+            if summary.clicked() {
+                self.request_dir_summary();
+            }
+            ui.checkbox(&mut self.dir_summary_recursive, "Recursivo");
+            if self.dir_summary_pending {
+                ui.spinner();
+            }
+        });
     }
 
     fn ui_center_results(&mut self, ui: &mut Ui) {
-        ui.heading("🧾 Resultados");
+        ui.heading(RichText::new("🧾 Resultados").color(self.accent));
         ui.add_space(8.0);
 
         // Info + acciones rápidas
@@ -940,23 +2819,151 @@ impl ClientApp {
                     }
                 });
             });
+            ui.add_space(4.0);
+            ui.horizontal(|ui| {
+                ui.add_enabled_ui(!self.summary_text.is_empty(), |ui| {
+                    if ui.button("📋 Copiar resumen").clicked() {
+                        let s = self.summary_text.clone();
+                        ui.output_mut(|o| o.copied_text = s);
+                        self.push_log("📋 Resumen copiado al portapapeles");
+                    }
+                    if ui.button("💾 Guardar resumen…").clicked() {
+                        self.save_summary_dialog();
+                    }
+                });
+                ui.add_enabled_ui(!self.metadata_text.is_empty(), |ui| {
+                    if ui.button("📋 Copiar metadatos").clicked() {
+                        let s = self.metadata_text.clone();
+                        ui.output_mut(|o| o.copied_text = s);
+                        self.push_log("📋 Metadatos copiados al portapapeles");
+                    }
+                    if ui.button("💾 Guardar metadatos…").clicked() {
+                        self.save_metadata_dialog();
+                    }
+                });
+            });
         });
 
+        ui.add_space(8.0);
+        self.ui_search(ui);
         ui.add_space(8.0);
 
         // Resumen / Metadatos lado a lado
         ui.columns(2, |cols| {
             cols[0].group(|ui| {
-                ui.heading("📝 Resumen");
+                ui.horizontal(|ui| {
+                    ui.heading(RichText::new("📝 Resumen").color(self.accent));
+                    if self.summary_pending {
+                        ui.spinner();
+                        ui.weak(self.summary_progress.as_deref().unwrap_or("Generando…"));
+                    }
+                    if let Some((done, total)) = self.batch_progress.filter(|_| self.summary_pending) {
+                        let fraction = done as f32 / total.max(1) as f32;
+                        ui.add(egui::ProgressBar::new(fraction).desired_width(140.0).text(format!("{done}/{total}")));
+                    }
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        let models = self.models.clone();
+                        let enabled = !self.summary_pending && !self.summary_text.is_empty() && !models.is_empty();
+                        ui.add_enabled_ui(enabled, |ui| {
+                            egui::ComboBox::from_id_source("regenerate_summary")
+                                .selected_text("🔁 Regenerar con…")
+                                .show_ui(ui, |ui| {
+                                    for m in models {
+                                        if ui.selectable_label(false, &m).clicked() {
+                                            let model = format!("{}:{}", self.llm.provider, m);
+                                            self.regenerate_summary(model);
+                                        }
+                                    }
+                                });
+                        });
+                    });
+                });
+                if !self.summary_text.is_empty() {
+                    let by = self.summary_model.as_deref().unwrap_or("modelo por defecto del summarizer");
+                    ui.weak(format!("Generado con {by}"));
+                }
+                if let Some(model) = self.pull_offer.clone() {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(Color32::YELLOW, format!("⚠️ '{model}' no está descargado en Ollama"));
+                        if ui.add_enabled(!self.pull_pending, egui::Button::new("⬇ Descargar")).clicked() {
+                            self.request_pull(model);
+                        }
+                        if self.pull_pending {
+                            ui.spinner();
+                            ui.weak(self.pull_progress.as_deref().unwrap_or("Descargando…"));
+                        }
+                    });
+                }
+                if let Some(report) = &self.batch_report {
+                    egui::CollapsingHeader::new(format!(
+                        "🗂 Lote: {} correctos, {} con error",
+                        report.succeeded, report.failed
+                    ))
+                    .id_source("batch_report")
+                    .show(ui, |ui| {
+                        egui::Grid::new("batch_report_grid").striped(true).num_columns(2).show(ui, |ui| {
+                            for item in &report.items {
+                                let name = Path::new(&item.path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                                ui.label(name).on_hover_text(&item.path);
+                                match &item.error {
+                                    None => ui.colored_label(Color32::from_rgb(60, 180, 75), "✅ resumido"),
+                                    Some(e) => ui.colored_label(Color32::from_rgb(200, 80, 80), format!("❌ {}", e.message)),
+                                };
+                                ui.end_row();
+                            }
+                        });
+                    });
+                }
+                if self.summary_session.is_some() && !self.summary_pending {
+                    ui.horizontal(|ui| {
+                        let input = ui.add_enabled(
+                            !self.followup_pending,
+                            egui::TextEdit::singleline(&mut self.followup_question)
+                                .hint_text("Pregunte sobre el archivo…")
+                                .desired_width(ui.available_width() - 110.0),
+                        );
+                        let enter = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                        let ask = ui.add_enabled(
+                            !self.followup_pending && !self.followup_question.trim().is_empty(),
+                            egui::Button::new("💬 Preguntar"),
+                        );
+                        if enter || ask.clicked() {
+                            self.ask_followup();
+                        }
+                        if self.followup_pending {
+                            ui.spinner();
+                        }
+                    });
+                }
                 ui.add_space(6.0);
                 egui::ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
                     ui.style_mut().override_text_style = Some(TextStyle::Monospace);
                     ui.label(&self.summary_text);
+                    for (question, answer) in &self.conversation {
+                        ui.separator();
+                        ui.label(RichText::new(format!("💬 {question}")).color(self.accent));
+                        if !answer.is_empty() {
+                            ui.label(answer);
+                        }
+                    }
+                    if let Some((model, text)) = &self.previous_summary {
+                        ui.separator();
+                        egui::CollapsingHeader::new(format!("Resumen anterior ({model})"))
+                            .default_open(true)
+                            .show(ui, |ui| {
+                                ui.label(text);
+                            });
+                    }
                     ui.style_mut().override_text_style = None;
                 });
             });
             cols[1].group(|ui| {
-                ui.heading("📊 Metadatos");
+                ui.horizontal(|ui| {
+                    ui.heading(RichText::new("📊 Metadatos").color(self.accent));
+                    if self.metadata_pending {
+                        ui.spinner();
+                    }
+                });
                 ui.add_space(6.0);
                 egui::ScrollArea::vertical().auto_shrink([false; 2]).show(ui, |ui| {
                     ui.style_mut().override_text_style = Some(TextStyle::Monospace);
@@ -970,25 +2977,73 @@ impl ClientApp {
 
         // Vista previa (monoespaciada) con scroll
         ui.group(|ui| {
-            ui.heading("👀 Vista previa del archivo");
+            ui.heading(RichText::new("👀 Vista previa del archivo").color(self.accent));
             ui.add_space(6.0);
 
             if let Some(err) = &self.preview_error {
                 ui.colored_label(Color32::from_rgb(200, 80, 80), err);
             }
 
+            ui.horizontal(|ui| {
+                ui.label("Tamaño máx.:");
+                let mut kb = self.preview_max_bytes / 1024;
+                let resp = ui.add(
+                    egui::Slider::new(&mut kb, 4..=1024)
+                        .logarithmic(true)
+                        .suffix(" KB"),
+                );
+                if resp.changed() {
+                    self.preview_max_bytes = kb * 1024;
+                    self.preview_dirty = true;
+                }
+            });
+
             let hint = format!(
-                "Mostrando primeras ~{} KB{}",
+                "Mostrando primeras ~{} KB{}{}",
                 self.preview_max_bytes / 1024,
-                if self.preview_text.ends_with("… (vista previa truncada)") { " (truncado)" } else { "" }
+                if self.preview_text.ends_with("… (vista previa truncada)") { " (truncado)" } else { "" },
+                self.preview_encoding.map(|e| format!(" · {e}")).unwrap_or_default()
             );
             ui.weak(hint);
 
+            let mut save = false;
+            ui.horizontal(|ui| {
+                let editing = self.preview_edit.is_some();
+                let toggle = ui
+                    .add_enabled(self.preview_editable, egui::SelectableLabel::new(editing, "✏ Editar"))
+                    .on_disabled_hover_text("Solo archivos UTF-8 sin comprimir que quepan enteros en la vista previa");
+                if toggle.clicked() {
+                    self.preview_edit = if editing { None } else { Some(self.preview_text.clone()) };
+                }
+                if let Some(edit) = &self.preview_edit {
+                    let changed = *edit != self.preview_text;
+                    if ui.add_enabled(changed, egui::Button::new("💾 Guardar")).clicked() {
+                        save = true;
+                    }
+                    if ui.add_enabled(changed, egui::Button::new("↩ Descartar")).clicked() {
+                        self.preview_edit = Some(self.preview_text.clone());
+                    }
+                }
+            });
+            if save {
+                self.save_preview_edit();
+            }
+
             egui::ScrollArea::vertical()
                 .id_source("preview_scroll")
                 .auto_shrink([false; 2])
                 .max_height(260.0)
                 .show(ui, |ui| {
+                    if let Some(texture) = &self.preview_image {
+                        let [w, h] = texture.size();
+                        ui.image((texture.id(), egui::vec2(w as f32, h as f32)));
+                        ui.weak(format!("Miniatura {w}×{h}"));
+                        return;
+                    }
+                    if let Some(edit) = &mut self.preview_edit {
+                        ui.add(egui::TextEdit::multiline(edit).code_editor().desired_width(f32::INFINITY));
+                        return;
+                    }
                     ui.style_mut().override_text_style = Some(TextStyle::Monospace);
                     if self.preview_text.is_empty() && self.preview_error.is_none() {
                         ui.weak("— No hay vista previa. Seleccione un archivo en el explorador.");
@@ -1002,10 +3057,111 @@ impl ClientApp {
         ui.add_space(8.0);
         ui.separator();
 
-        ui.heading("🧯 Log de eventos / errores");
-        egui::ScrollArea::vertical().max_height(180.0).show(ui, |ui| {
-            for line in &self.logs {
-                ui.label(line);
+        ui.heading(RichText::new("🧯 Log de eventos / errores").color(self.accent));
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.log_show_info, "Info");
+            ui.checkbox(&mut self.log_show_warn, "Avisos");
+            ui.checkbox(&mut self.log_show_error, "Errores");
+            ui.separator();
+            ui.label("🔎");
+            ui.add(egui::TextEdit::singleline(&mut self.log_search).hint_text("Buscar en el log"));
+        });
+        let search = self.log_search.to_lowercase();
+        egui::ScrollArea::vertical()
+            .id_source("log_scroll")
+            .max_height(180.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for (level, line) in &self.logs {
+                    let visible = match level {
+                        LogLevel::Info => self.log_show_info,
+                        LogLevel::Warn => self.log_show_warn,
+                        LogLevel::Error => self.log_show_error,
+                    };
+                    if !visible || (!search.is_empty() && !line.to_lowercase().contains(&search)) {
+                        continue;
+                    }
+                    match level.color() {
+                        Some(c) => ui.colored_label(c, line),
+                        None => ui.label(line),
+                    };
+                }
+            });
+    }
+
+    /// Búsqueda en el contenido de la carpeta actual; un clic en una línea abre el archivo.
+    fn ui_search(&mut self, ui: &mut Ui) {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new("🔎 Buscar en contenido").color(self.accent));
+                let edit = ui.add(egui::TextEdit::singleline(&mut self.search_query).hint_text("texto o regex"));
+                let enter = edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                ui.checkbox(&mut self.search_regex, "Regex");
+                ui.checkbox(&mut self.search_recursive, "Recursivo");
+                let can_search = !self.search_pending && !self.search_query.is_empty();
+                if ui.add_enabled(can_search, egui::Button::new("Buscar")).clicked() || (enter && can_search) {
+                    self.request_search();
+                }
+                if self.search_pending {
+                    ui.spinner();
+                }
+                if self.search_results.is_some() && ui.small_button("✖").on_hover_text("Cerrar resultados").clicked() {
+                    self.search_results = None;
+                }
+            });
+            let Some(found) = &self.search_results else {
+                return;
+            };
+            ui.weak(format!(
+                "{} archivos con coincidencias · {} examinados{}",
+                found.files.len(),
+                found.files_scanned,
+                if found.truncated { " · truncado" } else { "" }
+            ));
+            if !found.errors.is_empty() {
+                let skipped: Vec<String> = found.errors.iter().map(|e| format!("{}: {}", e.path, e.reason)).collect();
+                ui.colored_label(Color32::YELLOW, format!("⚠ {} rutas omitidas", found.errors.len()))
+                    .on_hover_text(skipped.join("\n"));
+            }
+            let mut open: Option<PathBuf> = None;
+            let mut edit: Option<(PathBuf, u64)> = None;
+            let mut copied: Option<String> = None;
+            egui::ScrollArea::vertical().id_source("search_results").max_height(220.0).show(ui, |ui| {
+                for file in &found.files {
+                    egui::CollapsingHeader::new(format!("📄 {} ({})", file.name, file.lines.len()))
+                        .id_source(&file.path)
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            for m in &file.lines {
+                                ui.horizontal(|ui| {
+                                    let reference = format!("{}:{}", file.path, m.line);
+                                    if ui.small_button("📋").on_hover_text(format!("Copiar {reference}")).clicked() {
+                                        copied = Some(reference);
+                                    }
+                                    if ui.small_button("✏").on_hover_text("Abrir en el editor en esta línea").clicked() {
+                                        edit = Some((PathBuf::from(&file.path), m.line));
+                                    }
+                                    let text = RichText::new(format!("{:>5}: {}", m.line, m.snippet)).monospace();
+                                    if ui.selectable_label(false, text).on_hover_text(&file.path).clicked() {
+                                        open = Some(PathBuf::from(&file.path));
+                                    }
+                                });
+                            }
+                        });
+                }
+            });
+            if let Some(reference) = copied {
+                self.push_log(&format!("📋 Copiado {reference}"));
+                ui.output_mut(|o| o.copied_text = reference);
+            }
+            if let Some((path, line)) = edit {
+                match Self::open_in_editor(&self.editor, &path, line) {
+                    Ok(()) => self.push_log(&format!("✏ Abriendo {}:{}", path.display(), line)),
+                    Err(e) => self.push_log(&format!("❌ {e:#}")),
+                }
+            }
+            if let Some(path) = open {
+                self.open_path(path);
             }
         });
     }
@@ -1025,15 +3181,43 @@ impl ClientApp {
                         trigger_list = true;
                     }
                     ui.label(format!("Total: {}", self.models.len()));
+                    match self.models_updated {
+                        Some(t) => ui.weak(format!("· actualizado hace {}", Self::age_since(t))),
+                        None => ui.weak("· sin actualizar"),
+                    };
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Buscar:");
+                    ui.text_edit_singleline(&mut self.models_filter);
+                    if ui.button("Limpiar").clicked() {
+                        self.models_filter.clear();
+                    }
                 });
                 ui.separator();
 
-                let models = self.models.clone();
+                let needle = self.models_filter.to_lowercase();
+                let mut models: Vec<String> = self
+                    .models
+                    .iter()
+                    .filter(|m| needle.is_empty() || m.to_lowercase().contains(&needle))
+                    .cloned()
+                    .collect();
+                models.sort_by_key(|m| m.to_lowercase());
+                if !needle.is_empty() {
+                    ui.weak(format!("Coincidencias: {}", models.len()));
+                }
+
                 egui::ScrollArea::vertical().show(ui, |ui| {
                     for m in models {
                         ui.horizontal(|ui| {
                             ui.label("•");
-                            if ui.selectable_label(self.llm.model == m, &m).clicked() {
+                            let is_current = self.llm.model == m;
+                            let text = if is_current {
+                                RichText::new(&m).strong().color(self.accent)
+                            } else {
+                                RichText::new(&m)
+                            };
+                            if ui.selectable_label(is_current, text).clicked() {
                                 self.llm.model = m.clone();
                                 self.push_log(&format!("✅ Modelo seleccionado: {}", m));
                             }
@@ -1044,13 +3228,14 @@ impl ClientApp {
         self.show_models_window = open;
 
         if trigger_list {
-            self.list_models();
+            self.list_models(true);
         }
     }
 
     fn ui_providers_window(&mut self, ctx: &EguiContext) {
         let mut open = self.show_providers_window;
         let mut trigger_inspect = false;
+        let mut trigger_export = false;
 
         egui::Window::new("🔍 Proveedores detectados")
             .open(&mut open)
@@ -1062,21 +3247,45 @@ impl ClientApp {
                     if ui.button("🔎 Inspeccionar").clicked() {
                         trigger_inspect = true;
                     }
+                    ui.checkbox(
+                        &mut self.inspect_only_active,
+                        format!("Solo proveedor activo ({})", self.llm.provider),
+                    );
+                    let has_report = self.provider_report.is_some();
+                    if ui.add_enabled(has_report, egui::Button::new("💾 Exportar informe")).clicked() {
+                        trigger_export = true;
+                    }
                 });
                 ui.separator();
 
                 egui::ScrollArea::vertical().show(ui, |ui| {
-                    let text = match &self.provider_report {
-                        Some(v) => serde_json::to_string_pretty(v).unwrap_or_else(|_| "<json inválido>".into()),
-                        None => "— (sin datos aún)".into(),
-                    };
-                    ui.style_mut().override_text_style = Some(TextStyle::Monospace);
-                    ui.label(text);
-                    ui.style_mut().override_text_style = None;
+                    let providers = self.provider_report.as_ref().and_then(|v| {
+                        v.get("Success").unwrap_or(v).get("providers").and_then(|p| p.as_array())
+                    });
+                    match providers {
+                        Some(list) => {
+                            for p in list {
+                                draw_provider_info(ui, p);
+                            }
+                        }
+                        None => {
+                            let text = match &self.provider_report {
+                                Some(v) => serde_json::to_string_pretty(v).unwrap_or_else(|_| "<json inválido>".into()),
+                                None => "— (sin datos aún)".into(),
+                            };
+                            ui.style_mut().override_text_style = Some(TextStyle::Monospace);
+                            ui.label(text);
+                            ui.style_mut().override_text_style = None;
+                        }
+                    }
                 });
             });
         self.show_providers_window = open;
 
+        if trigger_export {
+            self.export_provider_report_dialog();
+        }
+
         if trigger_inspect {
             self.inspect_providers();
         }
@@ -1086,6 +3295,9 @@ impl ClientApp {
         let mut open = self.show_monitor_window;
         let mut trigger_ping = false;
         let mut trigger_reconnect = false;
+        let mut trigger_gateway_cfg = false;
+        let mut trigger_stress = false;
+        let mut trigger_discovery = false;
 
         egui::Window::new("📡 Monitor")
             .open(&mut open)
@@ -1104,32 +3316,254 @@ impl ClientApp {
                     }
                 });
 
+                ui.horizontal(|ui| {
+                    ui.label("Ráfaga:");
+                    ui.add(egui::DragValue::new(&mut self.stress_ping_count).clamp_range(1..=1000).suffix(" pings"));
+                    let btn = ui.add_enabled(!self.stress_ping_running, egui::Button::new("🔥 Stress ping"));
+                    if btn.clicked() {
+                        trigger_stress = true;
+                    }
+                    if self.stress_ping_running {
+                        ui.spinner();
+                    }
+                });
+                if let Some(st) = &self.stress_ping_stats {
+                    let error_rate = st.errors as f64 * 100.0 / st.total.max(1) as f64;
+                    ui.label(format!(
+                        "min {:.1} · avg {:.1} · p95 {:.1} · max {:.1} ms — errores {}/{} ({:.1}%)",
+                        st.min_ms, st.avg_ms, st.p95_ms, st.max_ms, st.errors, st.total, error_rate
+                    ));
+                }
+
                 ui.separator();
                 ui.label(format!("NATS_URL: {}", self.nats_url));
                 if ui.button("🔌 Re-conectar NATS").clicked() {
                     trigger_reconnect = true;
                 }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label(RichText::new("LLM efectivo (gateway)").strong());
+                    if ui.button("🔄").on_hover_text("Consultar llm.config.get").clicked() {
+                        trigger_gateway_cfg = true;
+                    }
+                });
+                match &self.gateway_config {
+                    Some(cfg) => {
+                        let field = |k: &str| cfg.get(k).and_then(|v| v.as_str()).unwrap_or("— (por defecto)").to_string();
+                        ui.label(format!("Proveedor: {}", field("provider")));
+                        ui.label(format!("Modelo: {}", field("model")));
+                        ui.label(format!("Base URL: {}", field("base_url")));
+                        for diff in self.gateway_config_mismatches() {
+                            ui.colored_label(Color32::from_rgb(220, 160, 40), format!("⚠ {diff}"));
+                        }
+                    }
+                    None => {
+                        ui.weak("— (sin consultar)");
+                    }
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label(RichText::new("🧭 Servicios").strong());
+                    let btn = ui.add_enabled(!self.services_pending, egui::Button::new("🔍 Descubrir"));
+                    if btn.on_hover_text("Preguntar en discovery.request").clicked() {
+                        trigger_discovery = true;
+                    }
+                    if self.services_pending {
+                        ui.spinner();
+                    }
+                });
+                match &self.services {
+                    Some(list) if list.is_empty() => {
+                        ui.weak("Ningún agente respondió.");
+                    }
+                    Some(list) => {
+                        egui::Grid::new("services_grid").striped(true).num_columns(3).show(ui, |ui| {
+                            ui.strong("Agente");
+                            ui.strong("PID");
+                            ui.strong("Subjects");
+                            ui.end_row();
+                            for s in list {
+                                let label = ui.label(&s.agent);
+                                if let Some(g) = &s.queue_group {
+                                    label.on_hover_text(format!("v{} · grupo de cola '{}'", s.version, g));
+                                } else {
+                                    label.on_hover_text(format!("v{}", s.version));
+                                }
+                                ui.monospace(s.pid.to_string());
+                                ui.monospace(s.subjects.join("\n"));
+                                ui.end_row();
+                            }
+                        });
+                    }
+                    None => {
+                        ui.weak("— (sin consultar)");
+                    }
+                }
             });
 
         self.show_monitor_window = open;
+        if trigger_discovery {
+            self.discover_services();
+        }
+        if trigger_stress {
+            self.stress_ping();
+        }
+        if trigger_gateway_cfg {
+            self.sync_gateway_config(false);
+        }
         if trigger_ping {
             self.ping_gateway();
         }
         if trigger_reconnect {
-            self.nats = None;
-            if let Err(e) = self.ensure_nats() {
-                self.push_log(&format!("❌ Reconexión NATS falló: {e}"));
-            } else {
-                self.push_log("✅ Reconectado a NATS");
-            }
+            self.reconnect_nats();
         }
     }
 
+    fn ui_compare_window(&mut self, ctx: &EguiContext) {
+        let mut open = self.show_compare_window;
+        egui::Window::new("⚖ Comparar metadatos")
+            .open(&mut open)
+            .resizable(true)
+            .default_width(620.0)
+            .show(ctx, |ui| {
+                if self.compare_pending {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.weak("Solicitando metadatos…");
+                    });
+                }
+                let Some(cmp) = &self.comparison else {
+                    ui.weak("— Fije un archivo (📌) y seleccione otro para comparar.");
+                    return;
+                };
+                let (ma, mb) = (&cmp.a.1, &cmp.b.1);
+                let opt = |v: Option<u64>| v.map(|n| n.to_string()).unwrap_or_else(|| "—".into());
+                let time = |t: Option<SystemTime>| t.map(|t| format!("hace {}", Self::age_since(t))).unwrap_or_else(|| "—".into());
+                let rows = [
+                    ("Tipo", format!("{:?}", ma.file_type), format!("{:?}", mb.file_type)),
+                    ("Tamaño", Self::human_size(ma.len_bytes), Self::human_size(mb.len_bytes)),
+                    ("Creado", time(ma.created), time(mb.created)),
+                    ("Modificado", time(ma.modified), time(mb.modified)),
+                    ("Líneas", opt(ma.line_count), opt(mb.line_count)),
+                    ("Palabras", opt(ma.word_count), opt(mb.word_count)),
+                    ("Caracteres", opt(ma.char_count), opt(mb.char_count)),
+                    (
+                        "SHA-256",
+                        ma.sha256.clone().unwrap_or_else(|| "—".into()),
+                        mb.sha256.clone().unwrap_or_else(|| "—".into()),
+                    ),
+                    (
+                        "Enlace a",
+                        ma.symlink_target.clone().unwrap_or_else(|| "—".into()),
+                        mb.symlink_target.clone().unwrap_or_else(|| "—".into()),
+                    ),
+                ];
+                let name = |p: &PathBuf| p.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+                egui::Grid::new("compare_grid").striped(true).num_columns(3).show(ui, |ui| {
+                    ui.strong("Campo");
+                    ui.strong(format!("A: {}", name(&cmp.a.0)));
+                    ui.strong(format!("B: {}", name(&cmp.b.0)));
+                    ui.end_row();
+                    for (label, va, vb) in rows {
+                        ui.label(label);
+                        if va == vb {
+                            ui.monospace(va);
+                            ui.monospace(vb);
+                        } else {
+                            let diff = Color32::from_rgb(220, 150, 60);
+                            ui.label(RichText::new(va).monospace().color(diff));
+                            ui.label(RichText::new(vb).monospace().color(diff));
+                        }
+                        ui.end_row();
+                    }
+                });
+
+                ui.add_space(6.0);
+                match (&ma.sha256, &mb.sha256) {
+                    (Some(ha), Some(hb)) if ha == hb => {
+                        ui.colored_label(Color32::from_rgb(80, 170, 80), "✅ Contenido idéntico");
+                    }
+                    (Some(_), Some(_)) => {
+                        ui.colored_label(Color32::from_rgb(200, 80, 80), "❌ El contenido difiere");
+                    }
+                    _ => {
+                        ui.weak("Sin hash para comparar el contenido.");
+                    }
+                }
+            });
+        self.show_compare_window = open;
+    }
+
+    fn ui_dir_summary_window(&mut self, ctx: &EguiContext) {
+        let mut open = self.show_dir_summary;
+        egui::Window::new("📊 Composición de la carpeta")
+            .open(&mut open)
+            .resizable(true)
+            .default_width(480.0)
+            .show(ctx, |ui| {
+                if self.dir_summary_pending {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.weak("Analizando…");
+                    });
+                }
+                let Some((dir, summary)) = &self.dir_summary else {
+                    return;
+                };
+                ui.label(format!(
+                    "{} · {} archivos · {}",
+                    dir.display(),
+                    summary.total_files,
+                    Self::human_size(summary.total_bytes)
+                ));
+                if !summary.errors.is_empty() {
+                    let skipped: Vec<String> = summary.errors.iter().map(|e| format!("{}: {}", e.path, e.reason)).collect();
+                    ui.colored_label(Color32::YELLOW, format!("⚠ {} rutas omitidas", summary.errors.len()))
+                        .on_hover_text(skipped.join("\n"));
+                }
+                ui.add_space(6.0);
+                // Mayor volumen primero; la barra muestra la fracción de bytes de la carpeta.
+                let mut rows: Vec<_> = summary.extensions.iter().collect();
+                rows.sort_by_key(|r| std::cmp::Reverse(r.1.bytes));
+                egui::ScrollArea::vertical().max_height(360.0).show(ui, |ui| {
+                    egui::Grid::new("dir_summary_grid").striped(true).num_columns(4).show(ui, |ui| {
+                        ui.strong("Extensión");
+                        ui.strong("Archivos");
+                        ui.strong("Tamaño");
+                        ui.strong("");
+                        ui.end_row();
+                        for (ext, stats) in rows {
+                            ui.label(if ext.is_empty() { "(sin extensión)".to_string() } else { format!(".{ext}") });
+                            ui.label(stats.count.to_string());
+                            ui.label(Self::human_size(stats.bytes));
+                            let fraction = if summary.total_bytes > 0 {
+                                stats.bytes as f32 / summary.total_bytes as f32
+                            } else {
+                                0.0
+                            };
+                            ui.add(
+                                egui::ProgressBar::new(fraction)
+                                    .desired_width(140.0)
+                                    .text(format!("{:.1}%", fraction * 100.0)),
+                            );
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+        self.show_dir_summary = open;
+    }
+
     fn ui_settings_window(&mut self, ctx: &EguiContext) {
         let mut open = self.show_settings_window;
 
         // Disparadores diferidos para evitar préstamos simultáneos
         let mut trigger_list_models = false;
+        let mut trigger_sync = false;
+        let mut trigger_curl = false;
 
         egui::Window::new("⚙️ Ajustes LLM / Gateway")
             .open(&mut open)
@@ -1139,7 +3573,16 @@ impl ClientApp {
             .show(ctx, |ui| {
                 // Sección: Proveedor
                 ui.group(|ui| {
-                    ui.heading("Proveedor");
+                    ui.horizontal(|ui| {
+                        ui.heading("Proveedor");
+                        if ui
+                            .button("⬇ Sincronizar")
+                            .on_hover_text("Cargar la configuración efectiva del LLM Gateway")
+                            .clicked()
+                        {
+                            trigger_sync = true;
+                        }
+                    });
                     ui.separator();
                     ui.horizontal(|ui| {
                         ui.label("Proveedor:");
@@ -1175,10 +3618,17 @@ impl ClientApp {
                                     }
                                     provider_changed = true;
                                 }
+                                if ui
+                                    .selectable_value(&mut self.llm.provider, "mock".to_string(), "Mock (sin red)")
+                                    .clicked()
+                                {
+                                    provider_changed = true;
+                                }
                             });
 
                         if provider_changed {
                             self.models.clear();
+                            self.models_updated = None;
                             trigger_list_models = true; // auto carga lista del proveedor actual
                         }
 
@@ -1190,10 +3640,24 @@ impl ClientApp {
                     ui.add_space(6.0);
                     ui.horizontal(|ui| {
                         ui.label("Base URL:");
-                        ui.text_edit_singleline(&mut self.llm.base_url);
+                        let resp = ui.text_edit_singleline(&mut self.llm.base_url);
+                        if resp.lost_focus() {
+                            match normalize_base_url(&self.llm.base_url) {
+                                Ok(url) => {
+                                    self.llm.base_url = url;
+                                    self.base_url_error = None;
+                                }
+                                Err(e) => self.base_url_error = Some(e.to_string()),
+                            }
+                        }
                     });
+                    if let Some(err) = &self.base_url_error {
+                        ui.colored_label(Color32::from_rgb(200, 80, 80), err);
+                    }
 
-                    if self.llm.provider != "ollama" {
+                    if self.llm.provider == "mock" {
+                        ui.weak("Mock responde localmente en el gateway; no usa Base URL ni API Key.");
+                    } else if self.llm.provider != "ollama" {
                         ui.horizontal(|ui| {
                             ui.label("API Key:");
                             let mut masked = self.llm.api_key.clone();
@@ -1251,15 +3715,59 @@ impl ClientApp {
                         ui.add(egui::Slider::new(&mut self.llm.temperature, 0.0..=1.5).suffix(" ℃"));
                     });
 
+                    ui.horizontal(|ui| {
+                        ui.label("Top-p:");
+                        ui.add(egui::Slider::new(&mut self.llm.top_p, 0.0..=1.0));
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Penalización por frecuencia:");
+                        ui.add(egui::Slider::new(&mut self.llm.frequency_penalty, -2.0..=2.0));
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Penalización por presencia:");
+                        ui.add(egui::Slider::new(&mut self.llm.presence_penalty, -2.0..=2.0));
+                    });
+
                     ui.horizontal(|ui| {
                         ui.label("Máx. tokens:");
                         let mut val = self.llm.max_tokens as i64;
                         if ui.add(egui::DragValue::new(&mut val)).changed() {
-                            if val < 0 { val = 0; }
-                            if val > 32768 { val = 32768; }
-                            self.llm.max_tokens = val as u32;
+                            self.llm.max_tokens = val.clamp(0, 32768) as u32;
+                        }
+                    });
+                });
+
+                ui.add_space(8.0);
+
+                // Sección: Depuración
+                ui.group(|ui| {
+                    ui.horizontal(|ui| {
+                        ui.heading("Depuración");
+                        if ui
+                            .button("🐚 Generar cURL")
+                            .on_hover_text("Comando cURL equivalente a la llamada que haría el gateway (API key enmascarada)")
+                            .clicked()
+                        {
+                            trigger_curl = true;
+                        }
+                        if let Some(cmd) = &self.last_curl {
+                            if ui.button("📋 Copiar cURL").clicked() {
+                                ui.output_mut(|o| o.copied_text = cmd.clone());
+                            }
                         }
                     });
+                    if let Some(cmd) = &self.last_curl {
+                        egui::ScrollArea::vertical()
+                            .id_source("curl_scroll")
+                            .max_height(120.0)
+                            .show(ui, |ui| {
+                                ui.style_mut().override_text_style = Some(TextStyle::Monospace);
+                                ui.label(cmd);
+                                ui.style_mut().override_text_style = None;
+                            });
+                    }
                 });
 
                 ui.add_space(12.0);
@@ -1270,7 +3778,13 @@ impl ClientApp {
 
         // Ejecutar acciones diferidas fuera del cierre para evitar conflictos de préstamos
         if trigger_list_models {
-            self.list_models();
+            self.list_models(false);
+        }
+        if trigger_sync {
+            self.sync_gateway_config(true);
+        }
+        if trigger_curl {
+            self.request_curl();
         }
     }
 }
@@ -1278,10 +3792,21 @@ impl ClientApp {
 impl eframe::App for ClientApp {
     fn update(&mut self, ctx: &EguiContext, _frame: &mut eframe::Frame) {
         self.poll_events();
+        self.handle_dropped_files(ctx);
+        self.track_recent_selection();
+
+        // Auto-refresco del listado: solo con el explorador visible y sin escribir en el filtro.
+        if self.show_explorer && self.auto_refresh_secs > 0 {
+            let interval = Duration::from_secs(self.auto_refresh_secs as u64);
+            if self.last_refresh.elapsed() >= interval && !self.filter_has_focus {
+                self.needs_refresh = true;
+            }
+            ctx.request_repaint_after(interval.saturating_sub(self.last_refresh.elapsed()));
+        }
 
         // Si hay que refrescar vista previa, hazlo fuera de cierres UI:
         if self.preview_dirty {
-            self.load_preview_now();
+            self.load_preview_now(ctx);
             self.preview_dirty = false;
         }
 
@@ -1309,13 +3834,19 @@ impl eframe::App for ClientApp {
         self.ui_providers_window(ctx);
         self.ui_monitor_window(ctx);
         self.ui_settings_window(ctx);
+        self.ui_compare_window(ctx);
+        self.ui_dir_summary_window(ctx);
     }
 }
 
 /// Árbol de selección (opcional). No navega por sí mismo; sirve para elegir y luego "Abrir carpeta".
 fn draw_tree_select(ui: &mut Ui, node: &mut DirNode, selected_path: &mut Option<PathBuf>) {
     if node.is_dir {
-        let label = format!("📂 {}", node.name);
+        let badge = match node.ensure_counts_loaded() {
+            Some((dirs, files)) => format!("({dirs} 📁 · {files} 📄)"),
+            None => "(?)".to_string(),
+        };
+        let label = format!("📂 {}  {}", node.name, badge);
         egui::CollapsingHeader::new(label)
             .id_source(node.path.clone())
             .default_open(false)
@@ -1338,6 +3869,279 @@ fn draw_tree_select(ui: &mut Ui, node: &mut DirNode, selected_path: &mut Option<
     }
 }
 
+/// Manifiesto en CSV: una fila por archivo, con fechas en segundos Unix.
+fn dir_metadata_csv(entries: &[DirMetadataEntry]) -> String {
+    fn field(s: &str) -> String {
+        if s.contains([',', '"', '\n']) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_string()
+        }
+    }
+    let secs = |t: Option<SystemTime>| {
+        t.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or_default()
+    };
+    let mut out = String::from("name,path,type,size_bytes,created,modified,sha256,error\n");
+    for e in entries {
+        let (kind, size, created, modified, sha) = match &e.metadata {
+            Some(m) => (
+                format!("{:?}", m.file_type),
+                m.len_bytes.to_string(),
+                secs(m.created),
+                secs(m.modified),
+                m.sha256.clone().unwrap_or_default(),
+            ),
+            None => Default::default(),
+        };
+        let row = [
+            field(&e.name),
+            field(&e.path),
+            kind,
+            size,
+            created,
+            modified,
+            sha,
+            field(e.error.as_deref().unwrap_or("")),
+        ];
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Pide los metadatos de `path` (con estadísticas de texto y hash) al extractor.
+/// Evento de error para una acción: si el código es transitorio, la GUI ofrecerá reintentarla.
+fn failure(message: String, code: ErrorCode, action: RetryAction) -> GuiEvent {
+    if code.is_transient() {
+        GuiEvent::Retryable(message, action)
+    } else {
+        GuiEvent::Error(message)
+    }
+}
+
+/// Pide la lista de modelos al gateway. Acepta `{"models": [...]}` o un array plano.
+async fn fetch_models(c: &async_nats::Client, payload: &Value) -> Result<Vec<String>, String> {
+    let data = serde_json::to_vec(payload).unwrap_or_default();
+    let msg = request_with_timeout(c, "mcp.provider.list", data, timeouts().quick)
+        .await
+        .map_err(|e| format!("Solicitud de modelos falló: {e}"))?;
+    let body = String::from_utf8(msg.payload.to_vec())
+        .map_err(|_| "Respuesta binaria inválida al listar modelos".to_string())?;
+    let v: Value = serde_json::from_str(&body).map_err(|_| format!("No se pudo parsear modelos: {body}"))?;
+    let arr = v.get("models").and_then(|m| m.as_array()).or_else(|| v.as_array());
+    Ok(arr
+        .map(|arr| arr.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default())
+}
+
+/// Ejecuta `job` sobre cada ruta con concurrencia acotada (`BATCH_CONCURRENCY`) y une
+/// los resultados, en orden, en secciones `### nombre`. Los fallos se anotan en su
+/// sección sin detener el resto. Devuelve el texto y el número de fallos.
+async fn run_batch<F, Fut>(paths: Vec<PathBuf>, job: F) -> (String, usize)
+where
+    F: Fn(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    let mut results = stream::iter(paths)
+        .map(|path| {
+            let fut = job(path.clone());
+            async move { (path, fut.await) }
+        })
+        .buffered(BATCH_CONCURRENCY);
+    let mut out = String::new();
+    let mut failed = 0;
+    while let Some((path, result)) = results.next().await {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        out.push_str(&format!("### {name}\n\n"));
+        match result {
+            Ok(text) => out.push_str(&text),
+            Err(e) => {
+                failed += 1;
+                out.push_str(&format!("❌ {e}"));
+            }
+        }
+        out.push_str("\n\n");
+    }
+    (out, failed)
+}
+
+/// Identificador único para asociar los avisos de progreso a una petición.
+fn new_request_id() -> String {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| format!("{:x}", d.as_nanos()))
+        .unwrap_or_else(|_| "0".to_string())
+}
+
+/// Reenvía a la GUI los `ProgressUpdate` de `request_id` envueltos con `event`.
+/// Devuelve la tarea para abortarla al llegar la respuesta final.
+async fn forward_progress(
+    c: &NatsClient,
+    request_id: &str,
+    tx: Sender<GuiEvent>,
+    event: fn(ProgressUpdate) -> GuiEvent,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let mut sub = c.subscribe(progress_subject(request_id)).await.ok()?;
+    Some(tokio::spawn(async move {
+        while let Some(msg) = sub.next().await {
+            if let Ok(update) = serde_json::from_slice::<ProgressUpdate>(&msg.payload) {
+                let _ = tx.send(event(update));
+            }
+        }
+    }))
+}
+
+/// Texto de un aviso de progreso, con "i/n" si trae paso y total.
+fn progress_line(update: ProgressUpdate) -> String {
+    match (update.step, update.total) {
+        (Some(i), Some(n)) => format!("{} {i}/{n}", update.message),
+        _ => update.message,
+    }
+}
+
+async fn fetch_metadata(client: &NatsClient, path: &Path) -> Result<FileMetadata, String> {
+    let payload = serde_json::json!({ "path": path, "text_stats": true, "hash": true });
+    request_json(client, "metadata.request", &payload, timeouts().metadata)
+        .await
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Consulta `llm.config.get` y envía el resultado (o el error) a la GUI.
+async fn fetch_gateway_config(client: &NatsClient, tx: &Sender<GuiEvent>, apply: bool) {
+    match request_json::<_, Value>(client, "llm.config.get", &Value::Null, timeouts().quick).await {
+        Ok(cfg) => {
+            let _ = tx.send(GuiEvent::GatewayConfig { cfg, apply });
+        }
+        Err(e) => {
+            let _ = tx.send(GuiEvent::Error(format!("llm.config.get: {e}")));
+        }
+    }
+}
+
+/// Decodifica los bytes de la vista previa detectando la codificación.
+/// Orden: BOM (UTF-8/UTF-16), UTF-8 válido, UTF-16 sin BOM por la proporción de bytes
+/// nulos, Latin-1 si el texto parece legible y, en último caso, UTF-8 con pérdidas.
+fn decode_preview(bytes: &[u8]) -> (String, &'static str) {
+    fn utf16(bytes: &[u8], big_endian: bool) -> String {
+        let units = bytes.chunks_exact(2).map(|c| {
+            if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) }
+        });
+        char::decode_utf16(units)
+            .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return (String::from_utf8_lossy(rest).into_owned(), "UTF-8 (BOM)");
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return (utf16(rest, false), "UTF-16 LE (BOM)");
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return (utf16(rest, true), "UTF-16 BE (BOM)");
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(s) => return (s.to_string(), "UTF-8"),
+        // Solo falla el último carácter, cortado por el límite de la vista previa.
+        Err(e) if e.error_len().is_none() => {
+            return (String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned(), "UTF-8");
+        }
+        Err(_) => {}
+    }
+
+    // Texto ASCII en UTF-16 deja casi todos los bytes pares (o impares) a cero.
+    let pairs = bytes.len() / 2;
+    if pairs >= 2 {
+        let zeros_even = bytes.iter().step_by(2).filter(|b| **b == 0).count();
+        let zeros_odd = bytes.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+        if zeros_odd * 10 >= pairs * 4 && zeros_even * 10 < pairs {
+            return (utf16(bytes, false), "UTF-16 LE (detectado)");
+        }
+        if zeros_even * 10 >= pairs * 4 && zeros_odd * 10 < pairs {
+            return (utf16(bytes, true), "UTF-16 BE (detectado)");
+        }
+    }
+
+    // Latin-1: aceptable si apenas hay caracteres de control fuera de \t \r \n.
+    let controls = bytes
+        .iter()
+        .filter(|b| (**b < 0x20 && !matches!(**b, b'\t' | b'\r' | b'\n')) || **b == 0x7F)
+        .count();
+    if controls * 100 <= bytes.len() {
+        return (bytes.iter().map(|b| *b as char).collect(), "Latin-1 (heurística)");
+    }
+    (String::from_utf8_lossy(bytes).into_owned(), "UTF-8 (con pérdidas)")
+}
+
+/// Registra en el log cuánto tardó el agente y devuelve su resultado.
+fn report_timing<T>(tx: &Sender<GuiEvent>, env: AgentEnvelope<T>) -> AgentResponse<T> {
+    let _ = tx.send(GuiEvent::Status(format!(
+        "⏱️ {} respondió en {} ms",
+        env.agent_name, env.duration_ms
+    )));
+    env.result
+}
+
+/// Muestra un proveedor del informe de inspección con la tabla de capacidades de sus modelos.
+fn draw_provider_info(ui: &mut Ui, p: &Value) {
+    let name = p.get("name").and_then(|v| v.as_str()).unwrap_or("?");
+    let reachable = p.get("reachable").and_then(|v| v.as_bool()).unwrap_or(false);
+    let models = p.get("models").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    let header = format!("{} {} ({} modelos)", if reachable { "🟢" } else { "🔴" }, name, models.len());
+
+    egui::CollapsingHeader::new(header)
+        .id_source(format!("provider_{name}"))
+        .default_open(reachable)
+        .show(ui, |ui| {
+            if let Some(ep) = p.get("endpoint").and_then(|v| v.as_str()) {
+                ui.label(format!("Endpoint: {ep}"));
+            }
+            if let Some(ms) = p.get("latency_ms").and_then(|v| v.as_u64()) {
+                ui.label(format!("Latencia: {ms} ms"));
+            }
+            if let Some(err) = p.get("error").and_then(|v| v.as_str()) {
+                ui.colored_label(Color32::from_rgb(200, 80, 80), err);
+            }
+            if models.is_empty() {
+                return;
+            }
+
+            let opt_str = |m: &Value, k: &str| m.get(k).and_then(|v| v.as_str()).unwrap_or("—").to_string();
+            let opt_bool = |m: &Value, k: &str| match m.get(k).and_then(|v| v.as_bool()) {
+                Some(true) => "✔",
+                Some(false) => "✘",
+                None => "—",
+            };
+
+            egui::Grid::new(format!("models_grid_{name}"))
+                .striped(true)
+                .num_columns(7)
+                .show(ui, |ui| {
+                    for h in ["Modelo", "Familia", "Modalidad", "Contexto", "JSON", "Tools", "Imágenes"] {
+                        ui.label(RichText::new(h).strong());
+                    }
+                    ui.end_row();
+                    for m in &models {
+                        ui.label(opt_str(m, "id"));
+                        ui.label(opt_str(m, "family"));
+                        ui.label(opt_str(m, "modality"));
+                        ui.label(
+                            m.get("context_length")
+                                .and_then(|v| v.as_u64())
+                                .map(|c| c.to_string())
+                                .unwrap_or_else(|| "—".into()),
+                        );
+                        ui.label(opt_bool(m, "supports_json"));
+                        ui.label(opt_bool(m, "supports_tools"));
+                        ui.label(opt_bool(m, "supports_images"));
+                        ui.end_row();
+                    }
+                });
+        });
+}
+
 fn main() -> Result<()> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
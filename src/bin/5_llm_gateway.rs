@@ -3,32 +3,667 @@ use anyhow::{Context, Result};
 use futures_util::StreamExt;
 use multi_agent_file_processor::{
     connect_to_nats,
-    mcp_protocol::{McpRequest, McpResponse},
-    setup_tracing, AgentResponse,
+    mcp_protocol::{McpMessageTurn, McpRequest, McpResponse, UsageReport},
+    normalize_base_url, progress_subject, resubscribe, run_until_shutdown, setup_tracing, spawn_discovery, AgentError, AgentResponse, ErrorCode, InFlight,
+    OllamaPullRequest, ProgressUpdate, MODEL_NOT_PULLED,
 };
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
-use tracing::{error, info};
+use std::collections::{BinaryHeap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{debug, error, info, warn};
 
-#[derive(Debug, Clone, Default)]
+/// Protocolos que el gateway sabe hablar; cada proveedor declara el suyo en `kind`.
+const KNOWN_KINDS: &[&str] = &["openai", "ollama", "mock"];
+
+/// Archivo de proveedores por defecto (configurable con `GATEWAY_PROVIDERS_FILE`).
+const DEFAULT_PROVIDERS_FILE: &str = "providers.toml";
+
+/// Un proveedor del gateway: de `providers.toml` o, si no existe, uno de los integrados.
+/// Las variables de entorno (`<NOMBRE>_BASE_URL`, `<NOMBRE>_API_KEY`,
+/// `<NOMBRE>_EXTRA_HEADERS`) prevalecen sobre el archivo, y `llm.config.set` sobre ambas.
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderConfig {
+    name: String,
+    /// Protocolo: "openai" (API compatible con OpenAI), "ollama" o "mock".
+    /// Por defecto el nombre, salvo "groq", que habla el de OpenAI.
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    base_url: Option<String>,
+    /// Modelo si ni la petición ni `llm.config.set` indican uno.
+    #[serde(default)]
+    default_model: Option<String>,
+    /// Variable de entorno con la API key; por defecto `<NOMBRE>_API_KEY`.
+    #[serde(default)]
+    api_key_env: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Timeout de cada llamada HTTP al proveedor; sin él se usa el del cliente (15 s).
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ProviderConfig {
+    fn builtin(name: &str, base_url: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: None,
+            base_url: base_url.map(str::to_string),
+            default_model: None,
+            api_key_env: None,
+            headers: HashMap::new(),
+            timeout_secs: None,
+            enabled: true,
+        }
+    }
+
+    fn kind(&self) -> &str {
+        match self.kind.as_deref() {
+            Some(kind) => kind,
+            None if self.name == "groq" => "openai",
+            None => &self.name,
+        }
+    }
+
+    fn api_key_env(&self) -> String {
+        self.api_key_env
+            .clone()
+            .unwrap_or_else(|| format!("{}_API_KEY", self.name.to_ascii_uppercase()))
+    }
+}
+
+#[derive(Deserialize)]
+struct ProvidersFile {
+    #[serde(default)]
+    providers: Vec<ProviderConfig>,
+}
+
+/// Proveedores cuando no hay `providers.toml`: los tres remotos habituales y `mock`.
+fn builtin_providers() -> Vec<ProviderConfig> {
+    vec![
+        ProviderConfig::builtin("openai", Some("https://api.openai.com")),
+        ProviderConfig::builtin("groq", Some("https://api.groq.com/openai")),
+        ProviderConfig::builtin("ollama", Some("http://localhost:11434")),
+        ProviderConfig::builtin("mock", None),
+    ]
+}
+
+/// Carga `GATEWAY_PROVIDERS_FILE` (o `providers.toml`). Si el archivo existe define la lista
+/// completa de proveedores; si no, se usan los integrados. Un archivo pedido
+/// explícitamente que no existe, o uno inválido, es un error de arranque.
+fn load_providers() -> Result<Vec<ProviderConfig>> {
+    let explicit = std::env::var("GATEWAY_PROVIDERS_FILE").ok();
+    let path = PathBuf::from(explicit.as_deref().unwrap_or(DEFAULT_PROVIDERS_FILE));
+    if explicit.is_none() && !path.exists() {
+        return Ok(builtin_providers());
+    }
+    let raw = std::fs::read_to_string(&path).context(format!("No se pudo leer '{}'", path.display()))?;
+    let file: ProvidersFile = toml::from_str(&raw).context(format!("'{}' no es válido", path.display()))?;
+    let mut providers: Vec<ProviderConfig> = Vec::with_capacity(file.providers.len());
+    for mut p in file.providers {
+        if !KNOWN_KINDS.contains(&p.kind()) {
+            anyhow::bail!("Proveedor '{}': kind '{}' desconocido (válidos: {})", p.name, p.kind(), KNOWN_KINDS.join(", "));
+        }
+        if providers.iter().any(|other| other.name == p.name) {
+            anyhow::bail!("Proveedor '{}' repetido en '{}'", p.name, path.display());
+        }
+        if let Some(url) = p.base_url.as_deref() {
+            p.base_url = Some(normalize_base_url(url).context(format!("Proveedor '{}'", p.name))?);
+        }
+        providers.push(p);
+    }
+    info!("[LLM Gateway] Proveedores cargados de '{}'.", path.display());
+    Ok(providers)
+}
+
+/// Tabla de precios por defecto (configurable con `GATEWAY_PRICES_FILE`).
+const DEFAULT_PRICES_FILE: &str = "prices.toml";
+
+/// Precio de un modelo en USD por cada 1.000 tokens de entrada y de salida.
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct ModelPrice {
+    input_per_1k: f64,
+    output_per_1k: f64,
+}
+
+/// Formato de `prices.toml`: `[models."gpt-4o-mini"]` o, para fijarlo a un
+/// proveedor concreto, `[models."groq:llama3-8b-8192"]`.
+#[derive(Debug, Deserialize)]
+struct PricesFile {
+    #[serde(default)]
+    models: HashMap<String, ModelPrice>,
+}
+
+/// Carga `GATEWAY_PRICES_FILE` (o `prices.toml`). Sin archivo no hay tabla y el coste
+/// estimado de las respuestas queda en `None`; un archivo pedido explícitamente que
+/// no existe, o uno inválido, es un error de arranque.
+fn load_prices() -> Result<HashMap<String, ModelPrice>> {
+    let explicit = std::env::var("GATEWAY_PRICES_FILE").ok();
+    let path = PathBuf::from(explicit.as_deref().unwrap_or(DEFAULT_PRICES_FILE));
+    if explicit.is_none() && !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let raw = std::fs::read_to_string(&path).context(format!("No se pudo leer '{}'", path.display()))?;
+    let file: PricesFile = toml::from_str(&raw).context(format!("'{}' no es válido", path.display()))?;
+    for (model, price) in &file.models {
+        if price.input_per_1k < 0.0 || price.output_per_1k < 0.0 {
+            anyhow::bail!("Precio negativo para '{}' en '{}'", model, path.display());
+        }
+    }
+    info!("[LLM Gateway] Precios de {} modelos cargados de '{}'.", file.models.len(), path.display());
+    Ok(file.models)
+}
+
+/// Coste estimado en USD de una completion; `None` si el modelo no tiene precio
+/// (se busca primero `proveedor:modelo` y luego `modelo`) o no hay uso de tokens.
+fn estimate_cost(
+    prices: &HashMap<String, ModelPrice>,
+    provider: &str,
+    model: &str,
+    usage: Option<(u32, u32)>,
+) -> Option<f64> {
+    let (prompt, completion) = usage?;
+    let price = prices.get(&format!("{}:{}", provider, model)).or_else(|| prices.get(model))?;
+    Some(prompt as f64 / 1000.0 * price.input_per_1k + completion as f64 / 1000.0 * price.output_per_1k)
+}
+
+/// Modelos que ofrece el proveedor `mock` (sin red, para pruebas y demos).
+const MOCK_MODELS: &[&str] = &["mock-small", "mock-large"];
+
+/// Tiempo que se considera válida la lista de modelos cacheada de un proveedor.
+const MODEL_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Secuencias de parada que admite la API de OpenAI (y las compatibles, como Groq).
+const OPENAI_MAX_STOP: usize = 4;
+
+/// Rango documentado de `temperature` por protocolo (`kind`), (mín, máx). La API de OpenAI
+/// (y las compatibles, como Groq) admite 0-2; Ollama no documenta un máximo y `mock` la
+/// ignora, así que a esos se les pasa tal cual.
+const TEMPERATURE_RANGES: &[(&str, f32, f32)] = &[("openai", 0.0, 2.0)];
+
+/// Ajusta la temperatura al rango del protocolo del proveedor y deja constancia en el log si la recorta.
+fn clamp_temperature(cfg: &ProviderConfig, temp: f32) -> f32 {
+    let Some(&(_, min, max)) = TEMPERATURE_RANGES.iter().find(|(kind, _, _)| *kind == cfg.kind()) else {
+        return temp;
+    };
+    let clamped = temp.clamp(min, max);
+    if clamped != temp {
+        warn!(
+            "[LLM Gateway] Temperatura {} fuera de rango para '{}' ({}..={}); se usa {}",
+            temp, cfg.name, min, max, clamped
+        );
+    }
+    clamped
+}
+
+/// Plazo global por defecto de una petición MCP (configurable con `GATEWAY_DEADLINE_SECS`).
+const DEFAULT_DEADLINE_SECS: u64 = 300;
+
+/// Máximo que puede pedir un cliente con `deadline_secs` (configurable con
+/// `GATEWAY_MAX_DEADLINE_SECS`), para que una petición no retenga un hueco del proveedor sin fin.
+const DEFAULT_MAX_DEADLINE_SECS: u64 = 900;
+
+/// Peticiones simultáneas por proveedor (configurable con `GATEWAY_PROVIDER_CONCURRENCY`).
+const DEFAULT_PROVIDER_CONCURRENCY: usize = 4;
+
+/// Peticiones en espera por proveedor antes de rechazar (configurable con `GATEWAY_QUEUE_LIMIT`).
+const DEFAULT_QUEUE_LIMIT: usize = 64;
+
+/// Prioridad de las peticiones que no la indican. Valores mayores se atienden antes.
+const DEFAULT_PRIORITY: u8 = 128;
+
+/// Petición en espera de un hueco; se ordena por prioridad y, a igualdad, por llegada.
+struct Waiter {
+    priority: u8,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // BinaryHeap es de máximos: mayor prioridad primero y, dentro de ella, el más antiguo.
+        self.priority.cmp(&other.priority).then(other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct GateSlot {
+    active: usize,
+    seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+/// Semáforo por proveedor con cola acotada ordenada por prioridad.
+/// Al liberar un hueco se entrega directamente al siguiente en espera.
+struct ProviderGate {
+    limit: usize,
+    queue_limit: usize,
+    slots: std::sync::Mutex<HashMap<String, GateSlot>>,
+}
+
+/// Hueco ocupado en un proveedor; se libera al soltarse.
+struct GatePermit {
+    gate: Arc<ProviderGate>,
+    provider: String,
+}
+
+impl Drop for GatePermit {
+    fn drop(&mut self) {
+        self.gate.release(&self.provider);
+    }
+}
+
+/// Espera en cola; si se abandona (p. ej. por plazo) tras recibir el hueco, lo devuelve.
+struct PendingPermit {
+    gate: Arc<ProviderGate>,
+    provider: String,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for PendingPermit {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.gate.release(&self.provider);
+            }
+        }
+    }
+}
+
+impl ProviderGate {
+    fn from_env() -> Self {
+        let env_num = |key: &str| {
+            std::env::var(key).ok().and_then(|v| v.parse::<usize>().ok()).filter(|n| *n > 0)
+        };
+        Self {
+            limit: env_num("GATEWAY_PROVIDER_CONCURRENCY").unwrap_or(DEFAULT_PROVIDER_CONCURRENCY),
+            queue_limit: env_num("GATEWAY_QUEUE_LIMIT").unwrap_or(DEFAULT_QUEUE_LIMIT),
+            slots: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(self: &Arc<Self>, provider: &str, priority: u8) -> Result<GatePermit> {
+        let rx = {
+            let mut slots = self.slots.lock().unwrap();
+            let slot = slots.entry(provider.to_string()).or_default();
+            if slot.active < self.limit && slot.waiting.is_empty() {
+                slot.active += 1;
+                return Ok(GatePermit { gate: Arc::clone(self), provider: provider.to_string() });
+            }
+            if slot.waiting.len() >= self.queue_limit {
+                warn!("[LLM Gateway] Cola de '{}' llena ({} en espera); petición rechazada", provider, self.queue_limit);
+                return Err(AgentError::new(
+                    ErrorCode::Busy,
+                    format!("Gateway saturado: cola de '{}' llena, inténtelo más tarde", provider),
+                )
+                .into());
+            }
+            let (tx, rx) = oneshot::channel();
+            slot.seq += 1;
+            let seq = slot.seq;
+            slot.waiting.push(Waiter { priority, seq, tx });
+            info!(
+                "[LLM Gateway] '{}' saturado; petición en cola (prioridad {}, {} en espera)",
+                provider,
+                priority,
+                slot.waiting.len()
+            );
+            rx
+        };
+
+        let mut pending = PendingPermit { gate: Arc::clone(self), provider: provider.to_string(), rx: Some(rx) };
+        let result = pending.rx.as_mut().unwrap().await;
+        pending.rx = None;
+        result.context("Cola del gateway cerrada")?;
+        Ok(GatePermit { gate: Arc::clone(self), provider: provider.to_string() })
+    }
+
+    fn release(&self, provider: &str) {
+        let mut slots = self.slots.lock().unwrap();
+        let Some(slot) = slots.get_mut(provider) else { return };
+        // El hueco pasa al siguiente en espera que siga vivo; si no hay ninguno, queda libre.
+        while let Some(waiter) = slot.waiting.pop() {
+            if waiter.tx.send(()).is_ok() {
+                return;
+            }
+        }
+        slot.active = slot.active.saturating_sub(1);
+    }
+}
+
+/// Tamaño a partir del cual se rota el registro de auditoría (`GATEWAY_AUDIT_MAX_BYTES`).
+const DEFAULT_AUDIT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Entradas de auditoría pendientes de escribir; con la cola llena las peticiones esperan
+/// al disco en vez de acumular memoria sin límite.
+const AUDIT_QUEUE_CAPACITY: usize = 1024;
+
+/// Plazo para que la tarea de auditoría escriba las últimas entradas al apagar el gateway.
+const AUDIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Entrada del registro de auditoría: una línea JSON por petición de completion.
+/// El contenido de los mensajes solo se incluye con `GATEWAY_AUDIT_CONTENT=1`.
+#[derive(Serialize, Debug)]
+struct AuditRecord {
+    timestamp_ms: u128,
+    request_id: String,
+    provider: Option<String>,
+    model: String,
+    message_count: usize,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    latency_ms: u128,
+    /// "ok" | "error" | "deadline"
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<McpMessageTurn>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+}
+
+/// Registro de auditoría en JSON Lines, independiente de los logs de tracing.
+/// Se activa con `GATEWAY_AUDIT_LOG=<ruta>`; una tarea dedicada escribe las líneas en orden
+/// y termina cuando se sueltan todas las copias del registro.
+struct AuditLog {
+    tx: mpsc::Sender<AuditRecord>,
+    include_content: bool,
+    seq: AtomicU64,
+}
+
+impl AuditLog {
+    fn from_env() -> Option<(Arc<Self>, tokio::task::JoinHandle<()>)> {
+        let path = PathBuf::from(std::env::var("GATEWAY_AUDIT_LOG").ok().filter(|p| !p.trim().is_empty())?);
+        let max_bytes = std::env::var("GATEWAY_AUDIT_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_AUDIT_MAX_BYTES);
+        let include_content = matches!(std::env::var("GATEWAY_AUDIT_CONTENT").as_deref(), Ok("1") | Ok("true"));
+        info!(
+            "[LLM Gateway] Auditoría en {:?} (rotación a {} bytes{})",
+            path,
+            max_bytes,
+            if include_content { ", con contenido" } else { "" }
+        );
+        let (tx, rx) = mpsc::channel(AUDIT_QUEUE_CAPACITY);
+        let writer = tokio::spawn(audit_writer(path, max_bytes, rx));
+        Some((Arc::new(Self { tx, include_content, seq: AtomicU64::new(0) }), writer))
+    }
+
+    /// Identificador de petición para correlacionar la auditoría con los logs.
+    fn next_request_id(&self) -> String {
+        let n = self.seq.fetch_add(1, Ordering::Relaxed);
+        format!("{:x}-{}", unix_millis(), n)
+    }
+
+    async fn record(&self, record: AuditRecord) {
+        if self.tx.send(record).await.is_err() {
+            error!("[LLM Gateway] La tarea de auditoría ha terminado; se pierde una entrada");
+        }
+    }
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+async fn audit_writer(path: PathBuf, max_bytes: u64, mut rx: mpsc::Receiver<AuditRecord>) {
+    while let Some(record) = rx.recv().await {
+        let Ok(mut line) = serde_json::to_vec(&record) else { continue };
+        line.push(b'\n');
+        if let Err(e) = append_audit_line(&path, max_bytes, &line).await {
+            error!("[LLM Gateway] No se pudo escribir la auditoría en {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Añade `line` al registro y la sincroniza a disco. Si el archivo superaría
+/// `max_bytes`, antes se renombra a `<ruta>.1` (sustituyendo la copia anterior).
+async fn append_audit_line(path: &Path, max_bytes: u64, line: &[u8]) -> Result<()> {
+    if let Ok(meta) = tokio::fs::metadata(path).await {
+        if meta.len() > 0 && meta.len() + line.len() as u64 > max_bytes {
+            let mut rotated = path.as_os_str().to_owned();
+            rotated.push(".1");
+            tokio::fs::rename(path, &rotated).await.context("No se pudo rotar el registro")?;
+        }
+    }
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(line).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/// Tiempo máximo de una descarga de modelo en Ollama (`llm.ollama.pull`).
+const OLLAMA_PULL_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Lista de modelos por proveedor con el instante en que se obtuvo.
+type ModelCache = Arc<Mutex<HashMap<String, (Instant, Vec<String>)>>>;
+
+#[derive(Clone, Default)]
 struct LlmConfigState {
     provider: Option<String>,
     model: Option<String>,
     base_url: Option<String>,
     api_key: Option<String>,
     temperature: Option<f32>,
+    /// Valores por defecto de `top_p` y las penalizaciones si la petición no los trae.
+    top_p: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    /// API de OpenAI a usar: "chat" (por defecto, `/v1/chat/completions`) o "responses" (`/v1/responses`).
+    openai_api: Option<String>,
+    /// Cabeceras HTTP extra para el proveedor configurado (p. ej. `HTTP-Referer` de OpenRouter).
+    headers: Option<HashMap<String, String>>,
+    /// Proveedores cargados al arrancar (`providers.toml` o los integrados).
+    providers: Arc<Vec<ProviderConfig>>,
+    /// Tabla de precios por modelo (`prices.toml`) para estimar el coste de cada respuesta.
+    prices: Arc<HashMap<String, ModelPrice>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+impl LlmConfigState {
+    /// Configuración de `name`; error si no está definido o está deshabilitado.
+    fn provider_config(&self, name: &str) -> Result<&ProviderConfig> {
+        match self.providers.iter().find(|p| p.name == name) {
+            Some(p) if p.enabled => Ok(p),
+            Some(_) => Err(invalid_request(format!("El proveedor '{}' está deshabilitado", name))),
+            None => Err(invalid_request(format!("Proveedor no soportado: {}", name))),
+        }
+    }
+
+    /// Proveedor si nadie fija uno: "openai" si está habilitado, si no el primero habilitado.
+    fn default_provider(&self) -> String {
+        self.provider_config("openai")
+            .ok()
+            .or_else(|| self.providers.iter().find(|p| p.enabled))
+            .map(|p| p.name.clone())
+            .unwrap_or_else(|| "openai".to_string())
+    }
+
+    /// API key de `cfg`: la de `llm.config.set` o, si no, la de su variable de entorno.
+    fn api_key_for(&self, cfg: &ProviderConfig) -> Option<String> {
+        self.api_key.clone().or_else(|| std::env::var(cfg.api_key_env()).ok())
+    }
+
+    /// Indica si la petición a OpenAI debe ir por la Responses API: por config global
+    /// (`openai_api = "responses"`) o porque el modelo figura en `OPENAI_RESPONSES_MODELS`.
+    fn uses_responses_api(&self, provider: &str, model: &str) -> bool {
+        if provider != "openai" {
+            return false;
+        }
+        if self.openai_api.as_deref() == Some("responses") {
+            return true;
+        }
+        std::env::var("OPENAI_RESPONSES_MODELS")
+            .map(|list| list.split(',').any(|m| m.trim() == model))
+            .unwrap_or(false)
+    }
+
+    /// Copia de la configuración apta para enviarse a clientes (API key enmascarada).
+    fn masked(&self) -> LlmConfigSet {
+        LlmConfigSet {
+            provider: self.provider.clone(),
+            model: self.model.clone(),
+            base_url: self.base_url.clone(),
+            api_key: self.api_key.as_deref().map(mask_secret),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            openai_api: self.openai_api.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+/// Enmascara un secreto dejando visible solo el prefijo (p. ej. `sk-...****`).
+fn mask_secret(secret: &str) -> String {
+    if secret.chars().count() <= 8 {
+        return "****".to_string();
+    }
+    let prefix: String = secret.chars().take(3).collect();
+    format!("{}...****", prefix)
+}
+
+/// Error de una respuesta HTTP no exitosa del proveedor, con el código según su estado.
+fn provider_error(status: reqwest::StatusCode, message: String) -> anyhow::Error {
+    AgentError::new(ErrorCode::from_http_status(status.as_u16()), message).into()
+}
+
+fn invalid_request(message: String) -> anyhow::Error {
+    AgentError::new(ErrorCode::InvalidRequest, message).into()
+}
+
+fn model_not_pulled(model: &str) -> anyhow::Error {
+    AgentError::new(
+        ErrorCode::ModelNotPulled,
+        format!("{}: '{}' no está descargado en Ollama; descárguelo con llm.ollama.pull", MODEL_NOT_PULLED, model),
+    )
+    .into()
+}
+
+/// Sustituye cualquier aparición del secreto en `text` por su versión enmascarada.
+/// Se usa con los cuerpos de error de los proveedores, que a veces repiten la clave.
+fn redact(text: &str, secret: Option<&str>) -> String {
+    match secret {
+        Some(k) if !k.is_empty() => text.replace(k, &mask_secret(k)),
+        _ => text.to_string(),
+    }
+}
+
+/// `Debug` manual para que la API key nunca aparezca en los logs.
+impl std::fmt::Debug for LlmConfigState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LlmConfigState")
+            .field("provider", &self.provider)
+            .field("model", &self.model)
+            .field("base_url", &self.base_url)
+            .field("api_key", &self.api_key.as_deref().map(mask_secret))
+            .field("temperature", &self.temperature)
+            .field("top_p", &self.top_p)
+            .field("frequency_penalty", &self.frequency_penalty)
+            .field("presence_penalty", &self.presence_penalty)
+            .field("openai_api", &self.openai_api)
+            .field("headers", &self.headers.as_ref().map(|h| h.keys().collect::<Vec<_>>()))
+            .field("providers", &self.providers.iter().map(|p| p.name.as_str()).collect::<Vec<_>>())
+            .field("prices", &self.prices.len())
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
 struct LlmConfigSet {
     provider: Option<String>,
     model: Option<String>,
     base_url: Option<String>,
     api_key: Option<String>,
     temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    frequency_penalty: Option<f32>,
+    #[serde(default)]
+    presence_penalty: Option<f32>,
+    #[serde(default)]
+    openai_api: Option<String>,
+    #[serde(default)]
+    headers: Option<HashMap<String, String>>,
+}
+
+impl std::fmt::Debug for LlmConfigSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LlmConfigSet")
+            .field("provider", &self.provider)
+            .field("model", &self.model)
+            .field("base_url", &self.base_url)
+            .field("api_key", &self.api_key.as_deref().map(mask_secret))
+            .field("temperature", &self.temperature)
+            .field("top_p", &self.top_p)
+            .field("frequency_penalty", &self.frequency_penalty)
+            .field("presence_penalty", &self.presence_penalty)
+            .field("openai_api", &self.openai_api)
+            .field("headers", &self.headers.as_ref().map(|h| h.keys().collect::<Vec<_>>()))
+            .finish()
+    }
 }
 
 // -------- Provider inspection types ----------
+/// Filtro opcional de `llm.providers.inspect`. Sin payload (o sin filtro) se inspeccionan todos.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct InspectRequest {
+    #[serde(default)]
+    provider: Option<String>,
+    #[serde(default)]
+    providers: Option<Vec<String>>,
+}
+
+impl InspectRequest {
+    fn wants(&self, name: &str) -> bool {
+        let single = self.provider.iter();
+        let many = self.providers.iter().flatten();
+        let mut selected = single.chain(many).peekable();
+        if selected.peek().is_none() {
+            return true;
+        }
+        selected.any(|p| p.eq_ignore_ascii_case(name))
+    }
+
+    /// Como `wants`, pero solo si el proveedor se nombró explícitamente en el filtro.
+    fn names(&self, name: &str) -> bool {
+        self.provider.iter().chain(self.providers.iter().flatten()).any(|p| p.eq_ignore_ascii_case(name))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct ProviderReport {
     providers: Vec<ProviderInfo>,
@@ -59,212 +694,1095 @@ async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
     setup_tracing();
 
+    let providers = load_providers()?;
+    let enabled: Vec<&str> = providers.iter().filter(|p| p.enabled).map(|p| p.name.as_str()).collect();
+    info!("[LLM Gateway] Proveedores habilitados: {}", enabled.join(", "));
+    let prices = load_prices()?;
+
     let client = connect_to_nats().await?;
     info!("[LLM Gateway] Conectado a NATS.");
 
     let mut sub = client.subscribe("mcp.request.completion").await?;
     let mut ping_sub = client.subscribe("llm.ping").await?;
     let mut cfg_sub = client.subscribe("llm.config.set").await?;
+    let mut cfg_get_sub = client.subscribe("llm.config.get").await?;
     let mut models_sub = client.subscribe("llm.models.list").await?;
     let mut inspect_sub = client.subscribe("llm.providers.inspect").await?;
+    let mut curl_sub = client.subscribe("llm.debug.curl").await?;
+    let mut pull_sub = client.subscribe("llm.ollama.pull").await?;
+    spawn_discovery(
+        &client,
+        "LLM Gateway",
+        &[
+            "mcp.request.completion",
+            "llm.ping",
+            "llm.config.set",
+            "llm.config.get",
+            "llm.models.list",
+            "llm.providers.inspect",
+            "llm.debug.curl",
+            "llm.ollama.pull",
+        ],
+    );
     info!("[LLM Gateway] Escuchando en 'mcp.request.completion'.");
 
     let http = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(15))
         .build()?;
 
-    let mut state = LlmConfigState::default();
+    let default_deadline = Duration::from_secs(
+        std::env::var("GATEWAY_DEADLINE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_DEADLINE_SECS),
+    );
+    // El tope nunca queda por debajo del plazo por defecto.
+    let max_deadline_secs = std::env::var("GATEWAY_MAX_DEADLINE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_DEADLINE_SECS)
+        .max(default_deadline.as_secs());
+
+    let mut state = LlmConfigState {
+        providers: Arc::new(providers),
+        prices: Arc::new(prices),
+        ..Default::default()
+    };
+    let model_cache: ModelCache = Arc::new(Mutex::new(HashMap::new()));
+    let gate = Arc::new(ProviderGate::from_env());
+    let (audit, audit_writer) = AuditLog::from_env().unzip();
+    info!(
+        "[LLM Gateway] Hasta {} peticiones simultáneas por proveedor (cola de {}).",
+        gate.limit, gate.queue_limit
+    );
 
-    loop {
-        tokio::select! {
-            Some(msg) = sub.next() => {
-                let req: McpRequest = match serde_json::from_slice(&msg.payload) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!("[LLM Gateway] Solicitud MCP malformada: {}", e);
+    let tasks = InFlight::default();
+    let in_flight = tasks.clone();
+    let control = client.clone();
+    // El bucle es dueño de las suscripciones: al soltarlo en el apagado dejan de entrar peticiones.
+    let service = async move {
+        // Solo sale del bucle si no se puede recuperar una suscripción perdida.
+        let outcome: Result<()> = loop {
+            tokio::select! {
+                msg = sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "mcp.request.completion", None).await {
+                            Ok(s) => sub = s,
+                            Err(e) => break Err(e),
+                        }
                         continue;
-                    }
-                };
-                let rply = msg.reply.clone();
-                let http = http.clone();
-                let state_snapshot = state.clone();
-                let client2 = client.clone();
-
-                tokio::spawn(async move {
-                    let resp = match handle_mcp(req, &http, &state_snapshot).await {
-                        Ok(m) => AgentResponse::Success(m),
+                    };
+                    let req: McpRequest = match serde_json::from_slice(&msg.payload) {
+                        Ok(r) => r,
                         Err(e) => {
-                            error!("[LLM Gateway] Error LLM: {}", e);
-                            AgentResponse::Error(e.to_string())
+                            error!("[LLM Gateway] Solicitud MCP malformada: {}", e);
+                            if let Some(r) = msg.reply {
+                                let resp: AgentResponse<McpResponse> =
+                                    AgentResponse::error(ErrorCode::InvalidRequest, format!("Solicitud MCP malformada: {}", e));
+                                if let Ok(payload) = serde_json::to_vec(&resp) {
+                                    let _ = client.publish(r, payload.into()).await;
+                                }
+                            }
+                            continue;
                         }
                     };
-                    if let Some(r) = rply {
-                        if let Ok(payload) = serde_json::to_vec(&resp) {
-                            let _ = client2.publish(r, payload.into()).await;
+                    let rply = msg.reply.clone();
+                    let http = http.clone();
+                    let state_snapshot = state.clone();
+                    let client2 = client.clone();
+                    let cache = Arc::clone(&model_cache);
+                    let gate = Arc::clone(&gate);
+                    let audit = audit.clone();
+
+                    in_flight.spawn(async move {
+                        // Plazo externo para toda la operación; la petición puede acortarlo o ampliarlo.
+                        let deadline = req
+                            .deadline_secs
+                            .map(|secs| Duration::from_secs(secs.clamp(1, max_deadline_secs)))
+                            .unwrap_or(default_deadline);
+                        // Datos de auditoría tomados antes de ceder la petición al manejador.
+                        let audit_base = audit.as_ref().map(|log| {
+                            let (provider, model) = resolve_provider_and_model(&req, &state_snapshot)
+                                .map(|(p, m)| (Some(p), m))
+                                .unwrap_or_else(|_| (req.provider.clone(), req.model.clone()));
+                            AuditRecord {
+                                timestamp_ms: unix_millis(),
+                                request_id: log.next_request_id(),
+                                provider,
+                                model,
+                                message_count: req.messages.len(),
+                                prompt_tokens: None,
+                                completion_tokens: None,
+                                latency_ms: 0,
+                                outcome: "ok",
+                                error: None,
+                                messages: log.include_content.then(|| req.messages.clone()),
+                                response: None,
+                            }
+                        });
+                        let usage_key = resolve_provider_and_model(&req, &state_snapshot).ok();
+                        let started = Instant::now();
+                        let outcome = tokio::time::timeout(deadline, handle_mcp(req, &http, &state_snapshot, &cache, &gate)).await;
+                        if let (Some(log), Some(mut record)) = (audit.as_ref(), audit_base) {
+                            record.latency_ms = started.elapsed().as_millis();
+                            match &outcome {
+                                Ok(Ok(m)) => {
+                                    record.prompt_tokens = m.token_usage.map(|u| u.0);
+                                    record.completion_tokens = m.token_usage.map(|u| u.1);
+                                    if log.include_content {
+                                        record.response = Some(m.content.clone());
+                                    }
+                                }
+                                Ok(Err(e)) => {
+                                    record.outcome = "error";
+                                    record.error = Some(e.to_string());
+                                }
+                                Err(_) => record.outcome = "deadline",
+                            }
+                            log.record(record).await;
+                        }
+                        if let (Ok(Ok(m)), Some((provider, model))) = (&outcome, usage_key) {
+                            publish_usage(&client2, provider, model, m).await;
+                        }
+                        let resp = match outcome {
+                            Ok(Ok(m)) => AgentResponse::Success(m),
+                            Err(_) => {
+                                error!("[LLM Gateway] Plazo del gateway excedido ({}s)", deadline.as_secs());
+                                AgentResponse::error(
+                                    ErrorCode::Timeout,
+                                    format!("gateway deadline exceeded: plazo de {}s agotado", deadline.as_secs()),
+                                )
+                            }
+                            Ok(Err(e)) => {
+                                error!("[LLM Gateway] Error LLM: {}", e);
+                                AgentResponse::failed(&e)
+                            }
+                        };
+                        if let Some(r) = rply {
+                            if let Ok(payload) = serde_json::to_vec(&resp) {
+                                let _ = client2.publish(r, payload.into()).await;
+                            }
+                        }
+                    });
+                }
+                msg = ping_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.ping", None).await {
+                            Ok(s) => ping_sub = s,
+                            Err(e) => break Err(e),
                         }
+                        continue;
+                    };
+                    if let Some(r) = msg.reply {
+                        let _ = client.publish(r, "pong".into()).await;
                     }
-                });
-            }
-            Some(msg) = ping_sub.next() => {
-                if let Some(r) = msg.reply {
-                    let _ = client.publish(r, "pong".into()).await;
                 }
-            }
-            Some(msg) = cfg_sub.next() => {
-                match serde_json::from_slice::<LlmConfigSet>(&msg.payload) {
-                    Ok(cfg) => {
-                        state.provider = cfg.provider.or(state.provider);
-                        state.model = cfg.model.or(state.model);
-                        state.base_url = cfg.base_url.or(state.base_url);
-                        state.api_key = cfg.api_key.or(state.api_key);
-                        state.temperature = cfg.temperature.or(state.temperature);
-                        info!("[LLM Gateway] Config LLM actualizada: {:?}", state);
+                msg = cfg_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.config.set", None).await {
+                            Ok(s) => cfg_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    match serde_json::from_slice::<LlmConfigSet>(&msg.payload) {
+                        Ok(cfg) => {
+                            // Credenciales o endpoint nuevos pueden cambiar los modelos disponibles.
+                            model_cache.lock().await.clear();
+                            state.provider = cfg.provider.or(state.provider);
+                            state.model = cfg.model.or(state.model);
+                            match cfg.base_url.as_deref().map(normalize_base_url) {
+                                // Una URL vacía vuelve al endpoint por defecto del proveedor.
+                                Some(Err(_)) if cfg.base_url.as_deref().is_some_and(|u| u.trim().is_empty()) => {
+                                    state.base_url = None;
+                                }
+                                Some(Ok(url)) => state.base_url = Some(url),
+                                Some(Err(e)) => error!("[LLM Gateway] {}; se mantiene la URL base actual", e),
+                                None => {}
+                            }
+                            state.api_key = cfg.api_key.or(state.api_key);
+                            state.temperature = cfg.temperature.or(state.temperature);
+                            state.top_p = cfg.top_p.or(state.top_p);
+                            state.frequency_penalty = cfg.frequency_penalty.or(state.frequency_penalty);
+                            state.presence_penalty = cfg.presence_penalty.or(state.presence_penalty);
+                            state.headers = cfg.headers.or(state.headers);
+                            match cfg.openai_api.as_deref() {
+                                Some("chat") | Some("responses") => state.openai_api = cfg.openai_api,
+                                Some(other) => warn!("[LLM Gateway] openai_api desconocida '{}'; se mantiene la actual", other),
+                                None => {}
+                            }
+                            info!("[LLM Gateway] Config LLM actualizada: {:?}", state);
+                        }
+                        Err(e) => error!("[LLM Gateway] Config inválida: {}", e),
                     }
-                    Err(e) => error!("[LLM Gateway] Config inválida: {}", e),
                 }
-            }
-            Some(msg) = models_sub.next() => {
-                let rply = msg.reply.clone();
-                let http = http.clone();
-                let state_snapshot = state.clone();
-                let client2 = client.clone();
-
-                tokio::spawn(async move {
-                    let resp: AgentResponse<Vec<String>> = match list_models(&http, &state_snapshot).await {
-                        Ok(list) => AgentResponse::Success(list),
-                        Err(e) => AgentResponse::Error(e.to_string()),
+                msg = cfg_get_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.config.get", None).await {
+                            Ok(s) => cfg_get_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
                     };
-                    if let Some(r) = rply {
+                    if let Some(r) = msg.reply {
+                        let resp: AgentResponse<LlmConfigSet> = AgentResponse::Success(state.masked());
                         if let Ok(payload) = serde_json::to_vec(&resp) {
-                            let _ = client2.publish(r, payload.into()).await;
+                            let _ = client.publish(r, payload.into()).await;
                         }
                     }
-                });
-            }
-            Some(msg) = inspect_sub.next() => {
-                let rply = msg.reply.clone();
-                let http = http.clone();
-                let state_snapshot = state.clone();
-                let client2 = client.clone();
-
-                tokio::spawn(async move {
-                    let resp: AgentResponse<ProviderReport> = match inspect_providers(&http, &state_snapshot).await {
-                        Ok(rep) => AgentResponse::Success(rep),
-                        Err(e) => AgentResponse::Error(e.to_string()),
+                }
+                msg = curl_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.debug.curl", None).await {
+                            Ok(s) => curl_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    // Depuración: devuelve el cURL equivalente a la petición, sin enviarla.
+                    let resp = match serde_json::from_slice::<McpRequest>(&msg.payload) {
+                        Ok(req) => match curl_command(&req, &state) {
+                            Ok(cmd) => AgentResponse::Success(cmd),
+                            Err(e) => AgentResponse::failed(&e),
+                        },
+                        Err(e) => AgentResponse::error(ErrorCode::InvalidRequest, format!("Solicitud MCP malformada: {}", e)),
                     };
-                    if let Some(r) = rply {
+                    if let Some(r) = msg.reply {
                         if let Ok(payload) = serde_json::to_vec(&resp) {
-                            let _ = client2.publish(r, payload.into()).await;
+                            let _ = client.publish(r, payload.into()).await;
                         }
                     }
-                });
+                }
+                msg = pull_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.ollama.pull", None).await {
+                            Ok(s) => pull_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let http = http.clone();
+                    let state_snapshot = state.clone();
+                    let client2 = client.clone();
+                    let cache = Arc::clone(&model_cache);
+
+                    in_flight.spawn(async move {
+                        let resp: AgentResponse<String> = match serde_json::from_slice::<OllamaPullRequest>(&msg.payload) {
+                            Ok(req) => match pull_ollama_model(&http, &state_snapshot, &client2, &req).await {
+                                Ok(done) => {
+                                    // El modelo nuevo debe aparecer en la próxima validación.
+                                    cache.lock().await.remove("ollama");
+                                    AgentResponse::Success(done)
+                                }
+                                Err(e) => {
+                                    error!("[LLM Gateway] Descarga de '{}' fallida: {}", req.model, e);
+                                    AgentResponse::failed(&e)
+                                }
+                            },
+                            Err(e) => AgentResponse::error(ErrorCode::InvalidRequest, format!("Petición de descarga malformada: {}", e)),
+                        };
+                        if let Some(r) = msg.reply {
+                            if let Ok(payload) = serde_json::to_vec(&resp) {
+                                let _ = client2.publish(r, payload.into()).await;
+                            }
+                        }
+                    });
+                }
+                msg = models_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.models.list", None).await {
+                            Ok(s) => models_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let rply = msg.reply.clone();
+                    let http = http.clone();
+                    let state_snapshot = state.clone();
+                    let client2 = client.clone();
+                    let cache = Arc::clone(&model_cache);
+
+                    in_flight.spawn(async move {
+                        let resp: AgentResponse<Vec<String>> = match list_models(&http, &state_snapshot).await {
+                            Ok(list) => {
+                                // Misma resolución que `list_models`, para cachear bajo el proveedor consultado.
+                                cache.lock().await.insert(models_provider(&state_snapshot), (Instant::now(), list.clone()));
+                                AgentResponse::Success(list)
+                            }
+                            Err(e) => AgentResponse::failed(&e),
+                        };
+                        if let Some(r) = rply {
+                            if let Ok(payload) = serde_json::to_vec(&resp) {
+                                let _ = client2.publish(r, payload.into()).await;
+                            }
+                        }
+                    });
+                }
+                msg = inspect_sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, "LLM Gateway", "llm.providers.inspect", None).await {
+                            Ok(s) => inspect_sub = s,
+                            Err(e) => break Err(e),
+                        }
+                        continue;
+                    };
+                    let rply = msg.reply.clone();
+                    let http = http.clone();
+                    let state_snapshot = state.clone();
+                    let client2 = client.clone();
+                    let filter: InspectRequest = if msg.payload.is_empty() {
+                        InspectRequest::default()
+                    } else {
+                        serde_json::from_slice(&msg.payload).unwrap_or_else(|e| {
+                            error!("[LLM Gateway] Filtro de inspección inválido, se inspeccionan todos: {}", e);
+                            InspectRequest::default()
+                        })
+                    };
+
+                    in_flight.spawn(async move {
+                        let resp: AgentResponse<ProviderReport> = match inspect_providers(&http, &state_snapshot, &filter).await {
+                            Ok(rep) => AgentResponse::Success(rep),
+                            Err(e) => AgentResponse::failed(&e),
+                        };
+                        if let Some(r) = rply {
+                            if let Ok(payload) = serde_json::to_vec(&resp) {
+                                let _ = client2.publish(r, payload.into()).await;
+                            }
+                        }
+                    });
+                }
+            }
+        };
+        outcome
+    };
+    let result = run_until_shutdown("LLM Gateway", &control, &tasks, service).await;
+    // Con el servicio y las tareas terminados se sueltan los emisores: el escritor vacía la cola y sale.
+    if let Some(writer) = audit_writer {
+        if tokio::time::timeout(AUDIT_FLUSH_TIMEOUT, writer).await.is_err() {
+            warn!("[LLM Gateway] La auditoría no terminó de escribirse en {}s.", AUDIT_FLUSH_TIMEOUT.as_secs());
+        }
+    }
+    result
+}
+
+// ------------------------ URLs base por proveedor -------------------------
+/// URL base efectiva de un proveedor. `state.base_url` la sustituye cuando el
+/// proveedor es el activo (o no hay ninguno fijado), lo que permite apuntar
+/// openai/groq a servidores compatibles (LocalAI, vLLM, OpenRouter...).
+/// Si no, manda `<PROVEEDOR>_BASE_URL` y después el `base_url` de `providers.toml`.
+/// Para el protocolo de OpenAI la base excluye el sufijo `/v1`, que añade cada llamada.
+fn provider_base_url(provider: &str, state: &LlmConfigState) -> String {
+    let applies = !matches!(state.provider.as_deref(), Some(p) if p != provider);
+    let custom = state
+        .base_url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| applies && !u.is_empty())
+        .map(str::to_string);
+    let cfg = state.providers.iter().find(|p| p.name == provider);
+    let env_key = format!("{}_BASE_URL", provider.to_ascii_uppercase());
+    let configured = custom
+        .or_else(|| {
+            let raw = std::env::var(&env_key).ok()?;
+            normalize_base_url(&raw)
+                .map_err(|e| warn!("[LLM Gateway] {}: {}", env_key, e))
+                .ok()
+        })
+        .or_else(|| cfg.and_then(|c| c.base_url.clone()));
+
+    let base = match (cfg.map(ProviderConfig::kind).unwrap_or(provider), configured) {
+        ("ollama", Some(u)) => u,
+        ("ollama", None) => "http://localhost:11434".to_string(),
+        (_, Some(u)) => {
+            let u = u.trim_end_matches('/');
+            let u = u.strip_suffix("/v1").unwrap_or(u);
+            // La API de Groq compatible con OpenAI cuelga de /openai.
+            if u.ends_with("api.groq.com") {
+                format!("{}/openai", u)
+            } else {
+                u.to_string()
             }
-            else => break,
+        }
+        (_, None) => "https://api.openai.com".to_string(),
+    };
+    base.trim_end_matches('/').to_string()
+}
+
+/// Cabeceras extra para `provider`: las de `providers.toml`, encima las de
+/// `<PROVIDER>_EXTRA_HEADERS` (objeto JSON) y por último las de `llm.config.set`,
+/// que solo aplican al proveedor configurado.
+fn provider_headers(provider: &str, state: &LlmConfigState) -> HashMap<String, String> {
+    let mut headers: HashMap<String, String> = state
+        .providers
+        .iter()
+        .find(|p| p.name == provider)
+        .map(|p| p.headers.clone())
+        .unwrap_or_default();
+    let env_key = format!("{}_EXTRA_HEADERS", provider.to_ascii_uppercase());
+    if let Ok(raw) = std::env::var(&env_key) {
+        match serde_json::from_str::<HashMap<String, String>>(&raw) {
+            Ok(extra) => headers.extend(extra),
+            Err(e) => warn!("[LLM Gateway] {} no es un objeto JSON válido: {}", env_key, e),
+        }
+    }
+    let applies = !matches!(state.provider.as_deref(), Some(p) if p != provider);
+    if let (true, Some(custom)) = (applies, &state.headers) {
+        headers.extend(custom.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    headers
+}
+
+/// Añade las cabeceras extra del proveedor a la petición y su `timeout_secs`, si lo tiene.
+fn with_provider_headers(
+    builder: reqwest::RequestBuilder,
+    provider: &str,
+    state: &LlmConfigState,
+) -> reqwest::RequestBuilder {
+    let timeout = state
+        .providers
+        .iter()
+        .find(|p| p.name == provider)
+        .and_then(|p| p.timeout_secs);
+    let builder = match timeout {
+        Some(secs) => builder.timeout(Duration::from_secs(secs)),
+        None => builder,
+    };
+    provider_headers(provider, state)
+        .into_iter()
+        .fold(builder, |b, (k, v)| b.header(k, v))
+}
+
+// ------------------------ Validación de proveedor/modelo ------------------
+/// Determina proveedor y modelo efectivos: `req.provider` explícito, luego el
+/// prefijo del modelo ("groq:llama-3.1-70b"), luego la config del gateway. Sin
+/// modelo en la petición ni en la config se usa el `default_model` del proveedor.
+fn resolve_provider_and_model(req: &McpRequest, state: &LlmConfigState) -> Result<(String, String)> {
+    let mut model = req.model.trim().to_string();
+    let mut provider = req
+        .provider
+        .clone()
+        .filter(|p| !p.is_empty() && p != "auto");
+
+    if let Some((prefix, rest)) = model.split_once(':') {
+        if state.providers.iter().any(|p| p.name == prefix) {
+            provider = provider.or_else(|| Some(prefix.to_string()));
+            model = rest.to_string();
         }
     }
+    let provider = provider
+        .or_else(|| state.provider.clone())
+        .unwrap_or_else(|| state.default_provider());
+    let cfg = state.provider_config(&provider)?;
+    if model.is_empty() {
+        model = state.model.clone().or_else(|| cfg.default_model.clone()).unwrap_or_default();
+    }
+
+    if model.is_empty() {
+        return Err(invalid_request(format!("No se indicó modelo para el proveedor '{}'", provider)));
+    }
+    Ok((provider, model))
+}
+
+/// Comprueba que el modelo exista en la lista (cacheada) del proveedor. Si la lista
+/// no se puede obtener, no bloquea la petición: el proveedor dará su propio error.
+async fn validate_model(
+    http: &reqwest::Client,
+    state: &LlmConfigState,
+    cache: &ModelCache,
+    provider: &str,
+    model: &str,
+) -> Result<()> {
+    let cached = cache
+        .lock()
+        .await
+        .get(provider)
+        .filter(|(at, _)| at.elapsed() < MODEL_CACHE_TTL)
+        .map(|(_, list)| list.clone());
+
+    let models = match cached {
+        Some(list) => list,
+        None => {
+            let probe = LlmConfigState { provider: Some(provider.to_string()), ..state.clone() };
+            match list_models(http, &probe).await {
+                Ok(list) => {
+                    cache.lock().await.insert(provider.to_string(), (Instant::now(), list.clone()));
+                    list
+                }
+                Err(e) => {
+                    warn!("[LLM Gateway] No se pudo validar el modelo '{}' ({}): {}", model, provider, e);
+                    return Ok(());
+                }
+            }
+        }
+    };
 
+    // Ollama acepta el nombre sin etiqueta como alias de ":latest".
+    let latest = format!("{}:latest", model);
+    if !models.is_empty() && !models.iter().any(|m| m == model || *m == latest) {
+        if state.provider_config(provider).is_ok_and(|c| c.kind() == "ollama") {
+            return Err(model_not_pulled(model));
+        }
+        return Err(invalid_request(format!(
+            "El modelo '{}' no está disponible para el proveedor '{}'; disponibles: [{}]",
+            model,
+            provider,
+            models.join(", ")
+        )));
+    }
     Ok(())
 }
 
 // ------------------------ MCP handler (OpenAI/Groq/Ollama) ----------------
-async fn handle_mcp(req: McpRequest, http: &reqwest::Client, state: &LlmConfigState) -> Result<McpResponse> {
-    let provider = state.provider.clone().unwrap_or_else(|| "openai".to_string());
-    let model = req.model;
-    let temp = req.temperature.or(state.temperature).unwrap_or(0.7);
-
-    match provider.as_str() {
-        "openai" | "groq" => {
-            let (base, key_header) = if provider == "openai" {
-                ("https://api.openai.com", "OPENAI_API_KEY")
-            } else {
-                ("https://api.groq.com", "GROQ_API_KEY")
-            };
-            let api_key = state.api_key.clone().or_else(|| std::env::var(key_header).ok())
-                .context(format!("{} no definido", key_header))?;
+async fn handle_mcp(
+    mut req: McpRequest,
+    http: &reqwest::Client,
+    state: &LlmConfigState,
+    cache: &ModelCache,
+    gate: &Arc<ProviderGate>,
+) -> Result<McpResponse> {
+    let (provider, model) = resolve_provider_and_model(&req, state)?;
+    apply_sampling_defaults(&mut req, state);
+    check_sampling(&req)?;
+    validate_model(http, state, cache, &provider, &model).await?;
+    if req.json_mode == Some(true) {
+        check_json_mode(&model)?;
+    }
+    if req.trim_history == Some(true) {
+        let dropped = trim_history(&mut req, &model);
+        if dropped > 0 {
+            info!("[LLM Gateway] Historial recortado: {} turnos antiguos descartados para '{}'", dropped, model);
+        }
+    }
+    let cfg = state.provider_config(&provider)?;
+    check_stop(&mut req, cfg)?;
+    let _permit = gate.acquire(&provider, req.priority.unwrap_or(DEFAULT_PRIORITY)).await?;
+    let temp = clamp_temperature(cfg, req.temperature.or(state.temperature).unwrap_or(0.7));
 
-            // Arregla E0716: construye el URL en ramas separadas
-            let url = if provider == "openai" {
-                format!("{}/v1/chat/completions", base)
+    let mut response = match cfg.kind() {
+        "openai" => {
+            let key_header = cfg.api_key_env();
+            let api_key = state.api_key_for(cfg).context(format!("{} no definido", key_header))?;
+            let responses_api = state.uses_responses_api(&provider, &model);
+            let (url, payload) = if responses_api {
+                (
+                    format!("{}/v1/responses", provider_base_url(&provider, state)),
+                    build_responses_payload(&req, &model, temp),
+                )
             } else {
-                format!("{}/openai/v1/chat/completions", base)
+                (
+                    format!("{}/v1/chat/completions", provider_base_url(&provider, state)),
+                    build_openai_payload(&req, &model, temp),
+                )
             };
 
-            let payload = serde_json::json!({
-                "model": model,
-                "temperature": temp,
-                "messages": req.messages.iter().map(|m| {
-                    serde_json::json!({"role": m.role, "content": m.content})
-                }).collect::<Vec<_>>()
-            });
-
-            let resp = http.post(&url)
-                .bearer_auth(api_key)
+            let resp = with_provider_headers(http.post(&url), &provider, state)
+                .bearer_auth(&api_key)
                 .json(&payload)
                 .send()
                 .await?;
             if !resp.status().is_success() {
                 let status = resp.status();
-                let txt = resp.text().await.unwrap_or_default();
-                anyhow::bail!("OpenAI/Groq devolvió {}: {}", status, txt);
+                let txt = redact(&resp.text().await.unwrap_or_default(), Some(&api_key));
+                return Err(provider_error(status, format!("{} devolvió {}: {}", provider, status, txt)));
+            }
+            if responses_api {
+                parse_responses_response(&resp.bytes().await?)
+            } else {
+                parse_openai_response(&resp.bytes().await?)
             }
-            #[derive(Deserialize)]
-            struct ChoiceMsg { content: String }
-            #[derive(Deserialize)]
-            struct Choice { message: ChoiceMsg }
-            #[derive(Deserialize)]
-            struct ChatResp { choices: Vec<Choice> }
-            let jr: ChatResp = resp.json().await?;
-            let content = jr.choices.get(0).map(|c| c.message.content.clone()).unwrap_or_default();
-            Ok(McpResponse { content, token_usage: None })
         }
         "ollama" => {
-            let base = state.base_url.clone().unwrap_or_else(|| "http://localhost:11434".to_string());
-            let url = format!("{}/api/chat", base);
-            let messages: Vec<serde_json::Value> = req.messages.iter().map(|m| {
-                serde_json::json!({"role": m.role, "content": m.content})
-            }).collect();
-            let payload = serde_json::json!({
-                "model": model,
-                "stream": false,
-                "options": { "temperature": temp },
-                "messages": messages
-            });
-
-            let resp = http.post(&url).json(&payload).send().await?;
+            let url = format!("{}/api/chat", provider_base_url(&provider, state));
+            let payload = build_ollama_payload(&req, &model, temp);
+
+            let resp = with_provider_headers(http.post(&url), &provider, state)
+                .json(&payload)
+                .send()
+                .await?;
             if !resp.status().is_success() {
                 let status = resp.status();
                 let txt = resp.text().await.unwrap_or_default();
-                anyhow::bail!("Ollama devolvió {}: {}", status, txt);
+                // Ollama responde 404 "model ... not found, try pulling it first".
+                if status == reqwest::StatusCode::NOT_FOUND && txt.contains("not found") {
+                    return Err(model_not_pulled(&model));
+                }
+                return Err(provider_error(status, format!("{} devolvió {}: {}", provider, status, txt)));
             }
-            #[derive(Deserialize)]
-            struct Msg { content: String }
-            #[derive(Deserialize)]
-            struct OllamaResp { message: Msg }
-            let jr: OllamaResp = resp.json().await?;
-            Ok(McpResponse { content: jr.message.content, token_usage: None })
+            parse_ollama_response(&resp.bytes().await?)
+        }
+        "mock" => Ok(mock_completion(&model, &req)),
+        other => Err(invalid_request(format!("Proveedor no soportado: {}", other))),
+    }?;
+    response.estimated_cost_usd = estimate_cost(&state.prices, &provider, &model, response.token_usage);
+    Ok(response)
+}
+
+/// Publica en `llm.usage` el consumo de una completion para que los clientes lleven la
+/// cuenta de tokens y coste de la sesión. Solo se publica si el proveedor informó del uso.
+async fn publish_usage(client: &async_nats::Client, provider: String, model: String, resp: &McpResponse) {
+    let Some((prompt_tokens, completion_tokens)) = resp.token_usage else {
+        return;
+    };
+    let report = UsageReport {
+        provider,
+        model,
+        prompt_tokens,
+        completion_tokens,
+        estimated_cost_usd: resp.estimated_cost_usd,
+    };
+    if let Ok(payload) = serde_json::to_vec(&report) {
+        if let Err(e) = client.publish("llm.usage", payload.into()).await {
+            warn!("[LLM Gateway] No se pudo publicar el uso en 'llm.usage': {}", e);
         }
+    }
+}
+
+/// Descarga `req.model` en el Ollama configurado. Ollama emite una línea JSON por
+/// cambio de estado; cada cambio se reenvía como `ProgressUpdate` (porcentaje en
+/// `step`/`total`) cuando la petición trae `request_id`.
+async fn pull_ollama_model(
+    http: &reqwest::Client,
+    state: &LlmConfigState,
+    client: &async_nats::Client,
+    req: &OllamaPullRequest,
+) -> Result<String> {
+    let url = format!("{}/api/pull", provider_base_url("ollama", state));
+    info!("[LLM Gateway] Descargando modelo '{}' en Ollama", req.model);
+    let mut resp = with_provider_headers(http.post(&url), "ollama", state)
+        .timeout(OLLAMA_PULL_TIMEOUT)
+        .json(&serde_json::json!({ "model": req.model, "stream": true }))
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let txt = resp.text().await.unwrap_or_default();
+        return Err(provider_error(status, format!("Ollama devolvió {}: {}", status, txt)));
+    }
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut last: (String, Option<u32>) = (String::new(), None);
+    while let Some(chunk) = resp.chunk().await? {
+        buf.extend_from_slice(&chunk);
+        while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            let Ok(v) = serde_json::from_slice::<serde_json::Value>(&line) else { continue };
+            if let Some(err) = v.get("error").and_then(|e| e.as_str()) {
+                anyhow::bail!("Ollama: {}", err);
+            }
+            let status = v.get("status").and_then(|s| s.as_str()).unwrap_or_default().to_string();
+            let percent = match (
+                v.get("completed").and_then(|c| c.as_u64()),
+                v.get("total").and_then(|t| t.as_u64()),
+            ) {
+                (Some(done), Some(total)) if total > 0 => Some((done * 100 / total) as u32),
+                _ => None,
+            };
+            let current = (status, percent);
+            if current == last {
+                continue;
+            }
+            if let Some(id) = req.request_id.as_deref() {
+                let update = ProgressUpdate {
+                    message: current.0.clone(),
+                    step: current.1,
+                    total: current.1.map(|_| 100),
+                };
+                if let Ok(payload) = serde_json::to_vec(&update) {
+                    client.publish(progress_subject(id), payload.into()).await.ok();
+                }
+            }
+            last = current;
+        }
+    }
+
+    if last.0 != "success" {
+        anyhow::bail!("La descarga de '{}' terminó sin éxito (último estado: '{}')", req.model, last.0);
+    }
+    info!("[LLM Gateway] Modelo '{}' descargado en Ollama", req.model);
+    Ok(format!("Modelo '{}' descargado", req.model))
+}
+
+/// Comando cURL equivalente a la llamada HTTP que `handle_mcp` haría para `req`.
+/// La API key aparece enmascarada; no se valida el modelo ni se contacta al proveedor.
+fn curl_command(req: &McpRequest, state: &LlmConfigState) -> Result<String> {
+    let (provider, model) = resolve_provider_and_model(req, state)?;
+    let mut req = req.clone();
+    apply_sampling_defaults(&mut req, state);
+    check_sampling(&req)?;
+    let base = provider_base_url(&provider, state);
+    let cfg = state.provider_config(&provider)?;
+    check_stop(&mut req, cfg)?;
+    let temp = clamp_temperature(cfg, req.temperature.or(state.temperature).unwrap_or(0.7));
+    let (url, payload, auth) = match cfg.kind() {
+        "openai" => {
+            let key_header = cfg.api_key_env();
+            let key = state.api_key_for(cfg);
+            let auth = key.map(|k| mask_secret(&k)).unwrap_or_else(|| format!("${}", key_header));
+            if state.uses_responses_api(&provider, &model) {
+                (format!("{}/v1/responses", base), build_responses_payload(&req, &model, temp), Some(auth))
+            } else {
+                (
+                    format!("{}/v1/chat/completions", base),
+                    build_openai_payload(&req, &model, temp),
+                    Some(auth),
+                )
+            }
+        }
+        "ollama" => (format!("{}/api/chat", base), build_ollama_payload(&req, &model, temp), None),
+        "mock" => anyhow::bail!("El proveedor '{}' responde localmente; no hay llamada HTTP", provider),
         other => anyhow::bail!("Proveedor no soportado: {}", other),
+    };
+
+    // Comillas simples de shell: cada ' del JSON se cierra, escapa y reabre.
+    let body = serde_json::to_string(&payload)?.replace('\'', "'\\''");
+    let mut cmd = format!("curl -sS -X POST '{}' \\\n  -H 'Content-Type: application/json'", url);
+    let mut extra: Vec<_> = provider_headers(&provider, state).into_iter().collect();
+    extra.sort();
+    for (k, v) in extra {
+        cmd.push_str(&format!(" \\\n  -H '{}: {}'", k, v.replace('\'', "'\\''")));
+    }
+    if let Some(auth) = auth {
+        cmd.push_str(&format!(" \\\n  -H 'Authorization: Bearer {}'", auth));
+    }
+    cmd.push_str(&format!(" \\\n  -d '{}'", body));
+    Ok(cmd)
+}
+
+/// Tokens reservados para la respuesta al recortar el historial si la petición no fija `max_tokens`.
+const DEFAULT_RESPONSE_RESERVE: u32 = 1024;
+
+/// Estimación aproximada de tokens de un turno (≈4 caracteres por token más la envoltura del rol).
+fn estimate_tokens(turn: &McpMessageTurn) -> u32 {
+    (turn.content.chars().count() / 4) as u32 + 4
+}
+
+/// Descarta los turnos más antiguos hasta que la conversación quepa en el contexto del
+/// modelo (tabla de capacidades) menos la reserva de respuesta. Los mensajes de sistema y
+/// el último turno nunca se eliminan. Devuelve cuántos turnos se descartaron.
+fn trim_history(req: &mut McpRequest, model: &str) -> usize {
+    let Some(context) = infer_model_info(model).context_length else {
+        warn!("[LLM Gateway] Contexto de '{}' desconocido; no se recorta el historial", model);
+        return 0;
+    };
+    let budget = context.saturating_sub(req.max_tokens.unwrap_or(DEFAULT_RESPONSE_RESERVE));
+    let mut total: u32 = req.messages.iter().map(estimate_tokens).sum();
+    let last = req.messages.len().saturating_sub(1);
+    let mut keep = vec![true; req.messages.len()];
+    for (i, turn) in req.messages.iter().enumerate() {
+        if total <= budget {
+            break;
+        }
+        if i == last || turn.role == "system" {
+            continue;
+        }
+        keep[i] = false;
+        total -= estimate_tokens(turn);
+    }
+    if total > budget {
+        warn!(
+            "[LLM Gateway] La conversación (~{} tokens) sigue excediendo el presupuesto de '{}' ({})",
+            total, model, budget
+        );
+    }
+    let mut flags = keep.into_iter();
+    let before = req.messages.len();
+    req.messages.retain(|_| flags.next().unwrap_or(true));
+    before - req.messages.len()
+}
+
+/// Rechaza el modo JSON si la tabla de capacidades indica que el modelo no lo soporta.
+/// Los modelos desconocidos se aceptan: el proveedor tendrá la última palabra.
+fn check_json_mode(model: &str) -> Result<()> {
+    if infer_model_info(model).supports_json == Some(false) {
+        anyhow::bail!("El modelo '{}' no soporta salida JSON (json_mode)", model);
+    }
+    Ok(())
+}
+
+/// Completa `top_p` y las penalizaciones ausentes con los valores de `llm.config.set`.
+fn apply_sampling_defaults(req: &mut McpRequest, state: &LlmConfigState) {
+    req.top_p = req.top_p.or(state.top_p);
+    req.frequency_penalty = req.frequency_penalty.or(state.frequency_penalty);
+    req.presence_penalty = req.presence_penalty.or(state.presence_penalty);
+}
+
+/// Rechaza `top_p` fuera de 0.0-1.0 y penalizaciones fuera de -2.0-2.0.
+fn check_sampling(req: &McpRequest) -> Result<()> {
+    if let Some(p) = req.top_p.filter(|p| !(0.0..=1.0).contains(p)) {
+        return Err(invalid_request(format!("top_p={} fuera de rango (0.0-1.0)", p)));
+    }
+    for (name, value) in [("frequency_penalty", req.frequency_penalty), ("presence_penalty", req.presence_penalty)] {
+        if let Some(v) = value.filter(|v| !(-2.0..=2.0).contains(v)) {
+            return Err(invalid_request(format!("{}={} fuera de rango (-2.0-2.0)", name, v)));
+        }
+    }
+    Ok(())
+}
+
+/// Descarta las secuencias de parada vacías y rechaza más de las que admite el proveedor.
+fn check_stop(req: &mut McpRequest, cfg: &ProviderConfig) -> Result<()> {
+    if let Some(stop) = req.stop.as_mut() {
+        stop.retain(|s| !s.is_empty());
+    }
+    let count = req.stop.as_ref().map_or(0, Vec::len);
+    if count == 0 {
+        req.stop = None;
+    } else if cfg.kind() == "openai" && count > OPENAI_MAX_STOP {
+        return Err(invalid_request(format!(
+            "'{}' admite como máximo {} secuencias de parada; se recibieron {}",
+            cfg.name, OPENAI_MAX_STOP, count
+        )));
+    }
+    Ok(())
+}
+
+// ------------------------ Payloads y parsers por proveedor (puros) ---------
+fn messages_json(req: &McpRequest) -> Vec<serde_json::Value> {
+    req.messages
+        .iter()
+        .map(|m| serde_json::json!({"role": m.role, "content": m.content}))
+        .collect()
+}
+
+/// Cuerpo de `/v1/chat/completions` (OpenAI y Groq comparten formato).
+fn build_openai_payload(req: &McpRequest, model: &str, temp: f32) -> serde_json::Value {
+    let mut payload = serde_json::json!({
+        "model": model,
+        "temperature": temp,
+        "messages": messages_json(req),
+    });
+    if req.json_mode == Some(true) {
+        payload["response_format"] = serde_json::json!({ "type": "json_object" });
+    }
+    if let Some(seed) = req.seed {
+        payload["seed"] = serde_json::json!(seed);
+    }
+    if let Some(max_tokens) = req.max_tokens {
+        payload["max_tokens"] = serde_json::json!(max_tokens);
+    }
+    if let Some(top_p) = req.top_p {
+        payload["top_p"] = serde_json::json!(top_p);
+    }
+    if let Some(penalty) = req.frequency_penalty {
+        payload["frequency_penalty"] = serde_json::json!(penalty);
+    }
+    if let Some(penalty) = req.presence_penalty {
+        payload["presence_penalty"] = serde_json::json!(penalty);
+    }
+    if let Some(stop) = &req.stop {
+        payload["stop"] = serde_json::json!(stop);
+    }
+    payload
+}
+
+/// Cuerpo de `/v1/responses` (Responses API de OpenAI). Los turnos van en `input`.
+fn build_responses_payload(req: &McpRequest, model: &str, temp: f32) -> serde_json::Value {
+    let mut payload = serde_json::json!({
+        "model": model,
+        "temperature": temp,
+        "input": messages_json(req),
+    });
+    if req.json_mode == Some(true) {
+        payload["text"] = serde_json::json!({ "format": { "type": "json_object" } });
+    }
+    if let Some(max_tokens) = req.max_tokens {
+        payload["max_output_tokens"] = serde_json::json!(max_tokens);
+    }
+    if let Some(top_p) = req.top_p {
+        payload["top_p"] = serde_json::json!(top_p);
+    }
+    if let Some(seed) = req.seed {
+        debug!("[LLM Gateway] La Responses API no admite seed; se ignora seed={}", seed);
+    }
+    if req.frequency_penalty.is_some() || req.presence_penalty.is_some() {
+        debug!("[LLM Gateway] La Responses API no admite penalizaciones; se ignoran");
+    }
+    if req.stop.is_some() {
+        debug!("[LLM Gateway] La Responses API no admite secuencias de parada; se ignoran");
+    }
+    payload
+}
+
+/// Cuerpo de `/api/chat` de Ollama, sin streaming.
+fn build_ollama_payload(req: &McpRequest, model: &str, temp: f32) -> serde_json::Value {
+    let mut payload = serde_json::json!({
+        "model": model,
+        "stream": false,
+        "options": { "temperature": temp },
+        "messages": messages_json(req),
+    });
+    if req.json_mode == Some(true) {
+        payload["format"] = serde_json::json!("json");
+    }
+    if let Some(seed) = req.seed {
+        payload["options"]["seed"] = serde_json::json!(seed);
+    }
+    if let Some(max_tokens) = req.max_tokens {
+        payload["options"]["num_predict"] = serde_json::json!(max_tokens);
+    }
+    if let Some(top_p) = req.top_p {
+        payload["options"]["top_p"] = serde_json::json!(top_p);
+    }
+    if let Some(penalty) = req.frequency_penalty {
+        payload["options"]["frequency_penalty"] = serde_json::json!(penalty);
+    }
+    if let Some(penalty) = req.presence_penalty {
+        payload["options"]["presence_penalty"] = serde_json::json!(penalty);
+    }
+    if let Some(stop) = &req.stop {
+        payload["options"]["stop"] = serde_json::json!(stop);
+    }
+    payload
+}
+
+fn parse_openai_response(body: &[u8]) -> Result<McpResponse> {
+    #[derive(Deserialize)]
+    struct ChoiceMsg { content: String }
+    #[derive(Deserialize)]
+    struct Choice { message: ChoiceMsg }
+    #[derive(Deserialize)]
+    struct Usage { prompt_tokens: u32, completion_tokens: u32 }
+    #[derive(Deserialize)]
+    struct ChatResp {
+        choices: Vec<Choice>,
+        #[serde(default)]
+        usage: Option<Usage>,
+    }
+    let jr: ChatResp = serde_json::from_slice(body).context("Respuesta OpenAI/Groq malformada")?;
+    let content = jr.choices.first().map(|c| c.message.content.clone()).unwrap_or_default();
+    let token_usage = jr.usage.map(|u| (u.prompt_tokens, u.completion_tokens));
+    Ok(McpResponse { content, token_usage, estimated_cost_usd: None })
+}
+
+/// Une los fragmentos `output_text` de los mensajes de salida de la Responses API.
+fn parse_responses_response(body: &[u8]) -> Result<McpResponse> {
+    #[derive(Deserialize)]
+    struct Part {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        text: String,
+    }
+    #[derive(Deserialize)]
+    struct Item {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        content: Vec<Part>,
+    }
+    #[derive(Deserialize)]
+    struct Usage { input_tokens: u32, output_tokens: u32 }
+    #[derive(Deserialize)]
+    struct ResponsesResp {
+        output: Vec<Item>,
+        #[serde(default)]
+        usage: Option<Usage>,
+    }
+    let jr: ResponsesResp = serde_json::from_slice(body).context("Respuesta de la Responses API malformada")?;
+    let content = jr
+        .output
+        .iter()
+        .filter(|item| item.kind == "message")
+        .flat_map(|item| item.content.iter())
+        .filter(|part| part.kind == "output_text")
+        .map(|part| part.text.as_str())
+        .collect::<Vec<_>>()
+        .join("");
+    let token_usage = jr.usage.map(|u| (u.input_tokens, u.output_tokens));
+    Ok(McpResponse { content, token_usage, estimated_cost_usd: None })
+}
+
+/// Acepta tanto un único objeto como NDJSON: algunas versiones de Ollama ignoran
+/// `"stream": false` y envían la respuesta en trozos, uno por línea.
+fn parse_ollama_response(body: &[u8]) -> Result<McpResponse> {
+    #[derive(Deserialize, Default)]
+    struct Msg { content: String }
+    #[derive(Deserialize)]
+    struct OllamaChunk {
+        #[serde(default)]
+        message: Msg,
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        prompt_eval_count: Option<u32>,
+        #[serde(default)]
+        eval_count: Option<u32>,
+    }
+    let mut content = String::new();
+    let mut token_usage = None;
+    let mut chunks = 0usize;
+    for chunk in serde_json::Deserializer::from_slice(body).into_iter::<OllamaChunk>() {
+        let chunk = chunk.context("Respuesta de Ollama malformada")?;
+        if let Some(e) = chunk.error {
+            anyhow::bail!("Ollama: {}", e);
+        }
+        content.push_str(&chunk.message.content);
+        // Los contadores solo llegan en el último trozo (`"done": true`).
+        token_usage = chunk.prompt_eval_count.zip(chunk.eval_count).or(token_usage);
+        chunks += 1;
+    }
+    if chunks == 0 {
+        anyhow::bail!("Respuesta de Ollama vacía");
+    }
+    if chunks > 1 {
+        debug!("[LLM Gateway] Respuesta de Ollama en streaming: {} trozos reensamblados", chunks);
+    }
+    Ok(McpResponse { content, token_usage, estimated_cost_usd: None })
+}
+
+/// Respuesta determinista del proveedor `mock`: eco truncado del último mensaje de usuario.
+fn mock_completion(model: &str, req: &McpRequest) -> McpResponse {
+    const MAX_ECHO_CHARS: usize = 200;
+    if let Some(seed) = req.seed {
+        debug!("[LLM Gateway] 'mock' es determinista; se ignora seed={}", seed);
+    }
+    let last_user = req
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+        .unwrap_or("");
+    let mut echo: String = last_user.chars().take(MAX_ECHO_CHARS).collect();
+    if last_user.chars().count() > MAX_ECHO_CHARS {
+        echo.push('…');
+    }
+    let prompt_words: usize = req.messages.iter().map(|m| m.content.split_whitespace().count()).sum();
+    let content = if req.json_mode == Some(true) {
+        serde_json::json!({ "model": model, "summary": echo }).to_string()
+    } else {
+        format!("[{}] Resumen simulado: {}", model, echo)
+    };
+    let completion_words = content.split_whitespace().count();
+    McpResponse {
+        content,
+        token_usage: Some((prompt_words as u32, completion_words as u32)),
+        estimated_cost_usd: None,
     }
 }
 
 // ------------------------ List models (del proveedor activo) --------------
+/// Proveedor cuyos modelos lista `list_models`: el fijado o, si no hay, el por defecto.
+fn models_provider(state: &LlmConfigState) -> String {
+    state.provider.clone().unwrap_or_else(|| state.default_provider())
+}
+
 async fn list_models(http: &reqwest::Client, state: &LlmConfigState) -> Result<Vec<String>> {
-    let provider = state.provider.clone().unwrap_or_else(|| "openai".to_string());
-    match provider.as_str() {
-        "openai" | "groq" => {
-            let (base, key_header) = if provider == "openai" {
-                ("https://api.openai.com", "OPENAI_API_KEY")
-            } else {
-                ("https://api.groq.com/openai", "GROQ_API_KEY")
-            };
-            let api_key = state.api_key.clone().or_else(|| std::env::var(key_header).ok())
-                .context(format!("{} no definido", key_header))?;
-            let url = format!("{}/v1/models", base);
-            let resp = http.get(&url).bearer_auth(api_key).send().await?;
+    let provider = models_provider(state);
+    let cfg = state.provider_config(&provider)?;
+    match cfg.kind() {
+        "openai" => {
+            let key_header = cfg.api_key_env();
+            let api_key = state.api_key_for(cfg).context(format!("{} no definido", key_header))?;
+            let url = format!("{}/v1/models", provider_base_url(&provider, state));
+            let resp = with_provider_headers(http.get(&url), &provider, state)
+                .bearer_auth(&api_key)
+                .send()
+                .await?;
             if !resp.status().is_success() {
                 let status = resp.status();
-                let txt = resp.text().await.unwrap_or_default();
-                anyhow::bail!("{} /models devolvió {}: {}", provider, status, txt);
+                let txt = redact(&resp.text().await.unwrap_or_default(), Some(&api_key));
+                return Err(provider_error(status, format!("{} /models devolvió {}: {}", provider, status, txt)));
             }
             #[derive(Deserialize)]
             struct Model { id: String }
@@ -274,13 +1792,12 @@ async fn list_models(http: &reqwest::Client, state: &LlmConfigState) -> Result<V
             Ok(list.data.into_iter().map(|m| m.id).collect())
         }
         "ollama" => {
-            let base = state.base_url.clone().unwrap_or_else(|| "http://localhost:11434".to_string());
-            let url = format!("{}/api/tags", base);
-            let resp = http.get(&url).send().await?;
+            let url = format!("{}/api/tags", provider_base_url(&provider, state));
+            let resp = with_provider_headers(http.get(&url), &provider, state).send().await?;
             if !resp.status().is_success() {
                 let status = resp.status();
                 let txt = resp.text().await.unwrap_or_default();
-                anyhow::bail!("ollama /api/tags devolvió {}: {}", status, txt);
+                return Err(provider_error(status, format!("{} /api/tags devolvió {}: {}", provider, status, txt)));
             }
             #[derive(Deserialize)]
             struct Tag { name: String }
@@ -289,138 +1806,346 @@ async fn list_models(http: &reqwest::Client, state: &LlmConfigState) -> Result<V
             let tags: Tags = resp.json().await?;
             Ok(tags.models.into_iter().map(|t| t.name).collect())
         }
+        "mock" => Ok(MOCK_MODELS.iter().map(|m| m.to_string()).collect()),
         other => anyhow::bail!("Proveedor no soportado: {}", other),
     }
 }
 
 // ------------------------ Inspect providers (nuevo) -----------------------
-async fn inspect_providers(http: &reqwest::Client, state: &LlmConfigState) -> Result<ProviderReport> {
+async fn inspect_providers(
+    http: &reqwest::Client,
+    state: &LlmConfigState,
+    filter: &InspectRequest,
+) -> Result<ProviderReport> {
     let mut providers = Vec::new();
 
-    // OPENAI
-    {
-        let mut info = ProviderInfo {
-            name: "openai".into(),
-            endpoint: Some("https://api.openai.com".into()),
-            reachable: false,
-            latency_ms: None,
-            auth_mode: Some("bearer".into()),
-            error: None,
-            models: vec![],
+    for cfg in state.providers.iter().filter(|p| p.enabled) {
+        let info = match cfg.kind() {
+            "openai" if filter.wants(&cfg.name) => inspect_openai(http, state, cfg).await,
+            "ollama" if filter.wants(&cfg.name) => inspect_ollama(http, state, cfg).await,
+            // MOCK (solo si se pide explícitamente; no forma parte del sondeo por defecto)
+            "mock" if filter.names(&cfg.name) => ProviderInfo {
+                name: cfg.name.clone(),
+                endpoint: None,
+                reachable: true,
+                latency_ms: Some(0),
+                auth_mode: Some("none".into()),
+                error: None,
+                models: MOCK_MODELS.iter().map(|m| ModelInfo { id: m.to_string(), ..Default::default() }).collect(),
+            },
+            _ => continue,
         };
-        let key = state.api_key.clone().or_else(|| std::env::var("OPENAI_API_KEY").ok());
-        if key.is_none() {
-            info.error = Some("OPENAI_API_KEY no definido".into());
-        } else {
-            let start = Instant::now();
-            let res = http
-                .get("https://api.openai.com/v1/models")
-                .bearer_auth(key.unwrap())
-                .send()
-                .await;
-            match res {
-                Ok(resp) if resp.status().is_success() => {
-                    info.reachable = true;
-                    info.latency_ms = Some(start.elapsed().as_millis());
-                    #[derive(Deserialize)]
-                    struct Model { id: String }
-                    #[derive(Deserialize)]
-                    struct List { data: Vec<Model> }
-                    let list: List = resp.json().await.unwrap_or(List{data:vec![]});
-                    info.models = list.data.into_iter().map(|m| ModelInfo{ id: m.id, ..Default::default() }).collect();
-                }
-                Ok(resp) => {
-                    let status = resp.status();
-                    let txt = resp.text().await.unwrap_or_default();
-                    info.error = Some(format!("{} {}", status, txt));
-                }
-                Err(e) => info.error = Some(e.to_string()),
-            }
-        }
         providers.push(info);
     }
 
-    // GROQ
-    {
-        let mut info = ProviderInfo {
-            name: "groq".into(),
-            endpoint: Some("https://api.groq.com/openai".into()),
-            reachable: false,
-            latency_ms: None,
-            auth_mode: Some("bearer".into()),
-            error: None,
-            models: vec![],
-        };
-        let key = state.api_key.clone().or_else(|| std::env::var("GROQ_API_KEY").ok());
-        if key.is_none() {
-            info.error = Some("GROQ_API_KEY no definido".into());
-        } else {
-            let start = Instant::now();
-            let res = http
-                .get("https://api.groq.com/openai/v1/models")
-                .bearer_auth(key.unwrap())
-                .send()
-                .await;
-            match res {
-                Ok(resp) if resp.status().is_success() => {
-                    info.reachable = true;
-                    info.latency_ms = Some(start.elapsed().as_millis());
-                    #[derive(Deserialize)]
-                    struct Model { id: String }
-                    #[derive(Deserialize)]
-                    struct List { data: Vec<Model> }
-                    let list: List = resp.json().await.unwrap_or(List{data:vec![]});
-                    info.models = list.data.into_iter().map(|m| ModelInfo{ id: m.id, ..Default::default() }).collect();
+    Ok(ProviderReport { providers })
+}
+
+/// Sondea un proveedor con API compatible con OpenAI (`GET /v1/models`).
+async fn inspect_openai(http: &reqwest::Client, state: &LlmConfigState, cfg: &ProviderConfig) -> ProviderInfo {
+    let base = provider_base_url(&cfg.name, state);
+    let mut info = ProviderInfo {
+        name: cfg.name.clone(),
+        endpoint: Some(base.clone()),
+        reachable: false,
+        latency_ms: None,
+        auth_mode: Some("bearer".into()),
+        error: None,
+        models: vec![],
+    };
+    let Some(key) = state.api_key_for(cfg) else {
+        info.error = Some(format!("{} no definido", cfg.api_key_env()));
+        return info;
+    };
+    let start = Instant::now();
+    let res = with_provider_headers(http.get(format!("{}/v1/models", base)), &cfg.name, state)
+        .bearer_auth(&key)
+        .send()
+        .await;
+    match res {
+        Ok(resp) if resp.status().is_success() => {
+            info.reachable = true;
+            info.latency_ms = Some(start.elapsed().as_millis());
+            #[derive(Deserialize)]
+            struct Model { id: String }
+            #[derive(Deserialize)]
+            struct List { data: Vec<Model> }
+            let list: List = resp.json().await.unwrap_or(List{data:vec![]});
+            info.models = list.data.into_iter().map(|m| infer_model_info(&m.id)).collect();
+        }
+        Ok(resp) => {
+            let status = resp.status();
+            let txt = redact(&resp.text().await.unwrap_or_default(), Some(&key));
+            info.error = Some(format!("{} {}", status, txt));
+        }
+        Err(e) => info.error = Some(e.to_string()),
+    }
+    info
+}
+
+/// Sondea un servidor Ollama (`GET /api/tags`).
+async fn inspect_ollama(http: &reqwest::Client, state: &LlmConfigState, cfg: &ProviderConfig) -> ProviderInfo {
+    let base = provider_base_url(&cfg.name, state);
+    let mut info = ProviderInfo {
+        name: cfg.name.clone(),
+        endpoint: Some(base.clone()),
+        reachable: false,
+        latency_ms: None,
+        auth_mode: Some("none".into()),
+        error: None,
+        models: vec![],
+    };
+    let url = format!("{}/api/tags", base);
+    let start = Instant::now();
+    let res = with_provider_headers(http.get(&url), &cfg.name, state).send().await;
+    match res {
+        Ok(resp) if resp.status().is_success() => {
+            info.reachable = true;
+            info.latency_ms = Some(start.elapsed().as_millis());
+            #[derive(Deserialize, Default)]
+            struct TagDetails { family: Option<String>, families: Option<Vec<String>> }
+            #[derive(Deserialize)]
+            struct Tag { name: String, #[serde(default)] details: TagDetails }
+            #[derive(Deserialize)]
+            struct Tags { models: Vec<Tag> }
+            let tags: Tags = resp.json().await.unwrap_or(Tags{models:vec![]});
+            info.models = tags.models.into_iter().map(|t| {
+                let mut m = infer_model_info(&t.name);
+                if let Some(fam) = t.details.family {
+                    m.family = Some(fam);
                 }
-                Ok(resp) => {
-                    let status = resp.status();
-                    let txt = resp.text().await.unwrap_or_default();
-                    info.error = Some(format!("{} {}", status, txt));
+                // Ollama declara "clip" entre sus familias cuando el modelo acepta imágenes.
+                if t.details.families.unwrap_or_default().iter().any(|f| f == "clip" || f == "mllama") {
+                    m.modality = Some("multimodal".into());
+                    m.supports_images = Some(true);
                 }
-                Err(e) => info.error = Some(e.to_string()),
-            }
+                m
+            }).collect();
         }
-        providers.push(info);
+        Ok(resp) => {
+            let status = resp.status();
+            let txt = resp.text().await.unwrap_or_default();
+            info.error = Some(format!("{} {}", status, txt));
+        }
+        Err(e) => info.error = Some(e.to_string()),
     }
+    info
+}
 
-    // OLLAMA
+// ------------------------ Capacidades de modelos ---------------------------
+/// Capacidades conocidas por familia de modelo. Se busca el primer prefijo que
+/// coincida con el id (en minúsculas), así que los prefijos más específicos van antes.
+/// Columnas: (prefijo, familia, multimodal, contexto, json, tools)
+const MODEL_CAPABILITIES: &[(&str, &str, bool, u32, bool, bool)] = &[
+    ("gpt-4o", "gpt-4o", true, 128_000, true, true),
+    ("gpt-4.1", "gpt-4.1", true, 1_047_576, true, true),
+    ("gpt-4-turbo", "gpt-4", true, 128_000, true, true),
+    ("gpt-4", "gpt-4", false, 8_192, false, true),
+    ("gpt-3.5-turbo", "gpt-3.5", false, 16_385, true, true),
+    ("o1", "o1", true, 200_000, true, true),
+    ("o3", "o3", true, 200_000, true, true),
+    ("o4-mini", "o4", true, 200_000, true, true),
+    ("llama-3.2-90b-vision", "llama3.2", true, 128_000, true, true),
+    ("llama-3.2-11b-vision", "llama3.2", true, 128_000, true, true),
+    ("llama-3.3", "llama3.3", false, 128_000, true, true),
+    ("llama-3.1", "llama3.1", false, 128_000, true, true),
+    ("llama3.2-vision", "llama3.2", true, 128_000, true, true),
+    ("llama3.3", "llama3.3", false, 128_000, true, true),
+    ("llama3.2", "llama3.2", false, 128_000, true, true),
+    ("llama3.1", "llama3.1", false, 128_000, true, true),
+    ("llama3", "llama3", false, 8_192, true, false),
+    ("llava", "llava", true, 4_096, false, false),
+    ("mixtral", "mixtral", false, 32_768, true, true),
+    ("mistral", "mistral", false, 32_768, true, true),
+    ("gemma2", "gemma2", false, 8_192, true, false),
+    ("gemma", "gemma", false, 8_192, true, false),
+    ("qwen2.5", "qwen2.5", false, 32_768, true, true),
+    ("deepseek-r1", "deepseek-r1", false, 128_000, true, false),
+    ("phi3", "phi3", false, 128_000, true, false),
+];
+
+/// Deduce las capacidades de un modelo a partir de su id. Si el modelo no
+/// aparece en la tabla, todos los campos quedan en `None`.
+fn infer_model_info(id: &str) -> ModelInfo {
+    let lower = id.to_lowercase();
+    // Los ids de Ollama llevan etiqueta ("llama3.1:8b") y algunos un espacio de nombres ("library/...").
+    let base = lower.rsplit('/').next().unwrap_or(&lower);
+    let base = base.split(':').next().unwrap_or(base);
+
+    let mut info = ModelInfo { id: id.to_string(), ..Default::default() };
+    if let Some(&(_, family, multimodal, ctx, json, tools)) =
+        MODEL_CAPABILITIES.iter().find(|(prefix, ..)| base.starts_with(prefix))
     {
-        let base = state.base_url.clone().or_else(|| std::env::var("OLLAMA_BASE_URL").ok())
-            .unwrap_or_else(|| "http://localhost:11434".to_string());
-        let mut info = ProviderInfo {
-            name: "ollama".into(),
-            endpoint: Some(base.clone()),
-            reachable: false,
-            latency_ms: None,
-            auth_mode: Some("none".into()),
-            error: None,
-            models: vec![],
-        };
-        let url = format!("{}/api/tags", base);
-        let start = Instant::now();
-        let res = http.get(&url).send().await;
-        match res {
-            Ok(resp) if resp.status().is_success() => {
-                info.reachable = true;
-                info.latency_ms = Some(start.elapsed().as_millis());
-                #[derive(Deserialize)]
-                struct Tag { name: String }
-                #[derive(Deserialize)]
-                struct Tags { models: Vec<Tag> }
-                let tags: Tags = resp.json().await.unwrap_or(Tags{models:vec![]});
-                info.models = tags.models.into_iter().map(|t| ModelInfo{ id: t.name, ..Default::default() }).collect();
-            }
-            Ok(resp) => {
-                let status = resp.status();
-                let txt = resp.text().await.unwrap_or_default();
-                info.error = Some(format!("{} {}", status, txt));
-            }
-            Err(e) => info.error = Some(e.to_string()),
+        info.family = Some(family.to_string());
+        info.modality = Some(if multimodal { "multimodal" } else { "text" }.to_string());
+        info.context_length = Some(ctx);
+        info.supports_json = Some(json);
+        info.supports_tools = Some(tools);
+        info.supports_images = Some(multimodal);
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(extra: serde_json::Value) -> McpRequest {
+        let mut base = serde_json::json!({
+            "model": "ignored",
+            "messages": [
+                { "role": "system", "content": "Eres conciso." },
+                { "role": "user", "content": "Resume esto" },
+            ],
+        });
+        if let (Some(base), Some(extra)) = (base.as_object_mut(), extra.as_object()) {
+            base.extend(extra.clone());
         }
-        providers.push(info);
+        serde_json::from_value(base).unwrap()
     }
 
-    Ok(ProviderReport { providers })
-}
+    fn expected_messages() -> serde_json::Value {
+        serde_json::json!([
+            { "role": "system", "content": "Eres conciso." },
+            { "role": "user", "content": "Resume esto" },
+        ])
+    }
+
+    #[test]
+    fn openai_payload_maps_messages_model_and_options() {
+        let req = request(serde_json::json!({
+            "json_mode": true, "seed": 7, "max_tokens": 100, "top_p": 0.5, "stop": ["FIN"],
+        }));
+        let payload = build_openai_payload(&req, "gpt-4o-mini", 0.25);
+        assert_eq!(payload["model"], "gpt-4o-mini");
+        assert_eq!(payload["temperature"], 0.25);
+        assert_eq!(payload["messages"], expected_messages());
+        assert_eq!(payload["response_format"]["type"], "json_object");
+        assert_eq!(payload["seed"], 7);
+        assert_eq!(payload["max_tokens"], 100);
+        assert_eq!(payload["top_p"], 0.5);
+        assert_eq!(payload["stop"], serde_json::json!(["FIN"]));
+    }
+
+    #[test]
+    fn responses_payload_uses_input_and_drops_unsupported_options() {
+        let req = request(serde_json::json!({ "max_tokens": 100, "seed": 7, "stop": ["FIN"] }));
+        let payload = build_responses_payload(&req, "gpt-4.1", 0.5);
+        assert_eq!(payload["model"], "gpt-4.1");
+        assert_eq!(payload["temperature"], 0.5);
+        assert_eq!(payload["input"], expected_messages());
+        assert!(payload.get("messages").is_none());
+        assert_eq!(payload["max_output_tokens"], 100);
+        assert!(payload.get("seed").is_none());
+        assert!(payload.get("stop").is_none());
+    }
+
+    #[test]
+    fn ollama_payload_puts_options_under_options() {
+        let req = request(serde_json::json!({ "json_mode": true, "seed": 7, "max_tokens": 100 }));
+        let payload = build_ollama_payload(&req, "llama3.1:8b", 0.5);
+        assert_eq!(payload["model"], "llama3.1:8b");
+        assert_eq!(payload["stream"], false);
+        assert_eq!(payload["options"]["temperature"], 0.5);
+        assert_eq!(payload["options"]["seed"], 7);
+        assert_eq!(payload["options"]["num_predict"], 100);
+        assert_eq!(payload["format"], "json");
+        assert_eq!(payload["messages"], expected_messages());
+    }
 
+    #[test]
+    fn openai_response_parses_content_and_usage() {
+        let body = br#"{"choices":[{"message":{"role":"assistant","content":"Hola"}}],"usage":{"prompt_tokens":12,"completion_tokens":3,"total_tokens":15}}"#;
+        let resp = parse_openai_response(body).unwrap();
+        assert_eq!(resp.content, "Hola");
+        assert_eq!(resp.token_usage, Some((12, 3)));
+    }
+
+    #[test]
+    fn openai_error_body_is_rejected() {
+        let body = br#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error"}}"#;
+        let err = parse_openai_response(body).unwrap_err();
+        assert!(err.to_string().contains("malformada"), "{err}");
+    }
+
+    #[test]
+    fn responses_response_joins_output_text() {
+        let body = br#"{"output":[
+            {"type":"reasoning","content":[]},
+            {"type":"message","content":[{"type":"output_text","text":"Ho"},{"type":"refusal","text":"x"},{"type":"output_text","text":"la"}]}
+        ],"usage":{"input_tokens":5,"output_tokens":2}}"#;
+        let resp = parse_responses_response(body).unwrap();
+        assert_eq!(resp.content, "Hola");
+        assert_eq!(resp.token_usage, Some((5, 2)));
+    }
+
+    #[test]
+    fn responses_error_body_is_rejected() {
+        let body = br#"{"error":{"message":"model not found","code":"model_not_found"}}"#;
+        let err = parse_responses_response(body).unwrap_err();
+        assert!(err.to_string().contains("malformada"), "{err}");
+    }
 
+    #[test]
+    fn ollama_single_object_response() {
+        let body = br#"{"model":"llama3.1","message":{"role":"assistant","content":"Hola"},"done":true,"prompt_eval_count":9,"eval_count":4}"#;
+        let resp = parse_ollama_response(body).unwrap();
+        assert_eq!(resp.content, "Hola");
+        assert_eq!(resp.token_usage, Some((9, 4)));
+    }
+
+    #[test]
+    fn ollama_ndjson_chunks_are_reassembled() {
+        let body = concat!(
+            r#"{"model":"llama3.1","message":{"role":"assistant","content":"Ho"},"done":false}"#, "\n",
+            "\n",
+            r#"{"model":"llama3.1","message":{"role":"assistant","content":"la, "},"done":false}"#, "\n",
+            r#"{"model":"llama3.1","message":{"role":"assistant","content":"mundo"},"done":false}"#, "\r\n",
+            r#"{"model":"llama3.1","message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":9,"eval_count":4}"#, "\n",
+        );
+        let resp = parse_ollama_response(body.as_bytes()).unwrap();
+        assert_eq!(resp.content, "Hola, mundo");
+        assert_eq!(resp.token_usage, Some((9, 4)));
+    }
+
+    #[test]
+    fn ollama_done_line_without_message_is_accepted() {
+        let body = concat!(
+            r#"{"message":{"role":"assistant","content":"Hola"},"done":false}"#, "\n",
+            r#"{"done":true,"done_reason":"stop","prompt_eval_count":1,"eval_count":1}"#, "\n",
+        );
+        let resp = parse_ollama_response(body.as_bytes()).unwrap();
+        assert_eq!(resp.content, "Hola");
+        assert_eq!(resp.token_usage, Some((1, 1)));
+    }
+
+    fn provider(name: &str, kind: Option<&str>) -> ProviderConfig {
+        let mut cfg = serde_json::json!({ "name": name });
+        if let Some(kind) = kind {
+            cfg["kind"] = serde_json::json!(kind);
+        }
+        serde_json::from_value(cfg).unwrap()
+    }
+
+    #[test]
+    fn temperature_is_clamped_by_protocol_not_by_name() {
+        assert_eq!(clamp_temperature(&provider("openai", None), 3.5), 2.0);
+        assert_eq!(clamp_temperature(&provider("groq", None), -1.0), 0.0);
+        // Un proveedor de providers.toml con protocolo OpenAI se recorta igual.
+        assert_eq!(clamp_temperature(&provider("mi-vllm", Some("openai")), 2.5), 2.0);
+        assert_eq!(clamp_temperature(&provider("openai", None), 0.3), 0.3);
+        // Ollama no documenta un máximo: se respeta el valor pedido.
+        assert_eq!(clamp_temperature(&provider("ollama", None), 3.5), 3.5);
+        assert_eq!(clamp_temperature(&provider("gpu-box", Some("ollama")), 3.5), 3.5);
+    }
+
+    #[test]
+    fn ollama_error_bodies_are_rejected() {
+        let err = parse_ollama_response(br#"{"error":"model 'x' not found"}"#).unwrap_err();
+        assert!(err.to_string().contains("model 'x' not found"), "{err}");
+        assert!(parse_ollama_response(b"").is_err());
+        assert!(parse_ollama_response(b"<html>502 Bad Gateway</html>").is_err());
+        let truncated = concat!(r#"{"message":{"content":"Ho"},"done":false}"#, "\n", r#"{"message":{"con"#);
+        assert!(parse_ollama_response(truncated.as_bytes()).is_err());
+    }
+}
@@ -1,13 +1,17 @@
 // src/bin/6_agent_launcher.rs
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use futures_util::StreamExt;
+use multi_agent_file_processor::{connect_to_nats, resubscribe, spawn_discovery, AgentError, AgentResponse, ErrorCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::process::Stdio;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
 use tokio::signal;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
@@ -21,30 +25,329 @@ enum RestartPolicy {
     Always,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Ruta del manifiesto de agentes.
+const CONFIG_PATH: &str = "config.toml";
+
+/// Cada cuánto se comprueba si `config.toml` ha cambiado.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Plazo por defecto entre el SIGTERM y la parada forzada de un agente
+/// (`LAUNCHER_STOP_GRACE_SECS`); algo más que el `AGENT_DRAIN_SECS` por defecto de los agentes.
+const DEFAULT_STOP_GRACE_SECS: u64 = 35;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 struct AgentConfig {
     name: String,
     bin: String,
     enabled: bool,
     restart: RestartPolicy,
+    /// Filtro de tracing para este agente (se pasa como `RUST_LOG` al proceso hijo).
+    #[serde(default)]
+    log_level: Option<String>,
+    /// Si es `true`, se conserva una tubería a la entrada estándar del agente
+    /// para enviarle órdenes con `launcher.agent.stdin`.
+    #[serde(default)]
+    stdin: bool,
+    /// Directorio de trabajo del agente; por defecto, el del lanzador.
+    #[serde(default)]
+    cwd: Option<String>,
+    /// Argumentos de línea de comandos para el binario.
+    #[serde(default)]
+    args: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct LauncherConfig {
     build_profile: String,
+    /// Si es `true` no se ejecuta `cargo build`: se usan los binarios ya compilados.
+    #[serde(default)]
+    skip_build: bool,
     agents: Vec<AgentConfig>,
 }
 
 struct ManagedAgent {
     config: AgentConfig,
-    child: Arc<Mutex<Child>>,
     id: u32,
+    stdin: Option<ChildStdin>,
+    /// Pide al monitor del proceso que lo termine, con el motivo que se registrará.
+    kill: Option<oneshot::Sender<&'static str>>,
 }
 
 impl ManagedAgent {
     fn name(&self) -> &str {
         &self.config.name
     }
+
+    /// Solicita la parada del agente; su salida llega después por el canal del lanzador.
+    fn stop(&mut self, reason: &'static str) {
+        if let Some(kill) = self.kill.take() {
+            kill.send(reason).ok();
+        }
+    }
+}
+
+fn stop_grace() -> Duration {
+    Duration::from_secs(
+        std::env::var("LAUNCHER_STOP_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_STOP_GRACE_SECS),
+    )
+}
+
+/// Envía SIGTERM para que el agente termine sus peticiones en curso (`run_until_shutdown`)
+/// y solo lo mata si no ha salido pasado `grace`. Fuera de Unix se mata directamente.
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn terminate(child: &mut Child, name: &str, pid: u32, grace: Duration) {
+    #[cfg(unix)]
+    {
+        // SAFETY: `kill(2)` solo envía una señal; el hijo sigue sin recoger, así que el PID es suyo.
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+            match tokio::time::timeout(grace, child.wait()).await {
+                Ok(_) => return,
+                Err(_) => warn!(
+                    "[Launcher] '{}' no terminó {}s después de SIGTERM; se fuerza su parada.",
+                    name,
+                    grace.as_secs()
+                ),
+            }
+        }
+    }
+    if let Err(e) = child.kill().await {
+        error!("[Launcher] No se pudo detener al agente '{}': {}", name, e);
+    }
+}
+
+/// Lee y valida `config.toml`: los nombres de los agentes habilitados deben ser únicos.
+fn load_config(path: &Path) -> Result<LauncherConfig> {
+    let config_str = std::fs::read_to_string(path)
+        .context(format!("No se pudo encontrar o leer '{}'", path.display()))?;
+    let config: LauncherConfig = toml::from_str(&config_str)
+        .context(format!("Error al parsear '{}'", path.display()))?;
+    let mut seen = std::collections::BTreeSet::new();
+    for agent in config.agents.iter().filter(|a| a.enabled) {
+        if !seen.insert(agent.name.as_str()) {
+            anyhow::bail!("Nombre de agente duplicado en '{}': '{}'", path.display(), agent.name);
+        }
+    }
+    Ok(config)
+}
+
+fn config_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Aplica un `config.toml` nuevo: para los agentes retirados o modificados y arranca
+/// los nuevos o modificados; los que no cambian siguen en marcha. Si falta algún
+/// binario no se aplica nada (la recarga no recompila).
+async fn reload_agents(
+    new_config: LauncherConfig,
+    desired: &mut BTreeMap<String, AgentConfig>,
+    agents: &mut Vec<ManagedAgent>,
+    bin_path: &Path,
+    tx: &mpsc::Sender<(u32, AgentConfig, String)>,
+    fleet: &FleetState,
+) -> Result<()> {
+    let new_set: BTreeMap<String, AgentConfig> = new_config
+        .agents
+        .into_iter()
+        .filter(|a| a.enabled)
+        .map(|a| (a.name.clone(), a))
+        .collect();
+    let to_start: Vec<AgentConfig> = new_set
+        .values()
+        .filter(|cfg| desired.get(&cfg.name) != Some(*cfg))
+        .cloned()
+        .collect();
+    let missing: Vec<String> = to_start
+        .iter()
+        .map(|a| bin_path.join(format!("{}{}", a.bin, std::env::consts::EXE_SUFFIX)))
+        .filter(|p| !p.is_file())
+        .map(|p| p.display().to_string())
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("Faltan binarios (compile antes de recargar): {}", missing.join(", "));
+    }
+
+    for agent in agents.iter_mut() {
+        if new_set.get(&agent.config.name) != Some(&agent.config) {
+            info!("[Launcher] Recarga: deteniendo al agente '{}'", agent.name());
+            agent.stop("detenido por recarga de configuración");
+        }
+    }
+    *desired = new_set;
+    for agent_config in to_start {
+        info!("[Launcher] Recarga: iniciando al agente '{}'", agent_config.name);
+        let mut agent = spawn_agent(agent_config, bin_path, tx.clone()).await?;
+        record_start(fleet, &mut agent).await;
+        agents.push(agent);
+    }
+    Ok(())
+}
+
+/// Estado vivo de un agente, para el snapshot de `launcher.status.request`.
+struct AgentState {
+    bin: String,
+    pid: Option<u32>,
+    started: Instant,
+    restarts: u32,
+    last_exit: Option<String>,
+    stdin: Option<ChildStdin>,
+}
+
+/// Orden para escribir una línea en la entrada estándar de un agente.
+#[derive(Deserialize, Debug)]
+struct StdinCommand {
+    agent: String,
+    line: String,
+}
+
+/// Estado de todos los agentes gestionados, indexado por nombre.
+type FleetState = Arc<Mutex<BTreeMap<String, AgentState>>>;
+
+#[derive(Serialize, Debug)]
+struct AgentStatus {
+    name: String,
+    bin: String,
+    running: bool,
+    pid: Option<u32>,
+    uptime_secs: Option<u64>,
+    restarts: u32,
+    last_exit: Option<String>,
+}
+
+/// Registra el arranque (o reinicio) de un agente en el estado compartido.
+async fn record_start(fleet: &FleetState, agent: &mut ManagedAgent) {
+    let mut fleet = fleet.lock().await;
+    let entry = fleet.entry(agent.config.name.clone()).or_insert_with(|| AgentState {
+        bin: agent.config.bin.clone(),
+        pid: None,
+        started: Instant::now(),
+        restarts: 0,
+        last_exit: None,
+        stdin: None,
+    });
+    if entry.pid.is_some() || entry.last_exit.is_some() {
+        entry.restarts += 1;
+    }
+    entry.pid = Some(agent.id);
+    entry.started = Instant::now();
+    entry.stdin = agent.stdin.take();
+}
+
+/// Escribe `line` en la entrada estándar del agente. Si el hijo la cerró, se descarta la tubería.
+async fn write_stdin(fleet: &FleetState, cmd: StdinCommand) -> Result<()> {
+    let mut fleet = fleet.lock().await;
+    let st = fleet
+        .get_mut(&cmd.agent)
+        .ok_or_else(|| AgentError::new(ErrorCode::NotFound, format!("Agente desconocido: '{}'", cmd.agent)))?;
+    let stdin = st.stdin.as_mut().ok_or_else(|| {
+        AgentError::new(ErrorCode::Unavailable, format!("El agente '{}' no tiene stdin disponible", cmd.agent))
+    })?;
+    let written = async {
+        stdin.write_all(cmd.line.trim_end_matches('\n').as_bytes()).await?;
+        stdin.write_all(b"\n").await?;
+        stdin.flush().await
+    }
+    .await;
+    if let Err(e) = written {
+        st.stdin = None;
+        anyhow::bail!("El agente '{}' cerró su stdin: {}", cmd.agent, e);
+    }
+    Ok(())
+}
+
+async fn fleet_snapshot(fleet: &FleetState) -> Vec<AgentStatus> {
+    fleet
+        .lock()
+        .await
+        .iter()
+        .map(|(name, st)| AgentStatus {
+            name: name.clone(),
+            bin: st.bin.clone(),
+            running: st.pid.is_some(),
+            pid: st.pid,
+            uptime_secs: st.pid.map(|_| st.started.elapsed().as_secs()),
+            restarts: st.restarts,
+            last_exit: st.last_exit.clone(),
+        })
+        .collect()
+}
+
+/// Responde en `launcher.status.request` con el snapshot de los agentes y atiende
+/// `launcher.agent.stdin` (`{"agent": "...", "line": "..."}`).
+/// Es opcional (`LAUNCHER_NATS=1`): sin NATS el lanzador funciona igual.
+async fn serve_control(fleet: FleetState) {
+    let client = match connect_to_nats().await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("[Launcher] Sin NATS; no se publicará el estado de los agentes: {:#}", e);
+            return;
+        }
+    };
+    let mut sub = match client.subscribe("launcher.status.request").await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("[Launcher] No se pudo suscribir a 'launcher.status.request': {}", e);
+            return;
+        }
+    };
+    let mut stdin_sub = match client.subscribe("launcher.agent.stdin").await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("[Launcher] No se pudo suscribir a 'launcher.agent.stdin': {}", e);
+            return;
+        }
+    };
+    spawn_discovery(&client, "Agent Launcher", &["launcher.status.request", "launcher.agent.stdin"]);
+    info!("[Launcher] Escuchando en 'launcher.status.request' y 'launcher.agent.stdin'.");
+    loop {
+        tokio::select! {
+            msg = sub.next() => {
+                let Some(msg) = msg else {
+                    match resubscribe(&client, "Launcher", "launcher.status.request", None).await {
+                        Ok(s) => sub = s,
+                        Err(e) => {
+                            warn!("[Launcher] {:#}", e);
+                            return;
+                        }
+                    }
+                    continue;
+                };
+                if let Some(reply) = msg.reply {
+                    let resp = AgentResponse::Success(fleet_snapshot(&fleet).await);
+                    if let Ok(payload) = serde_json::to_vec(&resp) {
+                        client.publish(reply, payload.into()).await.ok();
+                    }
+                }
+            }
+            msg = stdin_sub.next() => {
+                let Some(msg) = msg else {
+                    match resubscribe(&client, "Launcher", "launcher.agent.stdin", None).await {
+                        Ok(s) => stdin_sub = s,
+                        Err(e) => {
+                            warn!("[Launcher] {:#}", e);
+                            return;
+                        }
+                    }
+                    continue;
+                };
+                let resp: AgentResponse<()> = match serde_json::from_slice::<StdinCommand>(&msg.payload) {
+                    Ok(cmd) => match write_stdin(&fleet, cmd).await {
+                        Ok(()) => AgentResponse::Success(()),
+                        Err(e) => {
+                            warn!("[Launcher] {}", e);
+                            AgentResponse::failed(&e)
+                        }
+                    },
+                    Err(e) => AgentResponse::error(ErrorCode::InvalidRequest, format!("Orden de stdin malformada: {}", e)),
+                };
+                if let (Some(reply), Ok(payload)) = (msg.reply, serde_json::to_vec(&resp)) {
+                    client.publish(reply, payload.into()).await.ok();
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
@@ -54,28 +357,56 @@ async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
 
     info!("Iniciando Agent Launcher...");
-    let config_str = std::fs::read_to_string("config.toml")
-        .context("No se pudo encontrar o leer 'config.toml'")?;
-    let config: LauncherConfig = toml::from_str(&config_str)
-        .context("Error al parsear 'config.toml'")?;
+    let config_path = Path::new(CONFIG_PATH);
+    let mut config_mtime = config_modified(config_path);
+    let config = load_config(config_path)?;
 
-    info!("Compilando agentes en perfil '{}'...", config.build_profile);
-    let build_status = Command::new("cargo")
-        .arg("build")
-        .args(if config.build_profile == "release" { vec!["--release"] } else { vec![] })
-        .status()
-        .await?;
+    let bin_path = Path::new("target").join(&config.build_profile);
+    let skip_build = config.skip_build || std::env::args().any(|a| a == "--skip-build");
 
-    if !build_status.success() {
-        anyhow::bail!("La compilación de los agentes ha fallado. Abortando.");
-    }
+    if skip_build {
+        info!("Compilación omitida; se usan los binarios de {:?}.", bin_path);
+        let missing: Vec<String> = config
+            .agents
+            .iter()
+            .filter(|a| a.enabled)
+            .map(|a| bin_path.join(format!("{}{}", a.bin, std::env::consts::EXE_SUFFIX)))
+            .filter(|p| !p.is_file())
+            .map(|p| p.display().to_string())
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "Faltan binarios precompilados (skip_build activo): {}",
+                missing.join(", ")
+            );
+        }
+    } else {
+        info!("Compilando agentes en perfil '{}'...", config.build_profile);
+        let build_status = Command::new("cargo")
+            .arg("build")
+            .args(if config.build_profile == "release" { vec!["--release"] } else { vec![] })
+            .status()
+            .await
+            .context("No se pudo ejecutar 'cargo build' (¿falta cargo? use skip_build o --skip-build)")?;
 
-    let bin_path = Path::new("target").join(&config.build_profile);
-    let (tx, mut rx) = mpsc::channel::<(u32, AgentConfig)>(100);
+        if !build_status.success() {
+            anyhow::bail!("La compilación de los agentes ha fallado. Abortando.");
+        }
+    }
+    let (tx, mut rx) = mpsc::channel::<(u32, AgentConfig, String)>(100);
+    let fleet: FleetState = Arc::new(Mutex::new(BTreeMap::new()));
+    if matches!(std::env::var("LAUNCHER_NATS").as_deref(), Ok("1") | Ok("true")) {
+        tokio::spawn(serve_control(Arc::clone(&fleet)));
+    }
 
+    let build_profile = config.build_profile.clone();
+    // Conjunto de agentes que la configuración vigente quiere en marcha, por nombre.
+    let mut desired: BTreeMap<String, AgentConfig> = BTreeMap::new();
     let mut agents = Vec::new();
     for agent_config in config.agents.into_iter().filter(|a| a.enabled) {
-        let agent = spawn_agent(agent_config, &bin_path, tx.clone()).await?;
+        desired.insert(agent_config.name.clone(), agent_config.clone());
+        let mut agent = spawn_agent(agent_config, &bin_path, tx.clone()).await?;
+        record_start(&fleet, &mut agent).await;
         agents.push(agent);
     }
     
@@ -85,6 +416,8 @@ async fn main() -> Result<()> {
     }
 
     info!("Todos los agentes habilitados han sido iniciados. Presione Ctrl+C para detenerlos.");
+    info!("[Launcher] Vigilando '{}' para recargar el conjunto de agentes.", CONFIG_PATH);
+    let mut config_poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
 
     loop {
         tokio::select! {
@@ -92,17 +425,57 @@ async fn main() -> Result<()> {
                 info!("Señal de apagado (Ctrl+C) recibida. Terminando todos los agentes...");
                 break;
             },
-            Some((id, config)) = rx.recv() => {
+            _ = config_poll.tick() => {
+                let mtime = config_modified(config_path);
+                if mtime == config_mtime {
+                    continue;
+                }
+                config_mtime = mtime;
+                info!("[Launcher] '{}' ha cambiado; recargando...", CONFIG_PATH);
+                let applied = match load_config(config_path) {
+                    Ok(new_config) => {
+                        if new_config.build_profile != build_profile {
+                            warn!("[Launcher] El cambio de build_profile requiere reiniciar el lanzador; se ignora.");
+                        }
+                        reload_agents(new_config, &mut desired, &mut agents, &bin_path, &tx, &fleet).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match applied {
+                    Ok(()) => info!("[Launcher] Configuración recargada: {} agentes habilitados.", desired.len()),
+                    Err(e) => error!("[Launcher] Recarga descartada, se mantienen los agentes actuales: {:#}", e),
+                }
+            }
+            Some((id, config, exit)) = rx.recv() => {
                 agents.retain(|a| a.id != id);
-                warn!("[Launcher] El agente '{}' (ID: {}) ha terminado.", config.name, id);
-                
-                if config.restart != RestartPolicy::Never {
+                warn!("[Launcher] El agente '{}' (ID: {}) ha terminado: {}.", config.name, id, exit);
+                // Solo una configuración vigente se reinicia; las retiradas o sustituidas en
+                // una recarga ya no cuentan (y el estado puede pertenecer ya al sustituto).
+                let current = desired.get(&config.name) == Some(&config);
+                {
+                    let mut fleet = fleet.lock().await;
+                    if fleet.get(&config.name).is_some_and(|st| st.pid == Some(id)) {
+                        if desired.contains_key(&config.name) {
+                            if let Some(st) = fleet.get_mut(&config.name) {
+                                st.pid = None;
+                                st.stdin = None;
+                                st.last_exit = Some(exit);
+                            }
+                        } else {
+                            fleet.remove(&config.name);
+                        }
+                    }
+                }
+
+                if current && config.restart != RestartPolicy::Never {
                     info!("[Launcher] Aplicando política de reinicio '{:?}' para '{}'", config.restart, config.name);
-                    let new_agent = spawn_agent(config, &bin_path, tx.clone()).await?;
+                    let mut new_agent = spawn_agent(config, &bin_path, tx.clone()).await?;
+                    record_start(&fleet, &mut new_agent).await;
                     agents.push(new_agent);
                 }
 
-                if agents.is_empty() {
+                // Si una recarga retiró todos los agentes se sigue vigilando la configuración.
+                if agents.is_empty() && !desired.is_empty() {
                     info!("Todos los agentes gestionados han terminado. Saliendo.");
                     break;
                 }
@@ -110,13 +483,23 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Apagado: matar procesos aún vivos
+    // Apagado: cada monitor envía SIGTERM a su proceso y avisa cuando ha salido.
+    let mut pending: std::collections::BTreeSet<u32> = agents.iter().map(|a| a.id).collect();
     for agent in &mut agents {
         info!("[Launcher] Deteniendo al agente '{}'...", agent.name());
-        let mut ch = agent.child.lock().await;
-        if let Err(e) = ch.kill().await {
-            error!("[Launcher] No se pudo detener al agente '{}': {}", agent.name(), e);
+        agent.stop("detenido al apagar el lanzador");
+    }
+    let wait_all = async {
+        while !pending.is_empty() {
+            let Some((id, config, exit)) = rx.recv().await else { break };
+            if pending.remove(&id) {
+                info!("[Launcher] El agente '{}' (ID: {}) ha terminado: {}.", config.name, id, exit);
+            }
         }
+    };
+    // Margen sobre el plazo de cada monitor, que ya fuerza la parada al agotarlo.
+    if tokio::time::timeout(stop_grace() + Duration::from_secs(5), wait_all).await.is_err() {
+        error!("[Launcher] {} agentes no confirmaron su parada.", pending.len());
     }
     info!("Agent Launcher finalizado.");
     Ok(())
@@ -124,12 +507,30 @@ async fn main() -> Result<()> {
 
 async fn spawn_agent(
     config: AgentConfig,
-    bin_path: &PathBuf,
-    tx: mpsc::Sender<(u32, AgentConfig)>,
+    bin_path: &Path,
+    tx: mpsc::Sender<(u32, AgentConfig, String)>,
 ) -> Result<ManagedAgent> {
-    let agent_path = bin_path.join(&config.bin);
+    let mut agent_path = bin_path.join(&config.bin);
     let mut command = Command::new(&agent_path);
+    if let Some(cwd) = &config.cwd {
+        if !Path::new(cwd).is_dir() {
+            anyhow::bail!("El directorio de trabajo '{}' del agente '{}' no existe", cwd, config.name);
+        }
+        // Con otro directorio de trabajo, una ruta relativa al binario dejaría de resolverse.
+        if let Ok(absolute) = std::fs::canonicalize(&agent_path) {
+            agent_path = absolute;
+            command = Command::new(&agent_path);
+        }
+        command.current_dir(cwd);
+    }
+    command.args(&config.args);
     command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    if config.stdin {
+        command.stdin(Stdio::piped());
+    }
+    if let Some(level) = &config.log_level {
+        command.env("RUST_LOG", level);
+    }
 
     // Spawn del proceso hijo
     let mut child = command.spawn().context(format!(
@@ -140,6 +541,7 @@ async fn spawn_agent(
     // Tomamos stdout/stderr ANTES de envolver el Child
     let stdout = child.stdout.take().expect("stdout no fue capturado");
     let stderr = child.stderr.take().expect("stderr no fue capturado");
+    let stdin = child.stdin.take();
 
     let id = child.id().expect("El proceso hijo debe tener un ID");
     info!(
@@ -165,26 +567,30 @@ async fn spawn_agent(
         }
     });
 
-    // Envolver Child para compartirlo: monitor + manejador
-    let child_arc = Arc::new(Mutex::new(child));
-
-    // Monitor de salida del proceso: hará wait() y notificará
+    // Monitor de salida del proceso: es dueño del Child, hará wait() y notificará
     let monitor_config = config.clone();
-    let child_for_monitor = Arc::clone(&child_arc);
+    let (kill_tx, mut kill_rx) = oneshot::channel::<&'static str>();
     tokio::spawn(async move {
-        // Espera a que el proceso termine
-        {
-            let mut ch = child_for_monitor.lock().await;
-            let _ = ch.wait().await;
-        }
-        if tx.send((id, monitor_config)).await.is_err() {
+        // Espera a que el proceso termine (o a que se pida detenerlo)
+        let exit = tokio::select! {
+            status = child.wait() => match status {
+                Ok(status) => status.to_string(),
+                Err(e) => format!("estado desconocido ({})", e),
+            },
+            Ok(reason) = &mut kill_rx => {
+                terminate(&mut child, &monitor_config.name, id, stop_grace()).await;
+                reason.to_string()
+            }
+        };
+        if tx.send((id, monitor_config, exit)).await.is_err() {
             error!("[Launcher] El canal de comunicación del lanzador está cerrado.");
         }
     });
     
     Ok(ManagedAgent {
         config,
-        child: child_arc,
         id,
+        stdin,
+        kill: Some(kill_tx),
     })
 }
@@ -0,0 +1,179 @@
+// src/compression.rs
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Por debajo de este tamaño no compensa comprimir.
+pub const COMPRESSION_THRESHOLD_BYTES: usize = 64 * 1024;
+
+/// Tope del contenido descomprimido de un `.gz`, para no reventar la memoria con una bomba de compresión.
+pub const MAX_GUNZIP_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Bytes mágicos de la cabecera gzip.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Lee `TRANSPARENT_GZIP`; solo "0"/"false" desactivan la descompresión transparente.
+pub fn transparent_gzip_from_env() -> bool {
+    !matches!(
+        std::env::var("TRANSPARENT_GZIP").as_deref(),
+        Ok("0") | Ok("false")
+    )
+}
+
+/// `true` si el archivo es gzip, por extensión `.gz` o por sus bytes mágicos.
+pub fn is_gzip(path: &Path, bytes: &[u8]) -> bool {
+    bytes.starts_with(&GZIP_MAGIC)
+        || path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("gz"))
+}
+
+/// Descomprime gzip leyendo como mucho `limit` bytes; más allá se trunca y se devuelve `true`.
+pub fn gunzip_limited(reader: impl Read, limit: u64) -> Result<(Vec<u8>, bool)> {
+    let mut out = Vec::new();
+    flate2::read::MultiGzDecoder::new(reader)
+        .take(limit + 1)
+        .read_to_end(&mut out)
+        .context("Contenido gzip inválido")?;
+    let truncated = out.len() as u64 > limit;
+    out.truncate(limit as usize);
+    Ok((out, truncated))
+}
+
+/// Devuelve el contenido legible de un archivo: si `enabled` y es gzip, descomprimido
+/// (falla si supera `MAX_GUNZIP_BYTES`); si no, los bytes tal cual.
+pub fn maybe_gunzip(path: &Path, bytes: Vec<u8>, enabled: bool) -> Result<Vec<u8>> {
+    if !enabled || !is_gzip(path, &bytes) {
+        return Ok(bytes);
+    }
+    let (out, truncated) = gunzip_limited(&bytes[..], MAX_GUNZIP_BYTES)?;
+    if truncated {
+        anyhow::bail!(
+            "El contenido descomprimido supera {} MB",
+            MAX_GUNZIP_BYTES / (1024 * 1024)
+        );
+    }
+    Ok(out)
+}
+
+/// Algoritmo de compresión, elegido con la variable `CONTENT_COMPRESSION`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// Lee `CONTENT_COMPRESSION` ("gzip" | "zstd"); gzip por defecto.
+    pub fn from_env() -> Self {
+        match std::env::var("CONTENT_COMPRESSION").as_deref() {
+            Ok("zstd") => Codec::Zstd,
+            _ => Codec::Gzip,
+        }
+    }
+}
+
+/// Contenido de un archivo tal como viaja por `file.request.content` cuando se pide `compress`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileContent {
+    /// Si es `true`, `data` es el contenido comprimido con `codec` y codificado en base64.
+    pub compressed: bool,
+    #[serde(default)]
+    pub codec: Option<Codec>,
+    pub data: String,
+}
+
+impl FileContent {
+    /// Comprime `text` con `codec` si supera el umbral; si no, lo deja tal cual.
+    pub fn encode(text: String, codec: Codec) -> Result<Self> {
+        if text.len() < COMPRESSION_THRESHOLD_BYTES {
+            return Ok(Self {
+                compressed: false,
+                codec: None,
+                data: text,
+            });
+        }
+        let bytes = match codec {
+            Codec::Gzip => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(text.as_bytes())?;
+                enc.finish()?
+            }
+            Codec::Zstd => zstd::encode_all(text.as_bytes(), 0)?,
+        };
+        Ok(Self {
+            compressed: true,
+            codec: Some(codec),
+            data: BASE64.encode(bytes),
+        })
+    }
+
+    /// Devuelve el texto original, descomprimiendo si hace falta.
+    pub fn decode(self) -> Result<String> {
+        if !self.compressed {
+            return Ok(self.data);
+        }
+        let bytes = BASE64
+            .decode(&self.data)
+            .context("Contenido base64 inválido")?;
+        let raw = match self.codec.context("Contenido comprimido sin códec")? {
+            Codec::Gzip => {
+                let mut out = Vec::new();
+                flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+                out
+            }
+            Codec::Zstd => zstd::decode_all(&bytes[..])?,
+        };
+        String::from_utf8(raw).context("El contenido descomprimido no es UTF-8")
+    }
+}
+
+/// Pide a `file.request.content` el archivo `path` con compresión (`compress`) y devuelve
+/// el texto ya descomprimido. Las respuestas troceadas se reensamblan por `request_json_chunked`.
+pub async fn request_file_content(
+    client: &async_nats::Client,
+    path: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let request = crate::ProcessFileRequest {
+        path: path.to_string(),
+        compress: true,
+        ..Default::default()
+    };
+    let content: FileContent =
+        crate::request_json_chunked(client, "file.request.content", &request, timeout).await?;
+    content.decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_content_travels_uncompressed() {
+        let content = FileContent::encode("hola".to_string(), Codec::Gzip).unwrap();
+        assert!(!content.compressed);
+        assert_eq!(content.decode().unwrap(), "hola");
+    }
+
+    #[test]
+    fn large_content_round_trips_with_each_codec() {
+        let text = "línea de prueba\n".repeat(COMPRESSION_THRESHOLD_BYTES / 8);
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let content = FileContent::encode(text.clone(), codec).unwrap();
+            assert!(content.compressed);
+            assert!(content.data.len() < text.len());
+            // Viaja como JSON: se comprueba también la (de)serialización.
+            let json = serde_json::to_string(&content).unwrap();
+            let back: FileContent = serde_json::from_str(&json).unwrap();
+            assert_eq!(back.decode().unwrap(), text);
+        }
+    }
+}
@@ -1,22 +1,309 @@
 // src/lib.rs
 
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::env;
-use std::time::SystemTime;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Notify;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 // Módulo para el protocolo de agentes externos
 pub mod mcp_protocol;
+// Compresión opcional del contenido de archivos enviado por NATS
+pub mod compression;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub enum FileType { File, Directory }
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileDiscovered {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProcessFileRequest {
+    pub path: String,
+    /// Si es `true`, el extractor de metadatos calcula además líneas/palabras/caracteres.
+    #[serde(default)]
+    pub text_stats: bool,
+    /// Si es `true`, el extractor de metadatos calcula el SHA-256 del contenido.
+    #[serde(default)]
+    pub hash: bool,
+    /// Si es `true`, la respuesta va envuelta en un `AgentEnvelope` con datos de tiempo.
+    #[serde(default)]
+    pub envelope: bool,
+    /// Si es `true`, el explorador puede comprimir el contenido (ver `compression::FileContent`).
+    #[serde(default)]
+    pub compress: bool,
+    /// Identificador opcional; si está, el summarizer publica su progreso en `progress_subject(id)`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Longitud objetivo del resumen; `None` mantiene el resumen "conciso" por defecto.
+    #[serde(default)]
+    pub target_length: Option<SummaryLength>,
+    /// Prompt de sistema que sustituye al elegido según el tipo de contenido.
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Formato de salida con nombre (ver `SUMMARY_STYLES`); `None` deja prosa libre.
+    #[serde(default)]
+    pub style: Option<String>,
+    /// Descomprime los `.gz` antes de leerlos; `None` usa `TRANSPARENT_GZIP` (activo por defecto).
+    #[serde(default)]
+    pub decompress: Option<bool>,
+    /// Modelo del resumen ("proveedor:modelo" o solo el nombre); `None` usa `SUMMARIZER_MODEL`.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Temperatura del resumen; `None` usa `SUMMARIZER_TEMPERATURE` (0.7 por defecto).
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Si está, el summarizer guarda la conversación (archivo + resumen) con este id para
+    /// continuarla con `summary.followup.request`.
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+/// Pregunta sobre un archivo ya resumido (`summary.followup.request`); la respuesta es el
+/// texto del LLM, que recibe el archivo, el resumen y las preguntas anteriores de la sesión.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FollowupRequest {
+    pub session_id: String,
+    pub question: String,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub envelope: bool,
+}
+
+/// Tipo de contenido deducido de la extensión, para adaptar el prompt de resumen.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Code,
+    Csv,
+    Markdown,
+    Json,
+    Log,
+    Text,
+}
+
+impl ContentKind {
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        // `app.log.gz` se clasifica por la extensión interior.
+        let path = path.as_ref();
+        let inner = path.file_stem().map(Path::new).filter(|_| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.eq_ignore_ascii_case("gz"))
+        });
+        let ext = inner
+            .unwrap_or(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "rs" | "py" | "js" | "ts" | "tsx" | "jsx" | "go" | "java" | "kt" | "c" | "h"
+            | "cpp" | "hpp" | "cs" | "rb" | "php" | "swift" | "scala" | "sh" | "sql" => {
+                ContentKind::Code
+            }
+            "csv" | "tsv" => ContentKind::Csv,
+            "md" | "markdown" => ContentKind::Markdown,
+            "json" | "jsonl" | "yaml" | "yml" | "toml" => ContentKind::Json,
+            "log" => ContentKind::Log,
+            _ => ContentKind::Text,
+        }
+    }
+
+    /// Prompt de sistema recomendado para resumir este tipo de contenido.
+    pub fn system_prompt(self) -> &'static str {
+        match self {
+            ContentKind::Code => "Eres un experto en software. Explica de forma concisa qué hace este código, sus componentes principales y cualquier punto destacable.",
+            ContentKind::Csv => "Eres un analista de datos. Describe las columnas de esta tabla y resume de forma concisa lo que muestran los datos.",
+            ContentKind::Markdown => "Eres un experto en resumir documentos. Resume de forma concisa este documento respetando su estructura de secciones.",
+            ContentKind::Json => "Eres un experto en configuración y datos estructurados. Describe de forma concisa la estructura y el propósito de este contenido.",
+            ContentKind::Log => "Eres un experto en operaciones. Resume de forma concisa este log destacando errores, avisos y eventos relevantes.",
+            ContentKind::Text => "Eres un experto en resumir textos de forma concisa.",
+        }
+    }
+}
+
+/// Longitud objetivo de un resumen, p. ej. `{"words": 150}` o `{"sentences": 5}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryLength {
+    Words(u32),
+    Sentences(u32),
+}
+
+impl SummaryLength {
+    /// Comprueba que el objetivo está en un rango razonable.
+    pub fn validate(self) -> Result<Self> {
+        match self {
+            SummaryLength::Words(n) if !(10..=2000).contains(&n) => {
+                anyhow::bail!(
+                    "target_length.words debe estar entre 10 y 2000 (recibido {})",
+                    n
+                )
+            }
+            SummaryLength::Sentences(n) if !(1..=100).contains(&n) => {
+                anyhow::bail!(
+                    "target_length.sentences debe estar entre 1 y 100 (recibido {})",
+                    n
+                )
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// Instrucción para el prompt de sistema.
+    pub fn instruction(self) -> String {
+        match self {
+            SummaryLength::Words(n) => {
+                format!("El resumen debe tener aproximadamente {} palabras.", n)
+            }
+            SummaryLength::Sentences(n) => {
+                format!("El resumen debe tener como máximo {} frases.", n)
+            }
+        }
+    }
+
+    /// Límite de tokens de salida con margen (≈1,3 tokens por palabra, ≈30 palabras por frase).
+    pub fn max_tokens(self) -> u32 {
+        match self {
+            SummaryLength::Words(n) => n * 2,
+            SummaryLength::Sentences(n) => n * 60,
+        }
+    }
+}
+
+/// Formatos de resumen predefinidos: nombre e instrucción que se añade al prompt de sistema.
+pub const SUMMARY_STYLES: &[(&str, &str)] = &[
+    ("bullets", "Presenta el resumen como una lista de viñetas breves, una idea por viñeta."),
+    ("tldr", "Resume en una o dos frases, al estilo TL;DR, solo lo esencial."),
+    ("abstract", "Redacta el resumen como el abstract de un artículo: objetivo, contenido principal y conclusiones en un único párrafo formal."),
+    ("eli5", "Explica el contenido como si el lector tuviera cinco años, con palabras sencillas y sin tecnicismos."),
+];
+
+/// Instrucción del formato `style`; un nombre desconocido devuelve error con los válidos.
+pub fn style_instruction(style: &str) -> Result<&'static str> {
+    SUMMARY_STYLES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(style.trim()))
+        .map(|(_, instruction)| *instruction)
+        .ok_or_else(|| {
+            let valid: Vec<&str> = SUMMARY_STYLES.iter().map(|(name, _)| *name).collect();
+            anyhow!(
+                "Estilo de resumen desconocido '{}'; válidos: {}",
+                style,
+                valid.join(", ")
+            )
+        })
+}
+
+/// Resumen conjunto de varios archivos (`summary.multi.request`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultiSummaryRequest {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub envelope: bool,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
 
+/// Resumen por separado de cada archivo de una lista (`summary.batch.request`).
+/// Con `request_id` se publica el avance archivo a archivo en `progress_subject(id)`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BatchSummaryRequest {
+    pub paths: Vec<String>,
+    /// Archivos resumidos a la vez; se acota al máximo del summarizer.
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+    #[serde(default)]
+    pub style: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub envelope: bool,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Resultado de un archivo del lote: su resumen o el error que lo impidió.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct FileDiscovered { pub name: String, pub path: String }
+pub struct BatchSummaryItem {
+    pub path: String,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub error: Option<AgentError>,
+}
 
+/// Informe final de `summary.batch.request`, con un elemento por ruta en el orden pedido.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BatchSummaryReport {
+    pub items: Vec<BatchSummaryItem>,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub elapsed_ms: u64,
+}
+
+/// Aviso de progreso (best-effort) de una operación larga.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ProcessFileRequest { pub path: String }
+pub struct ProgressUpdate {
+    pub message: String,
+    #[serde(default)]
+    pub step: Option<u32>,
+    #[serde(default)]
+    pub total: Option<u32>,
+}
+
+/// Subject donde se publica el progreso de la petición `request_id`.
+pub fn progress_subject(request_id: &str) -> String {
+    format!("summary.progress.{}", request_id)
+}
+
+/// Petición de descarga de un modelo en Ollama (`llm.ollama.pull`).
+/// Con `request_id`, el Gateway publica el avance en `progress_subject(id)`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OllamaPullRequest {
+    pub model: String,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Lectura parcial de un archivo (`file.request.range`). Si se indica `tail_bytes`
+/// se devuelven los últimos N bytes; si no, `length` bytes desde `offset` (0 por defecto).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileRangeRequest {
+    pub path: String,
+    #[serde(default)]
+    pub offset: Option<u64>,
+    #[serde(default)]
+    pub length: Option<u64>,
+    #[serde(default)]
+    pub tail_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileRangeResponse {
+    /// Posición real de inicio del fragmento devuelto.
+    pub offset: u64,
+    /// Tamaño total del archivo, para que el cliente pueda paginar.
+    pub total_len: u64,
+    /// Fragmento leído (UTF-8 con reemplazo si el corte parte un carácter).
+    pub content: String,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
@@ -24,16 +311,430 @@ pub struct FileMetadata {
     pub len_bytes: u64,
     pub created: Option<SystemTime>,
     pub modified: Option<SystemTime>,
+    /// Estadísticas de texto: solo para archivos UTF-8 y si se pidieron con `text_stats`.
+    #[serde(default)]
+    pub line_count: Option<u64>,
+    #[serde(default)]
+    pub word_count: Option<u64>,
+    #[serde(default)]
+    pub char_count: Option<u64>,
+    /// SHA-256 en hexadecimal, si se pidió con `hash`.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// La ruta pedida es un enlace simbólico; tamaño, fechas y estadísticas son los del destino.
+    #[serde(default)]
+    pub is_symlink: bool,
+    /// Destino del enlace tal como está escrito (puede ser relativo).
+    #[serde(default)]
+    pub symlink_target: Option<String>,
+}
+
+/// Metadatos de todos los archivos de un directorio (`metadata.dir.request`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirMetadataRequest {
+    pub directory: String,
+    #[serde(default)]
+    pub hash: bool,
+}
+
+/// Una fila del manifiesto de `metadata.dir.request`: metadatos o el error de ese archivo.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirMetadataEntry {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileListRequest {
+    /// Directorio a listar. Si es `None`, el explorador usa `DIRECTORY_TO_SCAN`.
+    #[serde(default)]
+    pub directory: Option<String>,
+    /// Si es `true`, se recorren también los subdirectorios (sin seguir enlaces simbólicos).
+    #[serde(default)]
+    pub recursive: bool,
 }
 
+/// Petición de composición de un directorio por extensión (`dir.summary.request`).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirSummaryRequest {
+    /// Directorio a analizar. Si es `None`, el explorador usa `DIRECTORY_TO_SCAN`.
+    #[serde(default)]
+    pub directory: Option<String>,
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+/// Número de archivos y bytes totales de una extensión.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct ExtensionStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Composición de un directorio: estadísticas por extensión (en minúsculas;
+/// `""` agrupa los archivos sin extensión) y rutas omitidas.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirSummary {
+    pub extensions: std::collections::BTreeMap<String, ExtensionStats>,
+    pub total_files: u64,
+    pub total_bytes: u64,
+    #[serde(default)]
+    pub errors: Vec<ScanError>,
+}
+
+/// Ruta que un escaneo no pudo leer y el motivo.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct FileListRequest;
+pub struct ScanError {
+    pub path: String,
+    pub reason: String,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct FileListResponse { pub files: Vec<FileDiscovered> }
+pub struct FileListResponse {
+    pub files: Vec<FileDiscovered>,
+    /// Subárboles o entradas omitidos (p. ej. por permisos); el resto del listado es válido.
+    #[serde(default)]
+    pub errors: Vec<ScanError>,
+}
+
+/// Búsqueda en el contenido de los archivos de un directorio (`files.search.request`).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileSearchRequest {
+    /// Directorio donde buscar. Si es `None`, el explorador usa `DIRECTORY_TO_SCAN`.
+    #[serde(default)]
+    pub directory: Option<String>,
+    /// Texto literal o, con `regex`, expresión regular.
+    pub query: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default)]
+    pub recursive: bool,
+    /// Máximo de líneas coincidentes en total; `None` usa el límite del explorador.
+    #[serde(default)]
+    pub max_results: Option<usize>,
+}
+
+/// Una línea coincidente: número (desde 1) y el texto de la línea, recortado.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LineMatch {
+    pub line: u64,
+    pub snippet: String,
+}
+
+/// Archivo con coincidencias y sus líneas.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMatches {
+    pub name: String,
+    pub path: String,
+    pub lines: Vec<LineMatch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileSearchResponse {
+    pub files: Vec<FileMatches>,
+    /// Archivos de texto examinados (los binarios y los demasiado grandes no cuentan).
+    pub files_scanned: u64,
+    /// `true` si se alcanzó `max_results` y puede haber más coincidencias.
+    pub truncated: bool,
+    #[serde(default)]
+    pub errors: Vec<ScanError>,
+}
+
+/// Tráfico servido por el explorador en un subject: totales desde el arranque y
+/// valores de la ventana deslizante (`window_secs`).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubjectMetrics {
+    pub requests: u64,
+    pub bytes: u64,
+    pub window_requests: u64,
+    pub window_bytes: u64,
+    /// Tamaño medio de respuesta en la ventana (bytes).
+    pub window_avg_bytes: u64,
+    /// Peticiones por segundo en la ventana.
+    pub window_rate: f64,
+}
+
+/// Métricas del explorador por subject (respuesta de `EXPLORER_METRICS_SUBJECT`).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExplorerMetrics {
+    pub window_secs: u64,
+    pub uptime_secs: u64,
+    pub subjects: std::collections::BTreeMap<String, SubjectMetrics>,
+}
+
+/// Versión del formato de `AgentResponse` en el cable. Se sube solo con cambios
+/// incompatibles; los lectores rechazan versiones mayores que la suya.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Respuesta de un agente. Se serializa como `{"version": 1, "Success": ...}` o
+/// `{"version": 1, "Error": {...}}`; al leer se acepta también el formato sin
+/// `version` de los agentes anteriores. Con `AGENT_RESPONSE_LEGACY=1` se escribe
+/// sin versión, para clientes antiguos que no toleran la clave extra.
+#[derive(Debug, Clone)]
+pub enum AgentResponse<T> {
+    Success(T),
+    Error(AgentError),
+}
+
+/// `true` si `AGENT_RESPONSE_LEGACY` pide el formato sin versión (se lee una vez).
+fn legacy_wire_format() -> bool {
+    static LEGACY: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *LEGACY.get_or_init(|| {
+        env::var("AGENT_RESPONSE_LEGACY").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+    })
+}
+
+impl<T: Serialize> Serialize for AgentResponse<T> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let legacy = legacy_wire_format();
+        let mut map = serializer.serialize_map(Some(if legacy { 1 } else { 2 }))?;
+        if !legacy {
+            map.serialize_entry("version", &PROTOCOL_VERSION)?;
+        }
+        match self {
+            AgentResponse::Success(value) => map.serialize_entry("Success", value)?,
+            AgentResponse::Error(err) => map.serialize_entry("Error", err)?,
+        }
+        map.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for AgentResponse<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        use serde::de::{Error, IgnoredAny, MapAccess, Visitor};
+
+        struct ResponseVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for ResponseVisitor<T> {
+            type Value = AgentResponse<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("un objeto con \"Success\" o \"Error\"")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut result = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "version" => {
+                            let version: u32 = map.next_value()?;
+                            if version > PROTOCOL_VERSION {
+                                return Err(A::Error::custom(format!(
+                                    "versión de protocolo {} no soportada (máxima {})",
+                                    version, PROTOCOL_VERSION
+                                )));
+                            }
+                        }
+                        "Success" if result.is_none() => {
+                            result = Some(AgentResponse::Success(map.next_value()?))
+                        }
+                        "Error" if result.is_none() => {
+                            result = Some(AgentResponse::Error(map.next_value()?))
+                        }
+                        "Success" | "Error" => {
+                            return Err(A::Error::custom(
+                                "respuesta con \"Success\" y \"Error\" a la vez",
+                            ))
+                        }
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                result.ok_or_else(|| A::Error::custom("falta \"Success\" o \"Error\""))
+            }
+        }
+
+        deserializer.deserialize_map(ResponseVisitor(std::marker::PhantomData))
+    }
+}
+
+impl<T> AgentResponse<T> {
+    /// Error con código explícito.
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        AgentResponse::Error(AgentError::new(code, message))
+    }
+
+    /// Error a partir de un `anyhow::Error`, con el código deducido por `ErrorCode::classify`.
+    pub fn failed(err: &anyhow::Error) -> Self {
+        AgentResponse::Error(AgentError::from_anyhow(err))
+    }
+}
+
+/// Causa de un error de agente, legible por máquina.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// La ruta o el recurso pedido no existe.
+    NotFound,
+    /// Ruta fuera de las raíces permitidas o sin permisos de lectura.
+    AccessDenied,
+    /// Petición malformada o con parámetros no válidos.
+    InvalidRequest,
+    /// Se agotó un plazo (agente, gateway o proveedor).
+    Timeout,
+    /// El agente o el proveedor no está disponible (sin respuesta, conexión rechazada, 5xx).
+    Unavailable,
+    /// Cola llena o límite de peticiones del proveedor (429).
+    Busy,
+    /// El modelo no está descargado en Ollama (ver `MODEL_NOT_PULLED`).
+    ModelNotPulled,
+    /// El proveedor LLM rechazó la petición.
+    Provider,
+    /// La entrada supera el tamaño máximo que acepta el agente.
+    TooLarge,
+    /// Cualquier otro fallo.
+    Internal,
+}
+
+impl ErrorCode {
+    /// `true` si reintentar la misma petición más tarde puede funcionar.
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Unavailable | ErrorCode::Busy
+        )
+    }
+
+    /// Código para una respuesta HTTP no exitosa de un proveedor.
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            408 | 504 => ErrorCode::Timeout,
+            429 => ErrorCode::Busy,
+            500..=599 => ErrorCode::Unavailable,
+            _ => ErrorCode::Provider,
+        }
+    }
+
+    /// Deduce el código de un error recorriendo su cadena de causas.
+    pub fn classify(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<AgentError>() {
+                return e.code;
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                match e.kind() {
+                    std::io::ErrorKind::NotFound => return ErrorCode::NotFound,
+                    std::io::ErrorKind::PermissionDenied => return ErrorCode::AccessDenied,
+                    std::io::ErrorKind::TimedOut => return ErrorCode::Timeout,
+                    _ => {}
+                }
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return ErrorCode::Timeout;
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                if e.is_timeout() {
+                    return ErrorCode::Timeout;
+                }
+                if e.is_connect() {
+                    return ErrorCode::Unavailable;
+                }
+                if let Some(status) = e.status() {
+                    return ErrorCode::from_http_status(status.as_u16());
+                }
+            }
+        }
+        let text = err.to_string();
+        if text.contains(ACCESS_DENIED) {
+            ErrorCode::AccessDenied
+        } else if text.contains(MODEL_NOT_PULLED) {
+            ErrorCode::ModelNotPulled
+        } else {
+            ErrorCode::Internal
+        }
+    }
+}
+
+/// Error estructurado de un agente: `code` para los programas, `message` para las personas.
+#[derive(Serialize, Debug, Clone, thiserror::Error)]
+#[error("{message}")]
+pub struct AgentError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl AgentError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Conserva el mensaje completo (con contexto) y deduce el código.
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        Self::new(ErrorCode::classify(err), format!("{:#}", err))
+    }
+}
 
+/// Acepta también el formato anterior (`"Error": "mensaje"`) con código `internal`,
+/// para seguir entendiendo a agentes sin actualizar.
+impl<'de> Deserialize<'de> for AgentError {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Structured { code: ErrorCode, message: String },
+            Legacy(String),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Structured { code, message } => AgentError { code, message },
+            Repr::Legacy(message) => AgentError::new(ErrorCode::Internal, message),
+        })
+    }
+}
+
+/// Respuesta con metadatos de procesamiento, para análisis de rendimiento.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum AgentResponse<T> { Success(T), Error(String) }
+pub struct AgentEnvelope<T> {
+    pub agent_name: String,
+    pub duration_ms: u64,
+    pub timestamp: SystemTime,
+    pub result: AgentResponse<T>,
+}
+
+impl<T> AgentEnvelope<T> {
+    pub fn wrap(agent_name: &str, started: Instant, result: AgentResponse<T>) -> Self {
+        Self {
+            agent_name: agent_name.to_string(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            timestamp: SystemTime::now(),
+            result,
+        }
+    }
+}
+
+/// Serializa la respuesta de un agente, envuelta en `AgentEnvelope` si el cliente lo pidió.
+pub fn encode_response<T: Serialize>(
+    agent_name: &str,
+    started: Instant,
+    envelope: bool,
+    result: AgentResponse<T>,
+) -> serde_json::Result<Vec<u8>> {
+    if envelope {
+        serde_json::to_vec(&AgentEnvelope::wrap(agent_name, started, result))
+    } else {
+        serde_json::to_vec(&result)
+    }
+}
 
 pub fn setup_tracing() {
     tracing_subscriber::registry()
@@ -42,10 +743,831 @@ pub fn setup_tracing() {
         .init();
 }
 
+/// Estado de la conexión a NATS, tal como lo notifica `connect_with_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Se perdió la conexión; el cliente reintenta por su cuenta.
+    Reconnecting,
+    /// Sin cliente: la conexión inicial falló o aún no se ha intentado.
+    Disconnected,
+}
+
+/// Conecta a `url` y llama a `on_change` cada vez que el cliente pierde o recupera la conexión.
+pub async fn connect_with_state<F>(url: &str, on_change: F) -> Result<async_nats::Client>
+where
+    F: Fn(ConnectionState) + Send + Sync + 'static,
+{
+    let on_change = Arc::new(on_change);
+    async_nats::ConnectOptions::new()
+        .event_callback(move |event| {
+            let on_change = Arc::clone(&on_change);
+            async move {
+                match event {
+                    async_nats::Event::Connected => on_change(ConnectionState::Connected),
+                    async_nats::Event::Disconnected => on_change(ConnectionState::Reconnecting),
+                    other => tracing::debug!("[NATS] Evento: {}", other),
+                }
+            }
+        })
+        .connect(url)
+        .await
+        .context(format!("No se pudo conectar a NATS en {}", url))
+}
+
 pub async fn connect_to_nats() -> Result<async_nats::Client> {
-    let nats_url = env::var("NATS_URL").context("La variable de entorno NATS_URL no está definida")?;
-    let client = async_nats::connect(&nats_url)
+    let nats_url =
+        env::var("NATS_URL").context("La variable de entorno NATS_URL no está definida")?;
+    connect_with_state(&nats_url, |state| match state {
+        ConnectionState::Connected => tracing::info!("[NATS] Conexión (re)establecida."),
+        ConnectionState::Reconnecting => tracing::warn!("[NATS] Conexión perdida; reintentando..."),
+        ConnectionState::Disconnected => {}
+    })
+    .await
+}
+
+/// Petición/respuesta JSON sobre NATS con timeout propio.
+/// Serializa `req`, espera la respuesta en `subject` como mucho `timeout`, y desenvuelve
+/// el `AgentResponse<Resp>`: la variante `Error` se convierte en un error de `anyhow`.
+/// Usa el inbox multiplexado del cliente; para respuestas troceadas, `request_json_chunked`.
+pub async fn request_json<Req: Serialize, Resp: DeserializeOwned>(
+    client: &async_nats::Client,
+    subject: &str,
+    req: &Req,
+    timeout: Duration,
+) -> Result<Resp> {
+    let msg = request_with_timeout(client, subject, serde_json::to_vec(req)?, timeout).await?;
+    decode_response(subject, &msg.payload)
+}
+
+/// Como `request_json`, pero reensambla la respuesta si llega troceada por `publish_chunked`
+/// (hoy solo `file.request.content`).
+pub async fn request_json_chunked<Req: Serialize, Resp: DeserializeOwned>(
+    client: &async_nats::Client,
+    subject: &str,
+    req: &Req,
+    timeout: Duration,
+) -> Result<Resp> {
+    let payload = request_chunked(client, subject, serde_json::to_vec(req)?, timeout).await?;
+    decode_response(subject, &payload)
+}
+
+/// Desenvuelve un `AgentResponse<Resp>` recibido en `subject`.
+fn decode_response<Resp: DeserializeOwned>(subject: &str, payload: &[u8]) -> Result<Resp> {
+    let response: AgentResponse<Resp> = serde_json::from_slice(payload)
+        .map_err(|e| anyhow!("Respuesta malformada en '{}': {}", subject, e))?;
+    match response {
+        AgentResponse::Success(resp) => Ok(resp),
+        AgentResponse::Error(e) => Err(e.into()),
+    }
+}
+
+/// Petición NATS con timeout propio y errores legibles: distingue el agente que no
+/// responde a tiempo del que no está escuchando.
+pub async fn request_with_timeout(
+    client: &async_nats::Client,
+    subject: &str,
+    payload: Vec<u8>,
+    timeout: Duration,
+) -> Result<async_nats::Message> {
+    let request = async_nats::Request::new()
+        .payload(payload.into())
+        .timeout(Some(timeout));
+    client
+        .send_request(subject.to_string(), request)
         .await
-        .context(format!("No se pudo conectar a NATS en {}", nats_url))?;
-    Ok(client)
-}
\ No newline at end of file
+        .map_err(|e| match e.kind() {
+            async_nats::RequestErrorKind::TimedOut => AgentError::new(
+                ErrorCode::Timeout,
+                format!(
+                    "Timeout esperando respuesta en '{}' ({}s)",
+                    subject,
+                    timeout.as_secs()
+                ),
+            )
+            .into(),
+            async_nats::RequestErrorKind::NoResponders => AgentError::new(
+                ErrorCode::Unavailable,
+                format!("No hay ningún agente escuchando en '{}'", subject),
+            )
+            .into(),
+            _ => anyhow!("Fallo en la petición a '{}': {}", subject, e),
+        })
+}
+
+/// Normaliza una URL base de proveedor: recorta espacios y barras finales, añade esquema
+/// si falta (`http://` para hosts locales, `https://` en otro caso) y comprueba que se pueda parsear.
+pub fn normalize_base_url(raw: &str) -> Result<String> {
+    let trimmed = raw.trim().trim_end_matches('/');
+    if trimmed.is_empty() {
+        anyhow::bail!("La URL base está vacía");
+    }
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        let local = ["localhost", "127.", "0.0.0.0", "[::1]"]
+            .iter()
+            .any(|h| trimmed.starts_with(h));
+        format!("{}://{}", if local { "http" } else { "https" }, trimmed)
+    };
+    let url = reqwest::Url::parse(&with_scheme)
+        .map_err(|e| anyhow!("URL base inválida '{}': {}", raw.trim(), e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        anyhow::bail!(
+            "URL base inválida '{}': se espera http(s)://host[:puerto]",
+            raw.trim()
+        );
+    }
+    Ok(with_scheme)
+}
+
+/// Cabecera con la posición (0..N) de un fragmento de una respuesta troceada.
+pub const CHUNK_INDEX_HEADER: &str = "Chunk-Index";
+/// Cabecera con el número total de fragmentos de una respuesta troceada.
+pub const CHUNK_TOTAL_HEADER: &str = "Chunk-Total";
+
+/// Margen reservado para cabeceras y protocolo al calcular el tamaño de cada fragmento.
+const CHUNK_MARGIN_BYTES: usize = 4 * 1024;
+
+/// Tamaño máximo de una respuesta reensamblada por `request_chunked`; acota el
+/// `Chunk-Total` que se acepta antes de reservar memoria para los fragmentos.
+const MAX_CHUNKED_RESPONSE_BYTES: usize = 512 * 1024 * 1024;
+
+/// Publica `payload` en `subject`; si supera el `max_payload` del servidor lo trocea en
+/// mensajes consecutivos al mismo subject con las cabeceras `Chunk-Index`/`Chunk-Total`.
+/// El solicitante debe usar `request_chunked` para reensamblarlos.
+pub async fn publish_chunked(
+    client: &async_nats::Client,
+    subject: async_nats::Subject,
+    payload: Vec<u8>,
+) -> Result<()> {
+    let Some(chunks) = split_payload(&payload, server_max_payload(client)) else {
+        client.publish(subject, payload.into()).await?;
+        return Ok(());
+    };
+    let total = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(CHUNK_INDEX_HEADER, i.to_string().as_str());
+        headers.insert(CHUNK_TOTAL_HEADER, total.to_string().as_str());
+        client
+            .publish_with_headers(subject.clone(), headers, chunk.to_vec().into())
+            .await?;
+    }
+    Ok(())
+}
+
+/// `max_payload` anunciado por el servidor (1 MiB si no lo indica).
+fn server_max_payload(client: &async_nats::Client) -> usize {
+    match client.server_info().max_payload {
+        0 => 1024 * 1024,
+        n => n,
+    }
+}
+
+/// Tamaño de cada fragmento para un servidor con `max_payload` `max`.
+fn chunk_size(max: usize) -> usize {
+    max.saturating_sub(CHUNK_MARGIN_BYTES).max(1)
+}
+
+/// Fragmentos en que `publish_chunked` divide `payload` para un servidor con `max_payload`
+/// `max`; `None` si cabe en un único mensaje y se envía sin cabeceras.
+fn split_payload(payload: &[u8], max: usize) -> Option<Vec<&[u8]>> {
+    if payload.len() <= max {
+        return None;
+    }
+    Some(payload.chunks(chunk_size(max)).collect())
+}
+
+/// Reúne los fragmentos de una respuesta troceada, que pueden llegar en cualquier orden.
+struct ChunkAssembler {
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+impl ChunkAssembler {
+    /// Prepara `total` fragmentos de un servidor con `max_payload` `max`. Un `Chunk-Total`
+    /// nulo o que supere `MAX_CHUNKED_RESPONSE_BYTES` se rechaza sin reservar memoria.
+    fn new(total: usize, max: usize) -> Result<Self> {
+        let limit = MAX_CHUNKED_RESPONSE_BYTES.div_ceil(chunk_size(max));
+        if total == 0 || total > limit {
+            return Err(AgentError::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "Respuesta troceada con {} fragmentos (máximo {})",
+                    total, limit
+                ),
+            )
+            .into());
+        }
+        Ok(Self {
+            parts: vec![None; total],
+            received: 0,
+        })
+    }
+
+    /// Guarda el fragmento `index`; devuelve el payload completo cuando llega el último.
+    fn push(&mut self, index: Option<usize>, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let total = self.parts.len();
+        let index = index
+            .filter(|i| *i < total)
+            .context("Fragmento con índice inválido")?;
+        if self.parts[index].replace(data).is_none() {
+            self.received += 1;
+        }
+        if self.received < total {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.parts
+                .iter_mut()
+                .flat_map(|p| p.take().unwrap_or_default())
+                .collect(),
+        ))
+    }
+}
+
+/// Petición con timeout que reensambla las respuestas troceadas por `publish_chunked`.
+/// Devuelve el payload completo; si la respuesta no viene troceada, tal cual. Los errores
+/// de timeout y de "nadie escuchando" son los mismos que los de `request_with_timeout`.
+/// Cada llamada abre y cierra su propia suscripción al inbox, así que solo debe usarse con
+/// subjects cuyas respuestas pueden llegar troceadas; el resto, con `request_with_timeout`.
+pub async fn request_chunked(
+    client: &async_nats::Client,
+    subject: &str,
+    payload: Vec<u8>,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    use futures_util::StreamExt;
+
+    let inbox = client.new_inbox();
+    let mut sub = client.subscribe(inbox.clone()).await?;
+    client
+        .publish_with_reply(subject.to_string(), inbox, payload.into())
+        .await?;
+
+    let header = |msg: &async_nats::Message, name: &str| -> Option<usize> {
+        msg.headers.as_ref()?.get(name)?.as_str().parse().ok()
+    };
+    let collect = async {
+        let first = sub
+            .next()
+            .await
+            .context("Suscripción cerrada antes de recibir respuesta")?;
+        if first.status == Some(async_nats::StatusCode::NO_RESPONDERS) {
+            return Err(AgentError::new(
+                ErrorCode::Unavailable,
+                format!("No hay ningún agente escuchando en '{}'", subject),
+            )
+            .into());
+        }
+        let Some(total) = header(&first, CHUNK_TOTAL_HEADER) else {
+            return Ok(first.payload.to_vec());
+        };
+        let mut assembler = ChunkAssembler::new(total, server_max_payload(client))?;
+        let mut msg = first;
+        loop {
+            if let Some(payload) =
+                assembler.push(header(&msg, CHUNK_INDEX_HEADER), msg.payload.to_vec())?
+            {
+                return Ok(payload);
+            }
+            msg = sub.next().await.context("Respuesta troceada incompleta")?;
+        }
+    };
+    let result = tokio::time::timeout(timeout, collect).await;
+    sub.unsubscribe().await.ok();
+    result.map_err(|_| {
+        AgentError::new(
+            ErrorCode::Timeout,
+            format!(
+                "Timeout esperando respuesta en '{}' ({}s)",
+                subject,
+                timeout.as_secs()
+            ),
+        )
+    })?
+}
+
+/// Plazo por defecto para terminar las tareas en curso al apagar un agente (`AGENT_DRAIN_SECS`).
+const DEFAULT_DRAIN_SECS: u64 = 30;
+
+/// Contador de las tareas que un agente tiene en curso, para esperarlas al apagarse.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    count: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl InFlight {
+    /// Lanza `fut` en su propia tarea, contabilizada hasta que termina (o entra en pánico).
+    pub fn spawn<F>(&self, fut: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard(self.clone());
+        tokio::spawn(async move {
+            let _guard = guard;
+            fut.await
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Espera a que no quede ninguna tarea; devuelve `false` si antes vence `timeout`.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let drained = async {
+            loop {
+                // `notified()` se crea antes de comprobar para no perder el aviso.
+                let notified = self.idle.notified();
+                if self.is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+}
+
+/// Completa al recibir SIGTERM (en Unix) o Ctrl+C.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Ejecuta el bucle de servicio de un agente hasta que termina o llega la señal de apagado.
+/// Al apagarse se suelta `service` (y con él sus suscripciones, así que no entran más
+/// peticiones), se esperan las tareas de `in_flight` hasta `AGENT_DRAIN_SECS` segundos
+/// y se vacía el buffer de NATS para que salgan las últimas respuestas.
+pub async fn run_until_shutdown<F>(
+    agent: &str,
+    client: &async_nats::Client,
+    in_flight: &InFlight,
+    service: F,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    tokio::select! {
+        result = service => return result,
+        _ = shutdown_signal() => {}
+    }
+    let timeout = Duration::from_secs(
+        env::var("AGENT_DRAIN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_DRAIN_SECS),
+    );
+    tracing::info!(
+        "[{}] Apagado solicitado: drenando {} tareas en curso (máx. {}s)...",
+        agent,
+        in_flight.len(),
+        timeout.as_secs()
+    );
+    if !in_flight.wait_idle(timeout).await {
+        tracing::warn!(
+            "[{}] Plazo de drenado agotado con {} tareas sin terminar.",
+            agent,
+            in_flight.len()
+        );
+    }
+    client.flush().await.ok();
+    tracing::info!("[{}] Apagado limpio.", agent);
+    Ok(())
+}
+
+/// Subject en el que los clientes preguntan qué servicios hay; cada agente responde con su `ServiceInfo`.
+pub const DISCOVERY_REQUEST_SUBJECT: &str = "discovery.request";
+/// Subject en el que cada agente se anuncia al arrancar y después periódicamente.
+pub const DISCOVERY_ANNOUNCE_SUBJECT: &str = "discovery.announce";
+/// Intervalo por defecto entre anuncios (`DISCOVERY_ANNOUNCE_SECS`; 0 los desactiva).
+const DEFAULT_DISCOVERY_ANNOUNCE_SECS: u64 = 60;
+
+/// Identidad de un agente en ejecución y los subjects que atiende.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceInfo {
+    pub agent: String,
+    pub subjects: Vec<String>,
+    pub version: String,
+    pub pid: u32,
+    #[serde(default)]
+    pub queue_group: Option<String>,
+    pub started_at_ms: u64,
+}
+
+/// Registra el agente en el descubrimiento de servicios: se anuncia en `discovery.announce`
+/// y responde en `discovery.request` hasta que el proceso termina. Los fallos solo se
+/// registran; el agente sigue funcionando aunque no sea descubrible.
+pub fn spawn_discovery(
+    client: &async_nats::Client,
+    agent: &str,
+    subjects: &[&str],
+) -> tokio::task::JoinHandle<()> {
+    use futures_util::StreamExt;
+
+    let client = client.clone();
+    let info = ServiceInfo {
+        agent: agent.to_string(),
+        subjects: subjects.iter().map(|s| s.to_string()).collect(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        pid: std::process::id(),
+        queue_group: queue_group_from_env(),
+        started_at_ms: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0),
+    };
+    let every = env::var("DISCOVERY_ANNOUNCE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_DISCOVERY_ANNOUNCE_SECS);
+    tokio::spawn(async move {
+        let payload = match serde_json::to_vec(&info) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::warn!(
+                    "[{}] No se pudo serializar el anuncio de descubrimiento: {}",
+                    info.agent,
+                    e
+                );
+                return;
+            }
+        };
+        let mut sub = match client.subscribe(DISCOVERY_REQUEST_SUBJECT).await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::warn!("[{}] Sin descubrimiento de servicios: {}", info.agent, e);
+                return;
+            }
+        };
+        let _ = client
+            .publish(DISCOVERY_ANNOUNCE_SUBJECT, payload.clone().into())
+            .await;
+        let period = Duration::from_secs(every.max(1));
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                _ = ticker.tick(), if every > 0 => {
+                    let _ = client.publish(DISCOVERY_ANNOUNCE_SUBJECT, payload.clone().into()).await;
+                }
+                msg = sub.next() => {
+                    let Some(msg) = msg else {
+                        match resubscribe(&client, &info.agent, DISCOVERY_REQUEST_SUBJECT, None).await {
+                            Ok(s) => sub = s,
+                            Err(e) => {
+                                tracing::warn!("[{}] {:#}", info.agent, e);
+                                return;
+                            }
+                        }
+                        continue;
+                    };
+                    if let Some(reply) = msg.reply {
+                        let _ = client.publish(reply, payload.clone().into()).await;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Pregunta en `discovery.request` qué servicios hay y reúne las respuestas que lleguen
+/// durante `wait` (una por instancia de agente), ordenadas por agente.
+pub async fn discover_services(
+    client: &async_nats::Client,
+    wait: Duration,
+) -> Result<Vec<ServiceInfo>> {
+    use futures_util::StreamExt;
+
+    let inbox = client.new_inbox();
+    let mut sub = client.subscribe(inbox.clone()).await?;
+    client
+        .publish_with_reply(DISCOVERY_REQUEST_SUBJECT, inbox, Vec::new().into())
+        .await?;
+    let mut services = Vec::new();
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            msg = sub.next() => {
+                let Some(msg) = msg else { break };
+                match serde_json::from_slice::<ServiceInfo>(&msg.payload) {
+                    Ok(info) => services.push(info),
+                    Err(e) => tracing::debug!("Respuesta de descubrimiento malformada: {}", e),
+                }
+            }
+        }
+    }
+    services.sort_by(|a, b| a.agent.cmp(&b.agent).then(a.pid.cmp(&b.pid)));
+    Ok(services)
+}
+
+/// Intentos de `resubscribe` antes de dar por perdida la suscripción.
+const RESUBSCRIBE_ATTEMPTS: u32 = 5;
+
+/// Grupo de cola NATS (`NATS_QUEUE_GROUP`) de los agentes que se pueden replicar:
+/// con grupo, cada petición la atiende una sola de las instancias.
+pub fn queue_group_from_env() -> Option<String> {
+    env::var("NATS_QUEUE_GROUP")
+        .ok()
+        .filter(|g| !g.trim().is_empty())
+}
+
+/// Suscribe a `subject` dentro de `group` si se indica; si no, con una suscripción normal.
+pub async fn subscribe_in_group(
+    client: &async_nats::Client,
+    subject: &str,
+    group: Option<&str>,
+) -> Result<async_nats::Subscriber> {
+    let sub = match group {
+        Some(group) => {
+            client
+                .queue_subscribe(subject.to_string(), group.to_string())
+                .await?
+        }
+        None => client.subscribe(subject.to_string()).await?,
+    };
+    Ok(sub)
+}
+
+/// Sustituye una suscripción cuyo stream terminó (`next()` devolvió `None`): registra
+/// qué subject se perdió y vuelve a suscribirse (en el mismo `group`) con espera
+/// creciente entre intentos. Si no lo consigue devuelve error, y el agente termina
+/// en vez de quedarse sordo.
+pub async fn resubscribe(
+    client: &async_nats::Client,
+    agent: &str,
+    subject: &str,
+    group: Option<&str>,
+) -> Result<async_nats::Subscriber> {
+    tracing::warn!(
+        "[{}] La suscripción a '{}' terminó inesperadamente; resuscribiendo...",
+        agent,
+        subject
+    );
+    let mut delay = Duration::from_millis(500);
+    for attempt in 1..=RESUBSCRIBE_ATTEMPTS {
+        match subscribe_in_group(client, subject, group).await {
+            Ok(sub) => {
+                tracing::info!(
+                    "[{}] Suscrito de nuevo a '{}' (intento {}).",
+                    agent,
+                    subject,
+                    attempt
+                );
+                return Ok(sub);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "[{}] Intento {} de resuscripción a '{}' fallido: {}",
+                    agent,
+                    attempt,
+                    subject,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+    Err(anyhow!(
+        "No se pudo recuperar la suscripción a '{}' tras {} intentos",
+        subject,
+        RESUBSCRIBE_ATTEMPTS
+    ))
+}
+
+/// Mensaje devuelto a los clientes cuando piden una ruta fuera de las raíces permitidas.
+/// Es deliberadamente genérico para no revelar si la ruta existe.
+pub const ACCESS_DENIED: &str = "acceso denegado";
+
+/// Prefijo del error del Gateway cuando el modelo pedido no está descargado en Ollama.
+pub const MODEL_NOT_PULLED: &str = "model not pulled";
+
+/// Modelo citado (entre comillas simples) en un error `MODEL_NOT_PULLED`, si lo es.
+pub fn not_pulled_model(error: &str) -> Option<&str> {
+    let rest = &error[error.find(MODEL_NOT_PULLED)? + MODEL_NOT_PULLED.len()..];
+    rest.split('\'').nth(1).filter(|m| !m.is_empty())
+}
+
+/// Raíces permitidas: las rutas de `ALLOWED_ROOTS` (separadas como en `PATH`)
+/// más `DIRECTORY_TO_SCAN` si está definida. Se descartan las que no existen.
+pub fn allowed_roots_from_env() -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = env::var_os("ALLOWED_ROOTS")
+        .map(|v| env::split_paths(&v).collect())
+        .unwrap_or_default();
+    if let Ok(dir) = env::var("DIRECTORY_TO_SCAN") {
+        roots.push(PathBuf::from(dir));
+    }
+    let mut canonical: Vec<PathBuf> = roots
+        .iter()
+        .filter(|r| !r.as_os_str().is_empty())
+        .filter_map(|r| r.canonicalize().ok())
+        .collect();
+    canonical.dedup();
+    canonical
+}
+
+/// Canonicaliza `path` (resolviendo `..` y enlaces) y comprueba que quede dentro
+/// de alguna de `allowed_roots`, que deben estar ya canonicalizadas.
+pub fn validate_path_within_roots(
+    path: impl AsRef<Path>,
+    allowed_roots: &[PathBuf],
+) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("No se pudo resolver la ruta '{}'", path.display()))?;
+    if allowed_roots.iter().any(|root| canonical.starts_with(root)) {
+        Ok(canonical)
+    } else {
+        anyhow::bail!(
+            "La ruta '{}' está fuera de las raíces permitidas",
+            path.display()
+        )
+    }
+}
+
+/// Error de agente para una ruta rechazada por `validate_path_within_roots`: `not_found` si
+/// cae dentro de una raíz permitida pero no existe; `access_denied` en cualquier otro caso,
+/// para no revelar qué existe fuera de las raíces.
+pub fn path_rejection(
+    path: impl AsRef<Path>,
+    allowed_roots: &[PathBuf],
+    err: &anyhow::Error,
+) -> AgentError {
+    let path = path.as_ref();
+    let missing = err
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == std::io::ErrorKind::NotFound);
+    if missing && missing_within_roots(path, allowed_roots) {
+        AgentError::new(
+            ErrorCode::NotFound,
+            format!("No existe '{}'", path.display()),
+        )
+    } else {
+        AgentError::new(ErrorCode::AccessDenied, ACCESS_DENIED)
+    }
+}
+
+/// Resuelve el antepasado existente más cercano de `path` y comprueba que, con el resto de
+/// la ruta, quede dentro de alguna raíz. Un `..` en la parte que no existe se rechaza.
+fn missing_within_roots(path: &Path, allowed_roots: &[PathBuf]) -> bool {
+    for ancestor in path.ancestors().skip(1) {
+        let Ok(base) = ancestor.canonicalize() else {
+            continue;
+        };
+        let Ok(rest) = path.strip_prefix(ancestor) else {
+            return false;
+        };
+        if rest
+            .components()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            return false;
+        }
+        let resolved = base.join(rest);
+        return allowed_roots.iter().any(|root| resolved.starts_with(root));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Carpeta temporal propia de cada prueba; se borra al terminar.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("mafp-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir.canonicalize().unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn rejection(path: &Path, roots: &[PathBuf]) -> ErrorCode {
+        let err = validate_path_within_roots(path, roots).unwrap_err();
+        path_rejection(path, roots, &err).code
+    }
+
+    #[test]
+    fn small_payloads_are_not_split() {
+        assert!(split_payload(b"hola", 1024).is_none());
+        assert!(split_payload(&[0u8; 1024], 1024).is_none());
+    }
+
+    #[test]
+    fn split_payload_round_trips_in_any_order() {
+        let max = CHUNK_MARGIN_BYTES + 1000;
+        let payload: Vec<u8> = (0..25_000u32).map(|i| (i % 251) as u8).collect();
+        let chunks = split_payload(&payload, max).unwrap();
+        assert_eq!(chunks.len(), 25);
+        assert!(chunks.iter().all(|c| c.len() <= max));
+
+        let mut assembler = ChunkAssembler::new(chunks.len(), max).unwrap();
+        let mut result = None;
+        // Llegan desordenadas y con un duplicado, que no debe contar dos veces.
+        assert!(assembler
+            .push(Some(3), chunks[3].to_vec())
+            .unwrap()
+            .is_none());
+        for (i, chunk) in chunks.iter().enumerate().rev() {
+            result = assembler.push(Some(i), chunk.to_vec()).unwrap();
+        }
+        assert_eq!(result.unwrap(), payload);
+    }
+
+    #[test]
+    fn chunk_with_bad_index_is_rejected() {
+        let mut assembler = ChunkAssembler::new(2, 1024 * 1024).unwrap();
+        assert!(assembler.push(Some(2), vec![1]).is_err());
+        assert!(assembler.push(None, vec![1]).is_err());
+    }
+
+    #[test]
+    fn bogus_chunk_total_is_rejected_before_allocating() {
+        let max = 1024 * 1024;
+        let code = |total: usize| {
+            let err = ChunkAssembler::new(total, max)
+                .err()
+                .expect("Chunk-Total aceptado");
+            err.downcast_ref::<AgentError>().map(|e| e.code)
+        };
+        assert_eq!(code(0), Some(ErrorCode::InvalidRequest));
+        assert_eq!(code(usize::MAX), Some(ErrorCode::InvalidRequest));
+        let limit = MAX_CHUNKED_RESPONSE_BYTES.div_ceil(chunk_size(max));
+        assert_eq!(code(limit + 1), Some(ErrorCode::InvalidRequest));
+        assert!(ChunkAssembler::new(limit, max).is_ok());
+    }
+
+    #[test]
+    fn missing_path_inside_root_is_not_found() {
+        let root = TempDir::new("rejection-inside");
+        let roots = vec![root.0.clone()];
+        assert_eq!(
+            rejection(&root.0.join("no-existe.txt"), &roots),
+            ErrorCode::NotFound
+        );
+        assert_eq!(
+            rejection(&root.0.join("falta/tampoco.txt"), &roots),
+            ErrorCode::NotFound
+        );
+    }
+
+    #[test]
+    fn paths_outside_root_are_denied_whether_or_not_they_exist() {
+        let root = TempDir::new("rejection-root");
+        let other = TempDir::new("rejection-other");
+        std::fs::write(other.0.join("existe.txt"), "x").unwrap();
+        let roots = vec![root.0.clone()];
+        assert_eq!(
+            rejection(&other.0.join("existe.txt"), &roots),
+            ErrorCode::AccessDenied
+        );
+        assert_eq!(
+            rejection(&other.0.join("no-existe.txt"), &roots),
+            ErrorCode::AccessDenied
+        );
+        // Un `..` en la parte inexistente podría salir de la raíz.
+        let escape = root.0.join("falta/../../fuera.txt");
+        assert_eq!(rejection(&escape, &roots), ErrorCode::AccessDenied);
+    }
+}
@@ -22,6 +22,38 @@ pub struct McpRequest {
     /// (Opcional) Parámetros de inferencia.
     #[serde(default)]
     pub temperature: Option<f32>,
+    /// (Opcional) Muestreo por núcleo (0.0-1.0).
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// (Opcional) Penalizaciones por frecuencia y por presencia (-2.0 a 2.0). La Responses API
+    /// de OpenAI no las admite y se ignoran.
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// (Opcional) Secuencias en las que el modelo deja de generar (OpenAI y Groq admiten hasta 4).
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    /// (Opcional) Presupuesto total en segundos para la operación completa en el Gateway,
+    /// limitado por `GATEWAY_MAX_DEADLINE_SECS`.
+    #[serde(default)]
+    pub deadline_secs: Option<u64>,
+    /// (Opcional) Exige que el modelo devuelva un objeto JSON válido.
+    #[serde(default)]
+    pub json_mode: Option<bool>,
+    /// (Opcional) Semilla para completions reproducibles (OpenAI, Groq y Ollama).
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// (Opcional) Límite de tokens de la respuesta.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// (Opcional) Prioridad en la cola del Gateway (0-255, mayor se atiende antes; por defecto 128).
+    #[serde(default)]
+    pub priority: Option<u8>,
+    /// (Opcional) Si es `true`, el Gateway descarta los turnos más antiguos (conservando los
+    /// mensajes de sistema y el último turno) para que la conversación quepa en el contexto del modelo.
+    #[serde(default)]
+    pub trim_history: Option<bool>,
 }
 
 /// La respuesta que el LLM Gateway devuelve al agente solicitante.
@@ -32,4 +64,19 @@ pub struct McpResponse {
     /// (Opcional) Información sobre el uso de tokens.
     #[serde(default)]
     pub token_usage: Option<(u32, u32)>, // (prompt_tokens, completion_tokens)
+    /// (Opcional) Coste estimado en USD según la tabla de precios del Gateway; `None` si
+    /// el modelo no tiene precio o el proveedor no informó del uso de tokens.
+    #[serde(default)]
+    pub estimated_cost_usd: Option<f64>,
+}
+
+/// Uso de una completion que el Gateway publica en `llm.usage` tras cada petición atendida.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageReport {
+    pub provider: String,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub estimated_cost_usd: Option<f64>,
 }
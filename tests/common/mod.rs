@@ -0,0 +1,63 @@
+//! Utilidades compartidas por las pruebas que necesitan un `nats-server` real.
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub const READY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Procesos lanzados por la prueba; se matan al terminar, también si la prueba falla.
+pub struct Children(pub Vec<Child>);
+
+impl Drop for Children {
+    fn drop(&mut self) {
+        for child in &mut self.0 {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+fn nats_server_bin() -> Option<String> {
+    let bin = std::env::var("NATS_SERVER_BIN").unwrap_or_else(|_| "nats-server".to_string());
+    let found = Command::new(&bin)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+    found.then_some(bin)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map(|a| a.port())
+        .expect("puerto libre")
+}
+
+/// Arranca un `nats-server` temporal en un puerto libre y devuelve el proceso y su URL.
+/// Falla si no encuentra el binario (en el `PATH` o en `NATS_SERVER_BIN`).
+pub fn start_nats_server() -> (Children, String) {
+    let server = nats_server_bin().expect("nats-server no encontrado en el PATH ni en NATS_SERVER_BIN");
+    let port = free_port();
+    let child = Command::new(server)
+        .args(["-a", "127.0.0.1", "-p", &port.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("nats-server");
+    (Children(vec![child]), format!("nats://127.0.0.1:{port}"))
+}
+
+/// Conecta con el servidor recién lanzado, reintentando mientras arranca.
+pub async fn connect(url: &str) -> async_nats::Client {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        match async_nats::connect(url).await {
+            Ok(client) => return client,
+            Err(e) if Instant::now() > deadline => panic!("nats-server no arrancó: {e}"),
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Contratos de mensajes de extremo a extremo: arranca un `nats-server` temporal, lanza el
+//! explorador y el extractor de metadatos compilados y comprueba sus respuestas.
+//!
+//! Necesita `nats-server` en el `PATH` (o en `NATS_SERVER_BIN`), así que está marcada como
+//! `#[ignore]`: se ejecuta con `cargo test --test message_contracts -- --ignored`, y entonces
+//! falla si no encuentra el servidor.
+
+use multi_agent_file_processor::{
+    compression::{request_file_content, COMPRESSION_THRESHOLD_BYTES},
+    request_chunked, AgentResponse, ErrorCode, FileListResponse, FileMetadata, FileRangeResponse, FileType,
+};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+mod common;
+use common::READY_TIMEOUT;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const SAMPLE: &str = "primera línea\nsegunda línea\ntercera línea\n";
+
+/// Carpeta temporal con los archivos de prueba; se borra al terminar.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+fn spawn_agent(exe: &str, nats_url: &str, root: &Path) -> Child {
+    Command::new(exe)
+        // Sin .env en la carpeta temporal: solo cuenta la configuración de la prueba.
+        .current_dir(root)
+        .env("NATS_URL", nats_url)
+        .env("DIRECTORY_TO_SCAN", root)
+        .env("ALLOWED_ROOTS", root)
+        .env_remove("NATS_QUEUE_GROUP")
+        .env_remove("AGENT_RESPONSE_LEGACY")
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("No se pudo lanzar {exe}: {e}"))
+}
+
+async fn request<T: DeserializeOwned>(client: &async_nats::Client, subject: &str, body: serde_json::Value) -> AgentResponse<T> {
+    let payload = serde_json::to_vec(&body).unwrap();
+    let reply = request_chunked(client, subject, payload, REQUEST_TIMEOUT)
+        .await
+        .unwrap_or_else(|e| panic!("{subject}: {e}"));
+    let raw: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+    assert_eq!(raw["version"], 1, "{subject}: respuesta sin versión: {raw}");
+    serde_json::from_value(raw).unwrap_or_else(|e| panic!("{subject}: respuesta inválida: {e}"))
+}
+
+/// Espera a que el agente atienda `subject` (sin respondedores la petición falla al instante).
+async fn wait_for(client: &async_nats::Client, subject: &str, body: &serde_json::Value) {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    let payload = serde_json::to_vec(body).unwrap();
+    while client.request(subject.to_string(), payload.clone().into()).await.is_err() {
+        assert!(Instant::now() < deadline, "'{subject}' no respondió a tiempo");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+fn expect_error<T: std::fmt::Debug>(response: AgentResponse<T>, code: ErrorCode) {
+    match response {
+        AgentResponse::Error(e) => assert_eq!(e.code, code, "{}", e.message),
+        AgentResponse::Success(v) => panic!("se esperaba {code:?} y llegó {v:?}"),
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires nats-server"]
+async fn explorer_and_metadata_round_trip() {
+    let root = std::env::temp_dir().join(format!("mafp-contracts-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let _cleanup = TempDir(root.clone());
+    let root = root.canonicalize().unwrap();
+    let sample = root.join("muestra.txt");
+    std::fs::write(&sample, SAMPLE).unwrap();
+    let sample = sample.to_string_lossy().to_string();
+
+    let (mut children, url) = common::start_nats_server();
+    let client = common::connect(&url).await;
+    children.0.push(spawn_agent(env!("CARGO_BIN_EXE_file_explorer"), &url, &root));
+    children.0.push(spawn_agent(env!("CARGO_BIN_EXE_metadata_extractor"), &url, &root));
+
+    let probe = serde_json::json!({ "path": sample });
+    wait_for(&client, "metadata.request", &probe).await;
+    wait_for(&client, "file.request.content", &probe).await;
+
+    // files.list.request: explícito y con el directorio por defecto (DIRECTORY_TO_SCAN)
+    for body in [serde_json::json!({ "directory": root }), serde_json::json!({})] {
+        match request::<FileListResponse>(&client, "files.list.request", body).await {
+            AgentResponse::Success(listing) => {
+                assert!(listing.files.iter().any(|f| f.name == "muestra.txt" && f.path == sample), "{listing:?}");
+                assert!(listing.errors.is_empty(), "{:?}", listing.errors);
+            }
+            AgentResponse::Error(e) => panic!("files.list.request: {e}"),
+        }
+    }
+    expect_error(
+        request::<FileListResponse>(&client, "files.list.request", serde_json::json!({ "directory": "/" })).await,
+        ErrorCode::AccessDenied,
+    );
+
+    // metadata.request
+    let body = serde_json::json!({ "path": sample, "text_stats": true });
+    match request::<FileMetadata>(&client, "metadata.request", body).await {
+        AgentResponse::Success(meta) => {
+            assert_eq!(meta.file_type, FileType::File);
+            assert_eq!(meta.len_bytes, SAMPLE.len() as u64);
+            assert_eq!(meta.line_count, Some(3));
+        }
+        AgentResponse::Error(e) => panic!("metadata.request: {e}"),
+    }
+    let outside = serde_json::json!({ "path": std::env::current_exe().unwrap() });
+    expect_error(request::<FileMetadata>(&client, "metadata.request", outside.clone()).await, ErrorCode::AccessDenied);
+    let missing = serde_json::json!({ "path": root.join("no-existe.txt") });
+    expect_error(request::<FileMetadata>(&client, "metadata.request", missing.clone()).await, ErrorCode::NotFound);
+
+    // file.request.content
+    match request::<String>(&client, "file.request.content", serde_json::json!({ "path": sample })).await {
+        AgentResponse::Success(text) => assert_eq!(text, SAMPLE),
+        AgentResponse::Error(e) => panic!("file.request.content: {e}"),
+    }
+    expect_error(request::<String>(&client, "file.request.content", outside).await, ErrorCode::AccessDenied);
+    expect_error(request::<String>(&client, "file.request.content", missing).await, ErrorCode::NotFound);
+
+    // file.request.content con compresión negociada: el cliente recibe el texto original.
+    let big = root.join("grande.txt");
+    let big_text = SAMPLE.repeat(COMPRESSION_THRESHOLD_BYTES / SAMPLE.len() + 1);
+    std::fs::write(&big, &big_text).unwrap();
+    let fetched = request_file_content(&client, &big.to_string_lossy(), REQUEST_TIMEOUT).await.unwrap();
+    assert_eq!(fetched, big_text);
+
+    // file.request.range
+    let body = serde_json::json!({ "path": sample, "tail_bytes": 15 });
+    match request::<FileRangeResponse>(&client, "file.request.range", body).await {
+        AgentResponse::Success(range) => {
+            assert_eq!(range.total_len, SAMPLE.len() as u64);
+            assert_eq!(range.offset, SAMPLE.len() as u64 - 15);
+            assert_eq!(range.content, "tercera línea\n");
+        }
+        AgentResponse::Error(e) => panic!("file.request.range: {e}"),
+    }
+}
@@ -0,0 +1,64 @@
+//! Medida de la rotación de suscripciones en peticiones NATS: compara peticiones por el
+//! inbox multiplexado del cliente (`request_with_timeout`, lo que usa el summarizer con el
+//! gateway) con las de `request_chunked`, que abre y cierra una suscripción en cada una.
+//!
+//! Necesita `nats-server`, así que está marcada como `#[ignore]`:
+//! `cargo test --release --test request_churn -- --ignored --nocapture`.
+
+use futures_util::StreamExt;
+use multi_agent_file_processor::{request_chunked, request_with_timeout};
+use std::time::{Duration, Instant};
+
+mod common;
+
+const REQUESTS: usize = 2_000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const SUBJECT: &str = "bench.echo";
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires nats-server"]
+async fn multiplexed_inbox_vs_per_request_subscription() {
+    let (_server, url) = common::start_nats_server();
+    let client = common::connect(&url).await;
+
+    // Respondedor de eco en una conexión aparte, como el LLM Gateway.
+    let responder = common::connect(&url).await;
+    let mut sub = responder.subscribe(SUBJECT).await.unwrap();
+    responder.flush().await.unwrap();
+    tokio::spawn(async move {
+        while let Some(msg) = sub.next().await {
+            if let Some(reply) = msg.reply {
+                responder.publish(reply, msg.payload).await.ok();
+            }
+        }
+    });
+
+    let payload = vec![b'x'; 256];
+    // Calentamiento: crea el inbox multiplexado y estabiliza las conexiones.
+    for _ in 0..100 {
+        request_with_timeout(&client, SUBJECT, payload.clone(), REQUEST_TIMEOUT).await.unwrap();
+    }
+
+    let started = Instant::now();
+    for _ in 0..REQUESTS {
+        let msg = request_with_timeout(&client, SUBJECT, payload.clone(), REQUEST_TIMEOUT).await.unwrap();
+        assert_eq!(msg.payload.len(), payload.len());
+    }
+    let multiplexed = started.elapsed();
+
+    let started = Instant::now();
+    for _ in 0..REQUESTS {
+        let reply = request_chunked(&client, SUBJECT, payload.clone(), REQUEST_TIMEOUT).await.unwrap();
+        assert_eq!(reply.len(), payload.len());
+    }
+    let per_request = started.elapsed();
+
+    let rate = |d: Duration| REQUESTS as f64 / d.as_secs_f64();
+    eprintln!(
+        "{REQUESTS} peticiones: inbox multiplexado {:?} ({:.0} req/s); suscripción por petición {:?} ({:.0} req/s)",
+        multiplexed,
+        rate(multiplexed),
+        per_request,
+        rate(per_request),
+    );
+}